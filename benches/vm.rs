@@ -0,0 +1,43 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use zaia::{
+    engine::{gc::Heap, vm::VM},
+    parser::{machinery::cstree::NodeCache, parse, syntax::Root},
+};
+
+const LOOPS: &str = "
+    local s = 0
+    for i = 1, 100000 do
+        s = s + i % 7
+    end
+
+    local t = {}
+    for i = 1, 10000 do
+        t[i] = i * 2
+    end
+
+    local function fib(n)
+        if n < 2 then return n end
+        return fib(n - 1) + fib(n - 2)
+    end
+
+    return s + fib(15)
+";
+
+fn criterion_benchmark(c: &mut Criterion) {
+    let mut cache = NodeCache::new();
+    let (tree, _) = parse(&mut cache, LOOPS);
+    let root = Root::cast(&tree).unwrap();
+
+    let mut group = c.benchmark_group("vm");
+    group.bench_function("loops", |b| {
+        b.iter(|| {
+            let mut vm = VM::new(Heap::new());
+            assert!(vm.eval(&root, cache.interner()).is_ok());
+        });
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, criterion_benchmark);
+criterion_main!(benches);
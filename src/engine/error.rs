@@ -1,4 +1,198 @@
+use std::fmt::{self, Display};
+
+use super::value::Value;
+use crate::parser::machinery::{diagnostic::Diagnostic, source_map::SourceMap, span::Span};
+
 pub enum Error {
-    UncaughtBreak,
-    UncaughtReturn,
+    InvalidLiteral(Diagnostic),
+    /// A construct that exceeds a limit of the bytecode, such as the number of
+    /// registers of a function.
+    Compile(Diagnostic),
+    Runtime(RuntimeError),
+    /// A limit of the call was hit while its limits are uncatchable. Unlike
+    /// runtime errors, neither `pcall` nor coroutines catch it.
+    Limit(RuntimeError),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidLiteral(diagnostic) | Self::Compile(diagnostic) => diagnostic.fmt(f),
+            Self::Runtime(error) | Self::Limit(error) => error.fmt(f),
+        }
+    }
+}
+
+/// The ways a primitive operation on values can fail.
+///
+/// Type names are those reported by Lua's `type` function.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpError {
+    Arithmetic(&'static str),
+    Bitwise(&'static str),
+    NoIntegerRepresentation,
+    DivideByZero,
+    ModuloByZero,
+    Compare(&'static str, &'static str),
+    Concat(&'static str),
+    Length(&'static str),
+    Index(&'static str),
+    IndexNil,
+    IndexNaN,
+    Call(&'static str),
+}
+
+impl Display for OpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Arithmetic(ty) => write!(f, "attempt to perform arithmetic on a {} value", ty),
+            Self::Bitwise(ty) =>
+                write!(f, "attempt to perform bitwise operation on a {} value", ty),
+            Self::NoIntegerRepresentation => f.write_str("number has no integer representation"),
+            Self::DivideByZero => f.write_str("attempt to perform 'n//0'"),
+            Self::ModuloByZero => f.write_str("attempt to perform 'n%0'"),
+            Self::Compare(a, b) if a == b => write!(f, "attempt to compare two {} values", a),
+            Self::Compare(a, b) => write!(f, "attempt to compare {} with {}", a, b),
+            Self::Concat(ty) => write!(f, "attempt to concatenate a {} value", ty),
+            Self::Length(ty) => write!(f, "attempt to get length of a {} value", ty),
+            Self::Index(ty) => write!(f, "attempt to index a {} value", ty),
+            Self::IndexNil => f.write_str("index is nil"),
+            Self::IndexNaN => f.write_str("index is NaN"),
+            Self::Call(ty) => write!(f, "attempt to call a {} value", ty),
+        }
+    }
+}
+
+/// An error raised while running a script, either by a failing operation or
+/// by a call to `error`.
+///
+/// Spans are offsets into the syntax tree, use a [`SourceMap`] to relate them
+/// to the original source.
+pub struct RuntimeError {
+    message: String,
+    value: Value,
+    span: Option<Span>,
+    traceback: Vec<TraceFrame>,
+}
+
+/// One activation on the Lua stack at the time an error was raised, innermost
+/// first.
+pub struct TraceFrame {
+    /// The name the function was called by, if known. The outermost frame
+    /// is always the main chunk.
+    pub function: Option<String>,
+    /// The expression being evaluated in the frame, `None` for native
+    /// functions.
+    pub span: Option<Span>,
+    /// Whether the function was entered through tail calls, whose frames it
+    /// replaced.
+    pub tail_calls: bool,
+}
+
+impl RuntimeError {
+    pub fn new(message: String, value: Value) -> Self {
+        Self {
+            message,
+            value,
+            span: None,
+            traceback: Vec::new(),
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    /// The value the error was raised with, as caught by `pcall`.
+    pub fn value(&self) -> Value {
+        self.value
+    }
+
+    /// The span of the expression that raised the error.
+    pub fn span(&self) -> Option<Span> {
+        self.span
+    }
+
+    pub fn traceback(&self) -> &[TraceFrame] {
+        &self.traceback
+    }
+
+    // Records where the error was raised if no location is known yet.
+    pub(crate) fn locate(&mut self, span: Span) {
+        if self.traceback.is_empty() {
+            self.span = Some(span);
+            self.traceback.push(TraceFrame {
+                function: None,
+                span: Some(span),
+                tail_calls: false,
+            });
+        }
+    }
+
+    // Records that the innermost frame recorded so far was tail called.
+    pub(crate) fn tail_called(&mut self) {
+        if let Some(frame) = self.traceback.last_mut() {
+            frame.tail_calls = true;
+        }
+    }
+
+    // Records that the error propagated out of a call made at `span`.
+    pub(crate) fn unwind(&mut self, function: Option<String>, span: Span) {
+        match self.traceback.last_mut() {
+            Some(frame) => frame.function = function,
+            None => self.traceback.push(TraceFrame {
+                function,
+                span: None,
+                tail_calls: false,
+            }),
+        }
+
+        if self.span.is_none() {
+            self.span = Some(span);
+        }
+
+        self.traceback.push(TraceFrame {
+            function: None,
+            span: Some(span),
+            tail_calls: false,
+        });
+    }
+
+    /// Renders the error with its traceback against the source it was raised
+    /// from.
+    pub fn report(&self, map: &SourceMap) -> ariadne::Report<Span> {
+        let span = self.span.map_or(Span::new(0, 0), |span| map.span(span));
+        let mut traceback = String::from("stack traceback:");
+
+        for (i, frame) in self.traceback.iter().enumerate() {
+            let location = match frame.span {
+                Some(span) => map.line(map.offset(span.start())).to_string(),
+                None => String::from("[C]"),
+            };
+
+            let function = match &frame.function {
+                _ if i + 1 == self.traceback.len() && !frame.tail_calls =>
+                    String::from("main chunk"),
+                Some(name) => format!("function '{}'", name),
+                None => String::from("?"),
+            };
+
+            traceback.push_str(&format!("\n    {}: in {}", location, function));
+            if frame.tail_calls {
+                traceback.push_str("\n    (...tail calls...)");
+            }
+        }
+
+        ariadne::Report::build(ariadne::ReportKind::Error, (), span.start() as usize)
+            .with_message(&self.message)
+            .with_label(ariadne::Label::new(span).with_message(&self.message))
+            .with_note(traceback)
+            .finish()
+    }
+}
+
+impl Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
 }
@@ -1,50 +1,149 @@
 use std::cell::Cell;
 
+use super::Generational;
+
 const INITIAL_THRESHOLD: usize = 128 * 1024;
 const THRESHOLD_FACTOR: f32 = 1.75;
 
+/// The number of bytes allocated during a cycle after which the next step
+/// is due.
+pub const STEP_SIZE: usize = 8 * 1024;
+
+/// The number of bytes allocated for each object a step traces or sweeps.
+/// Objects take up more than this, so cycles finish ahead of allocation.
+pub const BYTES_PER_WORK: usize = 32;
+
+/// The number of objects a step traces or sweeps at the least.
+pub const MIN_STEP_WORK: usize = 256;
+
+/// Allocation statistics of a heap.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// The bytes in use.
+    pub allocated: usize,
+    /// The bytes allocated over the life of the heap.
+    pub total_allocated: usize,
+    /// The bytes freed over the life of the heap.
+    pub total_freed: usize,
+    /// The bytes in use after the last full cycle or major collection.
+    pub live: usize,
+    /// The number of full cycles, major collections included.
+    pub cycles: usize,
+    pub minor_collections: usize,
+    /// The number of objects that became old.
+    pub promoted: usize,
+}
+
+/// Decides when the heap collects and keeps its statistics.
 pub struct Heuristics {
     allocated: Cell<usize>,
     threshold: Cell<usize>,
     should_collect: Cell<bool>,
+    // The bytes allocated since the last step or minor collection.
+    debt: Cell<usize>,
+    stats: Cell<Stats>,
 }
 
 impl Heuristics {
-    pub fn new() -> Self {
+    pub(super) fn new() -> Self {
         Self {
             allocated: Cell::new(0),
             threshold: Cell::new(INITIAL_THRESHOLD),
             should_collect: Cell::new(false),
+            debt: Cell::new(0),
+            stats: Cell::new(Stats::default()),
         }
     }
 
-    fn threshold(&self) -> usize {
-        (self.threshold.get() as f32 * THRESHOLD_FACTOR) as usize
+    // The next cycle is due once the heap outgrew what survived the last one.
+    pub(super) fn adjust(&self) {
+        let live = self.allocated.get();
+        let new_threshold = (live as f32 * THRESHOLD_FACTOR) as usize;
+        self.threshold.set(new_threshold.max(INITIAL_THRESHOLD));
+        self.should_collect.set(false);
+        self.debt.set(0);
+
+        let mut stats = self.stats.get();
+        stats.live = live;
+        stats.cycles += 1;
+        self.stats.set(stats);
+    }
+
+    pub(super) fn adjust_minor(&self, promoted: usize) {
+        self.debt.set(0);
+
+        let mut stats = self.stats.get();
+        stats.minor_collections += 1;
+        stats.promoted += promoted;
+        self.stats.set(stats);
     }
 
-    pub fn adjust(&self) {
-        let new_threshold = self.threshold();
-        self.threshold.set(new_threshold);
-        self.should_collect.set(false);
+    pub(super) fn promote(&self) {
+        let mut stats = self.stats.get();
+        stats.promoted += 1;
+        self.stats.set(stats);
     }
 
     fn check_collect(&self) {
         if self.allocated >= self.threshold {
             self.should_collect.set(true);
-            let new_threshold = self.threshold();
-            self.threshold.set(new_threshold);
         }
     }
 
-    pub fn update_allocated<F>(&self, f: F)
+    pub(super) fn update_allocated<F>(&self, f: F)
     where
         F: FnOnce(usize) -> usize,
     {
+        let before = self.allocated.get();
         self.allocated.update(f);
+        let after = self.allocated.get();
+
+        let mut stats = self.stats.get();
+        if after > before {
+            self.debt.update(|debt| debt + after - before);
+            stats.total_allocated += after - before;
+        } else {
+            stats.total_freed += before - after;
+        }
+
+        stats.allocated = after;
+        self.stats.set(stats);
         self.check_collect();
     }
 
+    pub fn stats(&self) -> Stats {
+        self.stats.get()
+    }
+
+    pub fn debt(&self) -> usize {
+        self.debt.get()
+    }
+
+    /// The number of objects the next step traces or sweeps, paying off the
+    /// debt.
+    pub(super) fn step_work(&self) -> usize {
+        let debt = self.debt.replace(0);
+        (debt / BYTES_PER_WORK).max(MIN_STEP_WORK)
+    }
+
     pub fn should_collect(&self) -> bool {
         self.should_collect.get()
     }
+
+    // The heap after the last major collection, which the generational
+    // multipliers are relative to.
+    fn base(&self) -> usize {
+        self.stats.get().live.max(INITIAL_THRESHOLD)
+    }
+
+    /// Whether a minor collection is due in generational mode.
+    pub fn should_collect_minor(&self, params: &Generational) -> bool {
+        self.debt.get() * 100 >= self.base() * params.minor_multiplier
+    }
+
+    /// Whether the heap grew enough since the last major collection for the
+    /// next collection to be a major one.
+    pub fn should_collect_major(&self, params: &Generational) -> bool {
+        self.allocated.get() * 100 >= self.base() * (100 + params.major_multiplier)
+    }
 }
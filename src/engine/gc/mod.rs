@@ -1,16 +1,78 @@
+//! An incremental tri-color mark and sweep collector.
+//!
+//! A cycle marks the roots gray and then traces the gray worklist, turning
+//! the objects it is done with black. Steps do this a bounded number of
+//! objects at a time, sized by the bytes allocated since the last step, so
+//! the program runs in between. Stores of references into objects that were
+//! already traced go through write barriers, which queue the object or the
+//! stored reference again so that marking does not miss it. Once the
+//! worklist is empty the roots and coroutines are traced again in one go and
+//! the cycle sweeps the white objects, again a bounded number at a time.
+//!
+//! In generational mode the heap instead runs minor collections, which only
+//! trace and sweep the young objects. Objects that survive enough of them are
+//! promoted to old and only collected by major collections, which are full
+//! cycles run once the heap grew enough since the last one. Old objects that
+//! may refer to young ones are remembered: the write barriers remember the
+//! old objects stored into, and promoted objects are remembered as their
+//! references may still be young. Minor collections trace the remembered
+//! objects along with the roots.
+
 mod handle;
 mod heuristics;
 mod set;
 mod trace;
 
-use std::{alloc, cell::RefCell, ptr, rc::Rc};
+use std::{
+    alloc,
+    cell::{Cell, RefCell},
+    mem,
+    ptr,
+    rc::Rc,
+};
 
 pub use handle::{Handle, PtrTag, TaggedHandle};
-use heuristics::Heuristics;
-use set::ObjectSet;
+use heuristics::STEP_SIZE;
+pub use heuristics::{Heuristics, Stats};
+use set::{Age, Color};
 pub use trace::{Trace, Visitor};
 
-use super::value::{encoding, ByteString, Function, Table, Userdata};
+use super::value::{encoding, BoxedInt, ByteString, Coroutine, Function, Table, Userdata};
+
+/// How a heap collects.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    /// Full cycles run in steps.
+    Incremental,
+    /// Minor collections of the young objects, with a major collection once
+    /// the heap outgrew the last one.
+    Generational(Generational),
+}
+
+/// The parameters of generational mode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Generational {
+    /// The number of minor collections an object survives before it is
+    /// promoted to old, at least one.
+    pub promote_after: u8,
+    /// How much the heap must have grown since the last major collection, in
+    /// percent, for the next collection to be a major one.
+    pub major_multiplier: usize,
+    /// How much must have been allocated since the last collection, in
+    /// percent of the heap after the last major collection, for a minor
+    /// collection to be due.
+    pub minor_multiplier: usize,
+}
+
+impl Default for Generational {
+    fn default() -> Self {
+        Generational {
+            promote_after: 2,
+            major_multiplier: 100,
+            minor_multiplier: 20,
+        }
+    }
+}
 
 pub struct Heap {
     internal: Rc<HeapInternal>,
@@ -41,6 +103,7 @@ impl Heap {
         self.internal.destroy(handle);
     }
 
+    /// Runs a full cycle, finishing the one in progress first.
     pub fn collect<F1, F2>(&self, trace: F1, finalize: F2)
     where
         F1: FnOnce(&mut Visitor),
@@ -49,9 +112,145 @@ impl Heap {
         self.internal.collect(trace, finalize);
     }
 
+    /// Runs a step of the current cycle, starting one if none is in progress.
+    /// `trace` marks the roots when the cycle starts and once more before it
+    /// sweeps. Returns whether the step finished the cycle.
+    ///
+    /// In generational mode a step is a whole minor or major collection.
+    pub fn step<F1, F2>(&self, trace: F1, finalize: F2) -> bool
+    where
+        F1: FnMut(&mut Visitor),
+        F2: FnMut(TaggedHandle),
+    {
+        self.internal.step(trace, finalize)
+    }
+
     pub fn should_collect(&self) -> bool {
         self.internal.heuristics.should_collect()
     }
+
+    /// Whether a step is due, either to start a cycle or to continue the one
+    /// in progress.
+    pub fn should_step(&self) -> bool {
+        let heuristics = &self.internal.heuristics;
+        match (self.internal.mode.get(), self.internal.phase.get()) {
+            (Mode::Generational(params), _) => heuristics.should_collect_minor(&params),
+            (Mode::Incremental, Phase::Idle) => heuristics.should_collect(),
+            (Mode::Incremental, _) => heuristics.debt() >= STEP_SIZE,
+        }
+    }
+
+    /// Marks an object for finalization. Once it is unreachable, the cycle
+    /// finding out queues it instead of destroying it, keeping it alive until
+    /// it was popped off the queue with [`Heap::pop_finalizer`] and a cycle
+    /// found it unreachable again.
+    pub fn set_finalizer(&self, handle: TaggedHandle) {
+        self.internal
+            .tree
+            .borrow_mut()
+            .visitor
+            .set_finalizer(handle);
+    }
+
+    /// The next object in the queue of objects to finalize.
+    pub fn pop_finalizer(&self) -> Option<TaggedHandle> {
+        self.internal.tree.borrow_mut().visitor.pop_finalizer()
+    }
+
+    /// Queues every object marked for finalization, reachable or not.
+    pub fn finalize_all(&self) {
+        self.internal.tree.borrow_mut().visitor.finalize_all();
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.internal.mode.get()
+    }
+
+    /// Switches the heap to another mode, running a full cycle that finishes
+    /// the one in progress. Entering generational mode makes every object
+    /// that survives the cycle old. Objects are promoted after one minor
+    /// collection at the earliest.
+    pub fn set_mode<F1, F2>(&self, mut mode: Mode, trace: F1, finalize: F2)
+    where
+        F1: FnOnce(&mut Visitor),
+        F2: FnMut(TaggedHandle),
+    {
+        // Promoting objects as they are allocated would leave nothing for
+        // minor collections to collect.
+        if let Mode::Generational(params) = &mut mode {
+            params.promote_after = params.promote_after.max(1);
+        }
+
+        self.internal.mode.set(mode);
+        self.internal.collect(trace, finalize);
+    }
+
+    /// Keeps an object found again through a reference the collector does
+    /// not trace, such as the table of interned strings, from being swept
+    /// by the cycle in progress.
+    pub fn revive(&self, handle: TaggedHandle) {
+        let internal = &*self.internal;
+        if internal.phase.get() != Phase::Sweep {
+            return;
+        }
+
+        // Objects not swept yet are white if the cycle did not reach them.
+        let mut tree = internal.tree.borrow_mut();
+        let cursor = tree.cursor;
+        let objects = tree.visitor.objects();
+        if let Some(position) = objects.position(handle) {
+            if position >= cursor {
+                objects.get_mut(position).color = Color::Black;
+            }
+        }
+    }
+
+    pub fn heuristics(&self) -> &Heuristics {
+        &self.internal.heuristics
+    }
+
+    pub fn is_collecting(&self) -> bool {
+        self.internal.phase.get() != Phase::Idle
+    }
+
+    /// The write barrier for stores into places the collector does not track,
+    /// such as upvalues: keeps the stored object from being missed by the
+    /// cycle in progress.
+    ///
+    /// In generational mode a young object stored this way is promoted and
+    /// remembered, as the place may belong to an old object.
+    pub fn barrier(&self, handle: TaggedHandle) {
+        let internal = &*self.internal;
+        match (internal.mode.get(), internal.phase.get()) {
+            (Mode::Generational(params), _) => {
+                let mut tree = internal.tree.borrow_mut();
+                if let Some(Age::Young(_)) = tree.visitor.objects().age(handle) {
+                    tree.remember(handle, params.promote_after);
+                    internal.heuristics.promote();
+                }
+            },
+            (Mode::Incremental, Phase::Mark) => internal.tree.borrow_mut().visitor.mark(handle),
+            _ => (),
+        }
+    }
+
+    /// The write barrier for stores into `object`: if the cycle in progress
+    /// already traced it, it is traced again. In generational mode old
+    /// objects are remembered.
+    pub fn barrier_back(&self, object: TaggedHandle) {
+        let internal = &*self.internal;
+        match (internal.mode.get(), internal.phase.get()) {
+            (Mode::Generational(params), _) => {
+                let mut tree = internal.tree.borrow_mut();
+                if let Some(Age::Old | Age::Touched(_)) = tree.visitor.objects().age(object) {
+                    tree.remember(object, params.promote_after);
+                }
+            },
+            (Mode::Incremental, Phase::Mark) =>
+                internal.tree.borrow_mut().visitor.mark_again(object),
+            _ => (),
+        }
+    }
 }
 
 impl Clone for Heap {
@@ -62,57 +261,212 @@ impl Clone for Heap {
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Phase {
+    Idle,
+    Mark,
+    Sweep,
+}
+
 struct Tree {
-    objects: ObjectSet,
     visitor: Visitor,
+    // The position of the next object to sweep.
+    cursor: usize,
+    // The young objects in generational mode.
+    young: Vec<TaggedHandle>,
+    // The remembered objects in generational mode, which are touched.
+    remembered: Vec<TaggedHandle>,
 }
 
 impl Tree {
-    fn collect<F1, F2>(&mut self, heap: &HeapInternal, trace: F1, mut finalize: F2)
+    // Destroys white objects and turns the others white until the sweep is
+    // done or `work` runs out, returning whether the sweep is done. In
+    // generational mode the objects left are old, and the coroutines among
+    // them remembered for good as their stacks change without barriers.
+    fn sweep<F>(
+        &mut self,
+        heap: &HeapInternal,
+        work: &mut usize,
+        finalize: &mut F,
+        generational: bool,
+    ) -> bool
+    where
+        F: FnMut(TaggedHandle),
+    {
+        let objects = self.visitor.objects();
+        while *work > 0 {
+            let entry = match objects.get(self.cursor) {
+                Some(entry) => entry,
+                None => return true,
+            };
+
+            if entry.color == Color::White {
+                finalize(entry.handle);
+                objects.remove_at(self.cursor);
+
+                unsafe {
+                    heap.destroy(entry.handle);
+                }
+            } else {
+                let survivor = objects.get_mut(self.cursor);
+                survivor.color = Color::White;
+                survivor.age = Age::Old;
+                if generational && encoding::is_thread(entry.handle.value()) {
+                    survivor.age = Age::Touched(0);
+                    self.remembered.push(entry.handle);
+                }
+
+                self.cursor += 1;
+            }
+
+            *work -= 1;
+        }
+
+        self.cursor >= objects.len()
+    }
+
+    // Remembers an old object for the next `count` minor collections.
+    fn remember(&mut self, handle: TaggedHandle, count: u8) {
+        let objects = self.visitor.objects();
+        if let Some(Age::Touched(_)) = objects.age(handle) {
+            if !encoding::is_thread(handle.value()) {
+                objects.set_age(handle, Age::Touched(count));
+            }
+
+            return;
+        }
+
+        objects.set_age(handle, Age::Touched(count));
+        self.remembered.push(handle);
+    }
+
+    // Traces the young objects reachable from the roots and the remembered
+    // objects, then destroys the unreachable ones and ages the others.
+    // Returns the number of objects promoted.
+    fn minor<F1, F2>(
+        &mut self,
+        heap: &HeapInternal,
+        trace: F1,
+        finalize: &mut F2,
+        params: &Generational,
+    ) -> usize
     where
         F1: FnOnce(&mut Visitor),
         F2: FnMut(TaggedHandle),
     {
+        self.visitor.set_minor(true);
         trace(&mut self.visitor);
+        for handle in &self.remembered {
+            self.visitor.mark_remembered(*handle);
+        }
 
-        for object in self.visitor.unmarked(&self.objects) {
-            finalize(object);
-            self.objects.remove(object);
+        self.visitor.finish();
+        self.visitor.set_minor(false);
 
-            unsafe {
-                heap.destroy(object);
+        // Remembered objects are forgotten once their references had the
+        // time to be promoted, except for coroutines.
+        let objects = self.visitor.objects();
+        self.remembered.retain(|handle| {
+            objects.set_color(*handle, Color::White);
+            match objects.age(*handle) {
+                _ if encoding::is_thread(handle.value()) => true,
+                Some(Age::Touched(count)) if count > 1 => {
+                    objects.set_age(*handle, Age::Touched(count - 1));
+                    true
+                },
+                _ => {
+                    objects.set_age(*handle, Age::Old);
+                    false
+                },
+            }
+        });
+
+        let mut promoted = 0;
+        for handle in mem::take(&mut self.young) {
+            let objects = self.visitor.objects();
+            let position = objects.position(handle).unwrap();
+            let entry = objects.get_mut(position);
+            let age = match entry.age {
+                // Promoted by a barrier, so already remembered.
+                Age::Old | Age::Touched(_) => {
+                    entry.color = Color::White;
+                    continue;
+                },
+                Age::Young(age) => age + 1,
+            };
+
+            if entry.color == Color::White {
+                finalize(handle);
+                objects.remove_at(position);
+
+                unsafe {
+                    heap.destroy(handle);
+                }
+            } else if age >= params.promote_after {
+                entry.color = Color::White;
+                self.remember(handle, params.promote_after);
+                promoted += 1;
+            } else {
+                entry.color = Color::White;
+                entry.age = Age::Young(age);
+                self.young.push(handle);
             }
         }
 
-        self.visitor.reset();
+        promoted
     }
 }
 
 struct HeapInternal {
     heuristics: Heuristics,
+    mode: Cell<Mode>,
+    phase: Cell<Phase>,
     tree: RefCell<Tree>,
 }
 
 impl HeapInternal {
     fn new() -> Self {
         let tree = RefCell::new(Tree {
-            objects: ObjectSet::new(),
             visitor: Visitor::new(),
+            cursor: 0,
+            young: Vec::new(),
+            remembered: Vec::new(),
         });
 
         Self {
             heuristics: Heuristics::new(),
+            mode: Cell::new(Mode::Incremental),
+            phase: Cell::new(Phase::Idle),
             tree,
         }
     }
 
+    fn generational(&self) -> bool {
+        matches!(self.mode.get(), Mode::Generational(_))
+    }
+
+    // Objects allocated while a cycle sweeps are black so that it does not
+    // destroy them, the sweep turns them white when it gets to them.
+    fn track(&self, handle: TaggedHandle) {
+        let color = match self.phase.get() {
+            Phase::Sweep => Color::Black,
+            _ => Color::White,
+        };
+
+        let mut tree = self.tree.borrow_mut();
+        tree.visitor.objects().insert(handle, color);
+        if self.generational() {
+            tree.young.push(handle);
+        }
+    }
+
     fn insert<T>(&self, value: T) -> Handle<T>
     where
         T: PtrTag,
     {
         let ptr = Box::into_raw(Box::new_in(value, self));
         let handle = Handle::new(ptr);
-        self.tree.borrow_mut().objects.insert(handle.tagged());
+        self.track(handle.tagged());
         handle
     }
 
@@ -125,7 +479,7 @@ impl HeapInternal {
             ByteString::initialize_into(ptr, len);
             ptr::copy_nonoverlapping(bytes.as_ptr(), (&mut *ptr).offset(0), len as usize);
             let handle = Handle::new(ptr);
-            self.tree.borrow_mut().objects.insert(handle.tagged());
+            self.track(handle.tagged());
             handle
         }
     }
@@ -153,18 +507,94 @@ impl HeapInternal {
                 let ptr = encoding::get_userdata(tagged) as *mut Userdata;
                 Box::from_raw_in(ptr, self);
             },
+            _ if encoding::is_boxed_int(tagged) => {
+                let ptr = encoding::get_boxed_int(tagged) as *mut BoxedInt;
+                Box::from_raw_in(ptr, self);
+            },
+            _ if encoding::is_thread(tagged) => {
+                let ptr = encoding::get_thread(tagged) as *mut Coroutine;
+                Box::from_raw_in(ptr, self);
+            },
             _ => panic!("unknown pointer type {:b}", tagged),
         }
     }
 
-    fn collect<F1, F2>(&self, trace: F1, finalize: F2)
+    fn collect<F1, F2>(&self, trace: F1, mut finalize: F2)
     where
         F1: FnOnce(&mut Visitor),
         F2: FnMut(TaggedHandle),
     {
-        self.tree.borrow_mut().collect(self, trace, finalize);
+        let generational = self.generational();
+        let mut unbounded = usize::MAX;
+        let mut tree = self.tree.borrow_mut();
+        if self.phase.get() == Phase::Sweep {
+            tree.sweep(self, &mut unbounded, &mut finalize, generational);
+        }
+
+        // A cycle that is still marking keeps the marks it has.
+        self.phase.set(Phase::Mark);
+        trace(&mut tree.visitor);
+        tree.visitor.finish();
+
+        self.phase.set(Phase::Sweep);
+        tree.cursor = 0;
+        tree.remembered.clear();
+        tree.sweep(self, &mut unbounded, &mut finalize, generational);
+        tree.young.clear();
+
+        self.phase.set(Phase::Idle);
+        drop(tree);
         self.heuristics.adjust();
     }
+
+    fn step<F1, F2>(&self, mut trace: F1, mut finalize: F2) -> bool
+    where
+        F1: FnMut(&mut Visitor),
+        F2: FnMut(TaggedHandle),
+    {
+        if let Mode::Generational(params) = self.mode.get() {
+            if self.heuristics.should_collect_major(&params) {
+                self.collect(trace, finalize);
+            } else {
+                let mut tree = self.tree.borrow_mut();
+                let promoted = tree.minor(self, trace, &mut finalize, &params);
+                drop(tree);
+                self.heuristics.adjust_minor(promoted);
+            }
+
+            return true;
+        }
+
+        let mut work = self.heuristics.step_work();
+        let mut tree = self.tree.borrow_mut();
+
+        if self.phase.get() == Phase::Idle {
+            self.phase.set(Phase::Mark);
+            trace(&mut tree.visitor);
+        }
+
+        if self.phase.get() == Phase::Mark {
+            if !tree.visitor.propagate(&mut work) {
+                return false;
+            }
+
+            // The roots and coroutines change without barriers, so marking
+            // only ends once they were traced again.
+            trace(&mut tree.visitor);
+            tree.visitor.finish();
+            self.phase.set(Phase::Sweep);
+            tree.cursor = 0;
+        }
+
+        if !tree.sweep(self, &mut work, &mut finalize, false) {
+            return false;
+        }
+
+        self.phase.set(Phase::Idle);
+        drop(tree);
+        self.heuristics.adjust();
+        true
+    }
 }
 
 unsafe impl alloc::Allocator for Heap {
@@ -255,8 +685,8 @@ unsafe impl alloc::Allocator for HeapInternal {
 
 impl Drop for HeapInternal {
     fn drop(&mut self) {
-        let tree = self.tree.borrow();
-        tree.objects.iter().for_each(|object| unsafe {
+        let mut tree = self.tree.borrow_mut();
+        tree.visitor.objects().iter().for_each(|object| unsafe {
             self.destroy(object);
         });
     }
@@ -266,7 +696,11 @@ impl Drop for HeapInternal {
 mod tests {
     use super::{
         super::value::{Table, Value},
+        Generational,
+        Handle,
         Heap,
+        Mode,
+        Visitor,
     };
     use crate::engine::gc::Trace;
 
@@ -343,4 +777,271 @@ mod tests {
         collect!();
         assert_eq!(ctr, 2);
     }
+
+    #[test]
+    fn step_barrier() {
+        let heap = Heap::new();
+        let root = heap.insert(Table::new(heap.clone()));
+        let tab = unsafe { root.get_unchecked_mut() };
+        for i in 0..1000 {
+            let child = heap.insert(Table::new(heap.clone()));
+            tab.insert(Value::from_int(i), Value::from_table(child));
+        }
+
+        // Pays off the allocation debt so that steps do little work.
+        heap.collect(|visitor| visitor.mark(root.tagged()), |_| unreachable!());
+        for _ in 0..10 {
+            heap.insert(Table::new(heap.clone()));
+        }
+
+        let mut ctr = 0;
+        assert!(!heap.step(|visitor| visitor.mark(root.tagged()), |_| ctr += 1));
+        assert!(heap.is_collecting());
+
+        // The root was traced already, the barrier makes the cycle trace it
+        // again.
+        let late = heap.insert(Table::new(heap.clone()));
+        tab.insert(Value::from_int(-1), Value::from_table(late));
+
+        while !heap.step(|visitor| visitor.mark(root.tagged()), |_| ctr += 1) {}
+        assert_eq!(ctr, 10);
+        assert!(!heap.is_collecting());
+
+        tab.remove(Value::from_int(-1));
+        heap.collect(|visitor| visitor.mark(root.tagged()), |_| ctr += 1);
+        assert_eq!(ctr, 11);
+    }
+
+    #[test]
+    fn step_collect_in_progress() {
+        let heap = Heap::new();
+        let root = heap.insert(Table::new(heap.clone()));
+        let tab = unsafe { root.get_unchecked_mut() };
+        for i in 0..1000 {
+            let child = heap.insert(Table::new(heap.clone()));
+            let value = if i % 2 == 0 {
+                Value::from_table(child)
+            } else {
+                Value::from_nil()
+            };
+            tab.insert(Value::from_int(i), value);
+        }
+
+        heap.collect(|visitor| visitor.mark(root.tagged()), |_| ());
+        for i in 0..1000 {
+            tab.remove(Value::from_int(i));
+        }
+
+        // A full collection finishes the cycle in progress.
+        let mut ctr = 0;
+        assert!(!heap.step(|visitor| visitor.mark(root.tagged()), |_| ctr += 1));
+        heap.collect(|visitor| visitor.mark(root.tagged()), |_| ctr += 1);
+        assert_eq!(ctr, 500);
+        assert!(!heap.is_collecting());
+    }
+
+    #[test]
+    fn generational() {
+        let heap = Heap::new();
+        let root = heap.insert(Table::new(heap.clone()));
+        let tab = unsafe { root.get_unchecked_mut() };
+        let params = Generational::default();
+        let mut ctr = 0;
+        heap.set_mode(
+            Mode::Generational(params),
+            |visitor| visitor.mark(root.tagged()),
+            |_| ctr += 1,
+        );
+        assert_eq!(heap.mode(), Mode::Generational(params));
+
+        // The root is old, the barrier remembers it.
+        let young = heap.insert(Table::new(heap.clone()));
+        tab.insert(Value::from_int(1), Value::from_table(young));
+        for _ in 0..100 {
+            heap.insert(Table::new(heap.clone()));
+        }
+
+        assert!(heap.step(|visitor| visitor.mark(root.tagged()), |_| ctr += 1));
+        assert_eq!(ctr, 100);
+        assert!(heap.step(|visitor| visitor.mark(root.tagged()), |_| ctr += 1));
+        assert_eq!(ctr, 100);
+
+        let stats = heap.heuristics().stats();
+        assert_eq!(stats.minor_collections, 2);
+        assert_eq!(stats.promoted, 1);
+        assert_eq!(stats.allocated, stats.total_allocated - stats.total_freed);
+
+        // Old objects are only collected by major collections.
+        tab.remove(Value::from_int(1));
+        heap.step(|visitor| visitor.mark(root.tagged()), |_| ctr += 1);
+        assert_eq!(ctr, 100);
+        heap.collect(|visitor| visitor.mark(root.tagged()), |_| ctr += 1);
+        assert_eq!(ctr, 101);
+
+        heap.set_mode(
+            Mode::Incremental,
+            |visitor| visitor.mark(root.tagged()),
+            |_| ctr += 1,
+        );
+        heap.insert(Table::new(heap.clone()));
+        while !heap.step(|visitor| visitor.mark(root.tagged()), |_| ctr += 1) {}
+        assert_eq!(ctr, 102);
+        assert_eq!(heap.heuristics().stats().cycles, 4);
+    }
+
+    #[test]
+    fn generational_promoted_references() {
+        let heap = Heap::new();
+        let params = Generational {
+            promote_after: 1,
+            ..Generational::default()
+        };
+        heap.set_mode(Mode::Generational(params), |_| (), |_| ());
+
+        // The parent is promoted by the first collection, the child stored
+        // into it after that is still young but reachable.
+        let parent = heap.insert(Table::new(heap.clone()));
+        let trace = |visitor: &mut Visitor| visitor.mark(parent.tagged());
+        let mut ctr = 0;
+        heap.step(trace, |_| ctr += 1);
+
+        let child = heap.insert(Table::new(heap.clone()));
+        let grandchild = heap.insert(Table::new(heap.clone()));
+        let tab = unsafe { child.get_unchecked_mut() };
+        tab.insert(Value::from_int(1), Value::from_table(grandchild));
+        unsafe { parent.get_unchecked_mut() }.insert(Value::from_int(1), Value::from_table(child));
+
+        for _ in 0..3 {
+            heap.step(trace, |_| ctr += 1);
+        }
+
+        assert_eq!(ctr, 0);
+        heap.collect(|_| (), |_| ctr += 1);
+        assert_eq!(ctr, 3);
+    }
+
+    #[test]
+    fn generational_promote_after_zero() {
+        let heap = Heap::new();
+        let params = Generational {
+            promote_after: 0,
+            ..Generational::default()
+        };
+        heap.set_mode(Mode::Generational(params), |_| (), |_| ());
+        let clamped = Generational {
+            promote_after: 1,
+            ..params
+        };
+        assert_eq!(heap.mode(), Mode::Generational(clamped));
+
+        // Young objects are still collected by minor collections.
+        let root = heap.insert(Table::new(heap.clone()));
+        let trace = |visitor: &mut Visitor| visitor.mark(root.tagged());
+        for _ in 0..10 {
+            heap.insert(Table::new(heap.clone()));
+        }
+
+        let mut ctr = 0;
+        heap.step(trace, |_| ctr += 1);
+        assert_eq!(ctr, 10);
+        assert_eq!(heap.heuristics().stats().promoted, 1);
+
+        heap.insert(Table::new(heap.clone()));
+        heap.step(trace, |_| ctr += 1);
+        assert_eq!(ctr, 11);
+    }
+
+    fn weak_table(heap: &Heap, mode: &[u8]) -> Handle<Table> {
+        let mut metatable = Table::new(heap.clone());
+        let key = Value::from_string(heap.insert_string(b"__mode"));
+        metatable.insert(key, Value::from_string(heap.insert_string(mode)));
+
+        let mut table = Table::new(heap.clone());
+        table.set_metatable(Some(heap.insert(metatable)));
+        heap.insert(table)
+    }
+
+    #[test]
+    fn collect_weak_values() {
+        let heap = Heap::new();
+        let weak = weak_table(&heap, b"v");
+        let tab = unsafe { weak.get_unchecked_mut() };
+        let kept = Value::from_table(heap.insert(Table::new(heap.clone())));
+        let name = Value::from_string(heap.insert_string(b"name"));
+        tab.insert(
+            Value::from_int(1),
+            Value::from_table(heap.insert(Table::new(heap.clone()))),
+        );
+        tab.insert(Value::from_int(2), kept);
+        tab.insert(
+            Value::from_int(3),
+            Value::from_string(heap.insert_string(b"gone")),
+        );
+        tab.insert(
+            name,
+            Value::from_table(heap.insert(Table::new(heap.clone()))),
+        );
+        tab.insert(Value::from_int(4), Value::from_int(4));
+
+        // Entries are cleared before anything is finalized.
+        let mut ctr = 0;
+        heap.collect(
+            |visitor| {
+                visitor.mark(weak.tagged());
+                kept.visit(visitor);
+                name.visit(visitor);
+            },
+            |_| {
+                let tab = unsafe { weak.get_unchecked() };
+                assert!(tab.get(Value::from_int(1)) == Value::from_nil());
+                assert!(tab.get(name) == Value::from_nil());
+                ctr += 1;
+            },
+        );
+
+        // Strings are never removed from weak tables.
+        assert_eq!(ctr, 2);
+        assert!(tab.get(Value::from_int(2)) == kept);
+        assert!(tab.get(Value::from_int(3)) != Value::from_nil());
+        assert!(tab.get(Value::from_int(4)) == Value::from_int(4));
+    }
+
+    #[test]
+    fn collect_ephemerons() {
+        let heap = Heap::new();
+        let weak = weak_table(&heap, b"k");
+        let tab = unsafe { weak.get_unchecked_mut() };
+        let table = || Value::from_table(heap.insert(Table::new(heap.clone())));
+
+        // A chain from a marked key is kept, an entry whose value refers to
+        // its own key is not.
+        let (a, b, c) = (table(), table(), table());
+        tab.insert(a, b);
+        tab.insert(b, c);
+
+        let (key, value) = (table(), table());
+        unsafe { value.cast_table().unwrap().get_unchecked_mut() }.insert(Value::from_int(1), key);
+        tab.insert(key, value);
+        tab.insert(table(), Value::from_int(1));
+
+        let mut ctr = 0;
+        heap.collect(
+            |visitor| {
+                visitor.mark(weak.tagged());
+                a.visit(visitor);
+            },
+            |_| ctr += 1,
+        );
+
+        assert_eq!(ctr, 3);
+        assert_eq!(tab.len(), 2);
+        assert!(tab.get(b) == c);
+
+        // Incremental cycles treat them the same.
+        tab.remove(a);
+        heap.insert(Table::new(heap.clone()));
+        while !heap.step(|visitor| visitor.mark(weak.tagged()), |_| ctr += 1) {}
+        assert_eq!(ctr, 7);
+        assert!(tab.is_empty());
+    }
 }
@@ -2,68 +2,173 @@ use hashbrown::{hash_map, HashMap};
 
 use super::TaggedHandle;
 
+/// The tri-color state of an object during a collection cycle.
+///
+/// White objects have not been reached yet, gray objects were reached but
+/// their references not traced and black objects are done with. Objects are
+/// white between cycles.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Color {
+    White,
+    Gray,
+    Black,
+}
+
+/// How long an object has been around, in generational mode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Age {
+    /// Survived the given number of minor collections.
+    Young(u8),
+    Old,
+    /// Old and remembered, that is traced by the given number of minor
+    /// collections to come as it may refer to young objects.
+    Touched(u8),
+}
+
+#[derive(Clone, Copy)]
+pub struct Entry {
+    pub handle: TaggedHandle,
+    pub color: Color,
+    pub age: Age,
+    /// Whether the object is marked for finalization.
+    pub finalizer: bool,
+}
+
+/// The objects of a heap with their colors and ages, kept in a vector that
+/// sweeps walk through and indexed by handle for marking.
 pub struct ObjectSet {
-    set: HashMap<TaggedHandle, (), ()>,
+    objects: Vec<Entry>,
+    index: HashMap<TaggedHandle, usize, ()>,
 }
 
 impl ObjectSet {
     #[allow(clippy::new_without_default)]
     pub fn new() -> Self {
         Self {
-            set: HashMap::with_hasher(()),
+            objects: Vec::new(),
+            index: HashMap::with_hasher(()),
         }
     }
 
     fn entry_mut(
         &mut self,
         handle: TaggedHandle,
-    ) -> hash_map::RawEntryMut<'_, TaggedHandle, (), ()> {
+    ) -> hash_map::RawEntryMut<'_, TaggedHandle, usize, ()> {
         let hash = handle.hash();
 
-        self.set
+        self.index
             .raw_entry_mut()
             .from_hash(hash, |other| handle.value() == other.value())
     }
 
-    pub fn insert(&mut self, handle: TaggedHandle) {
+    pub fn position(&self, handle: TaggedHandle) -> Option<usize> {
+        let hash = handle.hash();
+
+        self.index
+            .raw_entry()
+            .from_hash(hash, |other| handle.value() == other.value())
+            .map(|(_, position)| *position)
+    }
+
+    pub fn insert(&mut self, handle: TaggedHandle, color: Color) {
+        let position = self.objects.len();
         if let hash_map::RawEntryMut::Vacant(entry) = self.entry_mut(handle) {
             let hash = handle.hash();
-            entry.insert_with_hasher(hash, handle, (), |handle| handle.hash());
+            entry.insert_with_hasher(hash, handle, position, |handle| handle.hash());
+            self.objects.push(Entry {
+                handle,
+                color,
+                age: Age::Young(0),
+                finalizer: false,
+            });
         } else {
             unreachable!()
         }
     }
 
-    pub fn remove(&mut self, handle: TaggedHandle) {
+    /// Removes the object at `position`, moving the last object in its place.
+    pub fn remove_at(&mut self, position: usize) -> TaggedHandle {
+        let handle = self.objects.swap_remove(position).handle;
         if let hash_map::RawEntryMut::Occupied(entry) = self.entry_mut(handle) {
             entry.remove();
-            return;
+        } else {
+            unreachable!()
+        }
+
+        if let Some(moved) = self.objects.get(position).copied() {
+            if let hash_map::RawEntryMut::Occupied(mut entry) = self.entry_mut(moved.handle) {
+                *entry.get_mut() = position;
+            }
         }
 
-        unreachable!()
+        handle
+    }
+
+    pub fn remove(&mut self, handle: TaggedHandle) {
+        match self.position(handle) {
+            Some(position) => self.remove_at(position),
+            None => unreachable!(),
+        };
     }
 
     pub fn contains(&self, handle: TaggedHandle) -> bool {
-        let hash = handle.hash();
+        self.position(handle).is_some()
+    }
 
-        self.set
-            .raw_entry()
-            .from_hash(hash, |other| handle.value() == other.value())
-            .is_some()
+    /// The color of an object, `None` if it is not in the set.
+    pub fn color(&self, handle: TaggedHandle) -> Option<Color> {
+        self.position(handle)
+            .map(|position| self.objects[position].color)
     }
 
-    pub fn iter(&self) -> impl Iterator<Item = TaggedHandle> + '_ {
-        self.set.keys().copied()
+    pub fn set_color(&mut self, handle: TaggedHandle, color: Color) {
+        if let Some(position) = self.position(handle) {
+            self.objects[position].color = color;
+        }
     }
 
-    pub fn difference<'a>(&'a self, other: &'a Self) -> impl Iterator<Item = TaggedHandle> + 'a {
-        self.set
-            .keys()
-            .filter(|handle| !other.contains(**handle))
-            .copied()
+    /// The age of an object, `None` if it is not in the set.
+    pub fn age(&self, handle: TaggedHandle) -> Option<Age> {
+        self.position(handle)
+            .map(|position| self.objects[position].age)
     }
 
-    pub fn clear(&mut self) {
-        self.set.clear();
+    pub fn set_age(&mut self, handle: TaggedHandle, age: Age) {
+        if let Some(position) = self.position(handle) {
+            self.objects[position].age = age;
+        }
+    }
+
+    /// Whether an object is marked for finalization, `None` if it is not in
+    /// the set.
+    pub fn finalizer(&self, handle: TaggedHandle) -> Option<bool> {
+        self.position(handle)
+            .map(|position| self.objects[position].finalizer)
+    }
+
+    pub fn set_finalizer(&mut self, handle: TaggedHandle, finalizer: bool) {
+        if let Some(position) = self.position(handle) {
+            self.objects[position].finalizer = finalizer;
+        }
+    }
+
+    pub fn get(&self, position: usize) -> Option<Entry> {
+        self.objects.get(position).copied()
+    }
+
+    pub fn get_mut(&mut self, position: usize) -> &mut Entry {
+        &mut self.objects[position]
+    }
+
+    pub fn len(&self) -> usize {
+        self.objects.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.objects.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = TaggedHandle> + '_ {
+        self.objects.iter().map(|entry| entry.handle)
     }
 }
@@ -1,41 +1,237 @@
-use super::{handle::TaggedHandle, set::ObjectSet};
+use std::{collections::VecDeque, mem};
+
+use super::{
+    super::value::{encoding, Coroutine, Function, Table},
+    handle::TaggedHandle,
+    set::{Age, Color, ObjectSet},
+};
 
 pub trait Trace {
     fn visit(&self, visitor: &mut Visitor);
 }
 
+/// Marks the objects reached during a cycle.
+///
+/// Marking an object turns it gray and queues it on the gray worklist instead
+/// of tracing it on the spot, so that the collector can trace the worklist a
+/// bounded number of objects at a time.
 pub struct Visitor {
-    marked: ObjectSet,
-    stale: Vec<TaggedHandle>,
+    objects: ObjectSet,
+    gray: Vec<TaggedHandle>,
+    // Coroutines traced this cycle. Their stacks change without barriers, so
+    // they are traced again before the cycle sweeps.
+    threads: Vec<TaggedHandle>,
+    // Tables with weak keys or values traced this cycle, with their mode.
+    weak: Vec<(TaggedHandle, bool, bool)>,
+    // Objects marked for finalization.
+    finalizable: Vec<TaggedHandle>,
+    // Unreachable objects whose finalizers are due. They are kept alive
+    // until their finalizer ran.
+    pending: VecDeque<TaggedHandle>,
+    // Whether a minor collection is marking, which leaves old objects alone.
+    minor: bool,
 }
 
 impl Visitor {
     #[allow(clippy::new_without_default)]
     pub fn new() -> Self {
         Self {
-            marked: ObjectSet::new(),
-            stale: Vec::new(),
+            objects: ObjectSet::new(),
+            gray: Vec::new(),
+            threads: Vec::new(),
+            weak: Vec::new(),
+            finalizable: Vec::new(),
+            pending: VecDeque::new(),
+            minor: false,
         }
     }
 
     pub fn mark(&mut self, handle: TaggedHandle) {
-        self.marked.insert(handle);
-    }
+        let position = match self.objects.position(handle) {
+            Some(position) => position,
+            None => return,
+        };
 
-    pub fn unmarked<'a>(
-        &'a mut self,
-        objects: &ObjectSet,
-    ) -> impl Iterator<Item = TaggedHandle> + 'a {
-        self.stale.extend(objects.difference(&self.marked));
-        self.stale.iter().copied()
+        let entry = self.objects.get_mut(position);
+        let young = matches!(entry.age, Age::Young(_));
+        if entry.color == Color::White && (young || !self.minor) {
+            entry.color = Color::Gray;
+            self.gray.push(handle);
+        }
     }
 
     pub fn is_marked(&self, handle: TaggedHandle) -> bool {
-        self.marked.contains(handle)
+        matches!(self.objects.color(handle), Some(Color::Gray | Color::Black))
+    }
+
+    /// Whether an object is known to be alive: marked, or old while a minor
+    /// collection marks. Objects not on the heap count as alive.
+    pub fn is_reached(&self, handle: TaggedHandle) -> bool {
+        let position = match self.objects.position(handle) {
+            Some(position) => position,
+            None => return true,
+        };
+
+        let entry = self.objects.get(position).unwrap();
+        entry.color != Color::White || (self.minor && !matches!(entry.age, Age::Young(_)))
+    }
+
+    pub(super) fn objects(&mut self) -> &mut ObjectSet {
+        &mut self.objects
+    }
+
+    pub(super) fn set_finalizer(&mut self, handle: TaggedHandle) {
+        if self.objects.finalizer(handle) == Some(false) {
+            self.objects.set_finalizer(handle, true);
+            self.finalizable.push(handle);
+        }
+    }
+
+    pub(super) fn pop_finalizer(&mut self) -> Option<TaggedHandle> {
+        self.pending.pop_front()
     }
 
-    pub fn reset(&mut self) {
-        self.marked.clear();
-        self.stale.clear();
+    /// Makes the finalizers of all objects marked for finalization due.
+    pub(super) fn finalize_all(&mut self) {
+        for handle in mem::take(&mut self.finalizable) {
+            self.objects.set_finalizer(handle, false);
+            self.pending.push_back(handle);
+        }
+    }
+
+    pub(super) fn set_minor(&mut self, minor: bool) {
+        self.minor = minor;
+    }
+
+    /// Queues a remembered object, tracing its references even though it is
+    /// old.
+    pub(super) fn mark_remembered(&mut self, handle: TaggedHandle) {
+        self.objects.set_color(handle, Color::Gray);
+        self.gray.push(handle);
+    }
+
+    /// Queues a traced object to be traced again, as a reference to an
+    /// object that may be white was stored in it.
+    pub(super) fn mark_again(&mut self, handle: TaggedHandle) {
+        if self.objects.color(handle) == Some(Color::Black) {
+            self.objects.set_color(handle, Color::Gray);
+            self.gray.push(handle);
+        }
+    }
+
+    /// Traces gray objects until the worklist is empty or `work` runs out,
+    /// returning whether the worklist is empty.
+    pub(super) fn propagate(&mut self, work: &mut usize) -> bool {
+        while *work > 0 {
+            let handle = match self.gray.pop() {
+                Some(handle) => handle,
+                None => return true,
+            };
+
+            self.objects.set_color(handle, Color::Black);
+            self.trace(handle);
+            *work -= 1;
+        }
+
+        self.gray.is_empty()
+    }
+
+    /// Finishes marking once the roots were marked again: traces the
+    /// coroutines again and then everything left gray. Then clears the
+    /// entries of weak tables that were not reached and queues the
+    /// finalizers of unreachable objects, before anything is swept.
+    ///
+    /// As in Lua, objects being finalized are resurrected until their
+    /// finalizer ran. They are removed from weak values before that, but from
+    /// weak keys only once they are collected.
+    pub(super) fn finish(&mut self) {
+        let mut unbounded = usize::MAX;
+        self.propagate(&mut unbounded);
+        for handle in mem::take(&mut self.threads) {
+            let coroutine = unsafe { &*(encoding::get_thread(handle.value()) as *const Coroutine) };
+            coroutine.visit(self);
+        }
+
+        self.propagate(&mut unbounded);
+        self.converge();
+        let traced = self.weak.len();
+        self.clear_weak(0, false, true);
+
+        let (reached, unreached) = mem::take(&mut self.finalizable)
+            .into_iter()
+            .partition(|handle| self.is_reached(*handle));
+        self.finalizable = reached;
+        for handle in unreached {
+            self.objects.set_finalizer(handle, false);
+            self.pending.push_back(handle);
+        }
+
+        for index in 0..self.pending.len() {
+            self.mark(self.pending[index]);
+        }
+
+        self.propagate(&mut unbounded);
+        self.converge();
+        self.clear_weak(0, true, false);
+        self.clear_weak(traced, false, true);
+
+        self.weak.clear();
+        self.threads.clear();
+    }
+
+    // Values of weak keys reached since their table was traced may reach
+    // further keys in turn, so ephemerons are traced until nothing new is
+    // marked.
+    fn converge(&mut self) {
+        let mut unbounded = usize::MAX;
+        loop {
+            let mut index = 0;
+            while index < self.weak.len() {
+                let (handle, weak_keys, weak_values) = self.weak[index];
+                let table = unsafe { &*(encoding::get_table(handle.value()) as *const Table) };
+                if weak_keys && !weak_values {
+                    table.visit_weak(self, true, false);
+                }
+
+                index += 1;
+            }
+
+            if self.gray.is_empty() {
+                break;
+            }
+
+            self.propagate(&mut unbounded);
+        }
+    }
+
+    // Clears the weak keys or values of the weak tables traced since the
+    // table at `from`.
+    fn clear_weak(&self, from: usize, keys: bool, values: bool) {
+        for (handle, weak_keys, weak_values) in self.weak[from..].iter().copied() {
+            let table = unsafe { &mut *(encoding::get_table(handle.value()) as *mut Table) };
+            table.clear_weak(self, weak_keys && keys, weak_values && values);
+        }
+    }
+
+    fn trace(&mut self, handle: TaggedHandle) {
+        let tagged = handle.value();
+
+        if encoding::is_table(tagged) {
+            let table = unsafe { &*(encoding::get_table(tagged) as *const Table) };
+            match table.mode() {
+                (false, false) => table.visit(self),
+                (weak_keys, weak_values) => {
+                    table.visit_weak(self, weak_keys, weak_values);
+                    self.weak.push((handle, weak_keys, weak_values));
+                },
+            }
+        } else if encoding::is_function(tagged) {
+            let function = unsafe { &*(encoding::get_function(tagged) as *const Function) };
+            function.visit(self);
+        } else if encoding::is_thread(tagged) {
+            let coroutine = unsafe { &*(encoding::get_thread(tagged) as *const Coroutine) };
+            coroutine.visit(self);
+            self.threads.push(handle);
+        }
     }
 }
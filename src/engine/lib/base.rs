@@ -0,0 +1,516 @@
+use std::rc::Rc;
+
+use super::{
+    super::{
+        error::RuntimeError,
+        gc::{Generational, Mode},
+        value::{format_float, Closure, Function, Intrinsic, Upvalue, Value},
+        vm::{
+            compiler,
+            ctx::{Collection, Ctx},
+            dump,
+            eval::{self, runtime_error},
+            meta,
+        },
+        Error,
+    },
+    Lib,
+};
+use crate::parser::{
+    machinery::{cstree::NodeCache, diagnostic::Diagnostic, source_map::SourceMap, span::Span},
+    parse,
+    syntax::Root,
+};
+
+pub(super) fn open(lib: &mut Lib) {
+    lib.global("collectgarbage", collectgarbage);
+    lib.global("error", error);
+    lib.global("getmetatable", getmetatable);
+    lib.global("load", load);
+    let pcall = lib.intrinsic("pcall", pcall, Intrinsic::PCall);
+    lib.global_value("pcall", pcall);
+    lib.global("rawequal", rawequal);
+    lib.global("rawget", rawget);
+    lib.global("rawlen", rawlen);
+    lib.global("rawset", rawset);
+    lib.global("select", select);
+    lib.global("setmetatable", setmetatable);
+    lib.global("tostring", tostring);
+    lib.global("type", type_);
+}
+
+// Returns an argument, where missing arguments are nil.
+pub(super) fn arg(args: &[Value], index: usize) -> Value {
+    args.get(index).copied().unwrap_or_else(Value::from_nil)
+}
+
+// Checks that an argument is a table.
+fn table_arg(ctx: &Ctx, args: &[Value], index: usize, name: &str) -> eval::Result<Value> {
+    let value = arg(args, index);
+    if value.cast_table().is_some() {
+        return eval::Result::Value(value);
+    }
+
+    let message = format!("table expected, got {}", no_value(args, index));
+    bad_argument(ctx, index + 1, name, &message)
+}
+
+// Describes the type of an argument for error messages.
+pub(super) fn no_value(args: &[Value], index: usize) -> &'static str {
+    match args.get(index) {
+        Some(value) => value.type_name(),
+        None => "no value",
+    }
+}
+
+// Raises a runtime error complaining about an argument of a library function.
+pub(super) fn bad_argument<T>(
+    ctx: &Ctx,
+    index: usize,
+    name: &str,
+    message: &str,
+) -> eval::Result<T> {
+    let message = format!("bad argument #{} to '{}' ({})", index, name, message);
+    eval::Result::Error(runtime_error(message, ctx))
+}
+
+// error(message) raises a runtime error with any value as its error object.
+fn error(_ctx: &Ctx, args: Vec<Value>) -> eval::Result<Vec<Value>> {
+    let value = args.first().copied().unwrap_or_else(Value::from_nil);
+    let message = match value.cast_string() {
+        Some(string) => String::from_utf8_lossy(unsafe { string.get_unchecked() }).into_owned(),
+        None => format!("(error object is a {} value)", value.type_name()),
+    };
+
+    eval::Result::Error(Error::Runtime(RuntimeError::new(message, value)))
+}
+
+// pcall(f, ...) calls f in protected mode, returning true followed by its
+// results or false followed by the error object. Lua code calling it directly
+// is handled by the dispatch loop, this is only reached through native
+// functions.
+fn pcall(ctx: &Ctx, mut args: Vec<Value>) -> eval::Result<Vec<Value>> {
+    if args.is_empty() {
+        return bad_argument(ctx, 1, "pcall", "value expected");
+    }
+
+    let function = args.remove(0);
+    match eval::call(function, args, ctx) {
+        eval::Result::Value(mut values) => {
+            values.insert(0, Value::from_bool(true));
+            eval::Result::Value(values)
+        },
+        eval::Result::Error(Error::Runtime(error)) =>
+            eval::Result::Value(vec![Value::from_bool(false), error.value()]),
+        res => res,
+    }
+}
+
+// load(chunk [, chunkname [, mode]]) turns a chunk of source text or the output
+// of `string.dump` into a function. The chunk is a string or a function
+// returning its pieces. Returns nil and a message if it cannot be loaded.
+//
+// Spans of text chunks refer to the chunk rather than the script running, so
+// they are dropped along with other debug information.
+fn load(ctx: &Ctx, args: Vec<Value>) -> eval::Result<Vec<Value>> {
+    let chunk = arg(&args, 0);
+    let bytes = if let Some(string) = chunk.cast_string() {
+        unsafe { string.get_unchecked() }.to_vec()
+    } else if chunk.cast_function().is_some() {
+        let mut bytes = Vec::new();
+        loop {
+            let piece = eval::first(eval::call(chunk, Vec::new(), ctx)?);
+            match piece
+                .cast_string()
+                .map(|piece| unsafe { piece.get_unchecked() })
+            {
+                Some(piece) if !piece.is_empty() => bytes.extend_from_slice(piece),
+                Some(_) => break,
+                None if piece == Value::from_nil() => break,
+                None => {
+                    let message = String::from("reader function must return a string");
+                    return eval::Result::Error(runtime_error(message, ctx));
+                },
+            }
+        }
+
+        bytes
+    } else {
+        let message = format!("string expected, got {}", no_value(&args, 0));
+        return bad_argument(ctx, 1, "load", &message);
+    };
+
+    let chunkname = arg(&args, 1)
+        .cast_string()
+        .map(|name| String::from_utf8_lossy(unsafe { name.get_unchecked() }).into_owned());
+    let name = match &chunkname {
+        Some(chunkname) => format!("{}: ", chunkname),
+        None => String::new(),
+    };
+
+    let mode = match arg(&args, 2) {
+        mode if mode == Value::from_nil() => String::from("bt"),
+        mode => match mode.cast_string() {
+            Some(mode) => String::from_utf8_lossy(unsafe { mode.get_unchecked() }).into_owned(),
+            None => {
+                let message = format!("string expected, got {}", mode.type_name());
+                return bad_argument(ctx, 3, "load", &message);
+            },
+        },
+    };
+
+    if arg(&args, 3) != Value::from_nil() {
+        return bad_argument(ctx, 4, "load", "custom environments are not supported");
+    }
+
+    let fail = |message: String| {
+        let message = Value::from_string(ctx.intern(format!("{}{}", name, message).as_bytes()));
+        eval::Result::Value(vec![Value::from_nil(), message])
+    };
+
+    let binary = bytes.first() == Some(&dump::SIGNATURE[0]);
+    let (kind, allowed) = if binary {
+        ("binary", mode.contains('b'))
+    } else {
+        ("text", mode.contains('t'))
+    };
+
+    if !allowed {
+        return fail(format!(
+            "attempt to load a {} chunk (mode is '{}')",
+            kind, mode
+        ));
+    }
+
+    let proto = if binary {
+        match dump::undump(&bytes, ctx) {
+            Ok(proto) => proto,
+            Err(error) => return fail(error.to_string()),
+        }
+    } else {
+        let source = match std::str::from_utf8(&bytes) {
+            Ok(source) => source,
+            Err(_) => return fail(String::from("chunk is not valid UTF-8")),
+        };
+
+        // Syntax errors are reported the way Lua does, at the line of the
+        // chunk they were found on.
+        let map = SourceMap::new(source);
+        let default = if chunk.cast_string().is_some() {
+            source
+        } else {
+            "=(load)"
+        };
+        let chunk_id = chunk_id(chunkname.as_deref().unwrap_or(default));
+        let syntax_error = |diagnostic: &Diagnostic, span: Span| {
+            let line = map.line(span.start());
+            let message = format!("{}:{}: {}", chunk_id, line, diagnostic);
+            let message = Value::from_string(ctx.intern(message.as_bytes()));
+            eval::Result::Value(vec![Value::from_nil(), message])
+        };
+
+        let mut cache = NodeCache::new();
+        let (tree, reports) = parse(&mut cache, source);
+        if let Some(diagnostic) = reports.first() {
+            return syntax_error(diagnostic, diagnostic.span());
+        }
+
+        let root = Root::cast(&tree).unwrap();
+        let mut proto = match compiler::compile(&root, cache.interner(), ctx) {
+            Ok(proto) => proto,
+            // Compile errors are located in the tree rather than the source.
+            Err(Error::InvalidLiteral(diagnostic) | Error::Compile(diagnostic)) =>
+                return syntax_error(&diagnostic, map.span(diagnostic.span())),
+            Err(error) => return eval::Result::Error(error),
+        };
+
+        if let Some(proto) = Rc::get_mut(&mut proto) {
+            proto.strip();
+        }
+
+        proto
+    };
+
+    // Upvalues of a dumped function start out as fresh cells holding nil.
+    let upvalues = proto
+        .captures
+        .iter()
+        .map(|_| Upvalue::new(Value::from_nil()))
+        .collect();
+    let function = Function::Lua(Closure::new(proto, upvalues));
+    eval::Result::Value(vec![Value::from_function(ctx.heap().insert(function))])
+}
+
+// Formats the name of a chunk for messages like `luaO_chunkid`: names starting
+// with `=` or `@` are used without it, anything else is taken to be source text
+// and shortened to its first line.
+fn chunk_id(name: &str) -> String {
+    const MAX_LEN: usize = 45;
+
+    if let Some(name) = name.strip_prefix('=').or_else(|| name.strip_prefix('@')) {
+        return name.to_owned();
+    }
+
+    let line = name.split('\n').next().unwrap_or_default();
+    if line.len() == name.len() && line.len() < MAX_LEN {
+        return format!("[string \"{}\"]", line);
+    }
+
+    let mut end = line.len().min(MAX_LEN);
+    while !line.is_char_boundary(end) {
+        end -= 1;
+    }
+
+    format!("[string \"{}...\"]", &line[..end])
+}
+
+// select('#', ...) counts its varargs, select(n, ...) returns all varargs
+// from the n-th onwards, where a negative n counts from the end.
+fn select(ctx: &Ctx, args: Vec<Value>) -> eval::Result<Vec<Value>> {
+    let n = args.first().copied().unwrap_or_else(Value::from_nil);
+    let len = args.len().saturating_sub(1);
+
+    if let Some(string) = n.cast_string() {
+        if unsafe { &**string.get_unchecked() } == b"#" {
+            return eval::Result::Value(vec![ctx.int(len as i64)]);
+        }
+    }
+
+    let n = match n.to_int() {
+        Some(n) => n,
+        None => {
+            let message = format!("number expected, got {}", n.type_name());
+            return bad_argument(ctx, 1, "select", &message);
+        },
+    };
+
+    let start = match n {
+        n if n < 0 && n >= -(len as i64) => len as i64 + n,
+        n if n > 0 => (n - 1).min(len as i64),
+        _ => return bad_argument(ctx, 1, "select", "index out of range"),
+    };
+
+    eval::Result::Value(args[1 + start as usize..].to_vec())
+}
+
+// setmetatable(t, mt) sets or, if mt is nil, removes the metatable of t.
+fn setmetatable(ctx: &Ctx, args: Vec<Value>) -> eval::Result<Vec<Value>> {
+    let table = table_arg(ctx, &args, 0, "setmetatable")?;
+    let metatable = arg(&args, 1);
+
+    if metatable != Value::from_nil() && metatable.cast_table().is_none() {
+        return bad_argument(ctx, 2, "setmetatable", "nil or table expected");
+    }
+
+    if meta::metamethod(table, b"__metatable", ctx) != Value::from_nil() {
+        let message = String::from("cannot change a protected metatable");
+        return eval::Result::Error(runtime_error(message, ctx));
+    }
+
+    let table_ref = unsafe { table.cast_table().unwrap().get_unchecked_mut() };
+    table_ref.set_metatable(metatable.cast_table());
+
+    // Only a metatable with a `__gc` field when it is set marks the table for
+    // finalization.
+    if meta::metamethod(table, b"__gc", ctx) != Value::from_nil() {
+        ctx.heap().set_finalizer(table.handle().unwrap());
+    }
+
+    eval::Result::Value(vec![table])
+}
+
+// getmetatable(v) returns the `__metatable` field of the metatable of v if
+// there is one, and the metatable itself otherwise.
+fn getmetatable(ctx: &Ctx, args: Vec<Value>) -> eval::Result<Vec<Value>> {
+    let value = arg(&args, 0);
+    let metatable = match meta::metatable(value) {
+        Some(metatable) => metatable,
+        None => return eval::Result::Value(vec![Value::from_nil()]),
+    };
+
+    let protected = meta::metamethod(value, b"__metatable", ctx);
+    if protected != Value::from_nil() {
+        return eval::Result::Value(vec![protected]);
+    }
+
+    eval::Result::Value(vec![Value::from_table(metatable)])
+}
+
+// rawequal(a, b) compares two values without invoking `__eq`.
+fn rawequal(_ctx: &Ctx, args: Vec<Value>) -> eval::Result<Vec<Value>> {
+    eval::Result::Value(vec![arg(&args, 0).op_eq(arg(&args, 1))])
+}
+
+// rawget(t, k) indexes a table without invoking `__index`.
+fn rawget(ctx: &Ctx, args: Vec<Value>) -> eval::Result<Vec<Value>> {
+    let table = table_arg(ctx, &args, 0, "rawget")?;
+    let table = unsafe { table.cast_table().unwrap().get_unchecked() };
+    eval::Result::Value(vec![table.get(arg(&args, 1))])
+}
+
+// rawset(t, k, v) assigns to a table without invoking `__newindex`.
+fn rawset(ctx: &Ctx, args: Vec<Value>) -> eval::Result<Vec<Value>> {
+    let table = table_arg(ctx, &args, 0, "rawset")?;
+    if let Err(error) = table.op_set_property(arg(&args, 1), arg(&args, 2)) {
+        return eval::Result::Error(runtime_error(error.to_string(), ctx));
+    }
+
+    eval::Result::Value(vec![table])
+}
+
+// rawlen(v) returns the length of a table or string without invoking
+// `__len`.
+fn rawlen(ctx: &Ctx, args: Vec<Value>) -> eval::Result<Vec<Value>> {
+    let value = arg(&args, 0);
+    if value.cast_table().is_none() && value.cast_string().is_none() {
+        return bad_argument(ctx, 1, "rawlen", "table or string expected");
+    }
+
+    eval::Result::Value(vec![value.op_len().unwrap()])
+}
+
+// type(v) returns the name of the type of v.
+fn type_(ctx: &Ctx, args: Vec<Value>) -> eval::Result<Vec<Value>> {
+    if args.is_empty() {
+        return bad_argument(ctx, 1, "type", "value expected");
+    }
+
+    let name = ctx.intern(args[0].type_name().as_bytes());
+    eval::Result::Value(vec![Value::from_string(name)])
+}
+
+// collectgarbage([opt [, ...]]) controls the collector:
+// - "collect" runs a full cycle, "step" a step of the current one, returning
+//   whether it finished a cycle.
+// - "count" returns the memory in use in kilobytes.
+// - "incremental" and "generational" switch modes, returning the previous one.
+//   Generational mode takes the minor and major multipliers, where zero keeps
+//   the current ones.
+// - "isrunning" returns whether the collector runs, which it always does.
+fn collectgarbage(ctx: &Ctx, args: Vec<Value>) -> eval::Result<Vec<Value>> {
+    let option = match arg(&args, 0) {
+        option if option == Value::from_nil() => String::from("collect"),
+        option => match option.cast_string() {
+            Some(option) => String::from_utf8_lossy(unsafe { option.get_unchecked() }).into_owned(),
+            None => {
+                let message = format!("string expected, got {}", option.type_name());
+                return bad_argument(ctx, 1, "collectgarbage", &message);
+            },
+        },
+    };
+
+    let value = match option.as_str() {
+        "collect" => {
+            ctx.collect(Collection::Full);
+            Value::from_int(0)
+        },
+        "step" => Value::from_bool(ctx.collect(Collection::Step)),
+        "count" => {
+            let allocated = ctx.heap().heuristics().stats().allocated;
+            Value::from_float(allocated as f64 / 1024.0)
+        },
+        "incremental" | "generational" => {
+            let previous = ctx.heap().mode();
+            let mode = if option == "incremental" {
+                Mode::Incremental
+            } else {
+                let mut params = match previous {
+                    Mode::Generational(params) => params,
+                    Mode::Incremental => Generational::default(),
+                };
+
+                let minor = multiplier_arg(ctx, &args, 1)?;
+                let major = multiplier_arg(ctx, &args, 2)?;
+                params.minor_multiplier = minor.unwrap_or(params.minor_multiplier);
+                params.major_multiplier = major.unwrap_or(params.major_multiplier);
+                Mode::Generational(params)
+            };
+
+            ctx.collect(Collection::Mode(mode));
+            let name: &[u8] = match previous {
+                Mode::Incremental => b"incremental",
+                Mode::Generational(_) => b"generational",
+            };
+
+            Value::from_string(ctx.intern(name))
+        },
+        "isrunning" => Value::from_bool(true),
+        _ => {
+            let message = format!("invalid option '{}'", option);
+            return bad_argument(ctx, 1, "collectgarbage", &message);
+        },
+    };
+
+    eval::Result::Value(vec![value])
+}
+
+// Checks that an argument of `collectgarbage` is a multiplier in percent,
+// where nil and zero leave the multiplier as it is.
+fn multiplier_arg(ctx: &Ctx, args: &[Value], index: usize) -> eval::Result<Option<usize>> {
+    let value = arg(args, index);
+    if value == Value::from_nil() {
+        return eval::Result::Value(None);
+    }
+
+    match value.to_int() {
+        Some(0) => eval::Result::Value(None),
+        Some(x) if x > 0 => eval::Result::Value(Some(x as usize)),
+        Some(_) => bad_argument(
+            ctx,
+            index + 1,
+            "collectgarbage",
+            "multiplier must be positive",
+        ),
+        None => {
+            let message = format!("number expected, got {}", no_value(args, index));
+            bad_argument(ctx, index + 1, "collectgarbage", &message)
+        },
+    }
+}
+
+// tostring(v) converts any value to a string, honouring `__tostring` and
+// `__name`.
+fn tostring(ctx: &Ctx, args: Vec<Value>) -> eval::Result<Vec<Value>> {
+    if args.is_empty() {
+        return bad_argument(ctx, 1, "tostring", "value expected");
+    }
+
+    eval::Result::Value(vec![to_string(ctx, args[0])?])
+}
+
+/// Converts a value to a string the way `tostring` does.
+pub(crate) fn to_string(ctx: &Ctx, value: Value) -> eval::Result<Value> {
+    let handler = meta::metamethod(value, b"__tostring", ctx);
+    if handler != Value::from_nil() {
+        let string = eval::first(eval::call(handler, vec![value], ctx)?);
+        if string.cast_string().is_none() {
+            let message = String::from("'__tostring' must return a string");
+            return eval::Result::Error(runtime_error(message, ctx));
+        }
+
+        return eval::Result::Value(string);
+    }
+
+    if value.cast_string().is_some() {
+        return eval::Result::Value(value);
+    }
+
+    let string = match value.addr() {
+        Some(addr) => {
+            let name = meta::metamethod(value, b"__name", ctx);
+            match name.cast_string() {
+                Some(name) => {
+                    let name = unsafe { name.get_unchecked() };
+                    format!("{}: {:#016x}", String::from_utf8_lossy(name), addr)
+                },
+                None => format!("{}: {:#016x}", value.type_name(), addr),
+            }
+        },
+        None if value == Value::from_nil() => String::from("nil"),
+        None if value.is_int() => value.cast_int().to_string(),
+        None if value.type_name() == "number" => format_float(value.convert_float()),
+        None => value.is_truthy().to_string(),
+    };
+
+    eval::Result::Value(Value::from_string(ctx.intern(string.as_bytes())))
+}
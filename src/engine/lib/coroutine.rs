@@ -0,0 +1,302 @@
+use super::{
+    super::{
+        error::RuntimeError,
+        value::{Coroutine, Function, Intrinsic, Native, Status, Value},
+        vm::{
+            ctx::Ctx,
+            eval::{self, runtime_error},
+            interp::{self, Exit},
+        },
+        Error,
+    },
+    base::{arg, bad_argument, no_value},
+    Lib,
+};
+
+pub(super) fn open(lib: &mut Lib) {
+    let main = Value::from_thread(lib.heap.insert(Coroutine::main()));
+    let fields = vec![
+        ("close", lib.function("close", close)),
+        ("create", lib.function("create", create)),
+        ("isyieldable", lib.function("isyieldable", isyieldable)),
+        ("resume", lib.function("resume", resume)),
+        ("running", lib.closure("running", running, vec![main])),
+        ("status", lib.function("status", status)),
+        ("wrap", lib.function("wrap", wrap)),
+        ("yield", lib.intrinsic("yield", yield_, Intrinsic::Yield)),
+    ];
+
+    lib.module("coroutine", fields);
+}
+
+// Checks that an argument is a coroutine.
+fn coroutine_arg(
+    ctx: &Ctx,
+    args: &[Value],
+    index: usize,
+    name: &str,
+) -> eval::Result<&'static Coroutine> {
+    match arg(args, index).cast_thread() {
+        Some(handle) => eval::Result::Value(unsafe { handle.get_unchecked() }),
+        None => {
+            let message = format!("coroutine expected, got {}", no_value(args, index));
+            bad_argument(ctx, index + 1, name, &message)
+        },
+    }
+}
+
+// Checks that a coroutine can be resumed, returning the message otherwise.
+fn resumable(coroutine: &Coroutine) -> Option<&'static str> {
+    match coroutine.status() {
+        Status::Suspended if !coroutine.is_main() => None,
+        Status::Dead => Some("cannot resume dead coroutine"),
+        _ => Some("cannot resume non-suspended coroutine"),
+    }
+}
+
+// create(f) creates a suspended coroutine running f.
+fn create(ctx: &Ctx, args: Vec<Value>) -> eval::Result<Vec<Value>> {
+    let function = match arg(&args, 0).cast_function() {
+        Some(function) => function,
+        None => {
+            let message = format!("function expected, got {}", no_value(&args, 0));
+            return bad_argument(ctx, 1, "create", &message);
+        },
+    };
+
+    let coroutine = ctx.heap().insert(Coroutine::new(function));
+    eval::Result::Value(vec![Value::from_thread(coroutine)])
+}
+
+// resume(co, ...) starts or continues a coroutine, returning true followed by
+// the values it yields or returns, or false followed by an error object.
+fn resume(ctx: &Ctx, mut args: Vec<Value>) -> eval::Result<Vec<Value>> {
+    coroutine_arg(ctx, &args, 0, "resume")?;
+    let handle = args.remove(0).cast_thread().unwrap();
+    if let Some(message) = resumable(unsafe { handle.get_unchecked() }) {
+        let message = Value::from_string(ctx.intern(message.as_bytes()));
+        return eval::Result::Value(vec![Value::from_bool(false), message]);
+    }
+
+    let mut values = match interp::resume(handle, args, ctx) {
+        eval::Result::Value(Exit::Yield(values) | Exit::Return(values)) => values,
+        // Only the loops of async calls await.
+        eval::Result::Value(Exit::Await(_)) => unreachable!(),
+        eval::Result::Error(Error::Runtime(error)) =>
+            return eval::Result::Value(vec![Value::from_bool(false), error.value()]),
+        eval::Result::Error(error) => return eval::Result::Error(error),
+    };
+
+    values.insert(0, Value::from_bool(true));
+    eval::Result::Value(values)
+}
+
+// wrap(f) creates a coroutine running f and returns a function resuming it,
+// which returns the values it yields or returns and raises its errors.
+fn wrap(ctx: &Ctx, args: Vec<Value>) -> eval::Result<Vec<Value>> {
+    let coroutine = create(ctx, args)?;
+    let function = Function::Native(Native::closure("wrap", wrapped, coroutine));
+    eval::Result::Value(vec![Value::from_function(ctx.heap().insert(function))])
+}
+
+fn wrapped(ctx: &Ctx, upvalues: &[Value], args: Vec<Value>) -> eval::Result<Vec<Value>> {
+    let handle = upvalues[0].cast_thread().unwrap();
+    if let Some(message) = resumable(unsafe { handle.get_unchecked() }) {
+        return eval::Result::Error(runtime_error(String::from(message), ctx));
+    }
+
+    match interp::resume(handle, args, ctx) {
+        eval::Result::Value(Exit::Yield(values) | Exit::Return(values)) =>
+            eval::Result::Value(values),
+        eval::Result::Value(Exit::Await(_)) => unreachable!(),
+        // The traceback of the coroutine does not lead to the caller.
+        eval::Result::Error(Error::Runtime(error)) => eval::Result::Error(Error::Runtime(
+            RuntimeError::new(error.message().to_owned(), error.value()),
+        )),
+        eval::Result::Error(error) => eval::Result::Error(error),
+    }
+}
+
+// yield(...) suspends the running coroutine, which returns the values passed
+// to the resume continuing it. Lua code calling it directly is handled by the
+// dispatch loop, this is only reached through native functions.
+fn yield_(ctx: &Ctx, _args: Vec<Value>) -> eval::Result<Vec<Value>> {
+    let message = match ctx.coroutine() {
+        Some(_) => "attempt to yield across a C-call boundary",
+        None => "attempt to yield from outside a coroutine",
+    };
+
+    eval::Result::Error(runtime_error(String::from(message), ctx))
+}
+
+// status(co) returns "suspended", "running", "normal" or "dead".
+fn status(ctx: &Ctx, args: Vec<Value>) -> eval::Result<Vec<Value>> {
+    let coroutine = coroutine_arg(ctx, &args, 0, "status")?;
+    let status = match coroutine.status() {
+        Status::Running if coroutine.is_main() && ctx.coroutine().is_some() => Status::Normal,
+        status => status,
+    };
+
+    let name = ctx.intern(status.name().as_bytes());
+    eval::Result::Value(vec![Value::from_string(name)])
+}
+
+// running() returns the running coroutine and whether it is the main one.
+fn running(ctx: &Ctx, upvalues: &[Value], _args: Vec<Value>) -> eval::Result<Vec<Value>> {
+    let values = match ctx.coroutine() {
+        Some(coroutine) => vec![Value::from_thread(coroutine), Value::from_bool(false)],
+        None => vec![upvalues[0], Value::from_bool(true)],
+    };
+
+    eval::Result::Value(values)
+}
+
+// isyieldable() returns whether the running coroutine can yield.
+fn isyieldable(ctx: &Ctx, _args: Vec<Value>) -> eval::Result<Vec<Value>> {
+    eval::Result::Value(vec![Value::from_bool(ctx.is_yieldable())])
+}
+
+// close(co) kills a suspended or dead coroutine, closing its pending
+// to-be-closed variables. Returns true, or false followed by the error object
+// raised while closing them.
+fn close(ctx: &Ctx, args: Vec<Value>) -> eval::Result<Vec<Value>> {
+    let coroutine = coroutine_arg(ctx, &args, 0, "close")?;
+    match coroutine.status() {
+        Status::Suspended if !coroutine.is_main() => (),
+        Status::Dead => return eval::Result::Value(vec![Value::from_bool(true)]),
+        Status::Normal => {
+            let message = String::from("cannot close a normal coroutine");
+            return eval::Result::Error(runtime_error(message, ctx));
+        },
+        _ => {
+            let message = String::from("cannot close a running coroutine");
+            return eval::Result::Error(runtime_error(message, ctx));
+        },
+    }
+
+    // The coroutine is dead before its variables are closed so that their
+    // metamethods cannot resume it.
+    coroutine.set_status(Status::Dead);
+    let values = match coroutine.thread().close_all(ctx) {
+        eval::Result::Value(()) => vec![Value::from_bool(true)],
+        eval::Result::Error(Error::Runtime(error)) => vec![Value::from_bool(false), error.value()],
+        eval::Result::Error(error) => return eval::Result::Error(error),
+    };
+
+    eval::Result::Value(values)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::{value::Value, vm::tests::eval};
+
+    #[test]
+    fn eval_coroutines() {
+        let cases = &[
+            // Generators.
+            "local gen = coroutine.wrap(function(n) for i = 1, n do coroutine.yield(i) end end)
+            return gen(3) + gen() + gen() == 6",
+            // Values passed both ways.
+            "local co = coroutine.create(function(a, b)
+                local c = coroutine.yield(a + b)
+                local d, e = coroutine.yield(c * 2)
+                return d + e
+            end)
+            local _, x = coroutine.resume(co, 1, 2)
+            local _, y = coroutine.resume(co, 10)
+            local ok, z = coroutine.resume(co, 3, 4)
+            return x == 3 and y == 20 and ok and z == 7 and coroutine.status(co) == 'dead'",
+            // Yields across pcall, which still catches errors raised later.
+            "local co = coroutine.wrap(function()
+                local ok, err = pcall(function()
+                    local x = coroutine.yield(1)
+                    error(x)
+                end)
+                coroutine.yield(ok, err)
+                return pcall(coroutine.yield, 5)
+            end)
+            local a = co()
+            local ok, err = co('boom')
+            local b = co()
+            local ok2, c = co(6)
+            return a == 1 and not ok and err == 'boom' and b == 5 and ok2 and c == 6",
+            // Yields across metamethods and iterators.
+            "local t = setmetatable({}, {
+                __index = function(t, k) return coroutine.yield(k) end,
+                __add = function(a, b) return coroutine.yield('add') end,
+                __lt = function(a, b) return coroutine.yield('lt') end,
+            })
+            local function iter(s, i) if i < 3 then coroutine.yield('iter') return i + 1 end end
+            local co = coroutine.wrap(function()
+                local n = 0
+                for i in iter, nil, 0 do n = n + i end
+                return t.x, t + 1, t < t, n
+            end)
+            local log = {}
+            local a, b, c, d
+            local v = co()
+            while v ~= 'done' do
+                log[#log + 1] = v
+                if v == 'x' then v = co(10)
+                elseif v == 'add' then v = co(20)
+                elseif v == 'lt' then a, b, c, d = co(false) v = 'done'
+                else v = co() end
+            end
+            return #log == 6 and log[4] == 'x' and log[6] == 'lt'
+                and a == 10 and b == 20 and c == false and d == 6",
+            // Status, running and isyieldable.
+            "local main, ismain = coroutine.running()
+            local co
+            co = coroutine.create(function()
+                local inner = coroutine.create(function()
+                    return coroutine.status(co), coroutine.status(main)
+                end)
+                local _, outer, m = coroutine.resume(inner)
+                local running, ismain2 = coroutine.running()
+                coroutine.yield(outer, m, running == co, ismain2, coroutine.isyieldable())
+            end)
+            local before = coroutine.status(co)
+            local _, outer, m, same, ismain2, yieldable = coroutine.resume(co)
+            return ismain and before == 'suspended' and outer == 'normal' and m == 'normal'
+                and same and not ismain2 and yieldable and not coroutine.isyieldable()
+                and coroutine.status(co) == 'suspended' and coroutine.status(main) == 'running'",
+            // Errors kill the coroutine.
+            "local co = coroutine.create(function() error('oops') end)
+            local ok, err = coroutine.resume(co)
+            local ok2, err2 = coroutine.resume(co)
+            return not ok and err == 'oops' and coroutine.status(co) == 'dead'
+                and not ok2 and err2 == 'cannot resume dead coroutine'",
+            "local co
+            co = coroutine.create(function() return coroutine.resume(co) end)
+            local _, ok, err = coroutine.resume(co)
+            return not ok and err == 'cannot resume non-suspended coroutine'",
+            "local ok, err = pcall(coroutine.wrap(function() error('inner') end))
+            return not ok and err == 'inner'",
+            // Yields through native functions cannot be resumed.
+            "local co = coroutine.create(function()
+                local t = setmetatable({}, { __tostring = function() coroutine.yield() end })
+                return tostring(t)
+            end)
+            local ok, err = coroutine.resume(co)
+            return not ok and err == 'attempt to yield across a C-call boundary'",
+            "local ok, err = pcall(coroutine.yield)
+            return not ok and err == 'attempt to yield from outside a coroutine'",
+            // Closing runs pending to-be-closed variables.
+            "local closed = false
+            local co = coroutine.create(function()
+                local x <close> = setmetatable({}, { __close = function() closed = true end })
+                coroutine.yield()
+            end)
+            coroutine.resume(co)
+            local ok = coroutine.close(co)
+            return ok and closed and coroutine.status(co) == 'dead'
+                and not coroutine.resume(co)",
+        ];
+
+        for source in cases {
+            eval(source, |_, _, value| {
+                assert!(value == Value::from_bool(true), "{}", source)
+            });
+        }
+    }
+}
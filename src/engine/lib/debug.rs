@@ -0,0 +1,100 @@
+use super::{
+    super::{
+        value::Value,
+        vm::{
+            ctx::Ctx,
+            debug::{Hook, HookFunction, HookMask},
+            eval,
+        },
+    },
+    base::{arg, bad_argument, no_value},
+    Lib,
+};
+
+pub(super) fn open(lib: &mut Lib) {
+    let fields = vec![
+        ("gethook", lib.function("gethook", gethook)),
+        ("sethook", lib.function("sethook", sethook)),
+    ];
+
+    lib.module("debug", fields);
+}
+
+// sethook([f, mask [, count]]) sets the hook, which is called with the name of
+// each event and the current line. The mask holds 'c' for calls, 'r' for
+// returns and 'l' for lines, and a count above zero raises count events. The
+// hook is removed when called without a function or events.
+fn sethook(ctx: &Ctx, args: Vec<Value>) -> eval::Result<Vec<Value>> {
+    let function = arg(&args, 0);
+    if function == Value::from_nil() {
+        ctx.set_hook(None);
+        return eval::Result::Value(Vec::new());
+    }
+
+    if function.cast_function().is_none() {
+        let message = format!("function expected, got {}", no_value(&args, 0));
+        return bad_argument(ctx, 1, "sethook", &message);
+    }
+
+    let mask = match arg(&args, 1).cast_string() {
+        Some(mask) => unsafe { mask.get_unchecked() }.to_vec(),
+        None => {
+            let message = format!("string expected, got {}", no_value(&args, 1));
+            return bad_argument(ctx, 2, "sethook", &message);
+        },
+    };
+
+    let count = match arg(&args, 2) {
+        count if count == Value::from_nil() => 0,
+        count if count.is_int() && (0..=u32::MAX as i64).contains(&count.cast_int()) =>
+            count.cast_int() as u32,
+        _ => return bad_argument(ctx, 3, "sethook", "count out of range"),
+    };
+
+    let mask = HookMask {
+        call: mask.contains(&b'c'),
+        ret: mask.contains(&b'r'),
+        line: mask.contains(&b'l'),
+    };
+
+    let hook = if mask == HookMask::default() && count == 0 {
+        None
+    } else {
+        Some(Hook {
+            function: HookFunction::Lua(function),
+            mask,
+            count,
+        })
+    };
+
+    ctx.set_hook(hook);
+    eval::Result::Value(Vec::new())
+}
+
+// gethook() returns the hook function, its mask and count. Hooks set by the
+// host are reported as "external hook".
+fn gethook(ctx: &Ctx, _args: Vec<Value>) -> eval::Result<Vec<Value>> {
+    let hook = match ctx.hook() {
+        Some(hook) => hook,
+        None => return eval::Result::Value(Vec::new()),
+    };
+
+    let function = match hook.function {
+        HookFunction::Lua(function) => function,
+        HookFunction::Host(_) => Value::from_string(ctx.intern(b"external hook")),
+    };
+
+    let mut mask = Vec::new();
+    for (set, event) in [
+        (hook.mask.call, b'c'),
+        (hook.mask.ret, b'r'),
+        (hook.mask.line, b'l'),
+    ] {
+        if set {
+            mask.push(event);
+        }
+    }
+
+    let mask = Value::from_string(ctx.intern(&mask));
+    eval::Result::Value(vec![function, mask, ctx.int(hook.count as i64)])
+}
@@ -0,0 +1,74 @@
+use std::f64::consts::PI;
+
+use super::{
+    super::{
+        value::Value,
+        vm::{ctx::Ctx, eval},
+        OpError,
+    },
+    base::{arg, bad_argument},
+    Lib,
+};
+
+pub(super) fn open(lib: &mut Lib) {
+    let fields = vec![
+        ("huge", Value::from_float(f64::INFINITY)),
+        ("maxinteger", lib.int(i64::MAX)),
+        ("mininteger", lib.int(i64::MIN)),
+        ("pi", Value::from_float(PI)),
+        ("tointeger", lib.function("tointeger", tointeger)),
+        ("type", lib.function("type", type_)),
+        ("ult", lib.function("ult", ult)),
+    ];
+
+    lib.module("math", fields);
+}
+
+// Checks that an argument is a number with an integer representation.
+fn int_arg(ctx: &Ctx, args: &[Value], index: usize, name: &str) -> eval::Result<i64> {
+    let value = arg(args, index);
+    if let Some(x) = value.to_int() {
+        return eval::Result::Value(x);
+    }
+
+    let message = match value.type_name() {
+        "number" => OpError::NoIntegerRepresentation.to_string(),
+        ty => format!("number expected, got {}", ty),
+    };
+
+    bad_argument(ctx, index + 1, name, &message)
+}
+
+// math.tointeger(x) converts x to an integer if it has an exact integer
+// representation and returns nil otherwise.
+fn tointeger(ctx: &Ctx, args: Vec<Value>) -> eval::Result<Vec<Value>> {
+    let value = match arg(&args, 0).to_int() {
+        Some(x) => ctx.int(x),
+        None => Value::from_nil(),
+    };
+
+    eval::Result::Value(vec![value])
+}
+
+// math.type(x) returns "integer" or "float" for numbers and nil otherwise.
+fn type_(ctx: &Ctx, args: Vec<Value>) -> eval::Result<Vec<Value>> {
+    if args.is_empty() {
+        return bad_argument(ctx, 1, "type", "value expected");
+    }
+
+    let value = args[0];
+    let name: &[u8] = match value.type_name() {
+        "number" if value.is_int() => b"integer",
+        "number" => b"float",
+        _ => return eval::Result::Value(vec![Value::from_nil()]),
+    };
+
+    eval::Result::Value(vec![Value::from_string(ctx.intern(name))])
+}
+
+// math.ult(m, n) compares two integers as if they were unsigned.
+fn ult(ctx: &Ctx, args: Vec<Value>) -> eval::Result<Vec<Value>> {
+    let m = int_arg(ctx, &args, 0, "ult")?;
+    let n = int_arg(ctx, &args, 1, "ult")?;
+    eval::Result::Value(vec![Value::from_bool((m as u64) < (n as u64))])
+}
@@ -0,0 +1,116 @@
+//! The Lua standard library.
+
+mod base;
+mod coroutine;
+mod debug;
+mod math;
+mod string;
+
+use std::collections::hash_map::RandomState;
+
+use hashbrown::HashMap;
+
+use super::{
+    gc::{Handle, Heap},
+    value::{
+        BoxedInt,
+        ByteString,
+        Function,
+        Intrinsic,
+        Native,
+        NativeClosure,
+        NativeFunction,
+        Table,
+        Value,
+    },
+    vm::ctx,
+};
+
+/// Installs the standard library into a table of globals.
+pub fn open(
+    global: &mut Table,
+    heap: &Heap,
+    strings: &mut HashMap<Handle<ByteString>, (), RandomState>,
+    integers: &mut HashMap<i64, Handle<BoxedInt>, RandomState>,
+) {
+    let mut lib = Lib {
+        global,
+        heap,
+        strings,
+        integers,
+    };
+
+    base::open(&mut lib);
+    coroutine::open(&mut lib);
+    debug::open(&mut lib);
+    math::open(&mut lib);
+    string::open(&mut lib);
+}
+
+struct Lib<'a> {
+    global: &'a mut Table,
+    heap: &'a Heap,
+    strings: &'a mut HashMap<Handle<ByteString>, (), RandomState>,
+    integers: &'a mut HashMap<i64, Handle<BoxedInt>, RandomState>,
+}
+
+impl<'a> Lib<'a> {
+    fn string(&mut self, bytes: &[u8]) -> Value {
+        Value::from_string(ctx::intern(self.strings, self.heap, bytes))
+    }
+
+    fn int(&mut self, x: i64) -> Value {
+        match Value::try_from_int(x) {
+            Some(value) => value,
+            None => Value::from_boxed_int(ctx::box_int(self.integers, self.heap, x)),
+        }
+    }
+
+    fn function(&mut self, name: &'static str, function: NativeFunction) -> Value {
+        let function = Function::Native(Native::new(name, function));
+        Value::from_function(self.heap.insert(function))
+    }
+
+    fn closure(
+        &mut self,
+        name: &'static str,
+        function: NativeClosure,
+        upvalues: Vec<Value>,
+    ) -> Value {
+        let function = Function::Native(Native::closure(name, function, upvalues));
+        Value::from_function(self.heap.insert(function))
+    }
+
+    fn intrinsic(
+        &mut self,
+        name: &'static str,
+        function: NativeFunction,
+        intrinsic: Intrinsic,
+    ) -> Value {
+        let function = Function::Native(Native::with_intrinsic(name, function, intrinsic));
+        Value::from_function(self.heap.insert(function))
+    }
+
+    fn global(&mut self, name: &'static str, function: NativeFunction) {
+        let value = self.function(name, function);
+        self.global_value(name, value);
+    }
+
+    fn global_value(&mut self, name: &'static str, value: Value) {
+        let key = self.string(name.as_bytes());
+        self.global.insert(key, value);
+    }
+
+    // Installs a table of fields as a global, such as `math`.
+    fn module(&mut self, name: &'static str, fields: Vec<(&'static str, Value)>) {
+        let mut table = Table::new(self.heap.clone());
+        for (field, value) in fields {
+            let key = self.string(field.as_bytes());
+            table.insert(key, value);
+        }
+
+        let key = self.string(name.as_bytes());
+        let table = Value::from_table(self.heap.insert(table));
+        self.global.insert(key, table);
+    }
+}
@@ -0,0 +1,40 @@
+use super::{
+    super::{
+        value::{Function, Value},
+        vm::{
+            ctx::Ctx,
+            dump,
+            eval::{self, runtime_error},
+        },
+    },
+    base::{arg, bad_argument, no_value},
+    Lib,
+};
+
+pub(super) fn open(lib: &mut Lib) {
+    let fields = vec![("dump", lib.function("dump", dump))];
+    lib.module("string", fields);
+}
+
+// string.dump(f [, strip]) returns the binary representation of a Lua
+// function, which `load` turns back into a function. Stripping leaves out the
+// spans and names used in error messages.
+fn dump(ctx: &Ctx, args: Vec<Value>) -> eval::Result<Vec<Value>> {
+    let function = match arg(&args, 0).cast_function() {
+        Some(function) => function,
+        None => {
+            let message = format!("function expected, got {}", no_value(&args, 0));
+            return bad_argument(ctx, 1, "dump", &message);
+        },
+    };
+
+    let bytes = match unsafe { function.get_unchecked() } {
+        Function::Lua(closure) => dump::dump(closure.proto(), arg(&args, 1).is_truthy()),
+        Function::Native(_) => {
+            let message = String::from("unable to dump given function");
+            return eval::Result::Error(runtime_error(message, ctx));
+        },
+    };
+
+    eval::Result::Value(vec![Value::from_string(ctx.intern(&bytes))])
+}
@@ -1,7 +1,8 @@
 mod error;
 pub mod gc;
+mod lib;
 mod util;
 pub mod value;
 pub mod vm;
 
-pub use error::Error;
+pub use error::{Error, OpError, RuntimeError, TraceFrame};
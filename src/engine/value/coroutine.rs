@@ -0,0 +1,103 @@
+use std::cell::{Cell, RefCell, RefMut};
+
+use super::{
+    super::{
+        gc::{Handle, PtrTag, Trace, Visitor},
+        vm::interp::Thread,
+    },
+    encoding,
+    Function,
+};
+
+/// The states of a coroutine as reported by `coroutine.status`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status {
+    /// Created or yielded, waiting to be resumed.
+    Suspended,
+    Running,
+    /// Resumed another coroutine and waiting for it.
+    Normal,
+    /// Returned, failed or closed.
+    Dead,
+}
+
+impl Status {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Suspended => "suspended",
+            Self::Running => "running",
+            Self::Normal => "normal",
+            Self::Dead => "dead",
+        }
+    }
+}
+
+/// A coroutine: a thread of execution with its own stack that runs until it
+/// yields and continues where it left off when it is resumed.
+///
+/// The main coroutine stands for the code running outside of any coroutine.
+/// It has no stack of its own and is never suspended.
+pub struct Coroutine {
+    status: Cell<Status>,
+    main: bool,
+    thread: RefCell<Thread>,
+}
+
+impl Coroutine {
+    /// Creates a suspended coroutine that runs `function` when it is first
+    /// resumed.
+    pub fn new(function: Handle<Function>) -> Self {
+        Self {
+            status: Cell::new(Status::Suspended),
+            main: false,
+            thread: RefCell::new(Thread::new(Some(function))),
+        }
+    }
+
+    pub fn main() -> Self {
+        Self {
+            status: Cell::new(Status::Running),
+            main: true,
+            thread: RefCell::new(Thread::new(None)),
+        }
+    }
+
+    pub fn is_main(&self) -> bool {
+        self.main
+    }
+
+    /// The status of the coroutine. The main coroutine reports
+    /// [`Status::Running`] and leaves it to the caller to tell whether
+    /// another coroutine is running.
+    pub fn status(&self) -> Status {
+        self.status.get()
+    }
+
+    pub fn set_status(&self, status: Status) {
+        self.status.set(status);
+    }
+
+    pub fn thread(&self) -> RefMut<Thread> {
+        self.thread.borrow_mut()
+    }
+}
+
+impl Trace for Coroutine {
+    fn visit(&self, visitor: &mut Visitor) {
+        // The stack of a running coroutine is borrowed by the loop running
+        // it, which is responsible for it until the coroutine is suspended.
+        if let Ok(thread) = self.thread.try_borrow() {
+            thread.visit(visitor);
+        }
+    }
+}
+
+unsafe impl PtrTag for Coroutine {
+    fn is(x: u64) -> bool {
+        encoding::is_thread(x)
+    }
+
+    fn tag(x: usize) -> u64 {
+        encoding::make_thread(x as *mut u8)
+    }
+}
@@ -11,14 +11,30 @@ const TABLE_MASK: u64 = 0xFFFC000000000000;
 const STRING_MASK: u64 = 0xFFFE000000000000;
 const FUNCTION_MASK: u64 = 0xFFFA000000000000;
 const USERDATA_MASK: u64 = 0xFFFB000000000000;
+const BOXED_INT_MASK: u64 = 0xFFFD000000000000;
+const THREAD_MASK: u64 = 0xFFF9000000000000;
 const PTR_MASK: u64 = 0xFFFFFFFFFFFF;
 
+/// The range of integers stored inline in a value, the payload is a signed
+/// 48-bit integer. Integers outside of it are boxed on the heap.
+pub const INT_MIN: i64 = -(1 << 47);
+pub const INT_MAX: i64 = (1 << 47) - 1;
+
 const NIL_VALUE: u64 = 0x7FFE000000000000;
 const TRUE_VALUE: u64 = BOOL_MASK | 3;
 const FALSE_VALUE: u64 = BOOL_MASK | 2;
 
 pub fn is_ptr(x: u64) -> bool {
-    is_table(x) || is_string(x) || is_function(x) || is_userdata(x)
+    is_table(x)
+        || is_string(x)
+        || is_function(x)
+        || is_userdata(x)
+        || is_boxed_int(x)
+        || is_thread(x)
+}
+
+pub fn get_ptr(x: u64) -> *mut u8 {
+    (x & PTR_MASK) as *mut u8
 }
 
 pub fn is_nil(x: u64) -> bool {
@@ -30,7 +46,7 @@ pub fn make_nil() -> u64 {
 }
 
 pub fn is_bool(x: u64) -> bool {
-    x & BOOL_MASK == BOOL_MASK
+    x & !1 == FALSE_VALUE
 }
 
 pub fn make_bool(x: bool) -> u64 {
@@ -49,12 +65,19 @@ pub fn is_int(x: u64) -> bool {
     (x & FLOAT_MASK) == INTEGER_MASK
 }
 
-pub fn make_int(x: i32) -> u64 {
-    x as u64 | INTEGER_MASK
+pub fn fits_int(x: i64) -> bool {
+    (INT_MIN..=INT_MAX).contains(&x)
+}
+
+/// The integer must be within `INT_MIN..=INT_MAX`.
+pub fn make_int(x: i64) -> u64 {
+    debug_assert!(fits_int(x));
+    x as u64 & PTR_MASK | INTEGER_MASK
 }
 
-pub fn get_int(x: u64) -> i32 {
-    x as i32
+pub fn get_int(x: u64) -> i64 {
+    // Sign-extend the 48-bit payload.
+    ((x << 16) as i64) >> 16
 }
 
 pub fn is_float(x: u64) -> bool {
@@ -116,3 +139,27 @@ pub fn make_userdata(x: *mut u8) -> u64 {
 pub fn get_userdata(x: u64) -> *mut u8 {
     (x & PTR_MASK) as *mut u8
 }
+
+pub fn is_boxed_int(x: u64) -> bool {
+    (x & FLOAT_MASK) == BOXED_INT_MASK
+}
+
+pub fn make_boxed_int(x: *mut u8) -> u64 {
+    x as u64 | BOXED_INT_MASK
+}
+
+pub fn get_boxed_int(x: u64) -> *mut u8 {
+    (x & PTR_MASK) as *mut u8
+}
+
+pub fn is_thread(x: u64) -> bool {
+    (x & FLOAT_MASK) == THREAD_MASK
+}
+
+pub fn make_thread(x: *mut u8) -> u64 {
+    x as u64 | THREAD_MASK
+}
+
+pub fn get_thread(x: u64) -> *mut u8 {
+    (x & PTR_MASK) as *mut u8
+}
@@ -1 +1,227 @@
-pub struct Function {}
+use std::{cell::Cell, future::Future, pin::Pin, rc::Rc};
+
+use super::{
+    super::{
+        gc::{PtrTag, Trace, Visitor},
+        vm::{bytecode::Proto, ctx::Ctx, eval},
+    },
+    encoding,
+    Value,
+};
+
+/// The signature of a function implemented in Rust.
+///
+/// Natives receive their arguments already adjusted by the caller and may
+/// return any number of results.
+pub type NativeFunction = fn(&Ctx, Vec<Value>) -> eval::Result<Vec<Value>>;
+
+/// The signature of a native closure, which receives the values it was
+/// created with before its arguments.
+pub type NativeClosure = fn(&Ctx, &[Value], Vec<Value>) -> eval::Result<Vec<Value>>;
+
+/// What the future of an async host function produces, called with the
+/// context of the script once it continues to turn the outcome into values.
+pub type Completion = Box<dyn FnOnce(&Ctx) -> eval::Result<Vec<Value>>>;
+
+/// The future of a call to an async host function.
+pub type HostFuture = Pin<Box<dyn Future<Output = Completion>>>;
+
+/// The signature of an async host function, which receives its arguments and
+/// returns a future that the calling script waits on.
+pub type AsyncFunction = Rc<dyn Fn(&Ctx, Vec<Value>) -> HostFuture>;
+
+/// A shared mutable cell holding a local variable.
+///
+/// Every closure that captures a local holds a clone of the same cell, so
+/// writes through any of them are visible to all others and to the scope that
+/// declared the local. Cells outlive the scope they were declared in for as
+/// long as a closure refers to them.
+#[derive(Clone)]
+pub struct Upvalue {
+    cell: Rc<Cell<Value>>,
+}
+
+impl Upvalue {
+    pub fn new(value: Value) -> Self {
+        Self {
+            cell: Rc::new(Cell::new(value)),
+        }
+    }
+
+    pub fn get(&self) -> Value {
+        self.cell.get()
+    }
+
+    pub fn set(&self, value: Value) {
+        self.cell.set(value);
+    }
+
+    pub fn ptr_eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.cell, &other.cell)
+    }
+}
+
+impl Trace for Upvalue {
+    fn visit(&self, visitor: &mut Visitor) {
+        self.get().visit(visitor);
+    }
+}
+
+/// A callable value, either defined in Lua or provided by the host.
+pub enum Function {
+    Lua(Closure),
+    Native(Native),
+}
+
+impl Trace for Function {
+    fn visit(&self, visitor: &mut Visitor) {
+        match self {
+            Function::Lua(closure) => closure.visit(visitor),
+            Function::Native(native) => native.visit(visitor),
+        }
+    }
+}
+
+/// A Lua closure: a compiled function together with the cells it captured.
+pub struct Closure {
+    proto: Rc<Proto>,
+    upvalues: Vec<Upvalue>,
+}
+
+impl Closure {
+    pub fn new(proto: Rc<Proto>, upvalues: Vec<Upvalue>) -> Self {
+        Self { proto, upvalues }
+    }
+
+    pub fn proto(&self) -> &Rc<Proto> {
+        &self.proto
+    }
+
+    pub fn upvalues(&self) -> &[Upvalue] {
+        &self.upvalues
+    }
+}
+
+impl Trace for Closure {
+    fn visit(&self, visitor: &mut Visitor) {
+        self.proto.visit(visitor);
+
+        for upvalue in &self.upvalues {
+            upvalue.visit(visitor);
+        }
+    }
+}
+
+/// Natives whose calls the dispatch loop carries out itself when Lua code
+/// calls them directly, so that they do not hold on to the native stack.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Intrinsic {
+    /// `pcall`, which runs the called function in a frame of the loop.
+    PCall,
+    /// `coroutine.yield`, which suspends the coroutine running the loop.
+    Yield,
+}
+
+enum Body {
+    Function(NativeFunction),
+    Closure(NativeClosure, Vec<Value>),
+    Async(AsyncFunction),
+}
+
+/// A function implemented in Rust.
+pub struct Native {
+    name: &'static str,
+    body: Body,
+    intrinsic: Option<Intrinsic>,
+}
+
+impl Native {
+    pub fn new(name: &'static str, function: NativeFunction) -> Self {
+        Self {
+            name,
+            body: Body::Function(function),
+            intrinsic: None,
+        }
+    }
+
+    /// Creates a native closure over the given values.
+    pub fn closure(name: &'static str, function: NativeClosure, upvalues: Vec<Value>) -> Self {
+        Self {
+            name,
+            body: Body::Closure(function, upvalues),
+            intrinsic: None,
+        }
+    }
+
+    /// Creates an intrinsic, whose function is only called when it is not
+    /// called directly by Lua code.
+    pub fn with_intrinsic(
+        name: &'static str,
+        function: NativeFunction,
+        intrinsic: Intrinsic,
+    ) -> Self {
+        Self {
+            name,
+            body: Body::Function(function),
+            intrinsic: Some(intrinsic),
+        }
+    }
+
+    /// Creates an async host function, which only Lua code run by an async
+    /// call can call.
+    pub fn new_async(name: &'static str, function: AsyncFunction) -> Self {
+        Self {
+            name,
+            body: Body::Async(function),
+            intrinsic: None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// The function of an async host function.
+    pub fn async_function(&self) -> Option<&AsyncFunction> {
+        match &self.body {
+            Body::Async(function) => Some(function),
+            _ => None,
+        }
+    }
+
+    pub fn intrinsic(&self) -> Option<Intrinsic> {
+        self.intrinsic
+    }
+
+    pub fn call(&self, ctx: &Ctx, args: Vec<Value>) -> eval::Result<Vec<Value>> {
+        match &self.body {
+            Body::Function(function) => function(ctx, args),
+            Body::Closure(function, upvalues) => function(ctx, upvalues, args),
+            // The dispatch loop of an async call awaits these itself.
+            Body::Async(_) => {
+                let message = String::from("attempt to await outside of an async call");
+                eval::Result::Error(eval::runtime_error(message, ctx))
+            },
+        }
+    }
+}
+
+impl Trace for Native {
+    fn visit(&self, visitor: &mut Visitor) {
+        if let Body::Closure(_, upvalues) = &self.body {
+            for value in upvalues {
+                value.visit(visitor);
+            }
+        }
+    }
+}
+
+unsafe impl PtrTag for Function {
+    fn is(x: u64) -> bool {
+        encoding::is_function(x)
+    }
+
+    fn tag(x: usize) -> u64 {
+        encoding::make_function(x as *mut u8)
+    }
+}
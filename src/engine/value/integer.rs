@@ -0,0 +1,29 @@
+use super::{super::gc::PtrTag, encoding};
+
+/// An integer too large to be stored inline in a value.
+///
+/// Boxed integers are interned like strings, so that two values holding the
+/// same integer are always bitwise equal.
+pub struct BoxedInt {
+    value: i64,
+}
+
+impl BoxedInt {
+    pub fn new(value: i64) -> Self {
+        Self { value }
+    }
+
+    pub fn get(&self) -> i64 {
+        self.value
+    }
+}
+
+unsafe impl PtrTag for BoxedInt {
+    fn is(x: u64) -> bool {
+        encoding::is_boxed_int(x)
+    }
+
+    fn tag(x: usize) -> u64 {
+        encoding::make_boxed_int(x as *mut u8)
+    }
+}
@@ -1,18 +1,36 @@
+mod coroutine;
 pub mod encoding;
 mod function;
+mod integer;
+mod shape;
 mod string;
 mod table;
 mod userdata;
 
-use std::cmp::PartialEq;
+use std::cmp::{Ordering, PartialEq};
 
+pub use coroutine::{Coroutine, Status};
 use encoding::*;
-pub use function::Function;
+pub use function::{
+    AsyncFunction,
+    Closure,
+    Completion,
+    Function,
+    HostFuture,
+    Intrinsic,
+    Native,
+    NativeClosure,
+    NativeFunction,
+    Upvalue,
+};
+pub use integer::BoxedInt;
+pub use shape::{InlineCache, Shape};
 pub use string::ByteString;
 pub use table::Table;
 pub use userdata::Userdata;
 
 use super::{
+    error::OpError,
     gc::{Handle, TaggedHandle, Trace, Visitor},
     util::mix_u64,
     vm::ctx::Ctx,
@@ -28,6 +46,7 @@ enum ValueType {
     String,
     Function,
     Userdata,
+    Thread,
 }
 
 // Customized match using NaN-boxing type guards.
@@ -38,9 +57,13 @@ enum ValueType {
 //   - nil
 //   - table
 //   - string
-//   - float
 //   - function
 //   - userdata
+//   - thread
+//   - float
+//
+// Floats must come last as every bit pattern not claimed by another type
+// is a float.
 macro_rules! dispatch {
     ($x:expr, $($guard:ident => $arm:expr),*) => {{
         match $x {
@@ -57,19 +80,124 @@ macro_rules! dispatch {
     }}
 }
 
-fn int_op(iop: fn(i32, i32) -> i32, a: Value, b: Value) -> Value {
-    if a.ty() != ValueType::Int || b.ty() != ValueType::Int {
-        panic!("int_op: invalid types");
+fn int_op(iop: fn(i64, i64) -> i64, a: Value, b: Value, ctx: &Ctx) -> Result<Value, OpError> {
+    let x = a.bitwise_operand()?;
+    let y = b.bitwise_operand()?;
+    Ok(ctx.int(iop(x, y)))
+}
+
+fn arith_op(
+    iop: fn(i64, i64) -> Result<i64, OpError>,
+    fop: fn(f64, f64) -> f64,
+    a: Value,
+    b: Value,
+    ctx: &Ctx,
+) -> Result<Value, OpError> {
+    // Inline integers are the common case and never touch the heap unless
+    // the result has to be boxed.
+    if is_int(a.data) && is_int(b.data) {
+        return Ok(ctx.int(iop(get_int(a.data), get_int(b.data))?));
+    }
+
+    match (a.ty(), b.ty()) {
+        (ValueType::Int, ValueType::Int) => Ok(ctx.int(iop(a.cast_int(), b.cast_int())?)),
+        (ValueType::Int | ValueType::Float, ValueType::Int | ValueType::Float) =>
+            Ok(Value::from_float(fop(a.convert_float(), b.convert_float()))),
+        (ValueType::Int | ValueType::Float, _) => Err(OpError::Arithmetic(b.type_name())),
+        _ => Err(OpError::Arithmetic(a.type_name())),
     }
+}
 
-    Value::from_int(iop(get_int(a.data), get_int(b.data)))
+// Operators whose result is always a float.
+fn float_op(fop: fn(f64, f64) -> f64, a: Value, b: Value) -> Result<Value, OpError> {
+    match (a.ty(), b.ty()) {
+        (ValueType::Int | ValueType::Float, ValueType::Int | ValueType::Float) =>
+            Ok(Value::from_float(fop(a.convert_float(), b.convert_float()))),
+        (ValueType::Int | ValueType::Float, _) => Err(OpError::Arithmetic(b.type_name())),
+        _ => Err(OpError::Arithmetic(a.type_name())),
+    }
 }
 
-fn arith_op(iop: fn(i32, i32) -> Value, fop: fn(f64, f64) -> Value, a: Value, b: Value) -> Value {
-    if a.ty() == ValueType::Float || b.ty() == ValueType::Float {
-        fop(a.convert_float(), b.convert_float())
+fn compare_op(test: fn(Ordering) -> bool, a: Value, b: Value) -> Result<Value, OpError> {
+    let ordering = match (a.ty(), b.ty()) {
+        (ValueType::String, ValueType::String) =>
+            Some(a.cast_string_unchecked().cmp(b.cast_string_unchecked())),
+        _ => match a.number_cmp(b) {
+            Some(ordering) => ordering,
+            None => return Err(OpError::Compare(a.type_name(), b.type_name())),
+        },
+    };
+
+    // Comparisons involving NaN are always false.
+    Ok(Value::from_bool(ordering.map_or(false, test)))
+}
+
+// Compares an integer with a float exactly, without rounding the integer.
+fn int_float_cmp(i: i64, f: f64) -> Option<Ordering> {
+    const LIMIT: f64 = 9223372036854775808.0; // 2^63
+
+    if f.is_nan() {
+        None
+    } else if f >= LIMIT {
+        Some(Ordering::Less)
+    } else if f < -LIMIT {
+        Some(Ordering::Greater)
     } else {
-        iop(a.cast_int(), b.cast_int())
+        // The floor is in range and has an exact integer representation.
+        let floor = f.floor();
+        match i.cmp(&(floor as i64)) {
+            Ordering::Equal if f > floor => Some(Ordering::Less),
+            ordering => Some(ordering),
+        }
+    }
+}
+
+// Shifts left for positive and right for negative displacements, shifting in
+// zeros and producing zero once every bit has been shifted out.
+fn shift_left(x: i64, n: i64) -> i64 {
+    match n {
+        n if n <= -64 || n >= 64 => 0,
+        n if n >= 0 => ((x as u64) << n) as i64,
+        n => ((x as u64) >> -n) as i64,
+    }
+}
+
+// Formats a float the way Lua's "%.14g" does, marking integral values with
+// a trailing ".0" so they read back as floats.
+pub fn format_float(x: f64) -> String {
+    if x.is_nan() {
+        return String::from(if x.is_sign_negative() { "-nan" } else { "nan" });
+    }
+
+    if x.is_infinite() {
+        return String::from(if x < 0.0 { "-inf" } else { "inf" });
+    }
+
+    let scientific = format!("{:.13e}", x);
+    let (mantissa, exponent) = scientific.split_once('e').unwrap();
+    let exponent: i32 = exponent.parse().unwrap();
+
+    let mut out = if !(-4..14).contains(&exponent) {
+        let mantissa = trim_fraction(mantissa);
+        let sign = if exponent < 0 { '-' } else { '+' };
+        format!("{}e{}{:02}", mantissa, sign, exponent.abs())
+    } else {
+        let precision = (13 - exponent).max(0) as usize;
+        trim_fraction(&format!("{:.*}", precision, x)).to_string()
+    };
+
+    if !out.contains(['.', 'e', 'n', 'i']) {
+        out.push_str(".0");
+    }
+
+    out
+}
+
+fn trim_fraction(x: &str) -> &str {
+    if x.contains('.') {
+        x.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        x
     }
 }
 
@@ -81,13 +209,15 @@ fn arith_op(iop: fn(i32, i32) -> Value, fop: fn(f64, f64) -> Value, a: Value, b:
 //   - Nil
 //   - True
 //   - False
-//   - Integer: a signed 32-bit integer
+//   - Integer: a signed 64-bit integer, boxed on the heap if it does not fit
+//     into 48 bits
 //   - Float: a 64-bit IEEE-754 floating point number
 //   - Object
 //     - Table: a Lua table
 //     - String: a heap-allocated UTF-8 string
 //     - Function: a Lua function, possibly with captured upvalues
 //     - Userdata: a custom type defined outside of Lua
+//     - Thread: a coroutine
 #[derive(Clone, Copy, PartialEq)]
 pub struct Value {
     data: u64,
@@ -102,8 +232,35 @@ impl Value {
         Value { data: make_bool(x) }
     }
 
+    /// Integers of up to 32 bits are always stored inline. Use
+    /// [`Ctx::int`] for arbitrary integers.
     pub fn from_int(x: i32) -> Self {
-        Value { data: make_int(x) }
+        Value {
+            data: make_int(x as i64),
+        }
+    }
+
+    // Lengths are bounded by the address space and always fit inline.
+    fn from_len(len: usize) -> Self {
+        Value {
+            data: make_int(len as i64),
+        }
+    }
+
+    /// Stores an integer inline if it fits into the payload of a value.
+    pub fn try_from_int(x: i64) -> Option<Self> {
+        if fits_int(x) {
+            Some(Value { data: make_int(x) })
+        } else {
+            None
+        }
+    }
+
+    /// The boxed integer must not fit inline.
+    pub fn from_boxed_int(x: Handle<BoxedInt>) -> Self {
+        Value {
+            data: make_boxed_int(x.as_ptr() as *mut u8),
+        }
     }
 
     pub fn from_float(x: f64) -> Self {
@@ -124,9 +281,9 @@ impl Value {
         }
     }
 
-    pub fn from_function(x: *mut u8) -> Self {
+    pub fn from_function(x: Handle<Function>) -> Self {
         Value {
-            data: make_function(x),
+            data: make_function(x.as_ptr() as *mut u8),
         }
     }
 
@@ -136,6 +293,12 @@ impl Value {
         }
     }
 
+    pub fn from_thread(x: Handle<Coroutine>) -> Self {
+        Value {
+            data: make_thread(x.as_ptr() as *mut u8),
+        }
+    }
+
     fn cast_string_unchecked<'a>(self) -> &'a ByteString {
         unsafe { &*(get_string(self.data) as *const ByteString) }
     }
@@ -148,6 +311,51 @@ impl Value {
         unsafe { &*(get_table(self.data) as *const Table) }
     }
 
+    fn cast_table_unchecked_mut<'a>(self) -> &'a mut Table {
+        unsafe { &mut *(get_table(self.data) as *mut Table) }
+    }
+
+    pub fn cast_function(self) -> Option<Handle<Function>> {
+        if self.ty() == ValueType::Function {
+            Some(Handle::new(get_function(self.data) as *mut Function))
+        } else {
+            None
+        }
+    }
+
+    pub fn cast_thread(self) -> Option<Handle<Coroutine>> {
+        if self.ty() == ValueType::Thread {
+            Some(Handle::new(get_thread(self.data) as *mut Coroutine))
+        } else {
+            None
+        }
+    }
+
+    pub fn cast_table(self) -> Option<Handle<Table>> {
+        if self.ty() == ValueType::Table {
+            Some(Handle::new(get_table(self.data) as *mut Table))
+        } else {
+            None
+        }
+    }
+
+    /// The address of the object a reference type points to.
+    pub fn addr(self) -> Option<usize> {
+        if is_ptr(self.data) && !is_boxed_int(self.data) {
+            Some(get_ptr(self.data) as usize)
+        } else {
+            None
+        }
+    }
+
+    pub fn cast_string(self) -> Option<Handle<ByteString>> {
+        if self.ty() == ValueType::String {
+            Some(Handle::new(get_string(self.data) as *mut ByteString))
+        } else {
+            None
+        }
+    }
+
     pub fn is_truthy(self) -> bool {
         match self.ty() {
             ValueType::Nil => false,
@@ -159,15 +367,72 @@ impl Value {
     pub fn convert_float(self) -> f64 {
         match self.ty() {
             ValueType::Float => get_float(self.data),
-            ValueType::Int => get_int(self.data) as f64,
+            ValueType::Int => self.cast_int() as f64,
             _ => panic!("cannot convert to float"),
         }
     }
 
-    pub fn cast_int(self) -> i32 {
+    pub fn is_number(self) -> bool {
+        matches!(self.ty(), ValueType::Int | ValueType::Float)
+    }
+
+    pub fn is_int(self) -> bool {
+        self.ty() == ValueType::Int
+    }
+
+    pub fn cast_int(self) -> i64 {
+        if is_int(self.data) {
+            get_int(self.data)
+        } else if is_boxed_int(self.data) {
+            unsafe { (*(get_boxed_int(self.data) as *const BoxedInt)).get() }
+        } else {
+            panic!("value is not int")
+        }
+    }
+
+    /// Converts a number with an exact integer representation to an integer.
+    pub fn to_int(self) -> Option<i64> {
         match self.ty() {
-            ValueType::Int => get_int(self.data),
-            _ => panic!("value is not int"),
+            ValueType::Int => Some(self.cast_int()),
+            ValueType::Float => {
+                let x = get_float(self.data);
+                // -2^63 is the smallest integer, 2^63 is just out of range.
+                if x.fract() == 0.0 && x >= i64::MIN as f64 && x < -(i64::MIN as f64) {
+                    Some(x as i64)
+                } else {
+                    None
+                }
+            },
+            _ => None,
+        }
+    }
+
+    // Orders two numbers, `None` if either is not a number and `Some(None)`
+    // if they are unordered because one of them is NaN.
+    fn number_cmp(self, other: Self) -> Option<Option<Ordering>> {
+        Some(match (self.ty(), other.ty()) {
+            (ValueType::Int, ValueType::Int) => Some(self.cast_int().cmp(&other.cast_int())),
+            (ValueType::Int, ValueType::Float) =>
+                int_float_cmp(self.cast_int(), get_float(other.data)),
+            (ValueType::Float, ValueType::Int) =>
+                int_float_cmp(other.cast_int(), get_float(self.data)).map(Ordering::reverse),
+            (ValueType::Float, ValueType::Float) =>
+                get_float(self.data).partial_cmp(&get_float(other.data)),
+            _ => return None,
+        })
+    }
+
+    /// The name of the value's type as reported by Lua's `type` function.
+    pub fn type_name(self) -> &'static str {
+        match self.ty() {
+            ValueType::Nil => "nil",
+            ValueType::Bool => "boolean",
+            ValueType::Int | ValueType::Float => "number",
+            ValueType::Table => "table",
+            ValueType::String => "string",
+            ValueType::Function => "function",
+            ValueType::Userdata => "userdata",
+            ValueType::Thread => "thread",
         }
     }
 
@@ -178,267 +443,258 @@ impl Value {
             is_nil => ValueType::Nil,
             is_table => ValueType::Table,
             is_string => ValueType::String,
-            is_float => ValueType::Float,
             is_function => ValueType::Function,
-            is_userdata => ValueType::Userdata
+            is_userdata => ValueType::Userdata,
+            is_thread => ValueType::Thread,
+            is_boxed_int => ValueType::Int,
+            is_float => ValueType::Float
         )
     }
 
     pub fn op_eq(self, other: Self) -> Value {
-        Value::from_bool(self.data == other.data)
-    }
-
-    pub fn op_gt(self, other: Self) -> Value {
-        let ty_1 = self.ty();
-        let ty_2 = other.ty();
-
-        if ty_1 != ty_2 {
-            return Value::from_bool(false);
+        // Everything but floats is canonical and compares bitwise, floats
+        // compare by value with both floats and integers.
+        if self.ty() != ValueType::Float && other.ty() != ValueType::Float {
+            return Value::from_bool(self.data == other.data);
         }
 
-        Value::from_bool(match ty_1 {
-            ValueType::Int => get_int(self.data) > get_int(other.data),
-            ValueType::Float => get_float(self.data) > get_float(other.data),
-            ValueType::String => **self.cast_string_unchecked() > **other.cast_string_unchecked(),
-            _ => panic!("attempted op_gt on unsupported type: {:?}", ty_1),
-        })
+        Value::from_bool(self.number_cmp(other) == Some(Some(Ordering::Equal)))
     }
 
-    pub fn op_lt(self, other: Self) -> Value {
-        let ty_1 = self.ty();
-        let ty_2 = other.ty();
-
-        if ty_1 != ty_2 {
-            return Value::from_bool(false);
-        }
+    pub fn op_gt(self, other: Self) -> Result<Value, OpError> {
+        other.op_lt(self)
+    }
 
-        Value::from_bool(match ty_1 {
-            ValueType::Int => get_int(self.data) < get_int(other.data),
-            ValueType::Float => get_float(self.data) < get_float(other.data),
-            ValueType::String => **self.cast_string_unchecked() < **other.cast_string_unchecked(),
-            _ => panic!("attempted op_lt on unsupported type: {:?}", ty_1),
-        })
+    pub fn op_lt(self, other: Self) -> Result<Value, OpError> {
+        compare_op(|ordering| ordering == Ordering::Less, self, other)
     }
 
     pub fn op_and(self, other: Self) -> Self {
-        Value::from_bool(self.is_truthy() && other.is_truthy())
+        if self.is_truthy() {
+            other
+        } else {
+            self
+        }
     }
 
     pub fn op_or(self, other: Self) -> Self {
-        Value::from_bool(self.is_truthy() || other.is_truthy())
+        if self.is_truthy() {
+            self
+        } else {
+            other
+        }
     }
 
-    pub fn op_add(self, other: Self) -> Self {
-        arith_op(
-            |a, b| Value::from_int(a + b),
-            |a, b| Value::from_float(a + b),
-            self,
-            other,
-        )
+    pub fn op_add(self, other: Self, ctx: &Ctx) -> Result<Value, OpError> {
+        arith_op(|a, b| Ok(a.wrapping_add(b)), |a, b| a + b, self, other, ctx)
     }
 
-    pub fn op_sub(self, other: Self) -> Self {
-        arith_op(
-            |a, b| Value::from_int(a - b),
-            |a, b| Value::from_float(a - b),
-            self,
-            other,
-        )
+    pub fn op_sub(self, other: Self, ctx: &Ctx) -> Result<Value, OpError> {
+        arith_op(|a, b| Ok(a.wrapping_sub(b)), |a, b| a - b, self, other, ctx)
     }
 
-    pub fn op_mul(self, other: Self) -> Self {
-        arith_op(
-            |a, b| Value::from_int(a * b),
-            |a, b| Value::from_float(a * b),
-            self,
-            other,
-        )
+    pub fn op_mul(self, other: Self, ctx: &Ctx) -> Result<Value, OpError> {
+        arith_op(|a, b| Ok(a.wrapping_mul(b)), |a, b| a * b, self, other, ctx)
     }
 
-    pub fn op_div(self, other: Self) -> Self {
-        arith_op(
-            |a, b| Value::from_float(a as f64 / b as f64),
-            |a, b| Value::from_float(a / b),
-            self,
-            other,
-        )
+    pub fn op_div(self, other: Self) -> Result<Value, OpError> {
+        float_op(|a, b| a / b, self, other)
     }
 
-    pub fn op_int_div(self, other: Self) -> Self {
+    pub fn op_int_div(self, other: Self, ctx: &Ctx) -> Result<Value, OpError> {
         arith_op(
-            |a, b| Value::from_int(a / b),
-            |a, b| Value::from_int((a / b).floor() as i32),
+            |a, b| match b {
+                0 => Err(OpError::DivideByZero),
+                -1 => Ok(a.wrapping_neg()),
+                _ => {
+                    let q = a / b;
+                    if (a % b != 0) && ((a < 0) != (b < 0)) {
+                        Ok(q - 1)
+                    } else {
+                        Ok(q)
+                    }
+                },
+            },
+            |a, b| (a / b).floor(),
             self,
             other,
+            ctx,
         )
     }
 
-    pub fn op_exp(self, other: Self) -> Self {
-        arith_op(
-            |a, b| Value::from_int(a.pow(b as u32)),
-            |a, b| Value::from_float(a.powf(b)),
-            self,
-            other,
-        )
+    pub fn op_exp(self, other: Self) -> Result<Value, OpError> {
+        float_op(f64::powf, self, other)
     }
 
-    pub fn op_mod(self, other: Self) -> Self {
+    pub fn op_mod(self, other: Self, ctx: &Ctx) -> Result<Value, OpError> {
         arith_op(
-            |a, b| Value::from_int(a % b),
-            |a, b| Value::from_float(a % b),
+            |a, b| match b {
+                0 => Err(OpError::ModuloByZero),
+                -1 => Ok(0),
+                _ => {
+                    let r = a % b;
+                    if r != 0 && ((r < 0) != (b < 0)) {
+                        Ok(r + b)
+                    } else {
+                        Ok(r)
+                    }
+                },
+            },
+            |a, b| {
+                let r = a % b;
+                if r != 0.0 && ((r < 0.0) != (b < 0.0)) {
+                    r + b
+                } else {
+                    r
+                }
+            },
             self,
             other,
+            ctx,
         )
     }
 
-    pub fn op_bit_and(self, other: Self) -> Self {
-        int_op(|a, b| a & b, self, other)
+    pub fn op_bit_and(self, other: Self, ctx: &Ctx) -> Result<Value, OpError> {
+        int_op(|a, b| a & b, self, other, ctx)
     }
 
-    pub fn op_bit_or(self, other: Self) -> Self {
-        int_op(|a, b| a | b, self, other)
+    pub fn op_bit_or(self, other: Self, ctx: &Ctx) -> Result<Value, OpError> {
+        int_op(|a, b| a | b, self, other, ctx)
     }
 
-    pub fn op_lshift(self, other: Self) -> Self {
-        int_op(|a, b| a << b, self, other)
+    pub fn op_lshift(self, other: Self, ctx: &Ctx) -> Result<Value, OpError> {
+        int_op(shift_left, self, other, ctx)
     }
 
-    pub fn op_rshift(self, other: Self) -> Self {
-        int_op(|a, b| a >> b, self, other)
+    pub fn op_rshift(self, other: Self, ctx: &Ctx) -> Result<Value, OpError> {
+        int_op(|a, b| shift_left(a, b.wrapping_neg()), self, other, ctx)
     }
 
-    pub fn op_bit_xor(self, other: Self) -> Self {
-        int_op(|a, b| a ^ b, self, other)
+    pub fn op_bit_xor(self, other: Self, ctx: &Ctx) -> Result<Value, OpError> {
+        int_op(|a, b| a ^ b, self, other, ctx)
     }
 
     pub fn op_neq(self, other: Self) -> Self {
         Value::from_bool(!get_bool(self.op_eq(other).data))
     }
 
-    pub fn op_leq(self, other: Self) -> Self {
-        let ty_1 = self.ty();
-        let ty_2 = other.ty();
-
-        if ty_1 != ty_2 {
-            return Value::from_bool(false);
-        }
-
-        Value::from_bool(match ty_1 {
-            ValueType::Int => get_int(self.data) <= get_int(other.data),
-            ValueType::Float => get_float(self.data) <= get_float(other.data),
-            ValueType::String => **self.cast_string_unchecked() <= **other.cast_string_unchecked(),
-            _ => panic!("attempted op_leq on unsupported type: {:?}", ty_1),
-        })
+    pub fn op_leq(self, other: Self) -> Result<Value, OpError> {
+        compare_op(|ordering| ordering != Ordering::Greater, self, other)
     }
 
-    pub fn op_geq(self, other: Self) -> Self {
-        let ty_1 = self.ty();
-        let ty_2 = other.ty();
-
-        if ty_1 != ty_2 {
-            return Value::from_bool(false);
-        }
-
-        Value::from_bool(match ty_1 {
-            ValueType::Int => get_int(self.data) >= get_int(other.data),
-            ValueType::Float => get_float(self.data) >= get_float(other.data),
-            ValueType::String => **self.cast_string_unchecked() >= **other.cast_string_unchecked(),
-            _ => panic!("attempted op_geq on unsupported type: {:?}", ty_1),
-        })
+    pub fn op_geq(self, other: Self) -> Result<Value, OpError> {
+        other.op_leq(self)
     }
 
-    pub fn op_property(self, other: Self) -> Self {
+    pub fn op_property(self, other: Self) -> Result<Value, OpError> {
         if self.ty() != ValueType::Table {
-            panic!()
+            return Err(OpError::Index(self.type_name()));
         }
 
         let table = self.cast_table_unchecked();
-        table.get(other)
+        Ok(table.get(other))
     }
 
-    pub fn op_method(self, other: Self) -> Self {
+    pub fn op_set_property(self, key: Self, value: Self) -> Result<(), OpError> {
         if self.ty() != ValueType::Table {
-            panic!()
+            return Err(OpError::Index(self.type_name()));
         }
 
-        let table = self.cast_table_unchecked();
-        let value = table.get(other);
+        match key.ty() {
+            ValueType::Nil => return Err(OpError::IndexNil),
+            ValueType::Float if get_float(key.data).is_nan() => return Err(OpError::IndexNaN),
+            _ => (),
+        }
 
-        if value.ty() != ValueType::Function {
-            panic!()
+        let table = self.cast_table_unchecked_mut();
+        if value.ty() == ValueType::Nil {
+            table.remove(key);
+        } else {
+            table.insert(key, value);
         }
 
-        value
+        Ok(())
     }
 
-    pub fn op_concat(self, other: Self, ctx: &Ctx) -> Self {
-        let ty_1 = self.ty();
-        let ty_2 = other.ty();
-
-        if ty_1 != ValueType::String && ty_2 != ValueType::String {
-            panic!()
-        }
+    pub fn op_method(self, other: Self) -> Result<Value, OpError> {
+        self.op_property(other)
+    }
 
-        let str_1 = self.cast_string_unchecked();
-        let str_2 = other.cast_string_unchecked();
+    pub fn op_concat(self, other: Self, ctx: &Ctx) -> Result<Value, OpError> {
         let mut buf = Vec::new();
-        buf.extend_from_slice(str_1);
-        buf.extend_from_slice(str_2);
+        self.concat_operand(&mut buf)?;
+        other.concat_operand(&mut buf)?;
         let new_str = ctx.intern(&buf);
-        Value::from_string(new_str)
+        Ok(Value::from_string(new_str))
     }
 
-    pub fn op_neg(self) -> Self {
+    fn concat_operand(self, buf: &mut Vec<u8>) -> Result<(), OpError> {
         match self.ty() {
-            ValueType::Int => Value::from_int(-get_int(self.data)),
-            ValueType::Float => Value::from_float(-get_float(self.data)),
-            _ => panic!(),
+            ValueType::String => buf.extend_from_slice(self.cast_string_unchecked()),
+            ValueType::Int => buf.extend_from_slice(self.cast_int().to_string().as_bytes()),
+            ValueType::Float =>
+                buf.extend_from_slice(format_float(get_float(self.data)).as_bytes()),
+            _ => return Err(OpError::Concat(self.type_name())),
         }
+
+        Ok(())
     }
 
-    pub fn op_not(self) -> Self {
-        Value::from_bool(!self.is_truthy())
+    // Converts an operand of a bitwise operator to an integer.
+    fn bitwise_operand(self) -> Result<i64, OpError> {
+        match self.ty() {
+            ValueType::Int => Ok(self.cast_int()),
+            ValueType::Float => self.to_int().ok_or(OpError::NoIntegerRepresentation),
+            _ => Err(OpError::Bitwise(self.type_name())),
+        }
     }
 
-    pub fn op_len(self) -> Self {
+    pub fn op_neg(self, ctx: &Ctx) -> Result<Value, OpError> {
         match self.ty() {
-            ValueType::Table => {
-                let len = self.cast_table_unchecked().len();
-                Value::from_int(len as i32)
-            },
-            ValueType::String => {
-                let len = self.cast_string_unchecked().len();
-                Value::from_int(len as i32)
-            },
-            _ => panic!(),
+            ValueType::Int => Ok(ctx.int(self.cast_int().wrapping_neg())),
+            ValueType::Float => Ok(Value::from_float(-get_float(self.data))),
+            _ => Err(OpError::Arithmetic(self.type_name())),
         }
     }
 
-    pub fn op_bit_not(self) -> Self {
-        if let ValueType::Int = self.ty() {
-            let x = get_int(self.data);
-            return Value::from_int(!x);
+    pub fn op_not(self) -> Self {
+        Value::from_bool(!self.is_truthy())
+    }
+
+    pub fn op_len(self) -> Result<Value, OpError> {
+        match self.ty() {
+            ValueType::Table => Ok(Value::from_len(self.cast_table_unchecked().border())),
+            ValueType::String => Ok(Value::from_len(self.cast_string_unchecked().len())),
+            _ => Err(OpError::Length(self.type_name())),
         }
+    }
 
-        panic!()
+    pub fn op_bit_not(self, ctx: &Ctx) -> Result<Value, OpError> {
+        Ok(ctx.int(!self.bitwise_operand()?))
     }
 
     pub fn op_hash(self) -> u64 {
         mix_u64(self.data)
     }
+
+    /// The handle of the object the value refers to, if it is one.
+    pub fn handle(self) -> Option<TaggedHandle> {
+        is_ptr(self.data).then(|| TaggedHandle::new(self.data))
+    }
+
+    /// The value referring to an object.
+    pub fn from_handle(handle: TaggedHandle) -> Self {
+        Value {
+            data: handle.value(),
+        }
+    }
 }
 
 impl Trace for Value {
+    // The collector traces the object from its worklist.
     fn visit(&self, visitor: &mut Visitor) {
-        let handle = TaggedHandle::new(self.data);
-
-        if is_ptr(self.data) && !visitor.is_marked(handle) {
+        if let Some(handle) = self.handle() {
             visitor.mark(handle);
-
-            if is_table(self.data) {
-                let table = unsafe { &mut *(get_table(self.data) as *mut Table) };
-                table.visit(visitor);
-            }
         }
     }
 }
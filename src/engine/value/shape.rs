@@ -0,0 +1,275 @@
+//! Hidden classes for the string keys of tables.
+//!
+//! Tables that gain the same string keys in the same order share a [`Shape`]
+//! which maps each key to a slot in the fields of the table. Adding a key
+//! moves a table along a transition to a child shape, and transitions are
+//! remembered so that tables built alike end up with the very same shape.
+//! Removing a key moves a table back to the shape of the keys left, so a
+//! shape only holds keys that have a value and tables keep their keys alive
+//! by tracing the keys of their shape.
+//!
+//! Shapes are identified by a number that is never reused, which lets an
+//! [`InlineCache`] remember where a shape keeps a key without keeping the
+//! shape alive. What a shape says about a key never changes: a table that
+//! gains a key moves to another shape and the cache entries of its old shape
+//! simply stop matching. Metatables are not part of shapes, they are
+//! consulted on every access that does not find a value in the table itself.
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    rc::{Rc, Weak},
+};
+
+use super::{super::gc::Handle, ByteString};
+
+/// The number of string keys a shape holds at most. Further string keys of a
+/// table are kept in its hash part.
+pub const MAX_FIELDS: usize = 32;
+
+// Shapes with at most this many keys are searched linearly.
+const LINEAR_KEYS: usize = 8;
+
+// The number of shapes an inline cache remembers.
+const CACHE_WAYS: usize = 4;
+
+// The slot cached for a shape that does not have the key.
+const ABSENT: u32 = u32::MAX;
+
+pub struct Shape {
+    id: u64,
+    // Keeps the shapes along the way alive so that their transitions lead
+    // tables built alike to this very shape.
+    parent: Option<Rc<Shape>>,
+    keys: Vec<Handle<ByteString>>,
+    // The slots by key, built on the first lookup in shapes with many keys.
+    index: RefCell<Option<HashMap<Handle<ByteString>, u32>>>,
+    transitions: RefCell<HashMap<Handle<ByteString>, Weak<Shape>>>,
+}
+
+thread_local! {
+    static NEXT_ID: Cell<u64> = const { Cell::new(1) };
+    static ROOT: Rc<Shape> = Rc::new(Shape::new(None, Vec::new()));
+}
+
+impl Shape {
+    fn new(parent: Option<Rc<Shape>>, keys: Vec<Handle<ByteString>>) -> Self {
+        let id = NEXT_ID.with(|next| {
+            let id = next.get();
+            next.set(id + 1);
+            id
+        });
+
+        Shape {
+            id,
+            parent,
+            keys,
+            index: RefCell::new(None),
+            transitions: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// The shape of tables without string keys.
+    pub fn root() -> Rc<Shape> {
+        ROOT.with(Rc::clone)
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// The string keys in order of their slots.
+    pub fn keys(&self) -> &[Handle<ByteString>] {
+        &self.keys
+    }
+
+    /// The slot of a key.
+    pub fn slot(&self, key: Handle<ByteString>) -> Option<u32> {
+        if self.keys.len() <= LINEAR_KEYS {
+            return self
+                .keys
+                .iter()
+                .position(|other| *other == key)
+                .map(|slot| slot as u32);
+        }
+
+        let mut index = self.index.borrow_mut();
+        let index = index.get_or_insert_with(|| {
+            let slots = self.keys.iter().enumerate();
+            slots.map(|(slot, key)| (*key, slot as u32)).collect()
+        });
+        index.get(&key).copied()
+    }
+
+    /// The shape of a table with the keys of this one that gains `key`,
+    /// which must be a new key.
+    pub fn with(self: &Rc<Self>, key: Handle<ByteString>) -> Rc<Shape> {
+        let mut transitions = self.transitions.borrow_mut();
+        if let Some(shape) = transitions.get(&key).and_then(Weak::upgrade) {
+            return shape;
+        }
+
+        // Forget the transitions to shapes that are no longer used, whose
+        // keys may have been collected.
+        if transitions.len() >= 16 && transitions.len().is_power_of_two() {
+            transitions.retain(|_, shape| shape.strong_count() > 0);
+        }
+
+        let mut keys = Vec::with_capacity(self.keys.len() + 1);
+        keys.extend_from_slice(&self.keys);
+        keys.push(key);
+
+        let shape = Rc::new(Shape::new(Some(self.clone()), keys));
+        transitions.insert(key, Rc::downgrade(&shape));
+        shape
+    }
+
+    /// The shape of a table with the keys of this one that loses the key in
+    /// `slot`. The keys after it move up a slot, and the shape is the one of
+    /// tables that gained the keys left in order.
+    pub fn without(self: &Rc<Self>, slot: u32) -> Rc<Shape> {
+        let slot = slot as usize;
+        let mut shape = self;
+        while shape.keys.len() > slot {
+            shape = shape.parent.as_ref().unwrap();
+        }
+
+        let keys = self.keys[slot + 1..].iter();
+        keys.fold(shape.clone(), |shape, key| shape.with(*key))
+    }
+}
+
+/// Remembers where the tables seen by one field or global access keep the
+/// key of the access, by their shape.
+///
+/// A cache starts out monomorphic and becomes polymorphic as it sees tables
+/// of other shapes, replacing the oldest entry once it is full.
+#[derive(Default)]
+pub struct InlineCache {
+    entries: [Cell<(u64, u32)>; CACHE_WAYS],
+    next: Cell<u8>,
+}
+
+impl InlineCache {
+    /// The slot of `key` in `shape`. The key must be the same on every call.
+    pub fn slot(&self, shape: &Shape, key: Handle<ByteString>) -> Option<u32> {
+        let id = shape.id();
+        for entry in &self.entries {
+            let (cached, slot) = entry.get();
+            if cached == id {
+                return if slot == ABSENT { None } else { Some(slot) };
+            }
+        }
+
+        let slot = shape.slot(key);
+        let next = self.next.get() as usize;
+        self.entries[next].set((id, slot.unwrap_or(ABSENT)));
+        self.next.set(((next + 1) % CACHE_WAYS) as u8);
+        slot
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{super::vm::tests::eval, Value};
+
+    #[test]
+    fn eval_inline_caches() {
+        let cases = &[
+            // One site sees tables of many shapes, more than it remembers.
+            "local function get(t) return t.x end
+            local shapes = { { x = 1 }, { a = 0, x = 2 }, { b = 0, x = 3 }, { c = 0, x = 4 },
+                { d = 0, x = 5 }, { y = 0 } }
+            local sum = 0
+            for round = 1, 3 do
+                for i = 1, #shapes do sum = sum + (get(shapes[i]) or 0) end
+            end
+            return sum == 45",
+            // Tables change shape under a cached site.
+            "local function get(t) return t.x end
+            local t = {}
+            local a = get(t)
+            t.x = 1
+            local b = get(t)
+            t.x = nil
+            local c = get(t)
+            t.x = 2
+            return a == nil and b == 1 and c == nil and get(t) == 2",
+            // Metatables are consulted whenever a cached site finds nothing.
+            "local function get(t) return t.x end
+            local t = {}
+            local a = get(t)
+            setmetatable(t, { __index = { x = 1 } })
+            local b = get(t)
+            getmetatable(t).__index = { x = 2 }
+            local c = get(t)
+            t.x = 3
+            return a == nil and b == 1 and c == 2 and get(t) == 3",
+            "local log = {}
+            local function set(t, v) t.x = v end
+            local t = setmetatable({}, { __newindex = function(t, k, v) log[#log + 1] = v end })
+            set(t, 1)
+            rawset(t, 'x', 2)
+            set(t, 3)
+            t.x = nil
+            set(t, 4)
+            return #log == 2 and log[2] == 4 and rawget(t, 'x') == nil",
+            // String keys beyond the fields of a shape live in the hash part.
+            "local t, keys = {}, {}
+            for i = 1, 40 do keys[i] = 'k' .. i end
+            local function set(t, k, v) t[k] = v end
+            for i = 1, 40 do set(t, keys[i], i) end
+            local function get(t) return t.k1 + t.k32 + t.k40 end
+            t.k1 = nil
+            t.k1 = 100
+            return get(t) == 172 and get(t) == 172",
+            "local function f() return g end
+            local a = f()
+            g = 1
+            local b = f()
+            g = nil
+            return a == nil and b == 1 and f() == nil",
+            // Removing a key moves the keys after it up a slot.
+            "local function get(t) return t.z end
+            local t = { x = 1, y = 2, z = 3 }
+            local a = get(t)
+            t.y = nil
+            local b = get(t)
+            t.y = 4
+            return a == 3 and b == 3 and get(t) == 3 and t.x == 1 and t.y == 4",
+            "local obj = { n = 0 }
+            function obj:inc() self.n = self.n + 1 end
+            for i = 1, 10 do obj:inc() end
+            obj.inc = function(self) self.n = -1 end
+            obj:inc()
+            return obj.n == -1",
+        ];
+
+        for source in cases {
+            eval(source, |_, _, value| {
+                assert!(value == Value::from_bool(true), "{}", source)
+            });
+        }
+
+        let source = "
+            local function point(x, y) return { x = x, y = y } end
+            local other = {}
+            other.y, other.x = 1, 2
+            local removed = { x = 5, z = 0, y = 6 }
+            removed.z = nil
+            return { point(1, 2), point(3, 4), other, removed }
+        ";
+
+        eval(source, |_, _, value| {
+            let tables = unsafe { value.cast_table().unwrap().get_unchecked() };
+            let shape = |i| {
+                let table = tables.get(Value::from_int(i)).cast_table().unwrap();
+                unsafe { table.get_unchecked() }.shape().id()
+            };
+
+            assert_eq!(shape(1), shape(2));
+            assert_ne!(shape(1), shape(3));
+            assert_eq!(shape(1), shape(4));
+        });
+    }
+}
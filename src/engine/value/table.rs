@@ -1,22 +1,76 @@
 //! TODO(#29): Replace this with a butterfly-like structure.
+//!
+//! String keys are kept in fields laid out by the [`Shape`] of the table,
+//! every other key in a hash map.
+//!
+//! The `__mode` field of the metatable makes the keys (`k`), the values (`v`)
+//! or both weak. The collector clears entries whose weak key or value it did
+//! not reach, and the value of a weak key is only reached through the table
+//! once the key is reached, which makes the entries ephemerons. Values
+//! without an identity, such as strings and boxed integers, are never weak.
+
+use std::rc::Rc;
 
 use hashbrown::{hash_map, HashMap};
 
 use super::{
-    super::gc::{Heap, PtrTag, Trace, Visitor},
+    super::gc::{Handle, Heap, PtrTag, Trace, Visitor},
     encoding,
+    shape::{InlineCache, Shape, MAX_FIELDS},
+    ByteString,
     Value,
 };
 
 pub struct Table {
+    shape: Rc<Shape>,
+    // The values of the keys of the shape, never nil as removing a key moves
+    // the table to a shape without it.
+    fields: Vec<Value, Heap>,
     map: HashMap<Value, Value, (), Heap>,
+    metatable: Option<Handle<Table>>,
 }
 
 impl Table {
     pub fn new(heap: Heap) -> Self {
         Table {
+            shape: Shape::root(),
+            fields: Vec::new_in(heap.clone()),
             map: HashMap::with_hasher_in((), heap),
+            metatable: None,
+        }
+    }
+
+    pub fn shape(&self) -> &Shape {
+        &self.shape
+    }
+
+    pub fn metatable(&self) -> Option<Handle<Table>> {
+        self.metatable
+    }
+
+    pub fn set_metatable(&mut self, metatable: Option<Handle<Table>>) {
+        if metatable.is_some() {
+            self.barrier();
         }
+
+        self.metatable = metatable;
+    }
+
+    // Lets the cycle in progress trace the table again if it already did, as
+    // a reference is about to be stored in it. Tables that are not on the
+    // heap yet are not known to the collector and left alone.
+    fn barrier(&self) {
+        let handle = Handle::new(self as *const Table as *mut Table);
+        self.fields.allocator().barrier_back(handle.tagged());
+    }
+
+    // Floats with an integral value are stored as the equal integer so that
+    // `t[1]` and `t[1.0]` refer to the same entry. Keys then compare bitwise.
+    //
+    // TODO: Integral floats beyond the range of inline integers are kept as
+    // floats as normalizing them would require boxing the integer.
+    fn normalize(key: Value) -> Value {
+        key.to_int().and_then(Value::try_from_int).unwrap_or(key)
     }
 
     fn entry_mut(&mut self, key: Value) -> hash_map::RawEntryMut<'_, Value, Value, (), Heap> {
@@ -24,21 +78,75 @@ impl Table {
 
         self.map
             .raw_entry_mut()
-            .from_hash(hash, |other| key.op_eq(*other).cast_bool_unchecked())
+            .from_hash(hash, |other| key == *other)
     }
 
     pub fn get(&self, key: Value) -> Value {
+        let key = Self::normalize(key);
+        if let Some(name) = key.cast_string() {
+            if let Some(slot) = self.shape.slot(name) {
+                return self.fields[slot as usize];
+            }
+        }
+
+        self.get_hashed(key)
+    }
+
+    /// Looks up a string key, finding its slot through `cache` if given.
+    pub fn get_field(&self, key: Handle<ByteString>, cache: Option<&InlineCache>) -> Value {
+        let slot = match cache {
+            Some(cache) => cache.slot(&self.shape, key),
+            None => self.shape.slot(key),
+        };
+
+        match slot {
+            Some(slot) => self.fields[slot as usize],
+            None => self.get_hashed(Value::from_string(key)),
+        }
+    }
+
+    fn get_hashed(&self, key: Value) -> Value {
+        if self.map.is_empty() {
+            return Value::from_nil();
+        }
+
         let hash = key.op_hash();
 
         self.map
             .raw_entry()
-            .from_hash(hash, |other| key.op_eq(*other).cast_bool_unchecked())
+            .from_hash(hash, |other| key == *other)
             .map(|(_, v)| v)
             .copied()
             .unwrap_or_else(Value::from_nil)
     }
 
     pub fn insert(&mut self, key: Value, value: Value) {
+        let key = Self::normalize(key);
+        if key.handle().is_some() || value.handle().is_some() {
+            self.barrier();
+        }
+
+        if let Some(name) = key.cast_string() {
+            if let Some(slot) = self.shape.slot(name) {
+                self.fields[slot as usize] = value;
+                return;
+            }
+
+            // String keys only go to the hash map while the shape is full,
+            // and move to the fields once removing others made room.
+            if self.shape.keys().len() < MAX_FIELDS {
+                if !self.map.is_empty() {
+                    if let hash_map::RawEntryMut::Occupied(entry) = self.entry_mut(key) {
+                        entry.remove();
+                    }
+                }
+
+                self.shape = self.shape.with(name);
+                self.fields.push(value);
+                return;
+            }
+        }
+
         match self.entry_mut(key) {
             hash_map::RawEntryMut::Vacant(entry) => {
                 let hash = key.op_hash();
@@ -51,27 +159,203 @@ impl Table {
         }
     }
 
+    /// Stores a string key, finding its slot through `cache` if given. A nil
+    /// value removes the key.
+    pub fn set_field(
+        &mut self,
+        key: Handle<ByteString>,
+        value: Value,
+        cache: Option<&InlineCache>,
+    ) {
+        let slot = match cache {
+            Some(cache) => cache.slot(&self.shape, key),
+            None => self.shape.slot(key),
+        };
+
+        match slot {
+            Some(slot) if value == Value::from_nil() => self.remove_field(slot),
+            Some(slot) => {
+                if value.handle().is_some() {
+                    self.barrier();
+                }
+
+                self.fields[slot as usize] = value;
+            },
+            None if value == Value::from_nil() => self.remove(Value::from_string(key)),
+            None => self.insert(Value::from_string(key), value),
+        }
+    }
+
     pub fn remove(&mut self, key: Value) {
+        let key = Self::normalize(key);
+        if let Some(name) = key.cast_string() {
+            if let Some(slot) = self.shape.slot(name) {
+                self.remove_field(slot);
+                return;
+            }
+        }
+
         if let hash_map::RawEntryMut::Occupied(entry) = self.entry_mut(key) {
             entry.remove();
         }
     }
 
+    // Removes the key in a slot of the fields along with its value.
+    fn remove_field(&mut self, slot: u32) {
+        self.fields.remove(slot as usize);
+        self.shape = self.shape.without(slot);
+    }
+
     pub fn len(&self) -> usize {
-        self.map.len()
+        self.fields.len() + self.map.len()
+    }
+
+    /// Finds a border: a positive integer key whose value is non-nil and is
+    /// followed by a nil value, or zero if `t[1]` is nil.
+    pub fn border(&self) -> usize {
+        let present = |i: usize| match Value::try_from_int(i as i64) {
+            Some(key) => self.get(key) != Value::from_nil(),
+            None => false,
+        };
+
+        if !present(1) {
+            return 0;
+        }
+
+        // Find some nil index by doubling, then binary search for a border
+        // between the last present and the first absent index found.
+        let (mut lo, mut hi) = (1, 2);
+        while present(hi) {
+            lo = hi;
+            hi = match hi.checked_mul(2) {
+                Some(hi) => hi,
+                None => return lo,
+            };
+        }
+
+        while hi - lo > 1 {
+            let mid = lo + (hi - lo) / 2;
+            if present(mid) {
+                lo = mid;
+            } else {
+                hi = mid;
+            }
+        }
+
+        lo
     }
 
     pub fn is_empty(&self) -> bool {
-        self.map.is_empty()
+        self.len() == 0
+    }
+
+    // Looks up a string key by its bytes, for callers without the interned
+    // string at hand.
+    fn get_bytes(&self, name: &[u8]) -> Value {
+        let is_name = |key: &Value| match key.cast_string() {
+            Some(key) => **unsafe { key.get_unchecked() } == *name,
+            None => false,
+        };
+
+        let keys = self.shape.keys().iter().map(|key| Value::from_string(*key));
+        keys.zip(self.fields.iter().copied())
+            .chain(self.map.iter().map(|(key, value)| (*key, *value)))
+            .find(|(key, _)| is_name(key))
+            .map(|(_, value)| value)
+            .unwrap_or_else(Value::from_nil)
+    }
+
+    /// Whether the keys and the values of the table are weak, as given by the
+    /// `__mode` field of its metatable.
+    pub fn mode(&self) -> (bool, bool) {
+        let metatable = match self.metatable {
+            Some(metatable) => unsafe { metatable.get_unchecked() },
+            None => return (false, false),
+        };
+
+        match metatable.get_bytes(b"__mode").cast_string() {
+            Some(mode) => {
+                let mode = unsafe { mode.get_unchecked() };
+                (mode.contains(&b'k'), mode.contains(&b'v'))
+            },
+            None => (false, false),
+        }
+    }
+
+    /// Marks the strong references of a table with weak keys or values, and
+    /// the values of the weak keys that were reached.
+    pub fn visit_weak(&self, visitor: &mut Visitor, weak_keys: bool, weak_values: bool) {
+        let reached = |visitor: &Visitor, value: Value| {
+            !weak_ref(value) || visitor.is_reached(value.handle().unwrap())
+        };
+
+        for (key, value) in self.shape.keys().iter().zip(self.fields.iter()) {
+            Value::from_string(*key).visit(visitor);
+            if !weak_values || !weak_ref(*value) {
+                value.visit(visitor);
+            }
+        }
+
+        for (key, value) in self.map.iter() {
+            if !weak_keys || !weak_ref(*key) {
+                key.visit(visitor);
+            }
+
+            if weak_values && weak_ref(*value) {
+                continue;
+            }
+
+            if !weak_keys || reached(visitor, *key) {
+                value.visit(visitor);
+            }
+        }
+
+        if let Some(metatable) = self.metatable {
+            Value::from_table(metatable).visit(visitor);
+        }
+    }
+
+    /// Removes the entries whose weak key or value the collector did not
+    /// reach.
+    pub fn clear_weak(&mut self, visitor: &Visitor, weak_keys: bool, weak_values: bool) {
+        let cleared =
+            |value: Value| weak_ref(value) && !visitor.is_reached(value.handle().unwrap());
+
+        if weak_values {
+            for slot in (0..self.fields.len()).rev() {
+                if cleared(self.fields[slot]) {
+                    self.remove_field(slot as u32);
+                }
+            }
+        }
+
+        self.map.retain(|key, value| {
+            !(weak_keys && cleared(*key)) && !(weak_values && cleared(*value))
+        });
     }
 }
 
+// Whether a weak table holds a value weakly. Like numbers, strings are values
+// rather than objects and are never removed from weak tables.
+fn weak_ref(value: Value) -> bool {
+    value.addr().is_some() && value.cast_string().is_none()
+}
+
 impl Trace for Table {
     fn visit(&self, visitor: &mut Visitor) {
+        for (key, value) in self.shape.keys().iter().zip(self.fields.iter()) {
+            Value::from_string(*key).visit(visitor);
+            value.visit(visitor);
+        }
+
         self.map.iter().for_each(|(key, value)| {
             key.visit(visitor);
             value.visit(visitor);
         });
+
+        if let Some(metatable) = self.metatable {
+            Value::from_table(metatable).visit(visitor);
+        }
     }
 }
 
@@ -0,0 +1,219 @@
+//! The register-based instruction set executed by the interpreter.
+//!
+//! Every function is compiled into a [`Proto`] whose instructions address a
+//! window of registers on the value stack. Registers are numbered from the
+//! start of the window, the first ones holding the parameters. Locals that
+//! are captured by nested functions live in cells instead so that closures
+//! share them.
+//!
+//! Field and global accesses carry the index of an [`InlineCache`] of their
+//! function, which remembers where the tables they see keep their key.
+
+use std::rc::Rc;
+
+use super::{
+    super::{
+        gc::{Handle, Trace, Visitor},
+        value::{ByteString, InlineCache, Value},
+    },
+    meta::Arith,
+};
+use crate::parser::machinery::span::Span;
+
+/// A register index within the window of a function.
+pub type Reg = u8;
+
+/// Stands in for a count of values that is only known at runtime. As an
+/// argument count it means "up to the top of the stack" and as a result count
+/// it means "all results, setting the top of the stack".
+pub const MULTI: u8 = u8::MAX;
+
+/// The number of array entries a table constructor collects in registers
+/// before storing them.
+pub const FIELDS_PER_FLUSH: u8 = 50;
+
+/// The cache operand of an access that has no inline cache, used once a
+/// function runs out of them.
+pub const NO_CACHE: u16 = u16::MAX;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Instruction {
+    /// `R[a] = R[b]`
+    Move(Reg, Reg),
+    /// `R[a] = K[b]`
+    LoadK(Reg, u32),
+    /// `R[a] = b`
+    LoadInt(Reg, i32),
+    /// `R[a] = b`
+    LoadBool(Reg, bool),
+    /// `R[a], ..., R[a + b - 1] = nil`
+    LoadNil(Reg, u8),
+
+    /// `R[a] = U[b]`
+    GetUpval(Reg, u16),
+    /// `U[a] = R[b]`
+    SetUpval(u16, Reg),
+    /// Creates a fresh cell `C[a]` holding nil.
+    NewCell(u16),
+    /// `R[a] = C[b]`
+    GetCell(Reg, u16),
+    /// `C[a] = R[b]`
+    SetCell(u16, Reg),
+    /// `R[a] = G[K[b]]`, cached in `I[c]`
+    GetGlobal(Reg, u32, u16),
+    /// `G[K[a]] = R[b]`, cached in `I[c]`
+    SetGlobal(u32, Reg, u16),
+
+    /// `R[a] = R[b][R[c]]`
+    GetIndex(Reg, Reg, Reg),
+    /// `R[a] = R[b][K[c]]`, cached in `I[d]`
+    GetField(Reg, Reg, u16, u16),
+    /// `R[a][R[b]] = R[c]`
+    SetIndex(Reg, Reg, Reg),
+    /// `R[a][K[b]] = R[c]`, cached in `I[d]`
+    SetField(Reg, u16, Reg, u16),
+    /// `R[a + 1] = R[b]; R[a] = R[b][K[c]]`, cached in `I[d]`
+    SelfOp(Reg, Reg, u16, u16),
+    /// `R[a] = {}`
+    NewTable(Reg),
+    /// Stores `R[b], ..., R[b + c - 1]` into the table `R[a]` starting at
+    /// index `d`. A count of [`MULTI`] stores everything up to the top.
+    SetList(Reg, Reg, u8, u32),
+
+    /// `R[a] = R[b] op R[c]`
+    Arith(Arith, Reg, Reg, Reg),
+    /// `R[a] = R[b] op K[c]`
+    ArithK(Arith, Reg, Reg, u16),
+    /// `R[a] = -R[b]`
+    Neg(Reg, Reg),
+    /// `R[a] = ~R[b]`
+    BitNot(Reg, Reg),
+    /// `R[a] = #R[b]`
+    Len(Reg, Reg),
+    /// `R[a] = not R[b]`
+    Not(Reg, Reg),
+    /// `R[a] = R[b] == R[c]`
+    Eq(Reg, Reg, Reg),
+    /// `R[a] = R[b] ~= R[c]`
+    Ne(Reg, Reg, Reg),
+    /// `R[a] = R[b] < R[c]`
+    Lt(Reg, Reg, Reg),
+    /// `R[a] = R[b] <= R[c]`
+    Le(Reg, Reg, Reg),
+
+    /// Jumps by an offset relative to the next instruction.
+    Jump(i32),
+    /// Jumps if `R[a]` is truthy.
+    JumpIf(Reg, i32),
+    /// Jumps if `R[a]` is falsy.
+    JumpIfNot(Reg, i32),
+
+    /// Calls `R[a]` with the `b` arguments following it, storing `c` results
+    /// starting at `R[a]`.
+    Call(Reg, u8, u8),
+    /// Calls `R[a]` with the `b` arguments following it in place of the
+    /// running function, which returns what it returns. Always followed by
+    /// `Return(a, MULTI)`, which returns the results of native functions.
+    TailCall(Reg, u8),
+    /// Returns the `b` values starting at `R[a]`.
+    Return(Reg, u8),
+    /// `R[a] = closure(P[b])`
+    Closure(Reg, u16),
+    /// Copies `b` varargs starting at `R[a]`.
+    VarArg(Reg, u8),
+
+    /// Marks `R[a]` to be closed, where `K[b]` names the variable.
+    Tbc(Reg, u32),
+    /// Closes every value marked to be closed in `R[a]` or above.
+    Close(Reg),
+
+    /// Prepares a numeric loop over `R[a]`, `R[a + 1]` and `R[a + 2]`,
+    /// skipping it by `b` if it does not run at all.
+    ForPrep(Reg, i32),
+    /// Advances a numeric loop, jumping back by `b` if it continues.
+    ForLoop(Reg, i32),
+    /// Calls the iterator of a generic loop, storing `b` results starting at
+    /// `R[a + 4]`.
+    TForCall(Reg, u8),
+    /// Continues a generic loop by jumping by `b` if `R[a + 4]` is not nil.
+    TForLoop(Reg, i32),
+}
+
+/// Where a closure finds an upvalue when it is created.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Capture {
+    /// A cell of the enclosing function.
+    Cell(u16),
+    /// An upvalue of the enclosing function.
+    Upvalue(u16),
+}
+
+/// A compiled function.
+pub struct Proto {
+    pub code: Vec<Instruction>,
+    /// The span of the expression or statement each instruction belongs to,
+    /// empty if debug information was stripped.
+    pub spans: Vec<Span>,
+    pub constants: Vec<Value>,
+    pub protos: Vec<Rc<Proto>>,
+    pub captures: Vec<Capture>,
+    /// The names of the captured variables, in the order of `captures`.
+    pub upvalues: Vec<Handle<ByteString>>,
+    /// The names functions are called by for use in tracebacks, by the
+    /// position of the instruction that calls them.
+    pub names: Vec<(u32, String)>,
+    /// The inline caches of the field and global accesses.
+    pub caches: Vec<InlineCache>,
+    pub params: u8,
+    pub vararg: bool,
+    pub registers: u8,
+    pub cells: u16,
+}
+
+impl Proto {
+    /// The span of the instruction at `pc`.
+    pub fn span(&self, pc: usize) -> Span {
+        self.spans
+            .get(pc)
+            .copied()
+            .unwrap_or_else(|| Span::new(0, 0))
+    }
+
+    /// Removes the spans and names of the function and of the nested
+    /// functions it does not share with others.
+    pub fn strip(&mut self) {
+        self.spans.clear();
+        self.upvalues.clear();
+        self.names.clear();
+
+        for proto in &mut self.protos {
+            if let Some(proto) = Rc::get_mut(proto) {
+                proto.strip();
+            }
+        }
+    }
+
+    /// The name of the function called by the instruction at `pc`.
+    pub fn call_name(&self, pc: usize) -> Option<String> {
+        self.names
+            .binary_search_by_key(&(pc as u32), |(at, _)| *at)
+            .ok()
+            .map(|index| self.names[index].1.clone())
+    }
+}
+
+impl Trace for Proto {
+    fn visit(&self, visitor: &mut Visitor) {
+        for constant in &self.constants {
+            constant.visit(visitor);
+        }
+
+        for name in &self.upvalues {
+            Value::from_string(*name).visit(visitor);
+        }
+
+        for proto in &self.protos {
+            proto.visit(visitor);
+        }
+    }
+}
@@ -0,0 +1,1625 @@
+//! Compiles syntax trees into [`Proto`]s.
+//!
+//! Every function gets a window of registers that holds its locals, in order
+//! of declaration, followed by temporaries. Variables are looked up in the
+//! [`Resolution`] of the chunk. A local that is captured by a nested function
+//! is kept in a cell so that closures can share it, all other locals live
+//! directly in their register.
+//!
+//! Operators whose operands are constants, including `<const>` locals with
+//! constant values, are evaluated at compile time, and `if` and `while`
+//! statements whose conditions are constant only keep the code that can run.
+
+use std::{collections::HashMap, rc::Rc};
+
+use super::{
+    super::{
+        gc::Handle,
+        value::{ByteString, InlineCache, Value},
+        Error,
+    },
+    bytecode::{Capture, Instruction, Proto, Reg, FIELDS_PER_FLUSH, MULTI, NO_CACHE},
+    ctx::Ctx,
+    fold,
+    meta::Arith,
+};
+use crate::parser::{
+    machinery::{
+        cstree::interning::TokenInterner,
+        diagnostic::Diagnostic,
+        literal::LiteralValue,
+        span::Span,
+    },
+    resolve::{self, Binding, Resolution},
+    syntax::{
+        Assign,
+        BinaryOp,
+        BinaryOperator,
+        Block,
+        Decl,
+        DeclModifier,
+        ElseChain,
+        Expr,
+        ForGen,
+        ForNum,
+        Func,
+        FuncCall,
+        FuncExpr,
+        Ident,
+        If,
+        Literal,
+        PrefixOperator,
+        Repeat,
+        Return,
+        Root,
+        Stmt,
+        Table,
+        TableEntry,
+        While,
+    },
+};
+
+// The number of registers a function may use. The remaining values of a
+// register index are reserved for `MULTI` and the scratch registers of
+// generic loops.
+const MAX_REGISTERS: usize = 240;
+
+type Result<T = ()> = std::result::Result<T, Error>;
+
+/// Compiles the main chunk of a script into a vararg function without
+/// parameters. The interner is the one the script was parsed with.
+pub fn compile(root: &Root, interner: &TokenInterner, ctx: &Ctx) -> Result<Rc<Proto>> {
+    let resolution = resolve::resolve(root, interner);
+    if let Some((span, message)) = resolution.errors().first() {
+        return Err(compile_error(message, *span));
+    }
+
+    let mut compiler = Compiler {
+        ctx,
+        interner,
+        resolution: &resolution,
+        functions: Vec::new(),
+        folded: HashMap::new(),
+    };
+
+    compiler.open(resolution.main(), Vec::new(), 0, true);
+    compiler.stmts(root.block())?;
+    let proto = compiler.close(root.span());
+    Ok(Rc::new(proto))
+}
+
+// Where a variable lives as seen from the function being compiled.
+#[derive(Clone, Copy)]
+enum Var {
+    Reg(Reg),
+    Cell(u16),
+    Upvalue(u16),
+    Global(u32),
+}
+
+// A location that is assigned to once every value has been evaluated.
+enum Place {
+    Var(Var),
+    Index(Reg, Reg, Span),
+    Field(Reg, u16, Span),
+}
+
+struct Local {
+    // The slot of the local in the resolution, none for internal state.
+    slot: Option<u32>,
+    reg: Reg,
+    cell: Option<u16>,
+    close: bool,
+    // The value of a `<const>` local initialized with a constant.
+    constant: Option<Value>,
+}
+
+// A jump whose target is not known yet. Forward gotos that may leave the
+// scope of a to-be-closed variable are preceded by a placeholder that becomes
+// a `Close` once the level of their label is known.
+struct Pending {
+    label: Option<Handle<ByteString>>,
+    jump: usize,
+    close: Option<(usize, Reg)>,
+}
+
+struct BlockState {
+    locals: usize,
+    free: Reg,
+    cells: u16,
+    labels: Vec<(Handle<ByteString>, usize, Reg)>,
+    gotos: Vec<Pending>,
+    // The breaks of a loop body, `None` for every other block.
+    breaks: Option<Vec<Pending>>,
+}
+
+struct FuncState<'c> {
+    proto: Proto,
+    constants: HashMap<u64, u32>,
+    function: &'c resolve::Function,
+    locals: Vec<Local>,
+    blocks: Vec<BlockState>,
+    free: Reg,
+    cells: u16,
+}
+
+struct Compiler<'c, 'a> {
+    ctx: &'c Ctx<'a>,
+    interner: &'c TokenInterner,
+    resolution: &'c Resolution,
+    functions: Vec<FuncState<'c>>,
+    // The folded values of operators by their span, so that nested operators
+    // are only evaluated once.
+    folded: HashMap<(u32, u32), Option<Value>>,
+}
+
+impl<'c, 'a> Compiler<'c, 'a> {
+    fn state(&mut self) -> &mut FuncState<'c> {
+        self.functions.last_mut().unwrap()
+    }
+
+    fn pc(&self) -> usize {
+        self.functions.last().unwrap().proto.code.len()
+    }
+
+    fn emit(&mut self, instruction: Instruction, span: Span) -> usize {
+        let proto = &mut self.state().proto;
+        proto.code.push(instruction);
+        proto.spans.push(span);
+        proto.code.len() - 1
+    }
+
+    // Makes the jump at `at` land on the next instruction to be emitted.
+    fn patch(&mut self, at: usize) {
+        let offset = self.pc() as i32 - at as i32 - 1;
+        let code = &mut self.state().proto.code;
+        code[at] = match code[at] {
+            Instruction::Jump(_) => Instruction::Jump(offset),
+            Instruction::JumpIf(a, _) => Instruction::JumpIf(a, offset),
+            Instruction::JumpIfNot(a, _) => Instruction::JumpIfNot(a, offset),
+            Instruction::ForPrep(a, _) => Instruction::ForPrep(a, offset),
+            instruction => instruction,
+        };
+    }
+
+    // The offset of a jump emitted next that lands on `target`.
+    fn back(&self, target: usize) -> i32 {
+        target as i32 - self.pc() as i32 - 1
+    }
+
+    fn alloc(&mut self, n: usize, span: Span) -> Result<Reg> {
+        let state = self.state();
+        let reg = state.free;
+        let free = reg as usize + n;
+        if free > MAX_REGISTERS {
+            return Err(compile_error(
+                "function or expression needs too many registers",
+                span,
+            ));
+        }
+
+        state.free = free as Reg;
+        state.proto.registers = state.proto.registers.max(free as Reg);
+        Ok(reg)
+    }
+
+    // The first register that does not hold a local.
+    fn active(&self) -> Reg {
+        let state = self.functions.last().unwrap();
+        state.locals.last().map_or(0, |local| local.reg + 1)
+    }
+
+    fn is_temp(&self, reg: Reg) -> bool {
+        reg >= self.active()
+    }
+
+    fn constant(&mut self, value: Value, span: Span) -> Result<u32> {
+        let state = self.state();
+        let hash = value.op_hash();
+        if let Some(&index) = state.constants.get(&hash) {
+            if state.proto.constants[index as usize] == value {
+                return Ok(index);
+            }
+        }
+
+        let index = state.proto.constants.len();
+        if index > u32::MAX as usize {
+            return Err(compile_error("function has too many constants", span));
+        }
+
+        state.proto.constants.push(value);
+        state.constants.entry(hash).or_insert(index as u32);
+        Ok(index as u32)
+    }
+
+    // A constant that is addressed by a 16-bit operand.
+    fn short_constant(&mut self, value: Value, span: Span) -> Result<u16> {
+        let index = self.constant(value, span)?;
+        if index > u16::MAX as u32 {
+            return Err(compile_error("function has too many constants", span));
+        }
+
+        Ok(index as u16)
+    }
+
+    fn name(&self, ident: &Ident) -> Handle<ByteString> {
+        self.ctx
+            .intern(ident.name(self.interner).unwrap().as_bytes())
+    }
+
+    // A fresh inline cache for a field or global access.
+    fn cache(&mut self) -> u16 {
+        let caches = &mut self.state().proto.caches;
+        if caches.len() >= NO_CACHE as usize {
+            return NO_CACHE;
+        }
+
+        caches.push(InlineCache::default());
+        caches.len() as u16 - 1
+    }
+
+    fn string(&mut self, name: Handle<ByteString>, span: Span) -> Result<u16> {
+        self.short_constant(Value::from_string(name), span)
+    }
+
+    fn open(
+        &mut self,
+        function: &'c resolve::Function,
+        captures: Vec<Capture>,
+        params: u8,
+        vararg: bool,
+    ) {
+        let upvalues = function
+            .upvalues
+            .iter()
+            .map(|upvalue| self.ctx.intern(upvalue.name.as_bytes()))
+            .collect();
+        let proto = Proto {
+            code: Vec::new(),
+            spans: Vec::new(),
+            constants: Vec::new(),
+            protos: Vec::new(),
+            captures,
+            upvalues,
+            names: Vec::new(),
+            caches: Vec::new(),
+            params,
+            vararg,
+            registers: 0,
+            cells: 0,
+        };
+
+        self.functions.push(FuncState {
+            proto,
+            constants: HashMap::new(),
+            function,
+            locals: Vec::new(),
+            blocks: Vec::new(),
+            free: 0,
+            cells: 0,
+        });
+
+        self.enter_block(false);
+    }
+
+    // Finishes the innermost function, returning nothing if its body does not
+    // end with a `return`.
+    fn close(&mut self, span: Span) -> Proto {
+        self.leave_block(false, span);
+        self.emit(Instruction::Return(0, 0), span);
+        self.functions.pop().unwrap().proto
+    }
+
+    fn enter_block(&mut self, is_loop: bool) {
+        let state = self.state();
+        let block = BlockState {
+            locals: state.locals.len(),
+            free: state.free,
+            cells: state.cells,
+            labels: Vec::new(),
+            gotos: Vec::new(),
+            breaks: if is_loop { Some(Vec::new()) } else { None },
+        };
+
+        state.blocks.push(block);
+    }
+
+    // Leaves the innermost block, closing its to-be-closed variables if
+    // `close` is set. Gotos that have not found their label are left to the
+    // enclosing block.
+    fn leave_block(&mut self, close: bool, span: Span) -> BlockState {
+        let state = self.state();
+        let mut block = state.blocks.pop().unwrap();
+        let closable = state.locals[block.locals..].iter().any(|local| local.close);
+
+        state.locals.truncate(block.locals);
+        state.free = block.free;
+        state.cells = block.cells;
+        if let Some(parent) = state.blocks.last_mut() {
+            parent.gotos.append(&mut block.gotos);
+        }
+
+        if close && closable {
+            self.emit(Instruction::Close(block.free), span);
+        }
+
+        block
+    }
+
+    // The register of the innermost to-be-closed variable in scope.
+    fn closable(&self) -> Option<Reg> {
+        let state = self.functions.last().unwrap();
+        state
+            .locals
+            .iter()
+            .rev()
+            .find(|local| local.close)
+            .map(|local| local.reg)
+    }
+
+    // Brings the local with the given slot into scope. Captured locals get a
+    // fresh cell which is initialized from their register if `init` is set.
+    fn declare(&mut self, slot: Option<u32>, reg: Reg, init: bool, span: Span) {
+        let state = self.state();
+        let captured = slot.map_or(false, |slot| state.function.locals[slot as usize].captured);
+        let cell = if captured {
+            let cell = state.cells;
+            state.cells += 1;
+            state.proto.cells = state.proto.cells.max(state.cells);
+            Some(cell)
+        } else {
+            None
+        };
+
+        state.locals.push(Local {
+            slot,
+            reg,
+            cell,
+            close: false,
+            constant: None,
+        });
+
+        if let Some(cell) = cell {
+            self.emit(Instruction::NewCell(cell), span);
+            if init {
+                self.emit(Instruction::SetCell(cell, reg), span);
+            }
+        }
+    }
+
+    // Declares a register that holds internal state of a loop. Its name can
+    // never be referred to.
+    fn declare_hidden(&mut self, reg: Reg, span: Span) -> Result {
+        self.alloc(1, span)?;
+        self.declare(None, reg, false, span);
+        Ok(())
+    }
+
+    // The slot of a local at its declaration.
+    fn slot(&self, ident: &Ident) -> Option<u32> {
+        match self.resolution.binding(ident) {
+            Some(Binding::Local(slot)) => Some(slot),
+            _ => None,
+        }
+    }
+
+    // The local with the given slot in the innermost function.
+    fn local(&self, slot: u32) -> Option<Var> {
+        let state = self.functions.last().unwrap();
+        let local = state
+            .locals
+            .iter()
+            .rev()
+            .find(|local| local.slot == Some(slot))?;
+        Some(match local.cell {
+            Some(cell) => Var::Cell(cell),
+            None => Var::Reg(local.reg),
+        })
+    }
+
+    fn resolve(&mut self, ident: &Ident) -> Result<Var> {
+        let var = match self.resolution.binding(ident) {
+            Some(Binding::Local(slot)) => self.local(slot),
+            Some(Binding::Upvalue(index)) => Some(Var::Upvalue(index)),
+            Some(Binding::Global) | None => None,
+        };
+
+        match var {
+            Some(var) => Ok(var),
+            None => {
+                let name = self.name(ident);
+                Ok(Var::Global(
+                    self.constant(Value::from_string(name), ident.span())?,
+                ))
+            },
+        }
+    }
+
+    fn stmts<I>(&mut self, stmts: I) -> Result
+    where
+        I: Iterator<Item = Stmt>,
+    {
+        self.stmts_until(stmts, false)
+    }
+
+    // Compiles the statements of a block, where the body of a `repeat` loop
+    // is followed by its condition, which is in the scope of its locals.
+    fn stmts_until<I>(&mut self, stmts: I, repeat: bool) -> Result
+    where
+        I: Iterator<Item = Stmt>,
+    {
+        let stmts: Vec<_> = stmts.collect();
+        for (i, stmt) in stmts.iter().enumerate() {
+            match stmt {
+                Stmt::Label(label) => {
+                    // A label followed only by void statements is outside of
+                    // the scope of the locals declared before it.
+                    let last = !repeat
+                        && stmts[i + 1..]
+                            .iter()
+                            .all(|stmt| matches!(stmt, Stmt::Label(_)));
+                    self.label(&label.name().unwrap(), last)?;
+                },
+                stmt => self.stmt(stmt)?,
+            }
+
+            let active = self.active();
+            self.state().free = active;
+        }
+
+        Ok(())
+    }
+
+    fn block<I>(&mut self, stmts: I, span: Span) -> Result
+    where
+        I: Iterator<Item = Stmt>,
+    {
+        self.enter_block(false);
+        self.stmts(stmts)?;
+        self.leave_block(true, span);
+        Ok(())
+    }
+
+    fn stmt(&mut self, stmt: &Stmt) -> Result {
+        match stmt {
+            Stmt::Decl(decl) => self.decl(decl),
+            Stmt::Assign(assign) => self.assign(assign),
+            Stmt::Func(func) => self.func_stmt(func),
+            Stmt::Expr(Expr::FuncCall(call)) => self.call(call, 0).map(|_| ()),
+            Stmt::Expr(expr) => {
+                let reg = self.alloc(1, expr.span())?;
+                self.expr(expr, reg)
+            },
+            Stmt::Break(stmt) => self.r#break(stmt.span()),
+            Stmt::Goto(goto) => self.goto(&goto.label().unwrap(), goto.span()),
+            Stmt::Label(label) => self.label(&label.name().unwrap(), false),
+            Stmt::Return(stmt) => self.r#return(stmt),
+            Stmt::Do(stmt) => self.block(stmt.stmts(), stmt.span()),
+            Stmt::While(stmt) => self.r#while(stmt),
+            Stmt::Repeat(stmt) => self.repeat(stmt),
+            Stmt::If(stmt) => self.r#if(stmt),
+            Stmt::ForNum(stmt) => self.for_num(stmt),
+            Stmt::ForGen(stmt) => self.for_gen(stmt),
+        }
+    }
+
+    fn decl(&mut self, decl: &Decl) -> Result {
+        if let Some(func) = decl.function() {
+            let ident = match func.target() {
+                Some(Expr::Ident(ident)) => ident,
+                _ => unreachable!(),
+            };
+
+            // The local is declared before the function is created so that
+            // the function can refer to itself.
+            let span = func.span();
+            let reg = self.alloc(1, span)?;
+            self.declare(self.slot(&ident), reg, false, span);
+            let index = self.function(
+                false,
+                func.args().unwrap(),
+                func.is_vararg(),
+                func.block().unwrap(),
+            )?;
+            self.emit(Instruction::Closure(reg, index), span);
+            if let Var::Cell(cell) = self.resolve(&ident)? {
+                self.emit(Instruction::SetCell(cell, reg), span);
+            }
+
+            return Ok(());
+        }
+
+        let targets: Vec<_> = decl.targets().collect();
+        let base = self.state().free;
+        match decl.values() {
+            Some(values) => self.exprs_fixed(values, targets.len(), decl.span())?,
+            None => self.exprs_fixed(std::iter::empty(), targets.len(), decl.span())?,
+        }
+
+        for (i, target) in targets.iter().enumerate() {
+            let reg = base + i as Reg;
+            let ident = target.name().unwrap();
+            self.declare(self.slot(&ident), reg, true, target.span());
+
+            if matches!(target.modifier(), Some(DeclModifier::Const)) {
+                if let Some(value) = decl.values().and_then(|mut values| values.nth(i)) {
+                    let constant = self.fold(&value)?;
+                    self.state().locals.last_mut().unwrap().constant = constant;
+                }
+            }
+
+            if matches!(target.modifier(), Some(DeclModifier::Close)) {
+                let name = self.name(&ident);
+                let name = self.constant(Value::from_string(name), target.span())?;
+                self.emit(Instruction::Tbc(reg, name), target.span());
+                self.state().locals.last_mut().unwrap().close = true;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn assign(&mut self, assign: &Assign) -> Result {
+        let targets: Vec<_> = assign.targets().unwrap().collect();
+        let values: Vec<_> = assign.values().unwrap().collect();
+
+        // A single assignment can evaluate its value in place.
+        if let ([target], [value]) = (&targets[..], &values[..]) {
+            let place = self.place(target, false)?;
+            return match place {
+                Place::Var(Var::Reg(reg)) => self.expr(value, reg),
+                place => {
+                    let reg = self.expr_any(value)?;
+                    self.store(place, reg, value.span())
+                },
+            };
+        }
+
+        let mut places = Vec::new();
+        for target in &targets {
+            places.push(self.place(target, true)?);
+        }
+
+        // Every value is evaluated before any assignment takes place.
+        let base = self.state().free;
+        self.exprs_fixed(values.into_iter(), targets.len(), assign.span())?;
+
+        for (i, (place, target)) in places.into_iter().zip(&targets).enumerate() {
+            self.store(place, base + i as Reg, target.span())?;
+        }
+
+        Ok(())
+    }
+
+    // Evaluates the object and key of an assignment target. Registers of
+    // locals are copied if another assignment could change them first.
+    fn place(&mut self, target: &Expr, copy: bool) -> Result<Place> {
+        let operand = |compiler: &mut Self, expr: &Expr| -> Result<Reg> {
+            if copy {
+                let reg = compiler.alloc(1, expr.span())?;
+                compiler.expr(expr, reg)?;
+                Ok(reg)
+            } else {
+                compiler.expr_any(expr)
+            }
+        };
+
+        Ok(match target {
+            Expr::Ident(ident) => Place::Var(self.resolve(ident)?),
+            Expr::Index(index) => {
+                let object = operand(self, &index.target().unwrap())?;
+                match self.field(&index.index().unwrap())? {
+                    Some(key) => Place::Field(object, key, index.span()),
+                    None => {
+                        let key = operand(self, &index.index().unwrap())?;
+                        Place::Index(object, key, index.span())
+                    },
+                }
+            },
+            Expr::BinaryOp(op) if matches!(op.op(), Some(BinaryOperator::Property)) => {
+                let object = operand(self, &op.lhs().unwrap())?;
+                let key = self.property_key(op)?;
+                Place::Field(object, key, op.span())
+            },
+            _ => unreachable!(),
+        })
+    }
+
+    fn store(&mut self, place: Place, reg: Reg, span: Span) -> Result {
+        let instruction = match place {
+            Place::Var(Var::Reg(dst)) if dst == reg => return Ok(()),
+            Place::Var(Var::Reg(dst)) => Instruction::Move(dst, reg),
+            Place::Var(Var::Cell(cell)) => Instruction::SetCell(cell, reg),
+            Place::Var(Var::Upvalue(index)) => Instruction::SetUpval(index, reg),
+            Place::Var(Var::Global(name)) => Instruction::SetGlobal(name, reg, self.cache()),
+            Place::Index(object, key, span) => {
+                self.emit(Instruction::SetIndex(object, key, reg), span);
+                return Ok(());
+            },
+            Place::Field(object, key, span) => {
+                let cache = self.cache();
+                self.emit(Instruction::SetField(object, key, reg, cache), span);
+                return Ok(());
+            },
+        };
+
+        self.emit(instruction, span);
+        Ok(())
+    }
+
+    // The constant naming a `.field` or `:method` access.
+    fn property_key(&mut self, op: &BinaryOp) -> Result<u16> {
+        match op.rhs() {
+            Some(Expr::Ident(ident)) => {
+                let name = self.name(&ident);
+                self.string(name, ident.span())
+            },
+            _ => unreachable!(),
+        }
+    }
+
+    // The constant of an index that is a string literal.
+    fn field(&mut self, key: &Expr) -> Result<Option<u16>> {
+        match key {
+            Expr::Literal(literal) => match self.literal(literal)? {
+                value if value.cast_string().is_some() =>
+                    self.short_constant(value, literal.span()).map(Some),
+                _ => Ok(None),
+            },
+            _ => Ok(None),
+        }
+    }
+
+    fn func_stmt(&mut self, func: &Func) -> Result {
+        let target = func.target().unwrap();
+        let span = func.span();
+        let mut method = false;
+
+        // `function a.b:c() end` is sugar for `a.b.c = function(self) end`.
+        let place = match &target {
+            Expr::BinaryOp(op) if matches!(op.op(), Some(BinaryOperator::Method)) => {
+                method = true;
+                let object = self.expr_any(&op.lhs().unwrap())?;
+                let key = self.property_key(op)?;
+                Place::Field(object, key, op.span())
+            },
+            target => self.place(target, false)?,
+        };
+
+        let index = self.function(
+            method,
+            func.args().unwrap(),
+            func.is_vararg(),
+            func.block().unwrap(),
+        )?;
+        let reg = match place {
+            Place::Var(Var::Reg(reg)) => reg,
+            _ => self.alloc(1, span)?,
+        };
+
+        self.emit(Instruction::Closure(reg, index), span);
+        self.store(place, reg, span)
+    }
+
+    // Compiles a nested function and returns its index among the prototypes
+    // of the enclosing function. Methods get an implicit `self` parameter.
+    fn function<I>(&mut self, method: bool, args: I, vararg: bool, body: Block) -> Result<u16>
+    where
+        I: Iterator<Item = Ident>,
+    {
+        let params = method as usize + args.count();
+        let span = body.span();
+        if params > MAX_REGISTERS {
+            return Err(compile_error("function has too many parameters", span));
+        }
+
+        let function = self.resolution.function(&body).unwrap();
+        let mut captures = Vec::new();
+        for upvalue in &function.upvalues {
+            captures.push(match upvalue.capture {
+                resolve::Capture::Local(slot) => match self.local(slot) {
+                    Some(Var::Cell(cell)) => Capture::Cell(cell),
+                    // Captured locals are always kept in cells.
+                    _ => unreachable!(),
+                },
+                resolve::Capture::Upvalue(index) => Capture::Upvalue(index),
+            });
+        }
+
+        self.open(function, captures, params as Reg, vararg);
+        // The parameters take the first slots.
+        for slot in 0..params {
+            let reg = self.alloc(1, span)?;
+            self.declare(Some(slot as u32), reg, true, span);
+        }
+
+        self.stmts(body.stmts())?;
+        let proto = self.close(span);
+
+        let protos = &mut self.state().proto.protos;
+        if protos.len() > u16::MAX as usize {
+            return Err(compile_error(
+                "function has too many nested functions",
+                span,
+            ));
+        }
+
+        protos.push(Rc::new(proto));
+        Ok(protos.len() as u16 - 1)
+    }
+
+    fn r#break(&mut self, span: Span) -> Result {
+        let state = self.functions.last().unwrap();
+        let level = match state
+            .blocks
+            .iter()
+            .rev()
+            .find(|block| block.breaks.is_some())
+        {
+            Some(block) => block.free,
+            None => return Err(compile_error("break outside a loop", span)),
+        };
+
+        if self.closable().map_or(false, |reg| reg >= level) {
+            self.emit(Instruction::Close(level), span);
+        }
+
+        let jump = self.emit(Instruction::Jump(0), span);
+        let state = self.state();
+        let block = state
+            .blocks
+            .iter_mut()
+            .rev()
+            .find(|block| block.breaks.is_some())
+            .unwrap();
+
+        block.breaks.as_mut().unwrap().push(Pending {
+            label: None,
+            jump,
+            close: None,
+        });
+
+        Ok(())
+    }
+
+    fn goto(&mut self, label: &Ident, span: Span) -> Result {
+        let name = self.name(label);
+        let state = self.functions.last().unwrap();
+        let target = state
+            .blocks
+            .iter()
+            .rev()
+            .flat_map(|block| block.labels.iter())
+            .find(|(label, ..)| *label == name)
+            .map(|&(_, at, level)| (at, level));
+
+        // Jumping back leaves the scope of every local declared after the
+        // label.
+        if let Some((at, level)) = target {
+            if self.closable().map_or(false, |reg| reg >= level) {
+                self.emit(Instruction::Close(level), span);
+            }
+
+            let offset = self.back(at);
+            self.emit(Instruction::Jump(offset), span);
+            return Ok(());
+        }
+
+        let close = self
+            .closable()
+            .map(|reg| (self.emit(Instruction::Close(0), span), reg));
+
+        let jump = self.emit(Instruction::Jump(0), span);
+        self.state().blocks.last_mut().unwrap().gotos.push(Pending {
+            label: Some(name),
+            jump,
+            close,
+        });
+
+        Ok(())
+    }
+
+    fn label(&mut self, label: &Ident, last: bool) -> Result {
+        let name = self.name(label);
+        let at = self.pc();
+        let state = self.state();
+        let free = state.free;
+        let block = state.blocks.last_mut().unwrap();
+        let level = if last { block.free } else { free };
+        block.labels.push((name, at, level));
+
+        let (resolved, pending) = std::mem::take(&mut block.gotos)
+            .into_iter()
+            .partition::<Vec<_>, _>(|goto| goto.label == Some(name));
+        block.gotos = pending;
+
+        for goto in resolved {
+            if let Some((close, reg)) = goto.close {
+                let code = &mut self.state().proto.code;
+                code[close] = if reg >= level {
+                    Instruction::Close(level)
+                } else {
+                    Instruction::Jump(0)
+                };
+            }
+
+            self.patch(goto.jump);
+        }
+
+        Ok(())
+    }
+
+    fn r#return(&mut self, stmt: &Return) -> Result {
+        let exprs: Vec<_> = stmt.exprs().unwrap().collect();
+        let span = stmt.span();
+
+        let instruction = match &exprs[..] {
+            [] => Instruction::Return(0, 0),
+            [Expr::FuncCall(call)] => {
+                let reg = self.call(call, MULTI)?;
+
+                // Variables still to be closed once the call returns rule out
+                // reusing the frame for it.
+                if self.closable().is_none() {
+                    let code = &mut self.state().proto.code;
+                    if let Some(&Instruction::Call(a, b, _)) = code.last() {
+                        *code.last_mut().unwrap() = Instruction::TailCall(a, b);
+                    }
+                }
+
+                Instruction::Return(reg, MULTI)
+            },
+            [Expr::VarArg(vararg)] => {
+                let reg = self.alloc(1, vararg.span())?;
+                self.emit(Instruction::VarArg(reg, MULTI), vararg.span());
+                Instruction::Return(reg, MULTI)
+            },
+            [expr] => Instruction::Return(self.expr_any(expr)?, 1),
+            _ => {
+                let (base, count) = self.exprs_multi(exprs.into_iter(), span)?;
+                Instruction::Return(base, count)
+            },
+        };
+
+        self.emit(instruction, span);
+        Ok(())
+    }
+
+    // Emits a jump that is taken if the condition is truthy, or falsy if
+    // `negate` is set.
+    fn cond_jump(&mut self, cond: &Expr, negate: bool) -> Result<usize> {
+        let reg = self.expr_any(cond)?;
+        let active = self.active();
+        self.state().free = active;
+
+        let instruction = if negate {
+            Instruction::JumpIfNot(reg, 0)
+        } else {
+            Instruction::JumpIf(reg, 0)
+        };
+
+        Ok(self.emit(instruction, cond.span()))
+    }
+
+    fn patch_breaks(&mut self, block: BlockState) {
+        for pending in block.breaks.unwrap_or_default() {
+            self.patch(pending.jump);
+        }
+    }
+
+    fn r#while(&mut self, stmt: &While) -> Result {
+        let span = stmt.span();
+        let top = self.pc();
+        let cond = stmt.cond().unwrap();
+        let exit = match self.fold(&cond)? {
+            Some(value) if !value.is_truthy() => return Ok(()),
+            Some(_) => None,
+            None => Some(self.cond_jump(&cond, true)?),
+        };
+
+        self.enter_block(true);
+        self.stmts(stmt.block().unwrap())?;
+        let block = self.leave_block(true, span);
+
+        let offset = self.back(top);
+        self.emit(Instruction::Jump(offset), span);
+        if let Some(exit) = exit {
+            self.patch(exit);
+        }
+
+        self.patch_breaks(block);
+        Ok(())
+    }
+
+    fn repeat(&mut self, stmt: &Repeat) -> Result {
+        let span = stmt.span();
+        let top = self.pc();
+
+        // The condition can refer to the locals of the body, so the scope is
+        // only left once it has been evaluated.
+        self.enter_block(true);
+        self.stmts_until(stmt.block().unwrap(), true)?;
+        let closable = self.closable();
+        let level = self.state().blocks.last().unwrap().free;
+
+        if closable.map_or(false, |reg| reg >= level) {
+            let exit = self.cond_jump(&stmt.cond().unwrap(), false)?;
+            self.emit(Instruction::Close(level), span);
+            let offset = self.back(top);
+            self.emit(Instruction::Jump(offset), span);
+            self.patch(exit);
+        } else {
+            let reg = self.expr_any(&stmt.cond().unwrap())?;
+            let offset = self.back(top);
+            self.emit(Instruction::JumpIfNot(reg, offset), span);
+        }
+
+        let block = self.leave_block(true, span);
+        self.patch_breaks(block);
+        Ok(())
+    }
+
+    fn r#if(&mut self, stmt: &If) -> Result {
+        let span = stmt.span();
+        let cond = stmt.cond().unwrap();
+        match self.fold(&cond)? {
+            Some(value) if value.is_truthy() => return self.block(stmt.stmts().unwrap(), span),
+            Some(_) =>
+                return stmt
+                    .else_chain()
+                    .map_or(Ok(()), |chain| self.else_chain(&chain)),
+            None => (),
+        }
+
+        let next = self.cond_jump(&cond, true)?;
+        self.block(stmt.stmts().unwrap(), span)?;
+
+        let chain = match stmt.else_chain() {
+            Some(chain) => chain,
+            None => {
+                self.patch(next);
+                return Ok(());
+            },
+        };
+
+        let end = self.emit(Instruction::Jump(0), span);
+        self.patch(next);
+        self.else_chain(&chain)?;
+        self.patch(end);
+        Ok(())
+    }
+
+    fn else_chain(&mut self, chain: &ElseChain) -> Result {
+        if let Some(elseif) = chain.elseif_block() {
+            self.r#if(&elseif)?;
+        } else if let Some(stmts) = chain.else_block() {
+            self.block(stmts, chain.span())?;
+        }
+
+        Ok(())
+    }
+
+    fn for_num(&mut self, stmt: &ForNum) -> Result {
+        let span = stmt.span();
+        let (counter, init) = stmt.counter().unwrap();
+
+        // The initial value, limit and step are kept in hidden locals.
+        self.enter_block(false);
+        let base = self.state().free;
+        let init_reg = self.alloc(1, span)?;
+        self.expr(&init, init_reg)?;
+        let limit_reg = self.alloc(1, span)?;
+        self.expr(&stmt.end().unwrap(), limit_reg)?;
+        let step_reg = self.alloc(1, span)?;
+        match stmt.step() {
+            Some(step) => self.expr(&step, step_reg)?,
+            None => {
+                self.emit(Instruction::LoadInt(step_reg, 1), span);
+            },
+        }
+
+        self.state().free = base;
+        for i in 0..3 {
+            self.declare_hidden(base + i, span)?;
+        }
+
+        let prep = self.emit(Instruction::ForPrep(base, 0), span);
+        let body = self.pc();
+
+        self.enter_block(true);
+        let reg = self.alloc(1, span)?;
+        self.declare(self.slot(&counter), reg, true, counter.span());
+        self.stmts(stmt.block().unwrap())?;
+        let block = self.leave_block(true, span);
+
+        let offset = self.back(body);
+        self.emit(Instruction::ForLoop(base, offset), span);
+        self.patch(prep);
+        self.patch_breaks(block);
+        self.leave_block(false, span);
+        Ok(())
+    }
+
+    fn for_gen(&mut self, stmt: &ForGen) -> Result {
+        let span = stmt.span();
+
+        // The iterator function, state, control variable and closing value
+        // are kept in hidden locals, the last of them to be closed.
+        self.enter_block(false);
+        let base = self.state().free;
+        self.exprs_fixed(stmt.values().unwrap(), 4, span)?;
+        self.state().free = base;
+        for i in 0..4 {
+            self.declare_hidden(base + i, span)?;
+        }
+
+        let name = self.ctx.intern(b"(for state)");
+        let name = self.constant(Value::from_string(name), span)?;
+        self.emit(Instruction::Tbc(base + 3, name), span);
+        self.state().locals.last_mut().unwrap().close = true;
+
+        let call = self.emit(Instruction::Jump(0), span);
+        let body = self.pc();
+
+        let targets: Vec<_> = stmt.targets().unwrap().collect();
+        self.enter_block(true);
+        let vars = self.alloc(targets.len(), span)?;
+        // The iterator is called with a copy of the function and its two
+        // arguments in the registers of the variables.
+        let state = self.state();
+        let scratch = (vars as usize + 3).max(state.free as usize);
+        state.proto.registers = state.proto.registers.max(scratch as Reg);
+
+        for (i, target) in targets.iter().enumerate() {
+            self.declare(self.slot(target), vars + i as Reg, true, target.span());
+        }
+
+        self.stmts(stmt.block().unwrap())?;
+        let block = self.leave_block(true, span);
+
+        self.patch(call);
+        let at = self.emit(Instruction::TForCall(base, targets.len() as u8), span);
+        self.state()
+            .proto
+            .names
+            .push((at as u32, String::from("for iterator")));
+        let offset = self.back(body);
+        self.emit(Instruction::TForLoop(base, offset), span);
+
+        self.patch_breaks(block);
+        self.emit(Instruction::Close(base), span);
+        self.leave_block(false, span);
+        Ok(())
+    }
+
+    // Evaluates expressions into `n` consecutive registers starting at the
+    // first free one, truncating or padding the values with nil.
+    fn exprs_fixed<I>(&mut self, exprs: I, n: usize, span: Span) -> Result
+    where
+        I: Iterator<Item = Expr>,
+    {
+        let base = self.state().free;
+        let mut exprs = exprs.peekable();
+        let mut count = 0;
+
+        while let Some(expr) = exprs.next() {
+            let last = exprs.peek().is_none();
+            let wanted = n.saturating_sub(count);
+
+            match &expr {
+                Expr::FuncCall(call) if last && wanted > 1 => {
+                    self.call(call, wanted as u8)?;
+                    count = n;
+                },
+                Expr::VarArg(vararg) if last && wanted > 1 => {
+                    let reg = self.alloc(wanted, vararg.span())?;
+                    self.emit(Instruction::VarArg(reg, wanted as u8), vararg.span());
+                    count = n;
+                },
+                expr => {
+                    let reg = self.alloc(1, expr.span())?;
+                    self.expr(expr, reg)?;
+                    count += 1;
+                },
+            }
+        }
+
+        if count < n {
+            let reg = self.alloc(n - count, span)?;
+            self.emit(Instruction::LoadNil(reg, (n - count) as u8), span);
+        }
+
+        self.state().free = base;
+        self.alloc(n, span)?;
+        Ok(())
+    }
+
+    // Evaluates expressions into consecutive registers, where the last one
+    // may produce any number of values. Returns the first register and the
+    // number of values or `MULTI`.
+    fn exprs_multi<I>(&mut self, exprs: I, span: Span) -> Result<(Reg, u8)>
+    where
+        I: Iterator<Item = Expr>,
+    {
+        let base = self.state().free;
+        let mut exprs = exprs.peekable();
+        let mut count = 0;
+
+        while let Some(expr) = exprs.next() {
+            let last = exprs.peek().is_none();
+            match &expr {
+                Expr::FuncCall(call) if last => {
+                    self.call(call, MULTI)?;
+                    return Ok((base, MULTI));
+                },
+                Expr::VarArg(vararg) if last => {
+                    let reg = self.alloc(1, vararg.span())?;
+                    self.emit(Instruction::VarArg(reg, MULTI), vararg.span());
+                    return Ok((base, MULTI));
+                },
+                expr => {
+                    let reg = self.alloc(1, expr.span())?;
+                    self.expr(expr, reg)?;
+                    count += 1;
+                },
+            }
+        }
+
+        if count >= MULTI as usize {
+            return Err(compile_error("too many values in a list", span));
+        }
+
+        Ok((base, count as u8))
+    }
+
+    // Compiles a call with its function in the first free register, where
+    // the results are stored as well. A result count of `MULTI` leaves all
+    // results from there up to the top of the stack.
+    fn call(&mut self, call: &FuncCall, results: u8) -> Result<Reg> {
+        let span = call.span();
+        let base = self.state().free;
+        let target = call.target().unwrap();
+
+        let mut count = match &target {
+            // `obj:m(...)` evaluates `obj` once and passes it as `self`.
+            Expr::BinaryOp(op) if matches!(op.op(), Some(BinaryOperator::Method)) => {
+                let object = self.expr_any(&op.lhs().unwrap())?;
+                let key = self.property_key(op)?;
+                self.state().free = base;
+                self.alloc(2, span)?;
+                let cache = self.cache();
+                self.emit(Instruction::SelfOp(base, object, key, cache), op.span());
+                1
+            },
+            target => {
+                let reg = self.alloc(1, span)?;
+                self.expr(target, reg)?;
+                0
+            },
+        };
+
+        let (_, args) = self.exprs_multi(call.args().unwrap(), span)?;
+        if args == MULTI {
+            count = MULTI;
+        } else if count as usize + args as usize >= MULTI as usize {
+            return Err(compile_error("function call has too many arguments", span));
+        } else {
+            count += args;
+        }
+
+        let at = self.pc();
+        if let Some(name) = call_name(&target, self.interner) {
+            self.state().proto.names.push((at as u32, name));
+        }
+
+        self.emit(Instruction::Call(base, count, results), span);
+        self.state().free = base;
+        if results != MULTI {
+            self.alloc((results as usize).max(1), span)?;
+        }
+
+        self.state().free = base + if results == MULTI { 0 } else { results };
+        Ok(base)
+    }
+
+    // Evaluates an expression into some register, which is the register of a
+    // local if the expression is one and a new temporary otherwise.
+    fn expr_any(&mut self, expr: &Expr) -> Result<Reg> {
+        if let Expr::Ident(ident) = expr {
+            if let Var::Reg(reg) = self.resolve(ident)? {
+                return Ok(reg);
+            }
+        }
+
+        let reg = self.alloc(1, expr.span())?;
+        self.expr(expr, reg)?;
+        Ok(reg)
+    }
+
+    // Evaluates an expression into `dst`, using the registers from the first
+    // free one as temporaries.
+    fn expr(&mut self, expr: &Expr, dst: Reg) -> Result {
+        let span = expr.span();
+        let free = self.state().free;
+
+        if matches!(expr, Expr::Ident(_) | Expr::PrefixOp(_) | Expr::BinaryOp(_)) {
+            if let Some(value) = self.fold(expr)? {
+                return self.load(value, dst, span);
+            }
+        }
+
+        match expr {
+            Expr::Ident(ident) => {
+                let instruction = match self.resolve(ident)? {
+                    Var::Reg(reg) if reg == dst => return Ok(()),
+                    Var::Reg(reg) => Instruction::Move(dst, reg),
+                    Var::Cell(cell) => Instruction::GetCell(dst, cell),
+                    Var::Upvalue(index) => Instruction::GetUpval(dst, index),
+                    Var::Global(name) => Instruction::GetGlobal(dst, name, self.cache()),
+                };
+
+                self.emit(instruction, span);
+            },
+            Expr::Literal(literal) => {
+                let value = self.literal(literal)?;
+                self.load(value, dst, span)?;
+            },
+            Expr::Func(func) => self.func_expr(func, dst)?,
+            Expr::Table(table) => self.table(table, dst)?,
+            Expr::PrefixOp(op) => {
+                let rhs = op.rhs().unwrap();
+                let make = match op.op().unwrap() {
+                    PrefixOperator::None => return self.expr(&rhs, dst),
+                    PrefixOperator::Neg => Instruction::Neg,
+                    PrefixOperator::Not => Instruction::Not,
+                    PrefixOperator::Len => Instruction::Len,
+                    PrefixOperator::BitNot => Instruction::BitNot,
+                };
+
+                let reg = self.expr_any(&rhs)?;
+                self.emit(make(dst, reg), span);
+            },
+            Expr::BinaryOp(op) => self.binary_op(op, dst)?,
+            Expr::FuncCall(call) => {
+                let reg = self.call(call, 1)?;
+                if reg != dst {
+                    self.emit(Instruction::Move(dst, reg), span);
+                }
+            },
+            Expr::Index(index) => {
+                let object = self.expr_any(&index.target().unwrap())?;
+                let key = index.index().unwrap();
+                let instruction = match self.field(&key)? {
+                    Some(key) => Instruction::GetField(dst, object, key, self.cache()),
+                    None => Instruction::GetIndex(dst, object, self.expr_any(&key)?),
+                };
+
+                self.emit(instruction, span);
+            },
+            Expr::Paren(paren) => self.expr(&paren.inner().unwrap(), dst)?,
+            Expr::VarArg(_) => {
+                self.emit(Instruction::VarArg(dst, 1), span);
+            },
+        }
+
+        self.state().free = free;
+        Ok(())
+    }
+
+    // Loads a constant into `dst`.
+    fn load(&mut self, value: Value, dst: Reg, span: Span) -> Result {
+        let instruction = if value == Value::from_nil() {
+            Instruction::LoadNil(dst, 1)
+        } else if value == Value::from_bool(true) || value == Value::from_bool(false) {
+            Instruction::LoadBool(dst, value.is_truthy())
+        } else if value.is_int() && i32::try_from(value.cast_int()).is_ok() {
+            Instruction::LoadInt(dst, value.cast_int() as i32)
+        } else {
+            Instruction::LoadK(dst, self.constant(value, span)?)
+        };
+
+        self.emit(instruction, span);
+        Ok(())
+    }
+
+    // The value of an expression if it is known at compile time.
+    fn fold(&mut self, expr: &Expr) -> Result<Option<Value>> {
+        Ok(match expr {
+            Expr::Literal(literal) => Some(self.literal(literal)?),
+            Expr::Ident(ident) => self.known(ident),
+            Expr::Paren(paren) => self.fold(&paren.inner().unwrap())?,
+            Expr::PrefixOp(op) => {
+                let span = op.span();
+                let key = (span.start(), span.end());
+                if let Some(&value) = self.folded.get(&key) {
+                    return Ok(value);
+                }
+
+                let value = match self.fold(&op.rhs().unwrap())? {
+                    Some(value) => fold::unary(op.op().unwrap(), value, self.ctx),
+                    None => None,
+                };
+                self.folded.insert(key, value);
+                value
+            },
+            Expr::BinaryOp(op) => {
+                let span = op.span();
+                let key = (span.start(), span.end());
+                if let Some(&value) = self.folded.get(&key) {
+                    return Ok(value);
+                }
+
+                let value = self.fold_binary(op)?;
+                self.folded.insert(key, value);
+                value
+            },
+            _ => None,
+        })
+    }
+
+    fn fold_binary(&mut self, op: &BinaryOp) -> Result<Option<Value>> {
+        let operator = op.op().unwrap();
+        if matches!(operator, BinaryOperator::Property | BinaryOperator::Method) {
+            return Ok(None);
+        }
+
+        let a = match self.fold(&op.lhs().unwrap())? {
+            Some(a) => a,
+            None => return Ok(None),
+        };
+
+        // The right operand is not evaluated if the left one decides the
+        // result.
+        match operator {
+            BinaryOperator::And if !a.is_truthy() => return Ok(Some(a)),
+            BinaryOperator::Or if a.is_truthy() => return Ok(Some(a)),
+            BinaryOperator::And | BinaryOperator::Or => return self.fold(&op.rhs().unwrap()),
+            _ => (),
+        }
+
+        Ok(match self.fold(&op.rhs().unwrap())? {
+            Some(b) => fold::binary(operator, a, b, self.ctx),
+            None => None,
+        })
+    }
+
+    // The value of a `<const>` local, following upvalues to the function
+    // that declares it.
+    fn known(&self, ident: &Ident) -> Option<Value> {
+        let mut binding = self.resolution.binding(ident)?;
+        let mut level = self.functions.len() - 1;
+        loop {
+            let state = &self.functions[level];
+            binding = match binding {
+                Binding::Local(slot) => {
+                    let mut locals = state.locals.iter().rev();
+                    return locals.find(|local| local.slot == Some(slot))?.constant;
+                },
+                Binding::Upvalue(index) => match state.function.upvalues[index as usize].capture {
+                    resolve::Capture::Local(slot) => Binding::Local(slot),
+                    resolve::Capture::Upvalue(index) => Binding::Upvalue(index),
+                },
+                Binding::Global => return None,
+            };
+
+            level = level.checked_sub(1)?;
+        }
+    }
+
+    fn literal(&mut self, literal: &Literal) -> Result<Value> {
+        let value = match literal.value(self.interner) {
+            Ok(value) => value,
+            Err(diagnostic) => return Err(Error::InvalidLiteral(diagnostic)),
+        };
+
+        Ok(match value {
+            LiteralValue::Nil => Value::from_nil(),
+            LiteralValue::Bool(x) => Value::from_bool(x),
+            LiteralValue::Int(x) => self.ctx.int(x),
+            LiteralValue::Float(x) => Value::from_float(x),
+            LiteralValue::String(bytes) => Value::from_string(self.ctx.intern(&bytes)),
+        })
+    }
+
+    fn func_expr(&mut self, func: &FuncExpr, dst: Reg) -> Result {
+        let index = self.function(
+            false,
+            func.args().unwrap(),
+            func.is_vararg(),
+            func.block().unwrap(),
+        )?;
+        self.emit(Instruction::Closure(dst, index), func.span());
+        Ok(())
+    }
+
+    fn table(&mut self, table: &Table, dst: Reg) -> Result {
+        let span = table.span();
+
+        // A table assigned to a local is only stored once it is complete.
+        let reg = if self.is_temp(dst) {
+            dst
+        } else {
+            self.alloc(1, span)?
+        };
+
+        self.emit(Instruction::NewTable(reg), span);
+
+        let base = self.state().free;
+        let mut pending = 0;
+        let mut index = 1;
+        let mut entries = table.entries().peekable();
+
+        while let Some(entry) = entries.next() {
+            let span = entry.span();
+            match entry {
+                TableEntry::Array(entry) => {
+                    let value = entry.value().unwrap();
+                    let last = entries.peek().is_none();
+                    match &value {
+                        // A trailing positional entry expands to all of its
+                        // values.
+                        Expr::FuncCall(call) if last => {
+                            self.call(call, MULTI)?;
+                            self.emit(Instruction::SetList(reg, base, MULTI, index), span);
+                            pending = 0;
+                            break;
+                        },
+                        Expr::VarArg(_) if last => {
+                            let at = self.alloc(1, span)?;
+                            self.emit(Instruction::VarArg(at, MULTI), span);
+                            self.emit(Instruction::SetList(reg, base, MULTI, index), span);
+                            pending = 0;
+                            break;
+                        },
+                        value => {
+                            let at = self.alloc(1, span)?;
+                            self.expr(value, at)?;
+                            pending += 1;
+                        },
+                    }
+
+                    if pending == FIELDS_PER_FLUSH {
+                        self.emit(Instruction::SetList(reg, base, pending, index), span);
+                        index += pending as u32;
+                        pending = 0;
+                        self.state().free = base;
+                    }
+                },
+                TableEntry::Map(entry) => {
+                    let name = self.name(&entry.field().unwrap());
+                    let key = self.string(name, span)?;
+                    let value = self.expr_any(&entry.value().unwrap())?;
+                    let cache = self.cache();
+                    self.emit(Instruction::SetField(reg, key, value, cache), span);
+                    self.state().free = base + pending;
+                },
+                TableEntry::Generic(entry) => {
+                    let key = self.expr_any(&entry.index().unwrap())?;
+                    let value = self.expr_any(&entry.value().unwrap())?;
+                    self.emit(Instruction::SetIndex(reg, key, value), span);
+                    self.state().free = base + pending;
+                },
+            }
+        }
+
+        if pending > 0 {
+            self.emit(Instruction::SetList(reg, base, pending, index), span);
+        }
+
+        if reg != dst {
+            self.emit(Instruction::Move(dst, reg), span);
+        }
+
+        Ok(())
+    }
+
+    fn binary_op(&mut self, op: &BinaryOp, dst: Reg) -> Result {
+        let span = op.span();
+        let lhs = op.lhs().unwrap();
+
+        let arith = match op.op().unwrap() {
+            // `and` and `or` only evaluate their right operand when it
+            // decides the result.
+            operator @ (BinaryOperator::And | BinaryOperator::Or) => {
+                let reg = if self.is_temp(dst) {
+                    dst
+                } else {
+                    self.alloc(1, span)?
+                };
+
+                self.expr(&lhs, reg)?;
+                let jump = match operator {
+                    BinaryOperator::And => Instruction::JumpIfNot(reg, 0),
+                    _ => Instruction::JumpIf(reg, 0),
+                };
+                let skip = self.emit(jump, span);
+                self.expr(&op.rhs().unwrap(), reg)?;
+                self.patch(skip);
+
+                if reg != dst {
+                    self.emit(Instruction::Move(dst, reg), span);
+                }
+
+                return Ok(());
+            },
+            BinaryOperator::Property | BinaryOperator::Method => {
+                let object = self.expr_any(&lhs)?;
+                let key = self.property_key(op)?;
+                let cache = self.cache();
+                self.emit(Instruction::GetField(dst, object, key, cache), span);
+                return Ok(());
+            },
+            BinaryOperator::Eq => return self.compare(op, dst, Instruction::Eq, false),
+            BinaryOperator::NEq => return self.compare(op, dst, Instruction::Ne, false),
+            BinaryOperator::Lt => return self.compare(op, dst, Instruction::Lt, false),
+            BinaryOperator::LEq => return self.compare(op, dst, Instruction::Le, false),
+            BinaryOperator::Gt => return self.compare(op, dst, Instruction::Lt, true),
+            BinaryOperator::GEq => return self.compare(op, dst, Instruction::Le, true),
+            BinaryOperator::Add => Arith::Add,
+            BinaryOperator::Sub => Arith::Sub,
+            BinaryOperator::Mul => Arith::Mul,
+            BinaryOperator::Div => Arith::Div,
+            BinaryOperator::IntDiv => Arith::IntDiv,
+            BinaryOperator::Exp => Arith::Pow,
+            BinaryOperator::Mod => Arith::Mod,
+            BinaryOperator::BitAnd => Arith::BitAnd,
+            BinaryOperator::BitOr => Arith::BitOr,
+            BinaryOperator::BitXor => Arith::BitXor,
+            BinaryOperator::LShift => Arith::Shl,
+            BinaryOperator::RShift => Arith::Shr,
+            BinaryOperator::Concat => Arith::Concat,
+        };
+
+        let a = self.expr_any(&lhs)?;
+        let rhs = op.rhs().unwrap();
+        if let Some(value) = self.fold(&rhs)? {
+            if value.is_number() || value.cast_string().is_some() {
+                let k = self.short_constant(value, rhs.span())?;
+                self.emit(Instruction::ArithK(arith, dst, a, k), span);
+                return Ok(());
+            }
+        }
+
+        let b = self.expr_any(&rhs)?;
+        self.emit(Instruction::Arith(arith, dst, a, b), span);
+        Ok(())
+    }
+
+    // Compiles a comparison, evaluating the operands in order but swapping
+    // them in the instruction if `swap` is set.
+    fn compare(
+        &mut self,
+        op: &BinaryOp,
+        dst: Reg,
+        make: fn(Reg, Reg, Reg) -> Instruction,
+        swap: bool,
+    ) -> Result {
+        let a = self.expr_any(&op.lhs().unwrap())?;
+        let b = self.expr_any(&op.rhs().unwrap())?;
+        let instruction = if swap {
+            make(dst, b, a)
+        } else {
+            make(dst, a, b)
+        };
+        self.emit(instruction, op.span());
+        Ok(())
+    }
+}
+
+// Describes the function called by an expression for use in tracebacks.
+fn call_name(target: &Expr, interner: &TokenInterner) -> Option<String> {
+    let ident = match target {
+        Expr::Ident(ident) => ident.clone(),
+        Expr::BinaryOp(op) => match op.rhs() {
+            Some(Expr::Ident(ident)) => ident,
+            _ => return None,
+        },
+        _ => return None,
+    };
+
+    ident.name(interner).map(String::from)
+}
+
+fn compile_error(message: &str, span: Span) -> Error {
+    Error::Compile(Diagnostic::new(span, message))
+}
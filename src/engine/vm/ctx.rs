@@ -1,133 +1,547 @@
 use std::{
-    borrow::Borrow,
-    cell::{Ref, RefCell},
+    cell::{Cell, Ref, RefCell},
     collections::hash_map::RandomState,
+    hash::{BuildHasher, Hash, Hasher},
+    rc::Rc,
+    time::Instant,
 };
 
-use hashbrown::{HashMap, HashSet};
+use hashbrown::{hash_map, HashMap};
 
-use super::super::{
-    gc::{Handle, Heap},
-    value::{ByteString, Table, Value},
+use super::{
+    super::{
+        gc::{Handle, Heap, Mode, TaggedHandle, Trace, Visitor},
+        value::{BoxedInt, ByteString, Coroutine, InlineCache, Table, Value},
+    },
+    debug::{Hook, HookFunction, HookMask, Source},
+    eval,
+    limits::{Limit, Limits, CHECK_INTERVAL},
+    meta,
+    WarnFunction,
 };
-use crate::parser::{machinery::cstree::interning::TokenInterner, syntax::Ident};
+use crate::parser::machinery::span::Span;
 
 struct CtxInternal<'a> {
     global: &'a mut Table,
-    scope: Vec<HashMap<Handle<ByteString>, Value, RandomState>>,
     heap: &'a Heap,
-    interner: &'a TokenInterner,
-    strings: &'a mut HashSet<Handle<ByteString>, RandomState>,
+    strings: &'a mut HashMap<Handle<ByteString>, (), RandomState>,
+    integers: &'a mut HashMap<i64, Handle<BoxedInt>, RandomState>,
+}
+
+// The number of dispatch loops that can be nested on the native stack, through
+// native functions and metamethods calling Lua functions.
+const MAX_NESTING: usize = 200;
+
+// The native stack nested loops may take up. Unoptimized builds take far more
+// per loop than optimized ones, so this bounds nesting before MAX_NESTING does
+// there, well within the 2 MiB of the threads spawned by default.
+const MAX_NATIVE_STACK: usize = 1 << 20;
+
+/// The collections a context runs, see [`Ctx::collect`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Collection {
+    /// A step of the current cycle, see [`Heap::step`].
+    Step,
+    /// A full cycle, see [`Heap::collect`].
+    Full,
+    /// A switch to another mode, see [`Heap::set_mode`].
+    Mode(Mode),
 }
 
 pub struct Ctx<'a> {
     internal: RefCell<CtxInternal<'a>>,
+    nesting: Cell<usize>,
+    // The position on the native stack of the outermost loop.
+    stack_base: Cell<usize>,
+    // The running coroutine and the nesting of the loop running it.
+    coroutine: Cell<Option<(Handle<Coroutine>, usize)>>,
+    limits: Limits,
+    // The instructions left to run before the limits are checked again.
+    fuel: Cell<u64>,
+    // The instructions of the budget not yet handed out as fuel.
+    budget: Cell<Option<u64>>,
+    hook: RefCell<Option<Hook>>,
+    // Whether a hook is running, which stops events from being reported.
+    in_hook: Cell<bool>,
+    // Whether events are reported.
+    hooked: Cell<bool>,
+    // The instructions run since the last count event.
+    ticks: Cell<u32>,
+    source: Option<Rc<Source>>,
+    warn: Option<WarnFunction>,
+    // The threads of the loops waiting on the code being run.
+    threads: RefCell<Vec<*const (dyn Trace + 'a)>>,
+    // The values only native code refers to while it calls functions.
+    held: RefCell<Vec<Value>>,
 }
 
 impl<'a> Ctx<'a> {
     pub fn new(
         global: &'a mut Table,
         heap: &'a Heap,
-        interner: &'a TokenInterner,
-        strings: &'a mut HashSet<Handle<ByteString>, RandomState>,
+        strings: &'a mut HashMap<Handle<ByteString>, (), RandomState>,
+        integers: &'a mut HashMap<i64, Handle<BoxedInt>, RandomState>,
     ) -> Self {
         Ctx {
             internal: RefCell::new(CtxInternal {
                 global,
-                scope: vec![HashMap::with_hasher(RandomState::new())],
                 heap,
-                interner,
                 strings,
+                integers,
             }),
+            nesting: Cell::new(0),
+            stack_base: Cell::new(0),
+            coroutine: Cell::new(None),
+            limits: Limits::default(),
+            fuel: Cell::new(u64::MAX),
+            budget: Cell::new(None),
+            hook: RefCell::new(None),
+            in_hook: Cell::new(false),
+            hooked: Cell::new(false),
+            ticks: Cell::new(0),
+            source: None,
+            warn: None,
+            threads: RefCell::new(Vec::new()),
+            held: RefCell::new(Vec::new()),
         }
     }
 
-    pub fn heap(&self) -> Ref<Heap> {
-        Ref::map(self.internal.borrow(), |internal| internal.heap)
+    /// Sets the source the spans of the code being run refer to.
+    pub fn set_source(&mut self, source: Option<Rc<Source>>) {
+        self.source = source;
     }
 
-    pub fn scope(&self) -> ScopeKey<'a, '_> {
-        let mut internal = self.internal.borrow_mut();
-        internal
-            .scope
-            .push(HashMap::with_hasher(RandomState::new()));
+    pub fn source(&self) -> Option<&Source> {
+        self.source.as_deref()
+    }
 
-        ScopeKey { ctx: self }
+    /// The line a span of the code being run starts on, if its source is
+    /// known.
+    pub fn line(&self, span: Span) -> Option<usize> {
+        self.source.as_ref().map(|source| source.line(span))
     }
 
-    fn scope_destroy(&self) {
-        let mut internal = self.internal.borrow_mut();
-        internal.scope.pop();
+    /// Sets or removes the hook called on the events of the code being run.
+    pub fn set_hook(&self, hook: Option<Hook>) {
+        self.ticks.set(0);
+        self.hooked.set(hook.is_some() && !self.in_hook.get());
+        *self.hook.borrow_mut() = hook;
     }
 
-    pub fn local(&self, key: Handle<ByteString>) {
-        self.internal
-            .borrow_mut()
-            .scope
-            .last_mut()
-            .unwrap()
-            .insert(key, Value::from_nil());
+    pub fn hook(&self) -> Option<Hook> {
+        self.hook.borrow().clone()
     }
 
-    pub fn assign(&self, key: Handle<ByteString>, value: Value) {
-        let mut internal = self.internal.borrow_mut();
+    /// The events the hook is called on and the number of instructions
+    /// between count events.
+    pub fn hook_mask(&self) -> (HookMask, u32) {
+        match &*self.hook.borrow() {
+            Some(hook) => (hook.mask, hook.count),
+            None => (HookMask::default(), 0),
+        }
+    }
 
-        for scope in internal.scope.iter_mut().rev() {
-            if scope.contains_key(&key) {
-                scope.insert(key, value);
-                return;
-            }
+    /// Whether events are reported to a hook.
+    #[inline]
+    pub fn hooked(&self) -> bool {
+        self.hooked.get()
+    }
+
+    /// Stops or resumes reporting events to the hook, which is stopped while
+    /// it runs.
+    pub fn pause_hook(&self, paused: bool) {
+        self.in_hook.set(paused);
+        self.hooked.set(!paused && self.hook.borrow().is_some());
+    }
+
+    /// Counts an instruction towards the next count event of the hook,
+    /// returning whether it is due.
+    pub fn tick(&self, count: u32) -> bool {
+        let ticks = self.ticks.get() + 1;
+        if ticks < count {
+            self.ticks.set(ticks);
+            return false;
         }
 
-        let key = Value::from_string(key);
-        internal.global.insert(key, value);
+        self.ticks.set(0);
+        true
+    }
+
+    /// Subjects the code run with the context to limits.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.budget.set(limits.instructions);
+        self.fuel.set(0);
+        self.limits = limits;
     }
 
-    pub fn resolve(&self, key: Handle<ByteString>) -> Value {
-        let internal = self.internal.borrow();
+    /// The instructions the limits still allow to run, none if they do not
+    /// limit instructions.
+    pub fn instructions_left(&self) -> Option<u64> {
+        self.budget.get().map(|budget| budget + self.fuel.get())
+    }
+
+    /// Whether hitting a limit raises an error that cannot be caught.
+    pub fn limits_uncatchable(&self) -> bool {
+        self.limits.uncatchable
+    }
+
+    /// Counts an instruction against the limits, returning the limit that
+    /// was hit if any.
+    #[inline]
+    pub fn step(&self) -> Option<Limit> {
+        let fuel = self.fuel.get();
+        if fuel == 0 {
+            return self.check_limits();
+        }
+
+        self.fuel.set(fuel - 1);
+        None
+    }
 
-        for scope in internal.scope.iter().rev() {
-            if let Some(value) = scope.get(&key) {
-                return *value;
+    // Checks the limits once the fuel ran out, handing out the next fuel
+    // along with the instruction being counted. The code catching the error
+    // for a limit that was hit gets the fuel of one interval.
+    fn check_limits(&self) -> Option<Limit> {
+        if let Some(limit) = self.hit_limit() {
+            self.fuel.set(CHECK_INTERVAL);
+            return Some(limit);
+        }
+
+        let fuel = match self.budget.get() {
+            Some(budget) => {
+                let fuel = budget.min(CHECK_INTERVAL);
+                self.budget.set(Some(budget - fuel));
+                fuel
+            },
+            None => CHECK_INTERVAL,
+        };
+
+        self.fuel.set(fuel - 1);
+        None
+    }
+
+    // The limit that is hit, if any.
+    fn hit_limit(&self) -> Option<Limit> {
+        if let Some(interrupt) = &self.limits.interrupt {
+            if interrupt.is_interrupted() {
+                return Some(Limit::Interrupted);
+            }
+        }
+
+        if let Some(deadline) = self.limits.deadline {
+            if Instant::now() >= deadline {
+                return Some(Limit::Deadline);
             }
         }
 
-        let key = Value::from_string(key);
-        internal.global.get(key)
+        if self.budget.get() == Some(0) {
+            return Some(Limit::Instructions);
+        }
+
+        None
     }
 
-    pub fn intern(&self, key: &[u8]) -> Handle<ByteString> {
+    /// Counts a dispatch loop started on top of the running ones, returning
+    /// `false` if too many are running already or they take up too much of
+    /// the native stack.
+    pub fn nest(&self) -> bool {
+        let marker = 0u8;
+        let position = &marker as *const u8 as usize;
+        let nesting = self.nesting.get();
+        if nesting == 0 {
+            self.stack_base.set(position);
+        }
+
+        let used = position.abs_diff(self.stack_base.get());
+        if nesting >= MAX_NESTING || used > MAX_NATIVE_STACK {
+            return false;
+        }
+
+        self.nesting.set(nesting + 1);
+        true
+    }
+
+    /// Ends a dispatch loop counted by [`Ctx::nest`].
+    pub fn unnest(&self) {
+        self.nesting.set(self.nesting.get() - 1);
+    }
+
+    /// The running coroutine, `None` outside of coroutines.
+    pub fn coroutine(&self) -> Option<Handle<Coroutine>> {
+        self.coroutine.get().map(|(coroutine, _)| coroutine)
+    }
+
+    /// Whether the running coroutine can yield, which it cannot from a loop
+    /// nested in its own through a native function.
+    pub fn is_yieldable(&self) -> bool {
+        match self.coroutine.get() {
+            Some((_, nesting)) => nesting == self.nesting.get(),
+            None => false,
+        }
+    }
+
+    /// Makes `coroutine` the running one, run by the innermost loop. Returns
+    /// the coroutine running before, to be restored with
+    /// [`Ctx::restore_coroutine`].
+    pub fn enter_coroutine(
+        &self,
+        coroutine: Handle<Coroutine>,
+    ) -> Option<(Handle<Coroutine>, usize)> {
+        self.coroutine
+            .replace(Some((coroutine, self.nesting.get())))
+    }
+
+    pub fn restore_coroutine(&self, previous: Option<(Handle<Coroutine>, usize)>) {
+        self.coroutine.set(previous);
+    }
+
+    /// Sets the function warnings are reported to, dropping them if there is
+    /// none.
+    pub fn set_warn(&mut self, warn: Option<WarnFunction>) {
+        self.warn = warn;
+    }
+
+    /// Whether the collector is due to run a step.
+    #[inline]
+    pub fn should_step(&self) -> bool {
+        self.internal.borrow().heap.should_step()
+    }
+
+    /// Runs a step of the collector for the loop running `thread`, then
+    /// calls the finalizers of the objects found unreachable.
+    pub fn collect_step(&self, thread: &(dyn Trace + 'a)) {
+        self.call_out(thread, || self.collect(Collection::Step));
+    }
+
+    /// Runs `call`, which may run other loops and collections, for the loop
+    /// running `thread`. Collections see the values of `thread` until `call`
+    /// returns, as the loop does not touch them meanwhile.
+    pub fn call_out<T, F>(&self, thread: &(dyn Trace + 'a), call: F) -> T
+    where
+        F: FnOnce() -> T,
+    {
+        self.threads.borrow_mut().push(thread);
+        let result = call();
+        self.threads.borrow_mut().pop();
+        result
+    }
+
+    /// Runs `call` with `values` kept alive by the collections it runs, for
+    /// values only native code refers to.
+    pub fn holding<T, F>(&self, values: &[Value], call: F) -> T
+    where
+        F: FnOnce() -> T,
+    {
+        let len = self.held.borrow().len();
+        self.held.borrow_mut().extend_from_slice(values);
+        let result = call();
+        self.held.borrow_mut().truncate(len);
+        result
+    }
+
+    /// Runs a collection with the globals, the hook, the held values and the
+    /// threads of the waiting loops as roots, then the finalizers. Returns
+    /// whether a cycle finished.
+    pub fn collect(&self, collection: Collection) -> bool {
         let mut internal = self.internal.borrow_mut();
+        let CtxInternal {
+            global,
+            heap,
+            strings,
+            integers,
+        } = &mut *internal;
+        let (hook, threads, held) = (
+            self.hook.borrow(),
+            self.threads.borrow(),
+            self.held.borrow(),
+        );
 
-        impl Borrow<[u8]> for Handle<ByteString> {
-            fn borrow(&self) -> &[u8] {
-                unsafe { self.get_unchecked() }
+        let trace = |visitor: &mut Visitor| {
+            global.visit(visitor);
+            // Each thread is borrowed by a loop waiting in `call_out`.
+            for thread in threads.iter() {
+                unsafe { &**thread }.visit(visitor);
             }
+
+            for value in held.iter() {
+                value.visit(visitor);
+            }
+
+            if let Some(Hook {
+                function: HookFunction::Lua(function),
+                ..
+            }) = &*hook
+            {
+                function.visit(visitor);
+            }
+        };
+        let finalize = |handle| forget(strings, integers, handle);
+
+        let finished = match collection {
+            Collection::Step => heap.step(trace, finalize),
+            Collection::Full => {
+                heap.collect(trace, finalize);
+                true
+            },
+            Collection::Mode(mode) => {
+                heap.set_mode(mode, trace, finalize);
+                true
+            },
+        };
+
+        drop((hook, threads, held, internal));
+        self.run_finalizers();
+        finished
+    }
+
+    /// Calls the `__gc` metamethods of the objects queued for finalization,
+    /// with the hook paused. Errors raised by metamethods are reported as
+    /// warnings.
+    pub fn run_finalizers(&self) {
+        let paused = self.in_hook.get();
+        self.pause_hook(true);
+        loop {
+            let object = match self.heap().pop_finalizer() {
+                Some(handle) => Value::from_handle(handle),
+                None => break,
+            };
+
+            let finalizer = meta::metamethod(object, b"__gc", self);
+            if finalizer == Value::from_nil() {
+                continue;
+            }
+
+            let result = self.holding(&[object], || eval::call(finalizer, vec![object], self));
+            if let (eval::Result::Error(error), Some(warn)) = (result, &self.warn) {
+                warn(&format!("error in __gc ({})", error));
+            }
+        }
+
+        self.pause_hook(paused);
+    }
+
+    pub fn heap(&self) -> Ref<Heap> {
+        Ref::map(self.internal.borrow(), |internal| internal.heap)
+    }
+
+    /// The write barrier for stores into upvalues and cells, which the
+    /// collector does not track.
+    pub fn barrier(&self, value: Value) {
+        if let Some(handle) = value.handle() {
+            self.heap().barrier(handle);
         }
+    }
+
+    pub fn global(&self, key: Handle<ByteString>) -> Value {
+        self.global_cached(key, None)
+    }
 
-        if let Some(handle) = internal.strings.get(key) {
-            return *handle;
+    pub fn set_global(&self, key: Handle<ByteString>, value: Value) {
+        self.set_global_cached(key, value, None);
+    }
+
+    /// Reads a global, finding it through the inline cache of the access.
+    pub fn global_cached(&self, key: Handle<ByteString>, cache: Option<&InlineCache>) -> Value {
+        self.internal.borrow().global.get_field(key, cache)
+    }
+
+    /// Writes a global, finding it through the inline cache of the access.
+    pub fn set_global_cached(
+        &self,
+        key: Handle<ByteString>,
+        value: Value,
+        cache: Option<&InlineCache>,
+    ) {
+        self.internal
+            .borrow_mut()
+            .global
+            .set_field(key, value, cache);
+    }
+
+    pub fn intern(&self, key: &[u8]) -> Handle<ByteString> {
+        let mut internal = self.internal.borrow_mut();
+        let heap = internal.heap;
+        intern(&mut *internal.strings, heap, key)
+    }
+
+    /// Creates an integer value, boxing it if it does not fit inline.
+    pub fn int(&self, x: i64) -> Value {
+        if let Some(value) = Value::try_from_int(x) {
+            return value;
         }
 
-        let handle = internal.heap.insert_string(key);
-        internal.strings.insert(handle);
-        handle
+        let mut internal = self.internal.borrow_mut();
+        let heap = internal.heap;
+        Value::from_boxed_int(box_int(&mut *internal.integers, heap, x))
     }
+}
 
-    pub fn intern_ident(&self, ident: &Ident) -> Handle<ByteString> {
-        let internal = self.internal.borrow();
-        let name = ident.name(internal.interner).unwrap();
-        drop(internal);
-        self.intern(name.as_bytes())
+/// Returns the unique string with the given contents, allocating it if needed.
+pub fn intern(
+    strings: &mut HashMap<Handle<ByteString>, (), RandomState>,
+    heap: &Heap,
+    key: &[u8],
+) -> Handle<ByteString> {
+    let hasher = strings.hasher().clone();
+    let hash = hash_bytes(&hasher, key);
+
+    let entry = strings
+        .raw_entry_mut()
+        .from_hash(hash, |handle| unsafe { **handle.get_unchecked() == *key });
+
+    match entry {
+        hash_map::RawEntryMut::Occupied(entry) => {
+            heap.revive(entry.key().tagged());
+            *entry.key()
+        },
+        hash_map::RawEntryMut::Vacant(entry) => {
+            let handle = heap.insert_string(key);
+            entry.insert_with_hasher(hash, handle, (), |handle| unsafe {
+                hash_bytes(&hasher, handle.get_unchecked())
+            });
+
+            handle
+        },
     }
 }
 
-pub struct ScopeKey<'a, 'ctx> {
-    ctx: &'ctx Ctx<'a>,
+/// Returns the unique boxed integer with the given value, allocating it if
+/// needed.
+pub fn box_int(
+    integers: &mut HashMap<i64, Handle<BoxedInt>, RandomState>,
+    heap: &Heap,
+    x: i64,
+) -> Handle<BoxedInt> {
+    let handle = *integers
+        .entry(x)
+        .or_insert_with(|| heap.insert(BoxedInt::new(x)));
+    heap.revive(handle.tagged());
+    handle
 }
 
-impl<'a, 'ctx> Drop for ScopeKey<'a, 'ctx> {
-    fn drop(&mut self) {
-        self.ctx.scope_destroy();
+/// Forgets a string or boxed integer the collector destroys, which the
+/// tables of unique ones do not keep alive.
+pub fn forget(
+    strings: &mut HashMap<Handle<ByteString>, (), RandomState>,
+    integers: &mut HashMap<i64, Handle<BoxedInt>, RandomState>,
+    handle: TaggedHandle,
+) {
+    let value = Value::from_handle(handle);
+    if let Some(string) = value.cast_string() {
+        let hash = hash_bytes(strings.hasher(), unsafe { string.get_unchecked() });
+        let entry = strings
+            .raw_entry_mut()
+            .from_hash(hash, |other| *other == string);
+        if let hash_map::RawEntryMut::Occupied(entry) = entry {
+            entry.remove();
+        }
+    } else if value.is_int() {
+        integers.remove(&value.cast_int());
     }
 }
+
+fn hash_bytes(hasher: &RandomState, bytes: &[u8]) -> u64 {
+    let mut state = hasher.build_hasher();
+    bytes.hash(&mut state);
+    state.finish()
+}
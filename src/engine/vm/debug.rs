@@ -0,0 +1,283 @@
+//! Hooks letting the host follow the code being run.
+//!
+//! A hook is called on the events its mask selects: when a Lua function is
+//! called or returns, when the code reaches a new line or jumps back, and
+//! every `count` instructions. Native functions do not raise call and return
+//! events, and no events are reported while a hook runs.
+//!
+//! Spans of compiled code are offsets into the syntax tree, which has no
+//! whitespace to count lines by. Lines are thus only reported for code
+//! compiled from the [`Source`] registered with the VM.
+
+use std::rc::Rc;
+
+use super::{
+    super::value::Value,
+    ctx::Ctx,
+    eval::{self, Result},
+};
+use crate::parser::machinery::{source_map::SourceMap, span::Span};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HookEvent {
+    Call,
+    /// A call that replaced the frame of the function making it.
+    TailCall,
+    Return,
+    Line,
+    Count,
+}
+
+impl HookEvent {
+    /// The name of the event as passed to hooks set by `debug.sethook`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Call => "call",
+            Self::TailCall => "tail call",
+            Self::Return => "return",
+            Self::Line => "line",
+            Self::Count => "count",
+        }
+    }
+}
+
+/// The events a hook is called on, besides count events.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct HookMask {
+    /// Calls and tail calls.
+    pub call: bool,
+    pub ret: bool,
+    pub line: bool,
+}
+
+/// What a hook learns about the event and the function running.
+pub struct Activation {
+    pub event: HookEvent,
+    /// The name the running function was called by, if known.
+    pub name: Option<String>,
+    /// The name of the registered source.
+    pub source: Option<String>,
+    /// The line of the code being run, if known.
+    pub line: Option<usize>,
+    /// The span of the code being run, `None` if debug information was
+    /// stripped.
+    pub span: Option<Span>,
+}
+
+/// The signature of a hook implemented by the host. Errors it returns are
+/// raised at the code being run.
+pub type HostHook = Rc<dyn Fn(&Ctx, &Activation) -> Result<()>>;
+
+#[derive(Clone)]
+pub enum HookFunction {
+    Host(HostHook),
+    /// A Lua function set by `debug.sethook`, called with the name of the
+    /// event and the line.
+    Lua(Value),
+}
+
+#[derive(Clone)]
+pub struct Hook {
+    pub function: HookFunction,
+    pub mask: HookMask,
+    /// The number of instructions between count events, none if zero.
+    pub count: u32,
+}
+
+impl Hook {
+    /// Creates a hook implemented by the host.
+    pub fn new<F>(function: F, mask: HookMask, count: u32) -> Self
+    where
+        F: Fn(&Ctx, &Activation) -> Result<()> + 'static,
+    {
+        Hook {
+            function: HookFunction::Host(Rc::new(function)),
+            mask,
+            count,
+        }
+    }
+}
+
+/// The source text that the spans of compiled code refer to.
+pub struct Source {
+    name: String,
+    map: SourceMap,
+}
+
+impl Source {
+    pub fn new(name: &str, text: &str) -> Self {
+        Source {
+            name: String::from(name),
+            map: SourceMap::new(text),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The line a span starts on.
+    pub fn line(&self, span: Span) -> usize {
+        self.map.line(self.map.offset(span.start()))
+    }
+}
+
+/// Calls the hook of the context, not reporting the events of the code it
+/// runs in turn.
+pub fn call_hook(activation: &Activation, ctx: &Ctx) -> Result<()> {
+    let hook = match ctx.hook() {
+        Some(hook) => hook,
+        None => return Result::Value(()),
+    };
+
+    ctx.pause_hook(true);
+    let result = match &hook.function {
+        HookFunction::Host(function) => function(ctx, activation),
+        HookFunction::Lua(function) => {
+            let event = Value::from_string(ctx.intern(activation.event.name().as_bytes()));
+            let line = match activation.line {
+                Some(line) => ctx.int(line as i64),
+                None => Value::from_nil(),
+            };
+
+            match eval::call(*function, vec![event, line], ctx) {
+                Result::Value(_) => Result::Value(()),
+                Result::Error(error) => Result::Error(error),
+            }
+        },
+    };
+
+    ctx.pause_hook(false);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::{
+        super::{
+            super::{gc::Heap, value::Value, Error},
+            eval,
+            VM,
+        },
+        Hook,
+        HookEvent,
+        HookMask,
+        Source,
+    };
+    use crate::parser::{machinery::cstree::NodeCache, parse, syntax::Root};
+
+    fn eval_hooked(source: &str, hook: Option<Hook>) -> Result<Value, Error> {
+        let mut cache = NodeCache::new();
+        let (tree, reports) = parse(&mut cache, source);
+        assert!(reports.is_empty());
+
+        let mut vm = VM::new(Heap::new());
+        vm.set_source(Some(Source::new("test", source)));
+        vm.set_hook(hook);
+        let root = Root::cast(&tree).unwrap();
+        vm.eval(&root, cache.interner())
+    }
+
+    #[test]
+    fn eval_debug_hooks() {
+        let source = "local function add(a, b)
+            return a + b
+        end
+        local x = add(1, 2)
+        return x";
+
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let log = events.clone();
+        let mask = HookMask {
+            call: true,
+            ret: true,
+            line: true,
+        };
+        let hook = Hook::new(
+            move |_, activation| {
+                let event = activation.event.name();
+                let name = activation.name.clone();
+                log.borrow_mut().push((event, name, activation.line));
+                assert_eq!(activation.source.as_deref(), Some("test"));
+                eval::Result::Value(())
+            },
+            mask,
+            0,
+        );
+
+        assert!(eval_hooked(source, Some(hook)).is_ok());
+        let expected = [
+            ("call", None, 1),
+            ("line", None, 1),
+            ("line", None, 4),
+            ("call", Some("add"), 2),
+            ("line", Some("add"), 2),
+            ("return", Some("add"), 2),
+            ("line", None, 5),
+            ("return", None, 5),
+        ];
+        let events = events.borrow();
+        assert_eq!(events.len(), expected.len());
+        for ((event, name, line), expected) in events.iter().zip(expected) {
+            assert_eq!(
+                (*event, name.as_deref(), *line),
+                (expected.0, expected.1, Some(expected.2))
+            );
+        }
+
+        // Count hooks are called every `count` instructions.
+        let source = "local s = 0 for i = 1, 100 do s = s + i end return s";
+        let counts = Rc::new(RefCell::new([0; 2]));
+        for (index, count) in [1, 10].into_iter().enumerate() {
+            let counts = counts.clone();
+            let hook = Hook::new(
+                move |_, activation| {
+                    assert_eq!(activation.event, HookEvent::Count);
+                    counts.borrow_mut()[index] += 1;
+                    eval::Result::Value(())
+                },
+                HookMask::default(),
+                count,
+            );
+            assert!(eval_hooked(source, Some(hook)).ok().unwrap() == Value::from_int(5050));
+        }
+        let counts = counts.borrow();
+        assert!(counts[0] > 100);
+        assert_eq!(counts[1], counts[0] / 10);
+
+        // Errors raised by hooks propagate to the code being run.
+        let hook = Hook::new(
+            |ctx, activation| match activation.line {
+                Some(3) => eval::Result::Error(eval::runtime_error(String::from("stop"), ctx)),
+                _ => eval::Result::Value(()),
+            },
+            HookMask {
+                line: true,
+                ..HookMask::default()
+            },
+            0,
+        );
+        match eval_hooked("local a = 1\nlocal b = 2\nlocal c = 3", Some(hook)) {
+            Err(Error::Runtime(error)) => assert_eq!(error.message(), "stop"),
+            _ => panic!("the hook error was lost"),
+        }
+
+        let source = "local lines = {}
+            local function hook(event, line) lines[#lines + 1] = line end
+            debug.sethook(hook, 'l')
+            local a = 1
+            local b = 2
+            debug.sethook()
+            return #lines == 3 and lines[1] == 4 and lines[2] == 5 and lines[3] == 6";
+        assert!(eval_hooked(source, None).ok().unwrap() == Value::from_bool(true));
+
+        let source = "local function hook() end
+            debug.sethook(hook, 'lrc', 5)
+            local f, mask, count = debug.gethook()
+            debug.sethook()
+            return f == hook and mask == 'crl' and count == 5 and debug.gethook() == nil";
+        assert!(eval_hooked(source, None).ok().unwrap() == Value::from_bool(true));
+    }
+}
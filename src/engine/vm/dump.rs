@@ -0,0 +1,899 @@
+//! The binary format of precompiled functions written by `string.dump` and
+//! read by `load`.
+//!
+//! A chunk starts with a header:
+//!
+//! | bytes | contents                                          |
+//! |-------|---------------------------------------------------|
+//! | 5     | the signature `\x1bZaia`                          |
+//! | 1     | the format [`VERSION`]                            |
+//! | 1     | `0` if the chunk is little endian, `1` if big     |
+//! | 8     | the integer `0x5678`, to check the byte order     |
+//! | 8     | the float `370.5`, to check the float format      |
+//!
+//! followed by the main function. Integers are written in the byte order of
+//! the machine that dumped the chunk and converted when it is loaded on a
+//! machine with the other byte order.
+//!
+//! Loaded functions are verified before they can run: every register,
+//! constant, cell, upvalue, nested function and jump they refer to has to
+//! exist, so that malformed input is rejected instead of misbehaving.
+//!
+//! Inline caches are not part of the format. Loaded instructions that access
+//! fields or globals get fresh caches numbered in the order of the code, as
+//! the compiler numbers them.
+
+use std::{
+    fmt::{self, Display},
+    rc::Rc,
+    str,
+};
+
+use super::{
+    super::value::{InlineCache, Value},
+    bytecode::{Capture, Instruction, Proto, Reg, MULTI, NO_CACHE},
+    ctx::Ctx,
+    meta::Arith,
+};
+use crate::parser::machinery::span::Span;
+
+/// The first bytes of every binary chunk. Text chunks cannot start with an
+/// escape character.
+pub const SIGNATURE: &[u8; 5] = b"\x1bZaia";
+
+/// The version of the format, bumped whenever the format or the instruction
+/// set changes.
+pub const VERSION: u8 = 2;
+
+const CHECK_INT: i64 = 0x5678;
+const CHECK_FLOAT: f64 = 370.5;
+
+// Functions nested deeper than this are rejected rather than risking the
+// native stack while reading them.
+const MAX_DEPTH: usize = 200;
+
+/// The reasons a binary chunk can be rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UndumpError {
+    /// The chunk does not start with the signature.
+    NotAChunk,
+    /// The chunk was written by a different version of the format.
+    Version(u8),
+    /// The chunk was written by a machine with different number formats.
+    Format,
+    /// The chunk ends early.
+    Truncated,
+    /// The chunk is damaged or was crafted to break the interpreter.
+    Malformed(&'static str),
+}
+
+impl Display for UndumpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotAChunk => f.write_str("not a binary chunk"),
+            Self::Version(version) => write!(
+                f,
+                "version mismatch (chunk is {}, expected {})",
+                version, VERSION
+            ),
+            Self::Format => f.write_str("number format mismatch"),
+            Self::Truncated => f.write_str("truncated precompiled chunk"),
+            Self::Malformed(what) => write!(f, "bad binary format ({})", what),
+        }
+    }
+}
+
+/// Writes a function and the functions nested in it. Stripping leaves out
+/// spans and names.
+pub fn dump(proto: &Proto, strip: bool) -> Vec<u8> {
+    write(proto, strip, cfg!(target_endian = "big"))
+}
+
+fn write(proto: &Proto, strip: bool, big: bool) -> Vec<u8> {
+    let mut writer = Writer {
+        bytes: Vec::new(),
+        strip,
+        big,
+    };
+
+    writer.bytes.extend_from_slice(SIGNATURE);
+    writer.u8(VERSION);
+    writer.u8(big as u8);
+    writer.u64(CHECK_INT as u64);
+    writer.u64(CHECK_FLOAT.to_bits());
+    writer.proto(proto);
+    writer.bytes
+}
+
+/// Reads and verifies a function written by [`dump`].
+pub fn undump(bytes: &[u8], ctx: &Ctx) -> Result<Rc<Proto>, UndumpError> {
+    if !bytes.starts_with(SIGNATURE) {
+        return Err(UndumpError::NotAChunk);
+    }
+
+    let mut reader = Reader {
+        bytes,
+        cursor: SIGNATURE.len(),
+        big: false,
+        ctx,
+    };
+
+    let version = reader.u8()?;
+    if version != VERSION {
+        return Err(UndumpError::Version(version));
+    }
+
+    reader.big = match reader.u8()? {
+        0 => false,
+        1 => true,
+        _ => return Err(UndumpError::Format),
+    };
+
+    if reader.u64()? as i64 != CHECK_INT || f64::from_bits(reader.u64()?) != CHECK_FLOAT {
+        return Err(UndumpError::Format);
+    }
+
+    let proto = reader.proto(None, 0)?;
+    if reader.cursor != bytes.len() {
+        return Err(UndumpError::Malformed("trailing bytes"));
+    }
+
+    Ok(Rc::new(proto))
+}
+
+struct Writer {
+    bytes: Vec<u8>,
+    strip: bool,
+    big: bool,
+}
+
+impl Writer {
+    fn put<const N: usize>(&mut self, mut bytes: [u8; N]) {
+        if self.big != cfg!(target_endian = "big") {
+            bytes.reverse();
+        }
+
+        self.bytes.extend_from_slice(&bytes);
+    }
+
+    fn u8(&mut self, x: u8) {
+        self.bytes.push(x);
+    }
+
+    fn u16(&mut self, x: u16) {
+        self.put(x.to_ne_bytes());
+    }
+
+    fn u32(&mut self, x: u32) {
+        self.put(x.to_ne_bytes());
+    }
+
+    fn u64(&mut self, x: u64) {
+        self.put(x.to_ne_bytes());
+    }
+
+    fn len(&mut self, len: usize) {
+        self.u32(len as u32);
+    }
+
+    fn string(&mut self, bytes: &[u8]) {
+        self.len(bytes.len());
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    fn proto(&mut self, proto: &Proto) {
+        self.u8(proto.params);
+        self.u8(proto.vararg as u8);
+        self.u8(proto.registers);
+        self.u16(proto.cells);
+
+        self.len(proto.code.len());
+        for &instruction in &proto.code {
+            self.instruction(instruction);
+        }
+
+        self.len(proto.constants.len());
+        for &constant in &proto.constants {
+            self.constant(constant);
+        }
+
+        self.len(proto.captures.len());
+        for capture in &proto.captures {
+            match *capture {
+                Capture::Cell(c) => {
+                    self.u8(0);
+                    self.u16(c);
+                },
+                Capture::Upvalue(u) => {
+                    self.u8(1);
+                    self.u16(u);
+                },
+            }
+        }
+
+        self.len(proto.protos.len());
+        for child in &proto.protos {
+            self.proto(child);
+        }
+
+        if self.strip {
+            self.len(0);
+            self.len(0);
+            self.len(0);
+            return;
+        }
+
+        self.len(proto.spans.len());
+        for span in &proto.spans {
+            self.u32(span.start());
+            self.u32(span.end());
+        }
+
+        self.len(proto.names.len());
+        for (pc, name) in &proto.names {
+            self.u32(*pc);
+            self.string(name.as_bytes());
+        }
+
+        self.len(proto.upvalues.len());
+        for name in &proto.upvalues {
+            self.string(unsafe { name.get_unchecked() });
+        }
+    }
+
+    fn constant(&mut self, value: Value) {
+        if value == Value::from_nil() {
+            self.u8(0);
+        } else if value == Value::from_bool(false) {
+            self.u8(1);
+        } else if value == Value::from_bool(true) {
+            self.u8(2);
+        } else if value.is_int() {
+            self.u8(3);
+            self.u64(value.cast_int() as u64);
+        } else if value.is_number() {
+            self.u8(4);
+            self.u64(value.convert_float().to_bits());
+        } else if let Some(string) = value.cast_string() {
+            self.u8(5);
+            self.string(unsafe { string.get_unchecked() });
+        } else {
+            unreachable!("constants are numbers, strings, booleans or nil");
+        }
+    }
+
+    fn instruction(&mut self, instruction: Instruction) {
+        use Instruction::*;
+
+        match instruction {
+            Move(a, b) => self.ops(0, &[a, b]),
+            LoadK(a, k) => {
+                self.ops(1, &[a]);
+                self.u32(k);
+            },
+            LoadInt(a, x) => {
+                self.ops(2, &[a]);
+                self.u32(x as u32);
+            },
+            LoadBool(a, x) => self.ops(3, &[a, x as u8]),
+            LoadNil(a, n) => self.ops(4, &[a, n]),
+            GetUpval(a, u) => {
+                self.ops(5, &[a]);
+                self.u16(u);
+            },
+            SetUpval(u, b) => {
+                self.ops(6, &[]);
+                self.u16(u);
+                self.u8(b);
+            },
+            NewCell(c) => {
+                self.ops(7, &[]);
+                self.u16(c);
+            },
+            GetCell(a, c) => {
+                self.ops(8, &[a]);
+                self.u16(c);
+            },
+            SetCell(c, b) => {
+                self.ops(9, &[]);
+                self.u16(c);
+                self.u8(b);
+            },
+            GetGlobal(a, k, _) => {
+                self.ops(10, &[a]);
+                self.u32(k);
+            },
+            SetGlobal(k, b, _) => {
+                self.ops(11, &[]);
+                self.u32(k);
+                self.u8(b);
+            },
+            GetIndex(a, b, c) => self.ops(12, &[a, b, c]),
+            GetField(a, b, k, _) => {
+                self.ops(13, &[a, b]);
+                self.u16(k);
+            },
+            SetIndex(a, b, c) => self.ops(14, &[a, b, c]),
+            SetField(a, k, c, _) => {
+                self.ops(15, &[a, c]);
+                self.u16(k);
+            },
+            SelfOp(a, b, k, _) => {
+                self.ops(16, &[a, b]);
+                self.u16(k);
+            },
+            NewTable(a) => self.ops(17, &[a]),
+            SetList(a, b, c, index) => {
+                self.ops(18, &[a, b, c]);
+                self.u32(index);
+            },
+            Arith(op, a, b, c) => self.ops(19, &[arith_code(op), a, b, c]),
+            ArithK(op, a, b, k) => {
+                self.ops(20, &[arith_code(op), a, b]);
+                self.u16(k);
+            },
+            Neg(a, b) => self.ops(21, &[a, b]),
+            BitNot(a, b) => self.ops(22, &[a, b]),
+            Len(a, b) => self.ops(23, &[a, b]),
+            Not(a, b) => self.ops(24, &[a, b]),
+            Eq(a, b, c) => self.ops(25, &[a, b, c]),
+            Ne(a, b, c) => self.ops(26, &[a, b, c]),
+            Lt(a, b, c) => self.ops(27, &[a, b, c]),
+            Le(a, b, c) => self.ops(28, &[a, b, c]),
+            Jump(offset) => {
+                self.ops(29, &[]);
+                self.u32(offset as u32);
+            },
+            JumpIf(a, offset) => {
+                self.ops(30, &[a]);
+                self.u32(offset as u32);
+            },
+            JumpIfNot(a, offset) => {
+                self.ops(31, &[a]);
+                self.u32(offset as u32);
+            },
+            Call(a, b, c) => self.ops(32, &[a, b, c]),
+            Return(a, b) => self.ops(33, &[a, b]),
+            TailCall(a, b) => self.ops(42, &[a, b]),
+            Closure(a, p) => {
+                self.ops(34, &[a]);
+                self.u16(p);
+            },
+            VarArg(a, n) => self.ops(35, &[a, n]),
+            Tbc(a, k) => {
+                self.ops(36, &[a]);
+                self.u32(k);
+            },
+            Close(a) => self.ops(37, &[a]),
+            ForPrep(a, offset) => {
+                self.ops(38, &[a]);
+                self.u32(offset as u32);
+            },
+            ForLoop(a, offset) => {
+                self.ops(39, &[a]);
+                self.u32(offset as u32);
+            },
+            TForCall(a, n) => self.ops(40, &[a, n]),
+            TForLoop(a, offset) => {
+                self.ops(41, &[a]);
+                self.u32(offset as u32);
+            },
+        }
+    }
+
+    // Writes an opcode followed by its byte sized operands.
+    fn ops(&mut self, opcode: u8, operands: &[u8]) {
+        self.u8(opcode);
+        self.bytes.extend_from_slice(operands);
+    }
+}
+
+const ARITH: [Arith; 13] = [
+    Arith::Add,
+    Arith::Sub,
+    Arith::Mul,
+    Arith::Div,
+    Arith::Mod,
+    Arith::Pow,
+    Arith::IntDiv,
+    Arith::BitAnd,
+    Arith::BitOr,
+    Arith::BitXor,
+    Arith::Shl,
+    Arith::Shr,
+    Arith::Concat,
+];
+
+fn arith_code(op: Arith) -> u8 {
+    ARITH.iter().position(|&other| other == op).unwrap() as u8
+}
+
+struct Reader<'a, 'ctx> {
+    bytes: &'a [u8],
+    cursor: usize,
+    big: bool,
+    ctx: &'a Ctx<'ctx>,
+}
+
+impl<'a, 'ctx> Reader<'a, 'ctx> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], UndumpError> {
+        let bytes = self
+            .bytes
+            .get(self.cursor..self.cursor + N)
+            .ok_or(UndumpError::Truncated)?;
+        self.cursor += N;
+
+        let mut array = [0; N];
+        array.copy_from_slice(bytes);
+        if self.big != cfg!(target_endian = "big") {
+            array.reverse();
+        }
+
+        Ok(array)
+    }
+
+    fn u8(&mut self) -> Result<u8, UndumpError> {
+        Ok(self.take::<1>()?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, UndumpError> {
+        Ok(u16::from_ne_bytes(self.take()?))
+    }
+
+    fn u32(&mut self) -> Result<u32, UndumpError> {
+        Ok(u32::from_ne_bytes(self.take()?))
+    }
+
+    fn i32(&mut self) -> Result<i32, UndumpError> {
+        Ok(self.u32()? as i32)
+    }
+
+    fn u64(&mut self) -> Result<u64, UndumpError> {
+        Ok(u64::from_ne_bytes(self.take()?))
+    }
+
+    fn bool(&mut self) -> Result<bool, UndumpError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(UndumpError::Malformed("invalid boolean")),
+        }
+    }
+
+    // Reads the length of a list whose entries take at least `size` bytes,
+    // so that lengths cannot claim more memory than the chunk could fill.
+    fn len(&mut self, size: usize) -> Result<usize, UndumpError> {
+        let len = self.u32()? as usize;
+        if len.saturating_mul(size) > self.bytes.len() - self.cursor {
+            return Err(UndumpError::Truncated);
+        }
+
+        Ok(len)
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8], UndumpError> {
+        let len = self.len(1)?;
+        let bytes = &self.bytes[self.cursor..self.cursor + len];
+        self.cursor += len;
+        Ok(bytes)
+    }
+
+    // Reads a function nested in one with the given number of cells and
+    // upvalues.
+    fn proto(&mut self, parent: Option<(u16, usize)>, depth: usize) -> Result<Proto, UndumpError> {
+        if depth > MAX_DEPTH {
+            return Err(UndumpError::Malformed("functions nested too deeply"));
+        }
+
+        let params = self.u8()?;
+        let vararg = self.bool()?;
+        let registers = self.u8()?;
+        let cells = self.u16()?;
+
+        let len = self.len(1)?;
+        let mut code = Vec::with_capacity(len);
+        let mut caches = Vec::new();
+        for _ in 0..len {
+            code.push(self.instruction(&mut caches)?);
+        }
+
+        let len = self.len(1)?;
+        let mut constants = Vec::with_capacity(len);
+        for _ in 0..len {
+            constants.push(self.constant()?);
+        }
+
+        let len = self.len(3)?;
+        let mut captures = Vec::with_capacity(len);
+        for _ in 0..len {
+            captures.push(match self.u8()? {
+                0 => Capture::Cell(self.u16()?),
+                1 => Capture::Upvalue(self.u16()?),
+                _ => return Err(UndumpError::Malformed("invalid capture")),
+            });
+        }
+
+        let len = self.len(1)?;
+        let mut protos = Vec::with_capacity(len);
+        for _ in 0..len {
+            let child = self.proto(Some((cells, captures.len())), depth + 1)?;
+            protos.push(Rc::new(child));
+        }
+
+        let len = self.len(8)?;
+        let mut spans = Vec::with_capacity(len);
+        for _ in 0..len {
+            spans.push(Span::new(self.u32()?, self.u32()?));
+        }
+
+        let len = self.len(8)?;
+        let mut names = Vec::with_capacity(len);
+        for _ in 0..len {
+            let pc = self.u32()?;
+            let name = str::from_utf8(self.bytes()?)
+                .map_err(|_| UndumpError::Malformed("invalid name"))?;
+            names.push((pc, String::from(name)));
+        }
+
+        let len = self.len(4)?;
+        let mut upvalues = Vec::with_capacity(len);
+        for _ in 0..len {
+            upvalues.push(self.ctx.intern(self.bytes()?));
+        }
+
+        let proto = Proto {
+            code,
+            spans,
+            constants,
+            protos,
+            captures,
+            upvalues,
+            names,
+            caches,
+            params,
+            vararg,
+            registers,
+            cells,
+        };
+
+        verify(&proto, parent)?;
+        Ok(proto)
+    }
+
+    fn constant(&mut self) -> Result<Value, UndumpError> {
+        Ok(match self.u8()? {
+            0 => Value::from_nil(),
+            1 => Value::from_bool(false),
+            2 => Value::from_bool(true),
+            3 => self.ctx.int(self.u64()? as i64),
+            4 => {
+                // The bits of other NaNs may be taken for a tagged pointer.
+                let float = f64::from_bits(self.u64()?);
+                Value::from_float(if float.is_nan() { f64::NAN } else { float })
+            },
+            5 => Value::from_string(self.ctx.intern(self.bytes()?)),
+            _ => return Err(UndumpError::Malformed("invalid constant")),
+        })
+    }
+
+    fn arith(&mut self) -> Result<Arith, UndumpError> {
+        let code = self.u8()? as usize;
+        ARITH
+            .get(code)
+            .copied()
+            .ok_or(UndumpError::Malformed("invalid operator"))
+    }
+
+    // Reads an instruction, giving it a fresh inline cache if it uses one.
+    fn instruction(&mut self, caches: &mut Vec<InlineCache>) -> Result<Instruction, UndumpError> {
+        use Instruction::*;
+
+        let mut cache = || {
+            if caches.len() < NO_CACHE as usize {
+                caches.push(InlineCache::default());
+                caches.len() as u16 - 1
+            } else {
+                NO_CACHE
+            }
+        };
+
+        Ok(match self.u8()? {
+            0 => Move(self.u8()?, self.u8()?),
+            1 => LoadK(self.u8()?, self.u32()?),
+            2 => LoadInt(self.u8()?, self.i32()?),
+            3 => LoadBool(self.u8()?, self.bool()?),
+            4 => LoadNil(self.u8()?, self.u8()?),
+            5 => GetUpval(self.u8()?, self.u16()?),
+            6 => SetUpval(self.u16()?, self.u8()?),
+            7 => NewCell(self.u16()?),
+            8 => GetCell(self.u8()?, self.u16()?),
+            9 => SetCell(self.u16()?, self.u8()?),
+            10 => GetGlobal(self.u8()?, self.u32()?, cache()),
+            11 => SetGlobal(self.u32()?, self.u8()?, cache()),
+            12 => GetIndex(self.u8()?, self.u8()?, self.u8()?),
+            13 => GetField(self.u8()?, self.u8()?, self.u16()?, cache()),
+            14 => SetIndex(self.u8()?, self.u8()?, self.u8()?),
+            15 => {
+                let (a, c) = (self.u8()?, self.u8()?);
+                SetField(a, self.u16()?, c, cache())
+            },
+            16 => SelfOp(self.u8()?, self.u8()?, self.u16()?, cache()),
+            17 => NewTable(self.u8()?),
+            18 => SetList(self.u8()?, self.u8()?, self.u8()?, self.u32()?),
+            19 => Arith(self.arith()?, self.u8()?, self.u8()?, self.u8()?),
+            20 => ArithK(self.arith()?, self.u8()?, self.u8()?, self.u16()?),
+            21 => Neg(self.u8()?, self.u8()?),
+            22 => BitNot(self.u8()?, self.u8()?),
+            23 => Len(self.u8()?, self.u8()?),
+            24 => Not(self.u8()?, self.u8()?),
+            25 => Eq(self.u8()?, self.u8()?, self.u8()?),
+            26 => Ne(self.u8()?, self.u8()?, self.u8()?),
+            27 => Lt(self.u8()?, self.u8()?, self.u8()?),
+            28 => Le(self.u8()?, self.u8()?, self.u8()?),
+            29 => Jump(self.i32()?),
+            30 => JumpIf(self.u8()?, self.i32()?),
+            31 => JumpIfNot(self.u8()?, self.i32()?),
+            32 => Call(self.u8()?, self.u8()?, self.u8()?),
+            33 => Return(self.u8()?, self.u8()?),
+            34 => Closure(self.u8()?, self.u16()?),
+            35 => VarArg(self.u8()?, self.u8()?),
+            36 => Tbc(self.u8()?, self.u32()?),
+            37 => Close(self.u8()?),
+            38 => ForPrep(self.u8()?, self.i32()?),
+            39 => ForLoop(self.u8()?, self.i32()?),
+            40 => TForCall(self.u8()?, self.u8()?),
+            41 => TForLoop(self.u8()?, self.i32()?),
+            42 => TailCall(self.u8()?, self.u8()?),
+            _ => return Err(UndumpError::Malformed("invalid opcode")),
+        })
+    }
+}
+
+// Checks that every operand of a function refers to something that exists and
+// that debug information, if present, covers the code. Captures are checked
+// against the number of cells and upvalues of the enclosing function.
+fn verify(proto: &Proto, parent: Option<(u16, usize)>) -> Result<(), UndumpError> {
+    use Instruction::*;
+
+    let registers = proto.registers as usize;
+    let constants = proto.constants.len();
+    let upvalues = proto.captures.len();
+    let cells = proto.cells as usize;
+
+    // Whether the registers `a` to `a + n - 1` exist.
+    let regs = |a: Reg, n: usize| a as usize + n <= registers;
+    // Whether a count of values that may be `MULTI` fits from `a` on.
+    let multi = |a: usize, n: u8| a + if n == MULTI { 0 } else { n as usize } <= registers;
+    let constant = |k: u32| (k as usize) < constants;
+    let string = |k: u32| constant(k) && proto.constants[k as usize].cast_string().is_some();
+    let upvalue = |u: u16| (u as usize) < upvalues;
+    let cell = |c: u16| (c as usize) < cells;
+    let jump = |pc: usize, offset: i32| {
+        let target = pc as i64 + 1 + offset as i64;
+        target >= 0 && (target as usize) < proto.code.len()
+    };
+
+    if proto.params > proto.registers {
+        return Err(UndumpError::Malformed("more parameters than registers"));
+    }
+
+    match proto.code.last() {
+        Some(Return(..) | Jump(_)) => (),
+        _ => return Err(UndumpError::Malformed("code does not end in a return")),
+    }
+
+    for (pc, &instruction) in proto.code.iter().enumerate() {
+        let valid = match instruction {
+            Move(a, b) | Neg(a, b) | BitNot(a, b) | Len(a, b) | Not(a, b) =>
+                regs(a, 1) && regs(b, 1),
+            LoadK(a, k) => regs(a, 1) && constant(k),
+            LoadInt(a, _) | LoadBool(a, _) | NewTable(a) => regs(a, 1),
+            LoadNil(a, n) => regs(a, n as usize),
+            GetUpval(a, u) | SetUpval(u, a) => regs(a, 1) && upvalue(u),
+            NewCell(c) => cell(c),
+            GetCell(a, c) | SetCell(c, a) => regs(a, 1) && cell(c),
+            GetGlobal(a, k, _) | SetGlobal(k, a, _) => regs(a, 1) && string(k),
+            GetIndex(a, b, c) | SetIndex(a, b, c) => regs(a, 1) && regs(b, 1) && regs(c, 1),
+            Eq(a, b, c) | Ne(a, b, c) | Lt(a, b, c) | Le(a, b, c) | Arith(_, a, b, c) =>
+                regs(a, 1) && regs(b, 1) && regs(c, 1),
+            GetField(a, b, k, _) | SetField(a, k, b, _) | ArithK(_, a, b, k) =>
+                regs(a, 1) && regs(b, 1) && constant(k as u32),
+            SelfOp(a, b, k, _) => regs(a, 2) && regs(b, 1) && constant(k as u32),
+            SetList(a, b, c, _) => regs(a, 1) && multi(b as usize, c),
+            Jump(offset) => jump(pc, offset),
+            JumpIf(a, offset) | JumpIfNot(a, offset) => regs(a, 1) && jump(pc, offset),
+            Call(a, b, c) => regs(a, 1) && multi(a as usize + 1, b) && multi(a as usize, c),
+            TailCall(a, b) => regs(a, 1) && multi(a as usize + 1, b),
+            Return(a, b) => multi(a as usize, b),
+            Closure(a, p) => regs(a, 1) && (p as usize) < proto.protos.len(),
+            VarArg(a, _) => regs(a, 1),
+            Tbc(a, k) => regs(a, 1) && string(k),
+            Close(a) => a as usize <= registers,
+            ForPrep(a, offset) | ForLoop(a, offset) => regs(a, 4) && jump(pc, offset),
+            TForCall(a, n) => regs(a, 7) && regs(a, 4 + n as usize),
+            TForLoop(a, offset) => regs(a, 5) && jump(pc, offset),
+        };
+
+        if !valid {
+            return Err(UndumpError::Malformed("invalid operand"));
+        }
+    }
+
+    let captured = |capture: &Capture| match (*capture, parent) {
+        (Capture::Cell(c), Some((cells, _))) => c < cells,
+        (Capture::Upvalue(u), Some((_, upvalues))) => (u as usize) < upvalues,
+        // The upvalues of a loaded main function start out as fresh cells.
+        (_, None) => true,
+    };
+
+    if !proto.captures.iter().all(captured) {
+        return Err(UndumpError::Malformed("invalid capture"));
+    }
+
+    let spans = proto.spans.is_empty() || proto.spans.len() == proto.code.len();
+    let upvalues = proto.upvalues.is_empty() || proto.upvalues.len() == proto.captures.len();
+    let names = proto.names.windows(2).all(|pair| pair[0].0 < pair[1].0);
+
+    if spans && upvalues && names {
+        Ok(())
+    } else {
+        Err(UndumpError::Malformed("invalid debug information"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::hash_map::RandomState, rc::Rc};
+
+    use hashbrown::HashMap;
+
+    use super::{
+        super::{
+            super::{
+                gc::Heap,
+                value::{Table, Value},
+            },
+            bytecode::Proto,
+            compiler,
+            ctx::Ctx,
+        },
+        dump,
+        undump,
+        write,
+        UndumpError,
+    };
+    use crate::parser::{machinery::cstree::NodeCache, parse, syntax::Root};
+
+    // The length of the header.
+    const HEADER: usize = 23;
+
+    const SOURCE: &str = "
+        local t = {}
+        local function f(a, ...)
+            for i = 1, #t do
+                t[i] = (t[i] or 0) + a
+            end
+            return select('#', ...), t, 'x' .. 2.5
+        end
+        return f(1, 2, 3)
+    ";
+
+    fn with_proto<F>(check: F)
+    where
+        F: FnOnce(&Rc<Proto>, &Ctx),
+    {
+        with_source(SOURCE, check)
+    }
+
+    fn with_source<F>(source: &str, check: F)
+    where
+        F: FnOnce(&Rc<Proto>, &Ctx),
+    {
+        let mut cache = NodeCache::new();
+        let (tree, _) = parse(&mut cache, source);
+
+        let heap = Heap::new();
+        let mut global = Table::new(heap.clone());
+        let mut strings = HashMap::with_hasher(RandomState::new());
+        let mut integers = HashMap::with_hasher(RandomState::new());
+        let ctx = Ctx::new(&mut global, &heap, &mut strings, &mut integers);
+
+        let root = Root::cast(&tree).unwrap();
+        match compiler::compile(&root, cache.interner(), &ctx) {
+            Ok(proto) => check(&proto, &ctx),
+            Err(_) => panic!("compilation failed"),
+        }
+    }
+
+    fn assert_same(a: &Proto, b: &Proto) {
+        assert_eq!(a.code, b.code);
+        assert_eq!(a.spans, b.spans);
+        assert!(a.constants == b.constants);
+        assert_eq!(a.captures, b.captures);
+        assert!(a.upvalues == b.upvalues);
+        assert_eq!(a.names, b.names);
+        assert_eq!(
+            (a.params, a.vararg, a.registers, a.cells),
+            (b.params, b.vararg, b.registers, b.cells)
+        );
+        assert_eq!(a.protos.len(), b.protos.len());
+        for (a, b) in a.protos.iter().zip(&b.protos) {
+            assert_same(a, b);
+        }
+    }
+
+    #[test]
+    fn undump_either_byte_order() {
+        with_proto(|proto, ctx| {
+            for big in [false, true] {
+                let bytes = write(proto, false, big);
+                let loaded = undump(&bytes, ctx).unwrap();
+                assert_same(proto, &loaded);
+            }
+        });
+    }
+
+    #[test]
+    fn undump_stripped() {
+        with_proto(|proto, ctx| {
+            let loaded = undump(&dump(proto, true), ctx).unwrap();
+            assert!(loaded.spans.is_empty() && loaded.names.is_empty());
+            assert_eq!(loaded.code, proto.code);
+        });
+    }
+
+    #[test]
+    fn reject_bad_header() {
+        with_proto(|proto, ctx| {
+            let bytes = dump(proto, false);
+            assert_eq!(undump(b"return 1", ctx).err(), Some(UndumpError::NotAChunk));
+
+            let mut other = bytes.clone();
+            other[5] += 1;
+            assert!(matches!(undump(&other, ctx), Err(UndumpError::Version(_))));
+
+            let mut other = bytes.clone();
+            other[7..15].fill(0);
+            assert_eq!(undump(&other, ctx).err(), Some(UndumpError::Format));
+        });
+    }
+
+    #[test]
+    fn reject_truncated_and_damaged_chunks() {
+        with_proto(|proto, ctx| {
+            let bytes = dump(proto, false);
+            for len in 0..bytes.len() {
+                assert!(undump(&bytes[..len], ctx).is_err());
+            }
+
+            // Damaged chunks may still be valid, but must never panic.
+            for at in HEADER..bytes.len() {
+                for flip in [0x01, 0x80, 0xff] {
+                    let mut bytes = bytes.clone();
+                    bytes[at] ^= flip;
+                    let _ = undump(&bytes, ctx);
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn undump_nan_constants() {
+        with_source("return 0.25", |proto, ctx| {
+            let mut bytes = write(proto, false, false);
+            let float = 0.25f64.to_bits().to_le_bytes();
+            let at = bytes
+                .windows(9)
+                .position(|window| window[0] == 4 && window[1..] == float)
+                .unwrap();
+
+            // A NaN with the bits of a table pointer.
+            let forged = 0xFFFC_0000_0000_1000u64.to_le_bytes();
+            bytes[at + 1..at + 9].copy_from_slice(&forged);
+            let loaded = undump(&bytes, ctx).unwrap();
+            let constant = loaded.constants[0];
+            assert_eq!(constant.type_name(), "number");
+            assert!(constant.op_eq(constant) == Value::from_bool(false));
+        });
+    }
+}
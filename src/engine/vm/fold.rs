@@ -0,0 +1,139 @@
+//! Evaluation of operators on constant operands at compile time.
+//!
+//! Operators are folded with the same primitives that run them, and only when
+//! the primitive succeeds. Constants are never tables, so they have no
+//! metatables and a failing primitive means the operation raises an error,
+//! which is left for the program to raise when it runs.
+
+use super::{super::value::Value, ctx::Ctx, meta::Arith};
+use crate::parser::syntax::{BinaryOperator, PrefixOperator};
+
+/// Folds a binary operator other than `and`, `or`, `.` and `:`.
+pub fn binary(op: BinaryOperator, a: Value, b: Value, ctx: &Ctx) -> Option<Value> {
+    let result = match op {
+        BinaryOperator::Eq => return Some(a.op_eq(b)),
+        BinaryOperator::NEq => return Some(a.op_neq(b)),
+        BinaryOperator::Lt => a.op_lt(b),
+        BinaryOperator::LEq => a.op_leq(b),
+        BinaryOperator::Gt => a.op_gt(b),
+        BinaryOperator::GEq => a.op_geq(b),
+        BinaryOperator::And
+        | BinaryOperator::Or
+        | BinaryOperator::Property
+        | BinaryOperator::Method => return None,
+        op => arith(op)?.apply(a, b, ctx),
+    };
+
+    result.ok()
+}
+
+/// Folds a prefix operator.
+pub fn unary(op: PrefixOperator, a: Value, ctx: &Ctx) -> Option<Value> {
+    let result = match op {
+        PrefixOperator::None => return Some(a),
+        PrefixOperator::Not => return Some(a.op_not()),
+        PrefixOperator::Neg => a.op_neg(ctx),
+        PrefixOperator::BitNot => a.op_bit_not(ctx),
+        PrefixOperator::Len => a.op_len(),
+    };
+
+    result.ok()
+}
+
+fn arith(op: BinaryOperator) -> Option<Arith> {
+    Some(match op {
+        BinaryOperator::Add => Arith::Add,
+        BinaryOperator::Sub => Arith::Sub,
+        BinaryOperator::Mul => Arith::Mul,
+        BinaryOperator::Div => Arith::Div,
+        BinaryOperator::IntDiv => Arith::IntDiv,
+        BinaryOperator::Exp => Arith::Pow,
+        BinaryOperator::Mod => Arith::Mod,
+        BinaryOperator::BitAnd => Arith::BitAnd,
+        BinaryOperator::BitOr => Arith::BitOr,
+        BinaryOperator::BitXor => Arith::BitXor,
+        BinaryOperator::LShift => Arith::Shl,
+        BinaryOperator::RShift => Arith::Shr,
+        BinaryOperator::Concat => Arith::Concat,
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{
+        super::value::Value,
+        bytecode::Instruction,
+        meta::Arith,
+        tests::{compile, eval, eval_error},
+    };
+
+    #[test]
+    fn compile_constant_folding() {
+        let proto = compile("return 2^10, 'a' .. '_' .. 1, 1 < 2 and -(3 // 2)");
+        let code = &proto.code;
+        assert!(matches!(code[0], Instruction::LoadK(0, _)));
+        assert!(matches!(code[1], Instruction::LoadK(1, _)));
+        assert_eq!(code[2], Instruction::LoadInt(2, -1));
+        assert!(proto.constants[0] == Value::from_float(1024.0));
+        assert_eq!(proto.constants.len(), 2);
+
+        // Operations that raise errors are left for runtime.
+        let proto = compile("return 1 // 0, 'a' < 1, #{}, -'x'");
+        let code = &proto.code;
+        assert!(matches!(
+            code[1],
+            Instruction::ArithK(Arith::IntDiv, 0, 1, _)
+        ));
+        assert!(code.iter().any(|i| matches!(i, Instruction::Lt(..))));
+        assert!(code.iter().any(|i| matches!(i, Instruction::Neg(..))));
+
+        let source = "
+            local DEBUG <const> = not true
+            local LIMIT <const> = 2^4
+            if DEBUG then print('debug') elseif LIMIT > 8 then x = 1 else x = 2 end
+            while DEBUG do print('loop') end
+            return function() return LIMIT * 2 end
+        ";
+        let proto = compile(source);
+        let jumps = proto.code.iter().filter(|i| {
+            matches!(
+                i,
+                Instruction::Jump(_) | Instruction::JumpIf(..) | Instruction::JumpIfNot(..)
+            )
+        });
+        assert_eq!(jumps.count(), 0);
+        assert!(!proto
+            .code
+            .iter()
+            .any(|i| matches!(i, Instruction::Call(..))));
+
+        let inner = &proto.protos[0];
+        assert!(matches!(inner.code[0], Instruction::LoadK(0, _)));
+        assert!(inner.constants == [Value::from_float(32.0)]);
+    }
+
+    #[test]
+    fn eval_constant_folding() {
+        let cases = &[
+            "return 2^10 == 1024 and 7 // 2 == 3 and 7 % -3 == -2",
+            "return 'a' .. 1 .. 2.0 == 'a12.0' and 10 .. '' == '10'",
+            "return #'abc' == 3 and ~0 == -1 and 1 < 2.5 and 'a' < 'b'",
+            "local A <const> = 3 local function f() return A * A end return f() == 9",
+            "local S <const> = 'x' local t = { [S .. S] = 1 } return t.xx == 1",
+            "local N <const> = 0 if N then return true else return false end",
+            "local n = 0 while 1 < 2 do n = n + 1 if n == 3 then break end end return n == 3",
+            "return (nil and 1) == nil and (false or 'y') == 'y' and (1 and 2) == 2",
+        ];
+
+        for source in cases {
+            eval(source, |_, _, value| {
+                assert!(value == Value::from_bool(true), "{}", source)
+            });
+        }
+
+        eval_error("local Z <const> = 0 return 1 % Z", |_, _, error| {
+            assert_eq!(error.message(), "attempt to perform 'n%0'");
+        });
+    }
+}
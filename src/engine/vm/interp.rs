@@ -0,0 +1,1461 @@
+//! The dispatch loop running compiled functions.
+//!
+//! Calls between Lua functions do not recurse on the native stack: every
+//! [`execute`] owns a value stack holding the register windows of the Lua
+//! functions it runs along with a list of their frames. Native functions are
+//! called through [`eval::call`], which starts a new loop when they call Lua
+//! functions in turn.
+//!
+//! Functions called by `pcall` and Lua metamethods run in frames of the loop
+//! as well, which remember what becomes of their results. Errors unwind the
+//! frames up to the innermost one entered by `pcall`, and a coroutine
+//! suspends by leaving its loop with its frames in place, so coroutines can
+//! yield across everything but native functions. The loop of an async call
+//! suspends the same way to await the future of an async host function.
+//!
+//! A loop hands its thread to the context whenever it calls out of its frames,
+//! to native functions, metamethods run by nested loops or hooks, so that the
+//! collector sees the values of every loop waiting on the code it runs.
+//!
+//! Tail calls replace the frame of the calling function, so they run in
+//! constant space. Both the registers and cells of the frames of a loop and
+//! the loops nested on the native stack are limited, exceeding either raises a
+//! "stack overflow".
+
+use std::rc::Rc;
+
+use super::{
+    super::{
+        gc::{Handle, Trace, Visitor},
+        value::{
+            Closure,
+            Coroutine,
+            Function,
+            HostFuture,
+            Intrinsic,
+            Status,
+            Table,
+            Upvalue,
+            Value,
+        },
+        Error,
+        RuntimeError,
+    },
+    bytecode::{Capture, Instruction, Proto, Reg, MULTI},
+    ctx::Ctx,
+    debug::{self, Activation, HookEvent},
+    eval::{self, raise, runtime_error, OrRaise, Result},
+    limits::Limit,
+    meta::{self, Arith, Step, Unary},
+};
+use crate::parser::machinery::span::Span;
+
+// The number of registers and cells the frames of a dispatch loop hold at
+// most, like LUAI_MAXSTACK.
+const MAX_SLOTS: usize = 1_000_000;
+
+/// Calls a Lua function with the given arguments, returning all of its
+/// results.
+pub fn execute(function: Handle<Function>, args: Vec<Value>, ctx: &Ctx) -> Result<Vec<Value>> {
+    if !ctx.nest() {
+        return Result::Error(runtime_error(String::from("stack overflow"), ctx));
+    }
+
+    let mut thread = Thread::new(None);
+    let nargs = args.len();
+    thread.stack.push(Value::from_function(function));
+    thread.stack.extend(args);
+    thread.enter(function, 0, nargs, Continuation::Call(MULTI), false);
+
+    let result = thread.run(ctx);
+    ctx.unnest();
+    match result {
+        Result::Value(Exit::Return(values)) => Result::Value(values),
+        // Only the loops of coroutines yield and those of async calls await.
+        Result::Value(Exit::Yield(_) | Exit::Await(_)) => unreachable!(),
+        Result::Error(error) => Result::Error(error),
+    }
+}
+
+/// How a coroutine or async call left its loop.
+pub enum Exit {
+    /// Its function returned these values.
+    Return(Vec<Value>),
+    /// It yielded these values.
+    Yield(Vec<Value>),
+    /// It waits on the future of an async host function.
+    Await(HostFuture),
+}
+
+/// Resumes a suspended coroutine, passing the given values to its function
+/// the first time and as the results of the yield it waits in afterwards.
+/// A coroutine that returns or fails is dead.
+pub fn resume(handle: Handle<Coroutine>, args: Vec<Value>, ctx: &Ctx) -> Result<Exit> {
+    if !ctx.nest() {
+        return Result::Error(runtime_error(String::from("stack overflow"), ctx));
+    }
+
+    let coroutine = unsafe { handle.get_unchecked() };
+    let previous = ctx.enter_coroutine(handle);
+    if let Some((previous, _)) = previous {
+        unsafe { previous.get_unchecked() }.set_status(Status::Normal);
+    }
+
+    coroutine.set_status(Status::Running);
+    let result = coroutine.thread().resume(Result::Value(args), ctx);
+    coroutine.set_status(match result {
+        Result::Value(Exit::Yield(_)) => Status::Suspended,
+        _ => Status::Dead,
+    });
+
+    if let Some((previous, _)) = previous {
+        unsafe { previous.get_unchecked() }.set_status(Status::Running);
+    }
+
+    ctx.restore_coroutine(previous);
+    ctx.unnest();
+    result
+}
+
+/// Starts or continues the loop of an async call, passing it the arguments of
+/// its function the first time and the outcome of the future it awaits
+/// afterwards.
+pub fn resume_async(thread: &mut Thread, values: Result<Vec<Value>>, ctx: &Ctx) -> Result<Exit> {
+    if !ctx.nest() {
+        return Result::Error(runtime_error(String::from("stack overflow"), ctx));
+    }
+
+    let result = thread.resume(values, ctx);
+    ctx.unnest();
+    result
+}
+
+// What becomes of the results of a frame once it returns.
+#[derive(Clone, Copy)]
+enum Continuation {
+    // A call storing this many results where the called function sat.
+    Call(u8),
+    // A call made by `pcall`, storing `true` followed by this many results
+    // where `pcall` sat.
+    Protected(u8),
+    // A metamethod storing its first result in a register of the caller.
+    Value(Reg),
+    // A comparison metamethod storing whether its first result is truthy in a
+    // register of the caller, or whether it is falsy if the flag is set.
+    Test(Reg, bool),
+    // A metamethod whose results are dropped.
+    Discard,
+}
+
+// The outcome of calling a value from the loop.
+enum Called {
+    // A frame was pushed for a Lua function.
+    Frame,
+    // A native function was called and its results are stored.
+    Native,
+    // The coroutine yields these values.
+    Yield(Vec<Value>),
+    // The async call awaits this future.
+    Await(HostFuture),
+}
+
+struct Frame {
+    closure: Handle<Function>,
+    proto: Rc<Proto>,
+    // The position of the next instruction, only kept up to date for frames
+    // that are waiting on a call.
+    pc: usize,
+    // The position of the first register on the stack. The function being
+    // run sits right below it.
+    base: usize,
+    // The position of the first cell.
+    cells: usize,
+    varargs: Vec<Value>,
+    // The stack positions and values of the to-be-closed variables in scope.
+    tbc: Vec<(usize, Value)>,
+    cont: Continuation,
+    // The event of the metamethod run by the frame, naming it in tracebacks.
+    event: Option<&'static [u8]>,
+    // Whether the frame replaced the frames of functions that tail called it.
+    tail: bool,
+    // The line and position of the instruction last seen by a line hook.
+    hook_line: usize,
+    hook_pc: usize,
+}
+
+impl Frame {
+    // The name the function of the frame was called by, if known. The call
+    // made by the caller is not the one that entered a frame reached through
+    // tail calls, so its name does not apply.
+    fn name(&self, caller: &Frame) -> Option<String> {
+        match self.event {
+            _ if self.tail => None,
+            Some(event) => Some(String::from_utf8_lossy(event).into_owned()),
+            None => caller.proto.call_name(caller.pc - 1),
+        }
+    }
+}
+
+/// The stack and frames of a dispatch loop, which coroutines keep while they
+/// are suspended.
+pub struct Thread {
+    stack: Vec<Value>,
+    cells: Vec<Option<Upvalue>>,
+    frames: Vec<Frame>,
+    // The position of the next instruction of the innermost frame.
+    pc: usize,
+    // The end of the values produced by the last instruction with a variable
+    // number of results.
+    top: usize,
+    // Whether the loop runs a coroutine, which is what it yields from.
+    coroutine: bool,
+    // Whether the loop runs an async call, which awaits host futures.
+    host: bool,
+    // The function position and continuation of the yield or host future a
+    // suspended loop waits on.
+    pending: Option<(usize, Continuation)>,
+}
+
+impl Thread {
+    /// Creates the thread of a coroutine calling `function` when it is first
+    /// resumed, or of a plain loop if there is no function.
+    pub fn new(function: Option<Handle<Function>>) -> Self {
+        Thread {
+            stack: function.into_iter().map(Value::from_function).collect(),
+            cells: Vec::new(),
+            frames: Vec::new(),
+            pc: 0,
+            top: 0,
+            coroutine: function.is_some(),
+            host: false,
+            pending: None,
+        }
+    }
+
+    /// Creates the thread of an async call calling `function` when it is
+    /// first resumed.
+    pub fn host(function: Value) -> Self {
+        Thread {
+            stack: vec![function],
+            cells: Vec::new(),
+            frames: Vec::new(),
+            pc: 0,
+            top: 0,
+            coroutine: false,
+            host: true,
+            pending: None,
+        }
+    }
+
+    // Starts or continues the coroutine or async call run by the thread,
+    // raising an error in the call it waits on if resumed with one. The stack
+    // is dropped once the thread is done.
+    fn resume(&mut self, values: Result<Vec<Value>>, ctx: &Ctx) -> Result<Exit> {
+        let result = match (self.pending.take(), values) {
+            (Some((func, cont)), Result::Value(values)) => {
+                self.complete(func, cont, &values);
+                self.run(ctx)
+            },
+            (Some((func, cont)), Result::Error(error)) => self.fail(func, cont, error, ctx),
+            (None, Result::Value(args)) => self.start(args, ctx),
+            (None, Result::Error(error)) => Result::Error(error),
+        };
+
+        if let Result::Value(Exit::Yield(_) | Exit::Await(_)) = result {
+            return result;
+        }
+
+        self.stack.clear();
+        self.cells.clear();
+        self.frames.clear();
+        result
+    }
+
+    // Calls the function of a thread that was never resumed.
+    fn start(&mut self, args: Vec<Value>, ctx: &Ctx) -> Result<Exit> {
+        // Only async calls may be handed other values.
+        let handle = match self.stack[0].cast_function() {
+            Some(handle) => handle,
+            None => {
+                let message = format!("attempt to call a {} value", self.stack[0].type_name());
+                return Result::Error(runtime_error(message, ctx));
+            },
+        };
+
+        if let Function::Native(native) = unsafe { handle.get_unchecked() } {
+            return match ctx.call_out(&*self, || native.call(ctx, args)) {
+                Result::Value(values) => Result::Value(Exit::Return(values)),
+                Result::Error(error) => Result::Error(error),
+            };
+        }
+
+        let nargs = args.len();
+        self.stack.extend(args);
+        self.enter(handle, 0, nargs, Continuation::Call(MULTI), false);
+        self.run(ctx)
+    }
+
+    // Raises an error in the call at `func` a suspended loop waits on, which
+    // `pcall` catches right away.
+    fn fail(&mut self, func: usize, cont: Continuation, error: Error, ctx: &Ctx) -> Result<Exit> {
+        let error = match (cont, error) {
+            (Continuation::Protected(results), Error::Runtime(error)) => {
+                self.store(func - 1, &[Value::from_bool(false), error.value()], results);
+                return self.run(ctx);
+            },
+            (_, Error::Runtime(mut error)) => {
+                let proto = &self.frames.last().unwrap().proto;
+                error.unwind(proto.call_name(self.pc - 1), proto.span(self.pc - 1));
+                Error::Runtime(error)
+            },
+            (_, error) => error,
+        };
+
+        match self.throw(error, ctx) {
+            Some(error) => Result::Error(error),
+            None => self.run(ctx),
+        }
+    }
+
+    /// Closes the pending to-be-closed variables of a suspended coroutine,
+    /// innermost first, and drops its stack. An error raised by a `__close`
+    /// metamethod is passed on to the following ones and returned.
+    pub fn close_all(&mut self, ctx: &Ctx) -> Result<()> {
+        let mut error = None;
+        while let Some(frame) = self.frames.pop() {
+            error = ctx.call_out(&*self, || close_unwound(frame.tbc, error, ctx));
+        }
+
+        self.stack.clear();
+        self.cells.clear();
+        self.pending = None;
+        match error {
+            Some(error) => Result::Error(error),
+            None => Result::Value(()),
+        }
+    }
+
+    // Pushes a frame for a Lua function sitting at `func` on the stack and
+    // followed by `nargs` arguments.
+    fn enter(
+        &mut self,
+        function: Handle<Function>,
+        func: usize,
+        nargs: usize,
+        cont: Continuation,
+        tail: bool,
+    ) {
+        let proto = match unsafe { function.get_unchecked() } {
+            Function::Lua(closure) => closure.proto().clone(),
+            Function::Native(_) => unreachable!(),
+        };
+
+        let base = func + 1;
+        let params = proto.params as usize;
+        let varargs = if proto.vararg && nargs > params {
+            self.stack[base + params..base + nargs].to_vec()
+        } else {
+            Vec::new()
+        };
+
+        let size = base + proto.registers.max(proto.params) as usize;
+        if self.stack.len() < size {
+            self.stack.resize(size, Value::from_nil());
+        }
+
+        for param in &mut self.stack[base + nargs.min(params)..base + params] {
+            *param = Value::from_nil();
+        }
+
+        let cells = self.cells.len();
+        self.cells.resize(cells + proto.cells as usize, None);
+
+        if let Some(caller) = self.frames.last_mut() {
+            caller.pc = self.pc;
+        }
+
+        self.frames.push(Frame {
+            closure: function,
+            proto,
+            pc: 0,
+            base,
+            cells,
+            varargs,
+            tbc: Vec::new(),
+            cont,
+            event: None,
+            tail,
+            hook_line: 0,
+            hook_pc: 0,
+        });
+        self.pc = 0;
+    }
+
+    // Whether a frame for the Lua function `function` sitting at `func`, with
+    // its cells from `cells` on, keeps the loop within MAX_SLOTS.
+    fn fits(&self, function: Handle<Function>, func: usize, cells: usize) -> bool {
+        let proto = match unsafe { function.get_unchecked() } {
+            Function::Lua(closure) => closure.proto(),
+            Function::Native(_) => return true,
+        };
+
+        let registers = func + 1 + proto.registers.max(proto.params) as usize;
+        registers + cells + proto.cells as usize <= MAX_SLOTS
+    }
+
+    // Stores the results of a call whose function sat at `func`.
+    fn store(&mut self, func: usize, values: &[Value], results: u8) {
+        let count = if results == MULTI {
+            values.len()
+        } else {
+            results as usize
+        };
+
+        if self.stack.len() < func + count {
+            self.stack.resize(func + count, Value::from_nil());
+        }
+
+        for (i, slot) in self.stack[func..func + count].iter_mut().enumerate() {
+            *slot = values.get(i).copied().unwrap_or_else(Value::from_nil);
+        }
+
+        self.top = func + count;
+    }
+
+    // Hands the results of a call whose function sat at `func` to the
+    // innermost frame as its continuation says.
+    fn complete(&mut self, func: usize, cont: Continuation, values: &[Value]) {
+        let first = values.first().copied().unwrap_or_else(Value::from_nil);
+        match cont {
+            Continuation::Call(results) => self.store(func, values, results),
+            Continuation::Protected(results) => {
+                let mut all = Vec::with_capacity(values.len() + 1);
+                all.push(Value::from_bool(true));
+                all.extend_from_slice(values);
+                self.store(func - 1, &all, results);
+            },
+            Continuation::Value(a) => {
+                let base = self.frames.last().unwrap().base;
+                self.stack[base + a as usize] = first;
+            },
+            Continuation::Test(a, negate) => {
+                let base = self.frames.last().unwrap().base;
+                self.stack[base + a as usize] = Value::from_bool(first.is_truthy() != negate);
+            },
+            Continuation::Discard => (),
+        }
+    }
+
+    // Resolves the function called by the value at `func` with the `nargs`
+    // values following it, inserting the called objects of `__call` handlers
+    // into the arguments. Returns the function and the number of arguments.
+    fn callee(
+        &mut self,
+        func: usize,
+        mut nargs: usize,
+        (proto, pc): (&Proto, usize),
+        ctx: &Ctx,
+    ) -> Result<(Handle<Function>, usize)> {
+        let mut function = self.stack[func];
+        if function.cast_function().is_none() {
+            // Resolve `__call` handlers, which receive the called object.
+            let mut args = self.stack[func + 1..func + 1 + nargs].to_vec();
+            function = meta::callable(function, &mut args, ctx).or_raise(proto.span(pc), ctx)?;
+            nargs = args.len();
+            self.stack[func] = function;
+            if self.stack.len() < func + 1 + nargs {
+                self.stack.resize(func + 1 + nargs, Value::from_nil());
+            }
+
+            self.stack[func + 1..func + 1 + nargs].copy_from_slice(&args);
+        }
+
+        Result::Value((function.cast_function().unwrap(), nargs))
+    }
+
+    // Calls the value at `func` with the `nargs` values following it.
+    fn call(
+        &mut self,
+        func: usize,
+        nargs: usize,
+        results: u8,
+        (proto, pc): (&Proto, usize),
+        ctx: &Ctx,
+    ) -> Result<Called> {
+        let (handle, nargs) = self.callee(func, nargs, (proto, pc), ctx)?;
+        let native = match unsafe { handle.get_unchecked() } {
+            Function::Lua(_) => {
+                if !self.fits(handle, func, self.cells.len()) {
+                    return raise(String::from("stack overflow"), proto.span(pc), ctx);
+                }
+
+                self.enter(handle, func, nargs, Continuation::Call(results), false);
+                return Result::Value(Called::Frame);
+            },
+            Function::Native(native) => native,
+        };
+
+        let args = self.stack[func + 1..func + 1 + nargs].to_vec();
+        if let (Some(function), true) = (native.async_function(), self.host) {
+            let future = function(ctx, args);
+            self.pending = Some((func, Continuation::Call(results)));
+            return Result::Value(Called::Await(future));
+        }
+
+        match native.intrinsic() {
+            Some(Intrinsic::PCall) if nargs > 0 =>
+                return self.pcall(func, nargs, results, (proto, pc), ctx),
+            Some(Intrinsic::Yield) if self.coroutine => {
+                self.pending = Some((func, Continuation::Call(results)));
+                return Result::Value(Called::Yield(args));
+            },
+            _ => (),
+        }
+
+        match ctx.call_out(&*self, || native.call(ctx, args)) {
+            Result::Value(values) => {
+                self.store(func, &values, results);
+                Result::Value(Called::Native)
+            },
+            Result::Error(Error::Runtime(mut error)) => {
+                error.unwind(proto.call_name(pc), proto.span(pc));
+                Result::Error(Error::Runtime(error))
+            },
+            Result::Error(error) => Result::Error(error),
+        }
+    }
+
+    // Carries out `pcall` sitting at `func`, calling the first of the `nargs`
+    // values following it with the others. Lua functions run in a frame that
+    // catches the errors unwinding it.
+    fn pcall(
+        &mut self,
+        func: usize,
+        nargs: usize,
+        results: u8,
+        site: (&Proto, usize),
+        ctx: &Ctx,
+    ) -> Result<Called> {
+        let (handle, nargs) = match self.callee(func + 1, nargs - 1, site, ctx) {
+            Result::Value(callee) => callee,
+            Result::Error(Error::Runtime(error)) => {
+                self.store(func, &[Value::from_bool(false), error.value()], results);
+                return Result::Value(Called::Native);
+            },
+            Result::Error(error) => return Result::Error(error),
+        };
+
+        let native = match unsafe { handle.get_unchecked() } {
+            Function::Lua(_) => {
+                if !self.fits(handle, func + 1, self.cells.len()) {
+                    let message = Value::from_string(ctx.intern(b"stack overflow"));
+                    self.store(func, &[Value::from_bool(false), message], results);
+                    return Result::Value(Called::Native);
+                }
+
+                self.enter(
+                    handle,
+                    func + 1,
+                    nargs,
+                    Continuation::Protected(results),
+                    false,
+                );
+                return Result::Value(Called::Frame);
+            },
+            Function::Native(native) => native,
+        };
+
+        let args = self.stack[func + 2..func + 2 + nargs].to_vec();
+        if let (Some(function), true) = (native.async_function(), self.host) {
+            let future = function(ctx, args);
+            self.pending = Some((func + 1, Continuation::Protected(results)));
+            return Result::Value(Called::Await(future));
+        }
+
+        if native.intrinsic() == Some(Intrinsic::Yield) && self.coroutine {
+            self.pending = Some((func + 1, Continuation::Protected(results)));
+            return Result::Value(Called::Yield(args));
+        }
+
+        match ctx.call_out(&*self, || native.call(ctx, args)) {
+            Result::Value(values) =>
+                self.complete(func + 1, Continuation::Protected(results), &values),
+            Result::Error(Error::Runtime(error)) =>
+                self.store(func, &[Value::from_bool(false), error.value()], results),
+            Result::Error(error) => return Result::Error(error),
+        }
+
+        Result::Value(Called::Native)
+    }
+
+    // Calls the value at `func` with the `nargs` values following it in place
+    // of the innermost frame. The results of native functions are stored for
+    // the innermost frame to return.
+    fn tail_call(
+        &mut self,
+        func: usize,
+        nargs: usize,
+        (proto, pc): (&Proto, usize),
+        ctx: &Ctx,
+    ) -> Result<Called> {
+        let (handle, nargs) = self.callee(func, nargs, (proto, pc), ctx)?;
+        if let Function::Native(_) = unsafe { handle.get_unchecked() } {
+            return self.call(func, nargs, MULTI, (proto, pc), ctx);
+        }
+
+        let frame = self.frames.last().unwrap();
+        if !self.fits(handle, frame.base - 1, frame.cells) {
+            return raise(String::from("stack overflow"), proto.span(pc), ctx);
+        }
+
+        let frame = self.frames.pop().unwrap();
+        self.cells.truncate(frame.cells);
+
+        // The called function takes the place of the one calling it, and the
+        // frame waiting on that one is waiting on it now.
+        let at = frame.base - 1;
+        self.stack.copy_within(func..func + 1 + nargs, at);
+        if let Some(caller) = self.frames.last() {
+            self.pc = caller.pc;
+        }
+
+        self.enter(handle, at, nargs, frame.cont, true);
+        Result::Value(Called::Frame)
+    }
+
+    // Carries out the step of an overloaded operation of the instruction at
+    // `pc`, handing its value to `cont`. Lua handlers run in a frame of their
+    // own placed above the registers of the innermost frame. Returns whether
+    // the value is already handed over.
+    fn step(
+        &mut self,
+        step: Step,
+        cont: Continuation,
+        (proto, pc): (&Proto, usize),
+        ctx: &Ctx,
+    ) -> Result<bool> {
+        let (handler, args, event) = match step {
+            Step::Done(value) => {
+                self.complete(0, cont, &[value]);
+                return Result::Value(true);
+            },
+            Step::Call(handler, args, event) => (handler, args, event),
+        };
+
+        if let Some(handle) = handler.cast_function() {
+            if let Function::Lua(_) = unsafe { handle.get_unchecked() } {
+                let base = self.frames.last().unwrap().base;
+                let func = (base + proto.registers.max(proto.params) as usize).max(self.top);
+                if !self.fits(handle, func, self.cells.len()) {
+                    return raise(String::from("stack overflow"), proto.span(pc), ctx);
+                }
+
+                let end = func + 1 + args.len();
+                if self.stack.len() < end {
+                    self.stack.resize(end, Value::from_nil());
+                }
+
+                self.stack[func] = handler;
+                self.stack[func + 1..end].copy_from_slice(&args);
+                self.enter(handle, func, args.len(), cont, false);
+                self.frames.last_mut().unwrap().event = Some(event);
+                return Result::Value(false);
+            }
+        }
+
+        let span = proto.span(pc);
+        let value = ctx.call_out(&*self, || {
+            meta::call_metamethod(handler, args, event, span, ctx)
+        })?;
+        self.complete(0, cont, &[value]);
+        Result::Value(true)
+    }
+
+    // Calls the `__close` metamethods of the to-be-closed variables of the
+    // innermost frame at or above `level` in reverse order of declaration.
+    fn close(&mut self, level: usize, ctx: &Ctx) -> Result<()> {
+        loop {
+            let frame = self.frames.last_mut().unwrap();
+            let value = match frame.tbc.last() {
+                Some(&(at, value)) if at >= level => value,
+                _ => return Result::Value(()),
+            };
+
+            frame.tbc.pop();
+            let handler = meta::metamethod(value, b"__close", ctx);
+            ctx.call_out(&*self, || {
+                eval::call(handler, vec![value, Value::from_nil()], ctx)
+            })?;
+        }
+    }
+
+    // Unwinds frames with an error raised in the innermost one, closing their
+    // to-be-closed variables and recording the calls in the traceback, until
+    // a frame entered by `pcall` catches it. Returns the error if none does.
+    fn throw(&mut self, error: Error, ctx: &Ctx) -> Option<Error> {
+        let mut error = Some(error);
+        while let Some(mut frame) = self.frames.pop() {
+            let tbc = std::mem::take(&mut frame.tbc);
+            error = ctx.call_out(&*self, || close_unwound(tbc, error, ctx));
+            self.cells.truncate(frame.cells);
+
+            if let (true, Some(Error::Runtime(error))) = (frame.tail, &mut error) {
+                error.tail_called();
+            }
+
+            let caller = match self.frames.last() {
+                Some(caller) => caller,
+                None => break,
+            };
+
+            if let (Continuation::Protected(results), Some(Error::Runtime(caught))) =
+                (frame.cont, &error)
+            {
+                self.pc = caller.pc;
+                let values = [Value::from_bool(false), caught.value()];
+                self.store(frame.base - 2, &values, results);
+                return None;
+            }
+
+            if let Some(Error::Runtime(error)) = &mut error {
+                error.unwind(frame.name(caller), caller.proto.span(caller.pc - 1));
+            }
+        }
+
+        error
+    }
+
+    // Reports an event of the innermost frame at the instruction at `pc` to
+    // the hook.
+    fn report(&self, event: HookEvent, pc: usize, ctx: &Ctx) -> Result<()> {
+        let frame = self.frames.last().unwrap();
+        let span = frame.proto.spans.get(pc).copied();
+        let activation = Activation {
+            event,
+            name: match self.frames.len() {
+                1 => None,
+                n => frame.name(&self.frames[n - 2]),
+            },
+            source: ctx.source().map(|source| source.name().to_owned()),
+            line: span.and_then(|span| ctx.line(span)),
+            span,
+        };
+
+        ctx.call_out(self, || debug::call_hook(&activation, ctx))
+    }
+
+    // Reports the count and line events of the instruction about to run to
+    // the hook. A line event is due when the instruction is on another line
+    // than the last one of the frame or the frame jumped back.
+    fn hook_instruction(&mut self, ctx: &Ctx) -> Result<()> {
+        let (mask, count) = ctx.hook_mask();
+        if count > 0 && ctx.tick(count) {
+            self.report(HookEvent::Count, self.pc, ctx)?;
+        }
+
+        let frame = self.frames.last_mut().unwrap();
+        let span = frame.proto.spans.get(self.pc).copied();
+        if let (true, Some(line)) = (mask.line, span.and_then(|span| ctx.line(span))) {
+            let due = line != frame.hook_line || self.pc <= frame.hook_pc;
+            frame.hook_line = line;
+            frame.hook_pc = self.pc;
+            if due {
+                self.report(HookEvent::Line, self.pc, ctx)?;
+            }
+        }
+
+        Result::Value(())
+    }
+
+    // Runs the innermost frame until the loop is left, resuming after errors
+    // caught by `pcall`.
+    fn run(&mut self, ctx: &Ctx) -> Result<Exit> {
+        loop {
+            match self.dispatch(ctx) {
+                Result::Value(exit) => return Result::Value(exit),
+                Result::Error(error) =>
+                    if let Some(error) = self.throw(error, ctx) {
+                        return Result::Error(error);
+                    },
+            }
+        }
+    }
+
+    fn dispatch(&mut self, ctx: &Ctx) -> Result<Exit> {
+        'frames: loop {
+            let frame = self.frames.last().unwrap();
+            let proto = frame.proto.clone();
+            let base = frame.base;
+            let cells = frame.cells;
+            let upvalues = match unsafe { frame.closure.get_unchecked() } {
+                Function::Lua(closure) => closure.upvalues(),
+                Function::Native(_) => unreachable!(),
+            };
+
+            let code = &proto.code[..];
+            let constants = &proto.constants[..];
+            let caches = &proto.caches[..];
+
+            // Frames are only dispatched at their start when they are
+            // entered.
+            if self.pc == 0 && ctx.hooked() && ctx.hook_mask().0.call {
+                let event = if self.frames.last().unwrap().tail {
+                    HookEvent::TailCall
+                } else {
+                    HookEvent::Call
+                };
+                self.report(event, 0, ctx)?;
+            }
+
+            macro_rules! reg {
+                ($r:expr) => {
+                    self.stack[base + $r as usize]
+                };
+            }
+
+            macro_rules! span {
+                () => {
+                    proto.span(self.pc - 1)
+                };
+            }
+
+            // Cells are only read after being created, except by malformed
+            // precompiled code.
+            macro_rules! cell {
+                ($c:expr) => {
+                    match &self.cells[cells + $c as usize] {
+                        Some(cell) => cell,
+                        None => return raise(String::from("access to an unset cell"), span!(), ctx),
+                    }
+                };
+            }
+
+            // The instructions that allocate are where the collector runs its
+            // steps, as every value in use is on the stack then.
+            macro_rules! collect {
+                () => {
+                    if ctx.should_step() {
+                        ctx.collect_step(&*self);
+                    }
+                };
+            }
+
+            macro_rules! call {
+                ($called:expr) => {
+                    match $called? {
+                        Called::Frame => continue 'frames,
+                        Called::Native => collect!(),
+                        Called::Yield(values) => return Result::Value(Exit::Yield(values)),
+                        Called::Await(future) => return Result::Value(Exit::Await(future)),
+                    }
+                };
+            }
+
+            macro_rules! step {
+                ($step:expr, $cont:expr) => {{
+                    let step = $step;
+                    if !self.step(step, $cont, (&proto, self.pc - 1), ctx)? {
+                        continue 'frames;
+                    }
+                }};
+            }
+
+            loop {
+                if let Some(limit) = ctx.step() {
+                    return Result::Error(exceeded(limit, proto.span(self.pc), ctx));
+                }
+
+                if ctx.hooked() {
+                    self.hook_instruction(ctx)?;
+                }
+
+                let instruction = code[self.pc];
+                self.pc += 1;
+
+                match instruction {
+                    Instruction::Move(a, b) => reg!(a) = reg!(b),
+                    Instruction::LoadK(a, k) => reg!(a) = constants[k as usize],
+                    Instruction::LoadInt(a, x) => reg!(a) = Value::from_int(x),
+                    Instruction::LoadBool(a, x) => reg!(a) = Value::from_bool(x),
+                    Instruction::LoadNil(a, n) =>
+                        for i in 0..n {
+                            reg!(a + i) = Value::from_nil();
+                        },
+
+                    Instruction::GetUpval(a, u) => reg!(a) = upvalues[u as usize].get(),
+                    Instruction::SetUpval(u, b) => {
+                        ctx.barrier(reg!(b));
+                        upvalues[u as usize].set(reg!(b));
+                    },
+                    Instruction::NewCell(c) =>
+                        self.cells[cells + c as usize] = Some(Upvalue::new(Value::from_nil())),
+                    Instruction::GetCell(a, c) => reg!(a) = cell!(c).get(),
+                    Instruction::SetCell(c, b) => {
+                        ctx.barrier(reg!(b));
+                        cell!(c).set(reg!(b));
+                    },
+                    Instruction::GetGlobal(a, k, i) => {
+                        let name = constants[k as usize].cast_string().unwrap();
+                        reg!(a) = ctx.global_cached(name, caches.get(i as usize));
+                    },
+                    Instruction::SetGlobal(k, b, i) => {
+                        let name = constants[k as usize].cast_string().unwrap();
+                        ctx.set_global_cached(name, reg!(b), caches.get(i as usize));
+                    },
+
+                    Instruction::GetIndex(a, b, c) => step!(
+                        meta::index(reg!(b), reg!(c), span!(), ctx)?,
+                        Continuation::Value(a)
+                    ),
+                    Instruction::GetField(a, b, k, i) => {
+                        let (key, cache) = (constants[k as usize], caches.get(i as usize));
+                        step!(
+                            meta::index_cached(reg!(b), key, cache, span!(), ctx)?,
+                            Continuation::Value(a)
+                        );
+                    },
+                    Instruction::SetIndex(a, b, c) => step!(
+                        meta::new_index(reg!(a), reg!(b), reg!(c), span!(), ctx)?,
+                        Continuation::Discard
+                    ),
+                    Instruction::SetField(a, k, c, i) => {
+                        let (key, cache) = (constants[k as usize], caches.get(i as usize));
+                        step!(
+                            meta::new_index_cached(reg!(a), key, reg!(c), cache, span!(), ctx)?,
+                            Continuation::Discard
+                        );
+                    },
+                    Instruction::SelfOp(a, b, k, i) => {
+                        let object = reg!(b);
+                        let (key, cache) = (constants[k as usize], caches.get(i as usize));
+                        let method = meta::index_cached(object, key, cache, span!(), ctx)?;
+                        reg!(a + 1) = object;
+                        step!(method, Continuation::Value(a));
+                    },
+                    Instruction::NewTable(a) => {
+                        let heap = ctx.heap().clone();
+                        reg!(a) = Value::from_table(heap.insert(Table::new(heap.clone())));
+                        collect!();
+                    },
+                    Instruction::SetList(a, b, c, index) => {
+                        let table = reg!(a);
+                        let start = base + b as usize;
+                        let end = if c == MULTI {
+                            self.top
+                        } else {
+                            start + c as usize
+                        };
+
+                        for (i, at) in (start..end).enumerate() {
+                            let key = ctx.int(index as i64 + i as i64);
+                            table
+                                .op_set_property(key, self.stack[at])
+                                .or_raise(span!(), ctx)?;
+                        }
+                    },
+
+                    Instruction::Arith(op, a, b, c) => {
+                        let (x, y) = (reg!(b), reg!(c));
+                        match op.apply(x, y, ctx) {
+                            Ok(value) => {
+                                reg!(a) = value;
+                                if op == Arith::Concat {
+                                    collect!();
+                                }
+                            },
+                            Err(_) =>
+                                step!(meta::arith(op, x, y, span!(), ctx)?, Continuation::Value(a)),
+                        }
+                    },
+                    Instruction::ArithK(op, a, b, k) => {
+                        let (x, y) = (reg!(b), constants[k as usize]);
+                        match op.apply(x, y, ctx) {
+                            Ok(value) => {
+                                reg!(a) = value;
+                                if op == Arith::Concat {
+                                    collect!();
+                                }
+                            },
+                            Err(_) =>
+                                step!(meta::arith(op, x, y, span!(), ctx)?, Continuation::Value(a)),
+                        }
+                    },
+                    Instruction::Neg(a, b) => step!(
+                        meta::unary(Unary::Neg, reg!(b), span!(), ctx)?,
+                        Continuation::Value(a)
+                    ),
+                    Instruction::BitNot(a, b) => step!(
+                        meta::unary(Unary::BitNot, reg!(b), span!(), ctx)?,
+                        Continuation::Value(a)
+                    ),
+                    Instruction::Len(a, b) => step!(
+                        meta::unary(Unary::Len, reg!(b), span!(), ctx)?,
+                        Continuation::Value(a)
+                    ),
+                    Instruction::Not(a, b) => reg!(a) = reg!(b).op_not(),
+                    Instruction::Eq(a, b, c) => step!(
+                        meta::eq(reg!(b), reg!(c), ctx),
+                        Continuation::Test(a, false)
+                    ),
+                    Instruction::Ne(a, b, c) =>
+                        step!(meta::eq(reg!(b), reg!(c), ctx), Continuation::Test(a, true)),
+                    Instruction::Lt(a, b, c) => step!(
+                        meta::lt(reg!(b), reg!(c), span!(), ctx)?,
+                        Continuation::Test(a, false)
+                    ),
+                    Instruction::Le(a, b, c) => step!(
+                        meta::le(reg!(b), reg!(c), span!(), ctx)?,
+                        Continuation::Test(a, false)
+                    ),
+
+                    Instruction::Jump(offset) => self.jump(offset),
+                    Instruction::JumpIf(a, offset) =>
+                        if reg!(a).is_truthy() {
+                            self.jump(offset);
+                        },
+                    Instruction::JumpIfNot(a, offset) =>
+                        if !reg!(a).is_truthy() {
+                            self.jump(offset);
+                        },
+
+                    Instruction::Call(a, b, c) => {
+                        let func = base + a as usize;
+                        let nargs = if b == MULTI {
+                            self.top.saturating_sub(func + 1)
+                        } else {
+                            b as usize
+                        };
+
+                        call!(self.call(func, nargs, c, (&proto, self.pc - 1), ctx));
+                    },
+                    Instruction::TailCall(a, b) => {
+                        let func = base + a as usize;
+                        let nargs = if b == MULTI {
+                            self.top.saturating_sub(func + 1)
+                        } else {
+                            b as usize
+                        };
+
+                        // Only precompiled code tail calls with variables
+                        // left to close, which are closed before the call.
+                        if !self.frames.last().unwrap().tbc.is_empty() {
+                            self.close(base, ctx)?;
+                        }
+
+                        call!(self.tail_call(func, nargs, (&proto, self.pc - 1), ctx));
+                    },
+                    Instruction::Return(a, b) => {
+                        let start = base + a as usize;
+                        let end = if b == MULTI {
+                            self.top.max(start)
+                        } else {
+                            start + b as usize
+                        };
+
+                        if ctx.hooked() && ctx.hook_mask().0.ret {
+                            self.report(HookEvent::Return, self.pc - 1, ctx)?;
+                        }
+
+                        if !self.frames.last().unwrap().tbc.is_empty() {
+                            self.close(base, ctx)?;
+                        }
+
+                        let frame = self.frames.pop().unwrap();
+                        self.cells.truncate(frame.cells);
+
+                        let caller = match self.frames.last() {
+                            Some(caller) => caller,
+                            None => {
+                                let values = self.stack[start..end].to_vec();
+                                return Result::Value(Exit::Return(values));
+                            },
+                        };
+
+                        self.pc = caller.pc;
+                        let func = frame.base - 1;
+                        let results = match frame.cont {
+                            Continuation::Call(results) => results,
+                            cont => {
+                                let values = self.stack[start..end].to_vec();
+                                self.complete(func, cont, &values);
+                                continue 'frames;
+                            },
+                        };
+
+                        let count = if results == MULTI {
+                            end - start
+                        } else {
+                            results as usize
+                        };
+
+                        let copied = count.min(end - start);
+                        self.stack.copy_within(start..start + copied, func);
+                        for slot in &mut self.stack[func + copied..func + count] {
+                            *slot = Value::from_nil();
+                        }
+
+                        self.top = func + count;
+                        continue 'frames;
+                    },
+                    Instruction::Closure(a, index) => {
+                        let child = proto.protos[index as usize].clone();
+                        let mut captured = Vec::with_capacity(child.captures.len());
+                        for capture in &child.captures {
+                            captured.push(match *capture {
+                                Capture::Cell(c) => cell!(c).clone(),
+                                Capture::Upvalue(u) => upvalues[u as usize].clone(),
+                            });
+                        }
+
+                        let function = Function::Lua(Closure::new(child, captured));
+                        reg!(a) = Value::from_function(ctx.heap().insert(function));
+                        collect!();
+                    },
+                    Instruction::VarArg(a, n) => {
+                        let varargs = &self.frames.last().unwrap().varargs;
+                        let start = base + a as usize;
+                        let count = if n == MULTI {
+                            varargs.len()
+                        } else {
+                            n as usize
+                        };
+
+                        let values: Vec<_> = (0..count)
+                            .map(|i| varargs.get(i).copied().unwrap_or_else(Value::from_nil))
+                            .collect();
+                        self.store(start, &values, MULTI);
+                    },
+
+                    Instruction::Tbc(a, k) => {
+                        // `nil` and `false` are ignored, any other value must
+                        // be closable.
+                        let value = reg!(a);
+                        if value.is_truthy() {
+                            if meta::metamethod(value, b"__close", ctx) == Value::from_nil() {
+                                let name = constants[k as usize].cast_string().unwrap();
+                                let name = unsafe { name.get_unchecked() };
+                                let message = format!(
+                                    "variable '{}' got a non-closable value",
+                                    String::from_utf8_lossy(name)
+                                );
+                                return raise(message, span!(), ctx);
+                            }
+
+                            let frame = self.frames.last_mut().unwrap();
+                            frame.tbc.push((base + a as usize, value));
+                        }
+                    },
+                    Instruction::Close(a) => self.close(base + a as usize, ctx)?,
+
+                    Instruction::ForPrep(a, offset) => {
+                        let at = base + a as usize;
+                        if !self.for_prep(at, span!(), ctx)? {
+                            self.jump(offset);
+                        }
+                    },
+                    Instruction::ForLoop(a, offset) =>
+                        if self.for_loop(base + a as usize, ctx) {
+                            self.jump(offset);
+                        },
+                    Instruction::TForCall(a, n) => {
+                        let at = base + a as usize;
+                        self.stack.copy_within(at..at + 3, at + 4);
+                        call!(self.call(at + 4, 2, n, (&proto, self.pc - 1), ctx));
+                    },
+                    Instruction::TForLoop(a, offset) => {
+                        let control = reg!(a + 4);
+                        if control != Value::from_nil() {
+                            reg!(a + 2) = control;
+                            self.jump(offset);
+                        }
+                    },
+                }
+            }
+        }
+    }
+
+    fn jump(&mut self, offset: i32) {
+        self.pc = (self.pc as isize + offset as isize) as usize;
+    }
+
+    // Prepares a numeric loop whose initial value, limit and step are at
+    // `at`. Integer loops keep the counter, the number of further iterations
+    // and the step there, float loops the counter, limit and step. Returns
+    // whether the loop runs at all.
+    fn for_prep(&mut self, at: usize, span: Span, ctx: &Ctx) -> Result<bool> {
+        let (init, limit, step) = (self.stack[at], self.stack[at + 1], self.stack[at + 2]);
+
+        if init.is_int() && step.is_int() {
+            let (init, step) = (init.cast_int(), step.cast_int());
+            if step == 0 {
+                return raise(String::from("'for' step is zero"), span, ctx);
+            }
+
+            let limit = match for_limit(init, limit, step) {
+                Some(Some(limit)) => limit,
+                Some(None) => return Result::Value(false),
+                None => return raise(String::from("'for' limit must be a number"), span, ctx),
+            };
+
+            // The number of further iterations is computed upfront so that
+            // the counter never overflows.
+            let count = if step > 0 {
+                (limit as u64).wrapping_sub(init as u64) / step as u64
+            } else {
+                (init as u64).wrapping_sub(limit as u64) / (-(step + 1) as u64 + 1)
+            };
+
+            self.stack[at + 1] = ctx.int(count as i64);
+            self.stack[at + 3] = self.stack[at];
+            return Result::Value(true);
+        }
+
+        let limit = for_float(limit, "limit", span, ctx)?;
+        let step = for_float(step, "step", span, ctx)?;
+        let init = for_float(init, "initial value", span, ctx)?;
+        if step == 0.0 {
+            return raise(String::from("'for' step is zero"), span, ctx);
+        }
+
+        let value = Value::from_float(init);
+        self.stack[at] = value;
+        self.stack[at + 1] = Value::from_float(limit);
+        self.stack[at + 2] = Value::from_float(step);
+        self.stack[at + 3] = value;
+        Result::Value(if step > 0.0 {
+            init <= limit
+        } else {
+            init >= limit
+        })
+    }
+
+    // Advances a numeric loop, returning whether it continues. A loop whose
+    // state was overwritten, which only malformed precompiled code does, ends.
+    fn for_loop(&mut self, at: usize, ctx: &Ctx) -> bool {
+        let (value, count, step) = (self.stack[at], self.stack[at + 1], self.stack[at + 2]);
+
+        if step.is_int() && count.is_int() && value.is_int() {
+            let count = count.cast_int() as u64;
+            if count == 0 {
+                return false;
+            }
+
+            let value = ctx.int(self.stack[at].cast_int().wrapping_add(step.cast_int()));
+            self.stack[at] = value;
+            self.stack[at + 1] = ctx.int((count - 1) as i64);
+            self.stack[at + 3] = value;
+            return true;
+        }
+
+        if !(step.is_number() && count.is_number() && value.is_number()) {
+            return false;
+        }
+
+        let step = step.convert_float();
+        let limit = count.convert_float();
+        let i = value.convert_float() + step;
+        if if step > 0.0 { i <= limit } else { i >= limit } {
+            let value = Value::from_float(i);
+            self.stack[at] = value;
+            self.stack[at + 3] = value;
+            return true;
+        }
+
+        false
+    }
+}
+
+// Converts a control value of a float loop.
+fn for_float(value: Value, what: &str, span: Span, ctx: &Ctx) -> Result<f64> {
+    if value.is_number() {
+        Result::Value(value.convert_float())
+    } else {
+        raise(format!("'for' {} must be a number", what), span, ctx)
+    }
+}
+
+// Converts the limit of an integer loop to an integer, clipping floats to the
+// range of integers. Returns `None` if the limit is not a number and
+// `Some(None)` if the loop must not run at all.
+fn for_limit(init: i64, limit: Value, step: i64) -> Option<Option<i64>> {
+    let limit = match limit.to_int() {
+        Some(limit) => limit,
+        None if !limit.is_number() => return None,
+        None => {
+            let x = limit.convert_float();
+            let x = if step > 0 { x.floor() } else { x.ceil() };
+            match Value::from_float(x).to_int() {
+                Some(limit) => limit,
+                None if x.is_nan() => return Some(None),
+                None if x > 0.0 && step < 0 => return Some(None),
+                None if x > 0.0 => i64::MAX,
+                None if step > 0 => return Some(None),
+                None => i64::MIN,
+            }
+        },
+    };
+
+    let skip = if step > 0 { init > limit } else { init < limit };
+    Some(if skip { None } else { Some(limit) })
+}
+
+// Creates the error raised when a limit of the call is hit at `span`.
+fn exceeded(limit: Limit, span: Span, ctx: &Ctx) -> Error {
+    let message = limit.to_string();
+    let value = Value::from_string(ctx.intern(message.as_bytes()));
+    let mut error = RuntimeError::new(message, value);
+    error.locate(span);
+    if ctx.limits_uncatchable() {
+        Error::Limit(error)
+    } else {
+        Error::Runtime(error)
+    }
+}
+
+// Calls the `__close` metamethods of the to-be-closed variables of a frame
+// that is unwound in reverse order, passing them the error object if there is
+// an error. An error raised by a metamethod replaces the error.
+fn close_unwound(tbc: Vec<(usize, Value)>, mut error: Option<Error>, ctx: &Ctx) -> Option<Error> {
+    let mut values: Vec<Value> = tbc.into_iter().map(|(_, value)| value).collect();
+    while let Some(value) = values.pop() {
+        let object = match &error {
+            Some(Error::Runtime(error)) => error.value(),
+            _ => Value::from_nil(),
+        };
+
+        // The error object and the variables left to close are only
+        // referred to here.
+        let handler = meta::metamethod(value, b"__close", ctx);
+        values.push(object);
+        let result = ctx.holding(&values, || eval::call(handler, vec![value, object], ctx));
+        values.pop();
+        if let Result::Error(new) = result {
+            error = Some(new);
+        }
+    }
+
+    error
+}
+
+impl Trace for Thread {
+    fn visit(&self, visitor: &mut Visitor) {
+        for value in &self.stack {
+            value.visit(visitor);
+        }
+
+        for cell in self.cells.iter().flatten() {
+            cell.visit(visitor);
+        }
+
+        for frame in &self.frames {
+            Value::from_function(frame.closure).visit(visitor);
+            for value in frame
+                .varargs
+                .iter()
+                .chain(frame.tbc.iter().map(|(_, value)| value))
+            {
+                value.visit(visitor);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{
+        super::value::Value,
+        bytecode::Instruction,
+        tests::{compile, eval},
+    };
+
+    #[test]
+    fn eval_deep_recursion() {
+        let source = "
+            local function count(n)
+                if n == 0 then return 0 end
+                return 1 + count(n - 1)
+            end
+            return count(100000)
+        ";
+
+        eval(source, |_, _, value| {
+            assert!(value == Value::from_int(100000));
+        });
+    }
+
+    #[test]
+    fn eval_tail_calls() {
+        let source = "
+            local is_even, is_odd
+            function is_even(n) if n == 0 then return true end return is_odd(n - 1) end
+            function is_odd(n) if n == 0 then return false end return is_even(n - 1) end
+            local function sum(n, acc) if n == 0 then return acc end return sum(n - 1, acc + n) end
+            local t = setmetatable({}, { __call = function(self, n) return n end })
+            local function f(...) return select('#', ...) end
+            local function g() return f(1, nil, 3) end
+            return is_even(1000001) == false and sum(1000000, 0) == 500000500000
+                and (function() return t(7) end)() == 7 and g() == 3
+        ";
+
+        eval(source, |_, _, value| {
+            assert!(value == Value::from_bool(true));
+        });
+
+        let proto = compile("local function f() return f() end return (f)(1)");
+        assert!(proto
+            .code
+            .windows(2)
+            .any(|pair| matches!(pair, [Instruction::TailCall(..), Instruction::Return(..)])));
+        assert!(!proto.protos[0]
+            .code
+            .iter()
+            .any(|i| matches!(i, Instruction::Call(..))));
+
+        // Calls in the scope of a to-be-closed variable return to close it.
+        let proto = compile("local x <close> = nil return f()");
+        assert!(!proto
+            .code
+            .iter()
+            .any(|i| matches!(i, Instruction::TailCall(..))));
+    }
+
+    #[test]
+    fn report_stack_overflow() {
+        let locals: Vec<String> = (0..200).map(|i| format!("x{}", i)).collect();
+        let wide = format!(
+            "local function f(n) local {} depth = n return 1 + f(n + 1) end
+            local ok, err = pcall(f, 1)
+            return not ok and err == 'stack overflow' and depth < 5000",
+            locals.join(", ")
+        );
+
+        let cases = &[
+            "local function f() return 1 + f() end
+            local ok, err = pcall(f)
+            return not ok and err == 'stack overflow'",
+            "local t = setmetatable({}, { __index = function(t, k) return t[k] end })
+            local ok, err = pcall(function() return t.x end)
+            return not ok and err == 'stack overflow'",
+            // The stack can be used again once the error is caught.
+            "local function f() return 1 + f() end
+            pcall(f)
+            local function count(n) if n == 0 then return 0 end return 1 + count(n - 1) end
+            return count(1000) == 1000",
+            // Frames are limited by the registers they take up.
+            &wide,
+            // Native functions calling Lua functions nest loops.
+            "local t = setmetatable({}, { __tostring = function(t) return tostring(t) end })
+            local ok, err = pcall(tostring, t)
+            return not ok and err == 'stack overflow'",
+            "local function f() return coroutine.wrap(f)() end
+            local ok, err = pcall(f)
+            return not ok and err == 'stack overflow'",
+        ];
+
+        for source in cases {
+            eval(source, |_, _, value| {
+                assert!(value == Value::from_bool(true), "{}", source)
+            });
+        }
+    }
+}
@@ -0,0 +1,178 @@
+//! Limits on how long a call may run.
+//!
+//! The dispatch loops count the instructions they run and check the limits of
+//! the call every [`CHECK_INTERVAL`] instructions, or sooner when the
+//! instruction budget runs out. Time spent in native functions is not
+//! counted, so a deadline or interruption is only noticed once the Lua code
+//! continues.
+
+use std::{
+    fmt::{self, Display},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+/// The number of instructions run between checks of the deadline and the
+/// interrupt handle.
+pub const CHECK_INTERVAL: u64 = 1024;
+
+/// The limits of a call made by
+/// [`VM::eval_with_limits`](super::VM::eval_with_limits) or
+/// [`VM::call_async_with_limits`](super::VM::call_async_with_limits).
+///
+/// A call hitting a limit raises a runtime error, which `pcall` can catch
+/// unless the limits are uncatchable. The limit stays hit, so the code
+/// catching the error fails again after at most [`CHECK_INTERVAL`]
+/// instructions.
+#[derive(Clone, Default)]
+pub struct Limits {
+    /// The number of instructions the call may run.
+    pub instructions: Option<u64>,
+    /// The time by which the call must be done.
+    pub deadline: Option<Instant>,
+    /// A handle that stops the call once triggered.
+    pub interrupt: Option<InterruptHandle>,
+    /// Whether hitting a limit raises an
+    /// [`Error::Limit`](super::super::Error::Limit) instead of a runtime
+    /// error, which nothing in the script can catch.
+    pub uncatchable: bool,
+}
+
+/// The limit a call hit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Limit {
+    Instructions,
+    Deadline,
+    Interrupted,
+}
+
+impl Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Instructions => f.write_str("instruction limit exceeded"),
+            Self::Deadline => f.write_str("deadline exceeded"),
+            Self::Interrupted => f.write_str("interrupted"),
+        }
+    }
+}
+
+/// Stops calls from another thread.
+///
+/// Clones of a handle share its state, and a triggered handle stops every
+/// call it is passed to until it is reset.
+#[derive(Clone, Default)]
+pub struct InterruptHandle {
+    interrupted: Arc<AtomicBool>,
+}
+
+impl InterruptHandle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stops the calls using the handle.
+    pub fn interrupt(&self) {
+        self.interrupted.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_interrupted(&self) -> bool {
+        self.interrupted.load(Ordering::Relaxed)
+    }
+
+    /// Lets calls using the handle run again.
+    pub fn reset(&self) {
+        self.interrupted.store(false, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{
+        super::{
+            super::{gc::Heap, value::Value, Error},
+            VM,
+        },
+        InterruptHandle,
+        Limits,
+    };
+    use crate::parser::{machinery::cstree::NodeCache, parse, syntax::Root};
+
+    fn eval_limited(source: &str, limits: Limits) -> Result<Value, Error> {
+        let mut cache = NodeCache::new();
+        let (tree, reports) = parse(&mut cache, source);
+        assert!(reports.is_empty());
+
+        let mut vm = VM::new(Heap::new());
+        let root = Root::cast(&tree).unwrap();
+        vm.eval_with_limits(&root, cache.interner(), limits)
+    }
+
+    #[test]
+    fn report_limits() {
+        let budget = Limits {
+            instructions: Some(10_000),
+            ..Limits::default()
+        };
+
+        let source = "local s = 0 for i = 1, 100 do s = s + i end return s";
+        let value = eval_limited(source, budget.clone()).ok().unwrap();
+        assert!(value == Value::from_int(5050));
+
+        let source = "while true do end";
+        match eval_limited(source, budget.clone()) {
+            Err(Error::Runtime(error)) => {
+                assert_eq!(error.message(), "instruction limit exceeded");
+                assert!(error.span().is_some());
+            },
+            _ => panic!("the limit was not hit"),
+        }
+
+        // The code catching the error has some instructions left to run.
+        let source = "local ok, err = pcall(function() while true do end end)
+            return not ok and err == 'instruction limit exceeded'";
+        let value = eval_limited(source, budget.clone()).ok().unwrap();
+        assert!(value == Value::from_bool(true));
+
+        let uncatchable = Limits {
+            uncatchable: true,
+            ..budget
+        };
+        let source = "while true do pcall(function() while true do end end) end";
+        match eval_limited(source, uncatchable) {
+            Err(Error::Limit(error)) => assert_eq!(error.message(), "instruction limit exceeded"),
+            _ => panic!("the limit was not hit"),
+        }
+
+        let deadline = Limits {
+            deadline: Some(Instant::now() + Duration::from_millis(20)),
+            ..Limits::default()
+        };
+        match eval_limited("while true do end", deadline) {
+            Err(Error::Runtime(error)) => assert_eq!(error.message(), "deadline exceeded"),
+            _ => panic!("the limit was not hit"),
+        }
+
+        let handle = InterruptHandle::new();
+        let trigger = handle.clone();
+        let thread = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            trigger.interrupt();
+        });
+
+        let interrupt = Limits {
+            interrupt: Some(handle),
+            ..Limits::default()
+        };
+        match eval_limited("local function f() return f() end f()", interrupt) {
+            Err(Error::Runtime(error)) => assert_eq!(error.message(), "interrupted"),
+            _ => panic!("the limit was not hit"),
+        }
+
+        thread.join().unwrap();
+    }
+}
@@ -0,0 +1,383 @@
+//! Operations on values that fall back to metamethods.
+//!
+//! Every operation first attempts the primitive operation defined on
+//! [`Value`] and only consults the metatables of its operands when that
+//! operation is not defined for them, mirroring the reference implementation.
+//!
+//! Operations do not call the metamethods they resolve to: they return a
+//! [`Step`] for the dispatch loop to carry out, which runs Lua handlers in a
+//! frame of its own so that they can yield.
+
+use super::{
+    super::{
+        error::OpError,
+        gc::Handle,
+        value::{InlineCache, Table, Value},
+        Error,
+    },
+    ctx::Ctx,
+    eval::{call, first, raise, OrRaise, Result},
+};
+use crate::parser::machinery::span::Span;
+
+// The maximum length of a chain of `__index`, `__newindex` or `__call`
+// handlers before it is assumed to be a loop.
+const MAX_TAG_LOOP: usize = 2000;
+
+/// Binary operators that can be overloaded through metamethods.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Arith {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Pow,
+    IntDiv,
+    BitAnd,
+    BitOr,
+    BitXor,
+    Shl,
+    Shr,
+    Concat,
+}
+
+impl Arith {
+    fn event(self) -> &'static [u8] {
+        match self {
+            Self::Add => b"__add",
+            Self::Sub => b"__sub",
+            Self::Mul => b"__mul",
+            Self::Div => b"__div",
+            Self::Mod => b"__mod",
+            Self::Pow => b"__pow",
+            Self::IntDiv => b"__idiv",
+            Self::BitAnd => b"__band",
+            Self::BitOr => b"__bor",
+            Self::BitXor => b"__bxor",
+            Self::Shl => b"__shl",
+            Self::Shr => b"__shr",
+            Self::Concat => b"__concat",
+        }
+    }
+
+    pub fn apply(self, a: Value, b: Value, ctx: &Ctx) -> std::result::Result<Value, OpError> {
+        match self {
+            Self::Add => a.op_add(b, ctx),
+            Self::Sub => a.op_sub(b, ctx),
+            Self::Mul => a.op_mul(b, ctx),
+            Self::Div => a.op_div(b),
+            Self::Mod => a.op_mod(b, ctx),
+            Self::Pow => a.op_exp(b),
+            Self::IntDiv => a.op_int_div(b, ctx),
+            Self::BitAnd => a.op_bit_and(b, ctx),
+            Self::BitOr => a.op_bit_or(b, ctx),
+            Self::BitXor => a.op_bit_xor(b, ctx),
+            Self::Shl => a.op_lshift(b, ctx),
+            Self::Shr => a.op_rshift(b, ctx),
+            Self::Concat => a.op_concat(b, ctx),
+        }
+    }
+}
+
+/// Unary operators that can be overloaded through metamethods.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Unary {
+    Neg,
+    BitNot,
+    Len,
+}
+
+impl Unary {
+    fn event(self) -> &'static [u8] {
+        match self {
+            Self::Neg => b"__unm",
+            Self::BitNot => b"__bnot",
+            Self::Len => b"__len",
+        }
+    }
+}
+
+/// Returns the metatable of a value. Only tables carry metatables for now.
+pub fn metatable(value: Value) -> Option<Handle<Table>> {
+    value
+        .cast_table()
+        .and_then(|table| unsafe { table.get_unchecked() }.metatable())
+}
+
+/// Looks up a field of a value's metatable without invoking any metamethods.
+pub fn metamethod(value: Value, event: &[u8], ctx: &Ctx) -> Value {
+    match metatable(value) {
+        Some(metatable) => {
+            let key = Value::from_string(ctx.intern(event));
+            unsafe { metatable.get_unchecked() }.get(key)
+        },
+        None => Value::from_nil(),
+    }
+}
+
+fn is_nil(value: Value) -> bool {
+    value == Value::from_nil()
+}
+
+/// The outcome of an operation that may be overloaded.
+pub enum Step {
+    /// The operation produced a value without calling a metamethod.
+    Done(Value),
+    /// The operation is completed by calling a handler with some arguments
+    /// for an event. Its first result is the value of the operation, for
+    /// comparisons whether that result is truthy.
+    Call(Value, Vec<Value>, &'static [u8]),
+}
+
+/// Calls a metamethod, recording it in the traceback of any error it raises.
+pub fn call_metamethod(
+    handler: Value,
+    args: Vec<Value>,
+    event: &[u8],
+    span: Span,
+    ctx: &Ctx,
+) -> Result<Value> {
+    match call(handler, args, ctx) {
+        Result::Value(values) => Result::Value(first(values)),
+        Result::Error(Error::Runtime(mut error)) => {
+            let name = String::from_utf8_lossy(event).into_owned();
+            error.unwind(Some(name), span);
+            Result::Error(Error::Runtime(error))
+        },
+        Result::Error(error) => Result::Error(error),
+    }
+}
+
+// Finds the handler for a binary event, preferring the left operand.
+fn binary_handler(a: Value, b: Value, event: &'static [u8], ctx: &Ctx) -> Option<Value> {
+    let handler = metamethod(a, event, ctx);
+    if !is_nil(handler) {
+        return Some(handler);
+    }
+
+    let handler = metamethod(b, event, ctx);
+    if !is_nil(handler) {
+        return Some(handler);
+    }
+
+    None
+}
+
+/// Evaluates `target[key]`, following `__index` handlers.
+pub fn index(target: Value, key: Value, span: Span, ctx: &Ctx) -> Result<Step> {
+    index_cached(target, key, None, span, ctx)
+}
+
+/// Evaluates `target[key]` for a constant key, looking it up in tables through
+/// the inline cache of the access.
+pub fn index_cached(
+    target: Value,
+    key: Value,
+    cache: Option<&InlineCache>,
+    span: Span,
+    ctx: &Ctx,
+) -> Result<Step> {
+    let mut target = target;
+
+    for _ in 0..MAX_TAG_LOOP {
+        // Present keys never consult the metatable.
+        let table = target.cast_table();
+        if let Some(table) = table {
+            let value = raw_get(unsafe { table.get_unchecked() }, key, cache);
+            if !is_nil(value) {
+                return Result::Value(Step::Done(value));
+            }
+        }
+
+        let handler = metamethod(target, b"__index", ctx);
+        if is_nil(handler) {
+            return match table {
+                Some(_) => Result::Value(Step::Done(Value::from_nil())),
+                None => Result::Value(Step::Done(target.op_property(key).or_raise(span, ctx)?)),
+            };
+        }
+
+        if handler.cast_function().is_some() {
+            return Result::Value(Step::Call(handler, vec![target, key], b"__index"));
+        }
+
+        target = handler;
+    }
+
+    raise(
+        String::from("'__index' chain too long; possible loop"),
+        span,
+        ctx,
+    )
+}
+
+/// Performs `target[key] = value`, following `__newindex` handlers. Steps
+/// that are done produce nil.
+pub fn new_index(target: Value, key: Value, value: Value, span: Span, ctx: &Ctx) -> Result<Step> {
+    new_index_cached(target, key, value, None, span, ctx)
+}
+
+/// Performs `target[key] = value` for a constant key, storing it in tables
+/// through the inline cache of the access.
+pub fn new_index_cached(
+    target: Value,
+    key: Value,
+    value: Value,
+    cache: Option<&InlineCache>,
+    span: Span,
+    ctx: &Ctx,
+) -> Result<Step> {
+    let done = Step::Done(Value::from_nil());
+    let mut target = target;
+
+    for _ in 0..MAX_TAG_LOOP {
+        let handler = metamethod(target, b"__newindex", ctx);
+
+        if let Some(table) = target.cast_table() {
+            let present = !is_nil(raw_get(unsafe { table.get_unchecked() }, key, cache));
+            if present || is_nil(handler) {
+                match (key.cast_string(), cache) {
+                    (Some(name), Some(_)) => {
+                        let table = unsafe { table.get_unchecked_mut() };
+                        table.set_field(name, value, cache);
+                    },
+                    _ => target.op_set_property(key, value).or_raise(span, ctx)?,
+                }
+
+                return Result::Value(done);
+            }
+        } else if is_nil(handler) {
+            target.op_set_property(key, value).or_raise(span, ctx)?;
+            return Result::Value(done);
+        }
+
+        if handler.cast_function().is_some() {
+            let args = vec![target, key, value];
+            return Result::Value(Step::Call(handler, args, b"__newindex"));
+        }
+
+        target = handler;
+    }
+
+    raise(
+        String::from("'__newindex' chain too long; possible loop"),
+        span,
+        ctx,
+    )
+}
+
+// Reads a key of a table without consulting its metatable.
+fn raw_get(table: &Table, key: Value, cache: Option<&InlineCache>) -> Value {
+    match (key.cast_string(), cache) {
+        (Some(name), Some(_)) => table.get_field(name, cache),
+        _ => table.get(key),
+    }
+}
+
+/// Resolves the function that calling `function` invokes, prepending the
+/// called objects to the arguments whenever a `__call` handler is used.
+pub fn callable(
+    function: Value,
+    args: &mut Vec<Value>,
+    ctx: &Ctx,
+) -> std::result::Result<Value, OpError> {
+    let mut target = function;
+
+    for _ in 0..MAX_TAG_LOOP {
+        if target.cast_function().is_some() {
+            return Ok(target);
+        }
+
+        let handler = metamethod(target, b"__call", ctx);
+        if is_nil(handler) {
+            break;
+        }
+
+        args.insert(0, target);
+        target = handler;
+    }
+
+    Err(OpError::Call(function.type_name()))
+}
+
+/// Evaluates a binary arithmetic, bitwise or concatenation operator.
+pub fn arith(op: Arith, a: Value, b: Value, span: Span, ctx: &Ctx) -> Result<Step> {
+    let error = match op.apply(a, b, ctx) {
+        Ok(value) => return Result::Value(Step::Done(value)),
+        // Both operands were numbers, there is nothing left to overload.
+        Err(error @ (OpError::DivideByZero | OpError::ModuloByZero)) => {
+            return raise(error.to_string(), span, ctx);
+        },
+        Err(error) => error,
+    };
+
+    match binary_handler(a, b, op.event(), ctx) {
+        Some(handler) => Result::Value(Step::Call(handler, vec![a, b], op.event())),
+        None => raise(error.to_string(), span, ctx),
+    }
+}
+
+/// Evaluates a unary operator other than `not`.
+pub fn unary(op: Unary, a: Value, span: Span, ctx: &Ctx) -> Result<Step> {
+    let handler = metamethod(a, op.event(), ctx);
+
+    // `__len` takes precedence over the primitive length of tables.
+    if op == Unary::Len && !is_nil(handler) {
+        return Result::Value(Step::Call(handler, vec![a, a], op.event()));
+    }
+
+    let value = match op {
+        Unary::Neg => a.op_neg(ctx),
+        Unary::BitNot => a.op_bit_not(ctx),
+        Unary::Len => a.op_len(),
+    };
+
+    match value {
+        Ok(value) => Result::Value(Step::Done(value)),
+        Err(_) if !is_nil(handler) => Result::Value(Step::Call(handler, vec![a, a], op.event())),
+        Err(error) => raise(error.to_string(), span, ctx),
+    }
+}
+
+/// Evaluates `a == b`, consulting `__eq` only for two distinct tables.
+pub fn eq(a: Value, b: Value, ctx: &Ctx) -> Step {
+    let equal = a.op_eq(b);
+    if equal.cast_bool_unchecked() || a.cast_table().is_none() || b.cast_table().is_none() {
+        return Step::Done(equal);
+    }
+
+    match binary_handler(a, b, b"__eq", ctx) {
+        Some(handler) => Step::Call(handler, vec![a, b], b"__eq"),
+        None => Step::Done(equal),
+    }
+}
+
+/// Evaluates `a < b`.
+pub fn lt(a: Value, b: Value, span: Span, ctx: &Ctx) -> Result<Step> {
+    compare(a.op_lt(b), a, b, b"__lt", span, ctx)
+}
+
+/// Evaluates `a <= b`.
+pub fn le(a: Value, b: Value, span: Span, ctx: &Ctx) -> Result<Step> {
+    compare(a.op_leq(b), a, b, b"__le", span, ctx)
+}
+
+fn compare(
+    value: std::result::Result<Value, OpError>,
+    a: Value,
+    b: Value,
+    event: &'static [u8],
+    span: Span,
+    ctx: &Ctx,
+) -> Result<Step> {
+    let error = match value {
+        Ok(value) => return Result::Value(Step::Done(value)),
+        Err(error) => error,
+    };
+
+    match binary_handler(a, b, event, ctx) {
+        Some(handler) => Result::Value(Step::Call(handler, vec![a, b], event)),
+        None => raise(error.to_string(), span, ctx),
+    }
+}
@@ -1,49 +1,1667 @@
+pub mod bytecode;
+pub mod compiler;
 pub mod ctx;
+pub mod debug;
+pub mod dump;
 pub mod eval;
+mod fold;
+pub mod interp;
+pub mod limits;
+pub mod meta;
+pub mod task;
 
-use std::collections::hash_map::RandomState;
+use std::{collections::hash_map::RandomState, future::Future, mem, rc::Rc};
 
 use ctx::Ctx;
-use eval::Eval;
-use hashbrown::{HashMap, HashSet};
+use debug::{Hook, HookFunction, Source};
+use hashbrown::HashMap;
+use limits::Limits;
+use task::AsyncCall;
 
 use super::{
-    gc::{Handle, Heap},
-    value::{ByteString, Table, Value},
+    gc::{Handle, Heap, Mode, Stats, Trace, Visitor},
+    lib,
+    value::{
+        AsyncFunction,
+        BoxedInt,
+        ByteString,
+        Closure,
+        Function,
+        HostFuture,
+        Native,
+        Table,
+        Value,
+    },
     Error,
 };
-use crate::parser::machinery::cstree::interning::TokenInterner;
+use crate::parser::{machinery::cstree::interning::TokenInterner, syntax::Root};
+
+/// The signature of the function a VM reports warnings to, such as errors
+/// raised by finalizers.
+pub type WarnFunction = Rc<dyn Fn(&str)>;
 
 // TODO:
-//   - vm eval impl
 //   - gc root tracked values in the api
 //   - impl _ENV
-//   - handle multivalue
 pub struct VM {
     global: Table,
-    strings: HashSet<Handle<ByteString>, RandomState>,
+    strings: HashMap<Handle<ByteString>, (), RandomState>,
+    integers: HashMap<i64, Handle<BoxedInt>, RandomState>,
     extern_ref: HashMap<Value, usize, RandomState>,
+    hook: Option<Hook>,
+    source: Option<Rc<Source>>,
+    heap: Heap,
+    warn: Option<WarnFunction>,
 }
 
 impl VM {
     pub fn new(heap: Heap) -> Self {
-        VM {
-            global: Table::new(heap),
-            strings: HashSet::with_hasher(RandomState::new()),
+        let mut vm = VM {
+            global: Table::new(heap.clone()),
+            strings: HashMap::with_hasher(RandomState::new()),
+            integers: HashMap::with_hasher(RandomState::new()),
             extern_ref: HashMap::with_hasher(RandomState::new()),
-        }
+            hook: None,
+            source: None,
+            heap: heap.clone(),
+            warn: None,
+        };
+
+        lib::open(&mut vm.global, &heap, &mut vm.strings, &mut vm.integers);
+        vm
+    }
+
+    /// Compiles and runs a chunk, returning the first value it returns.
+    pub fn eval(&mut self, root: &Root, interner: &TokenInterner) -> Result<Value, Error> {
+        self.eval_with_limits(root, interner, Limits::default())
     }
 
-    pub fn eval<T>(
+    /// Compiles and runs a chunk within limits, returning the first value it
+    /// returns.
+    pub fn eval_with_limits(
         &mut self,
-        item: &T,
-        heap: &Heap,
+        root: &Root,
+        interner: &TokenInterner,
+        limits: Limits,
+    ) -> Result<Value, Error> {
+        let mut ctx = Ctx::new(
+            &mut self.global,
+            &self.heap,
+            &mut self.strings,
+            &mut self.integers,
+        );
+        ctx.set_limits(limits);
+        ctx.set_source(self.source.clone());
+        ctx.set_warn(self.warn.clone());
+
+        let proto = compiler::compile(root, interner, &ctx)?;
+        let main = self
+            .heap
+            .insert(Function::Lua(Closure::new(proto, Vec::new())));
+
+        // Scripts may set hooks of their own with `debug.sethook`.
+        ctx.set_hook(self.hook.take());
+        let values: Result<_, _> = interp::execute(main, Vec::new(), &ctx).into();
+        self.hook = ctx.hook();
+        Ok(eval::first(values?))
+    }
+
+    /// Sets or removes the hook called on the events of the code run by the
+    /// VM, see [`debug`].
+    pub fn set_hook(&mut self, hook: Option<Hook>) {
+        self.hook = hook;
+    }
+
+    /// Registers the source of the code run by the VM, which hooks learn the
+    /// lines of the code being run from. Chunks evaluated afterwards are
+    /// assumed to be parsed from this source.
+    pub fn set_source(&mut self, source: Option<Source>) {
+        self.source = source.map(Rc::new);
+    }
+
+    /// Compiles a chunk and runs it as an async call, see
+    /// [`VM::call_async`]. Resolves to the first value it returns.
+    pub fn eval_async<'a>(
+        &'a mut self,
+        root: &Root,
+        interner: &TokenInterner,
+    ) -> impl Future<Output = Result<Value, Error>> + 'a {
+        self.eval_async_with_limits(root, interner, Limits::default())
+    }
+
+    /// Compiles a chunk and runs it as an async call within limits, see
+    /// [`VM::call_async_with_limits`].
+    pub fn eval_async_with_limits<'a>(
+        &'a mut self,
+        root: &Root,
         interner: &TokenInterner,
-    ) -> Result<Value, Error>
+        limits: Limits,
+    ) -> impl Future<Output = Result<Value, Error>> + 'a {
+        let ctx = Ctx::new(
+            &mut self.global,
+            &self.heap,
+            &mut self.strings,
+            &mut self.integers,
+        );
+
+        let heap = &self.heap;
+        let main = compiler::compile(root, interner, &ctx)
+            .map(|proto| heap.insert(Function::Lua(Closure::new(proto, Vec::new()))));
+
+        async move {
+            let main = Value::from_function(main?);
+            let values = self
+                .call_async_with_limits(main, Vec::new(), limits)
+                .await?;
+            Ok(eval::first(values))
+        }
+    }
+
+    /// Calls a function as an async call, which suspends whenever the code it
+    /// runs calls an async host function until the future of that function is
+    /// ready. Async host functions cannot be called from coroutines or from
+    /// Lua code called by native functions, nor outside of async calls.
+    ///
+    /// Like [`VM::eval`], the call runs with the source, hook and warn
+    /// function of the VM.
+    pub fn call_async(&mut self, function: Value, args: Vec<Value>) -> AsyncCall {
+        self.call_async_with_limits(function, args, Limits::default())
+    }
+
+    /// Calls a function as an async call within limits. Instructions count
+    /// against the limits across polls, time spent waiting on the futures of
+    /// async host functions counts towards the deadline only.
+    pub fn call_async_with_limits(
+        &mut self,
+        function: Value,
+        args: Vec<Value>,
+        limits: Limits,
+    ) -> AsyncCall {
+        AsyncCall::new(self, function, args, limits)
+    }
+
+    /// Installs an async host function as a global.
+    pub fn set_async<F>(&mut self, name: &'static str, function: F)
+    where
+        F: Fn(&Ctx, Vec<Value>) -> HostFuture + 'static,
+    {
+        let key = ctx::intern(&mut self.strings, &self.heap, name.as_bytes());
+        let function: AsyncFunction = Rc::new(function);
+        let function = self
+            .heap
+            .insert(Function::Native(Native::new_async(name, function)));
+        self.global
+            .insert(Value::from_string(key), Value::from_function(function));
+    }
+
+    /// Sets the function warnings are reported to. Without one, as when the
+    /// VM is created, warnings are dropped.
+    pub fn set_warn(&mut self, warn: Option<WarnFunction>) {
+        self.warn = warn;
+    }
+
+    /// Runs a full collection of the heap, then the `__gc` metamethods of
+    /// the objects it found unreachable. Errors raised by metamethods are
+    /// reported as warnings.
+    pub fn collect(&mut self) {
+        self.run_collection(None);
+    }
+
+    /// Switches the heap to another mode, running a full collection like
+    /// [`VM::collect`], see [`Heap::set_mode`].
+    pub fn set_mode(&mut self, mode: Mode) {
+        self.run_collection(Some(mode));
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.heap.mode()
+    }
+
+    /// The allocation statistics of the heap.
+    pub fn stats(&self) -> Stats {
+        self.heap.heuristics().stats()
+    }
+
+    // Runs a full collection, switching modes if given one, then the
+    // finalizers.
+    fn run_collection(&mut self, mode: Option<Mode>) {
+        // Strings and boxed integers are unique but not roots, those the
+        // collector destroys are forgotten.
+        let mut strings = mem::take(&mut self.strings);
+        let mut integers = mem::take(&mut self.integers);
+        let heap = self.heap.clone();
+        let trace = |visitor: &mut Visitor| self.visit(visitor);
+        let finalize = |handle| ctx::forget(&mut strings, &mut integers, handle);
+        match mode {
+            Some(mode) => heap.set_mode(mode, trace, finalize),
+            None => heap.collect(trace, finalize),
+        }
+
+        self.strings = strings;
+        self.integers = integers;
+        self.run_finalizers();
+    }
+
+    // Calls the `__gc` metamethods of the objects queued for finalization.
+    fn run_finalizers(&mut self) {
+        let heap = self.heap.clone();
+        let mut ctx = Ctx::new(
+            &mut self.global,
+            &heap,
+            &mut self.strings,
+            &mut self.integers,
+        );
+
+        ctx.set_warn(self.warn.clone());
+        ctx.set_hook(self.hook.take());
+        ctx.run_finalizers();
+        self.hook = ctx.hook();
+    }
+}
+
+// The roots of the VM when no code runs.
+impl Trace for VM {
+    fn visit(&self, visitor: &mut Visitor) {
+        self.global.visit(visitor);
+
+        for value in self.extern_ref.keys() {
+            value.visit(visitor);
+        }
+
+        if let Some(Hook {
+            function: HookFunction::Lua(function),
+            ..
+        }) = &self.hook
+        {
+            function.visit(visitor);
+        }
+    }
+}
+
+// Like closing a Lua state, dropping the VM runs the finalizers of every
+// object marked for finalization.
+impl Drop for VM {
+    fn drop(&mut self) {
+        self.heap.finalize_all();
+        self.run_finalizers();
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::{
+        super::{
+            gc::{Generational, Heap, Mode},
+            value::{Function, Upvalue, Value},
+            Error,
+            RuntimeError,
+        },
+        bytecode::{Capture, Instruction, Proto},
+        compiler,
+        ctx::Ctx,
+        meta::Arith,
+        Limits,
+        VM,
+    };
+    use crate::parser::{
+        machinery::{cstree::NodeCache, source_map::SourceMap},
+        parse,
+        syntax::Root,
+    };
+
+    pub(crate) fn eval<F>(source: &str, check: F)
     where
-        T: Eval,
+        F: FnOnce(&mut VM, &Heap, Value),
     {
-        let ctx = Ctx::new(&mut self.global, heap, interner, &mut self.strings);
-        item.eval(&ctx).into()
+        let mut cache = NodeCache::new();
+        let (tree, reports) = parse(&mut cache, source);
+        assert!(reports.is_empty());
+
+        let heap = Heap::new();
+        let mut vm = VM::new(heap.clone());
+        let root = Root::cast(&tree).unwrap();
+        let value = match vm.eval(&root, cache.interner()) {
+            Ok(value) => value,
+            Err(_) => panic!("evaluation failed"),
+        };
+
+        check(&mut vm, &heap, value);
+    }
+
+    fn string(vm: &mut VM, heap: &Heap, bytes: &[u8]) -> Value {
+        let ctx = Ctx::new(&mut vm.global, heap, &mut vm.strings, &mut vm.integers);
+        Value::from_string(ctx.intern(bytes))
+    }
+
+    #[test]
+    fn eval_literals() {
+        let cases: &[(&str, Value)] = &[
+            ("return nil", Value::from_nil()),
+            ("return true", Value::from_bool(true)),
+            ("return false", Value::from_bool(false)),
+            ("return 581", Value::from_int(581)),
+            ("return 0xFF", Value::from_int(255)),
+            ("return 0.5e5", Value::from_float(50000.0)),
+            ("return 3.", Value::from_float(3.0)),
+            ("return .25", Value::from_float(0.25)),
+            ("return 0x1.9p-3", Value::from_float(0.1953125)),
+            ("return 0x1p4", Value::from_float(16.0)),
+        ];
+
+        for (source, expected) in cases {
+            eval(source, |_, _, value| {
+                assert!(value == *expected, "{}", source)
+            });
+        }
+    }
+
+    #[test]
+    fn eval_strings() {
+        eval(r#"return "a\tb\x41\u{20AC}""#, |vm, heap, value| {
+            assert!(value == string(vm, heap, "a\tbA€".as_bytes()));
+        });
+
+        eval("return [==[\nlong]]string]==]", |vm, heap, value| {
+            assert!(value == string(vm, heap, b"long]]string"));
+        });
+    }
+
+    #[test]
+    fn eval_local_decl() {
+        let cases: &[(&str, Value)] = &[
+            ("local a, b, c = 1, 2 return c", Value::from_nil()),
+            ("local a, b = 1, 2, 3 return b", Value::from_int(2)),
+            (
+                "local x = 1 do local x = 2 end return x",
+                Value::from_int(1),
+            ),
+            ("local x = 1 local x = x + 1 return x", Value::from_int(2)),
+            ("local x <const> = 7 return x", Value::from_int(7)),
+        ];
+
+        for (source, expected) in cases {
+            eval(source, |_, _, value| {
+                assert!(value == *expected, "{}", source)
+            });
+        }
+    }
+
+    #[test]
+    fn eval_assign() {
+        let cases: &[(&str, Value)] = &[
+            ("a, b = 1, 2 a, b = b, a return a", Value::from_int(2)),
+            (
+                "local t = {} t.x = 5 t['y'] = t.x + 1 return t.y",
+                Value::from_int(6),
+            ),
+            (
+                "local t = {} local i = 1 i, t[i] = i + 1, 20 return t[1]",
+                Value::from_int(20),
+            ),
+            (
+                "local t = {10, 20, x = 3} t[2] = nil return t[2]",
+                Value::from_nil(),
+            ),
+            (
+                "local t = {10, 20, x = 3} return t[2] + t.x",
+                Value::from_int(23),
+            ),
+        ];
+
+        for (source, expected) in cases {
+            eval(source, |_, _, value| {
+                assert!(value == *expected, "{}", source)
+            });
+        }
+    }
+
+    // Looks up the cell a closure captured under the given name.
+    fn upvalue(vm: &mut VM, heap: &Heap, function: Value, name: &[u8]) -> Upvalue {
+        let name = string(vm, heap, name);
+        let closure = match unsafe { function.cast_function().unwrap().get_unchecked() } {
+            Function::Lua(closure) => closure,
+            Function::Native(_) => panic!("not a closure"),
+        };
+
+        let index = closure
+            .proto()
+            .upvalues
+            .iter()
+            .position(|key| Value::from_string(*key) == name)
+            .unwrap();
+        closure.upvalues()[index].clone()
+    }
+
+    #[test]
+    fn eval_closures() {
+        let source = "local x = 1 local f = function() return x end x = 2 return f";
+        eval(source, |vm, heap, f| {
+            assert!(upvalue(vm, heap, f, b"x").get() == Value::from_int(2));
+        });
+
+        let source = "local x = 1 local function f() return x end local x = 2 return f";
+        eval(source, |vm, heap, f| {
+            assert!(upvalue(vm, heap, f, b"x").get() == Value::from_int(1));
+        });
+
+        let source = "
+            local n = 0
+            local t = {}
+            function t.inc() n = n + 1 end
+            function t:get() return n end
+            return t
+        ";
+        eval(source, |vm, heap, t| {
+            let inc = t.op_property(string(vm, heap, b"inc")).unwrap();
+            let get = t.op_property(string(vm, heap, b"get")).unwrap();
+            let a = upvalue(vm, heap, inc, b"n");
+            let b = upvalue(vm, heap, get, b"n");
+            assert!(a.ptr_eq(&b));
+
+            match unsafe { get.cast_function().unwrap().get_unchecked() } {
+                Function::Lua(closure) => assert_eq!(closure.proto().params, 1),
+                Function::Native(_) => panic!("not a closure"),
+            }
+        });
+    }
+
+    #[test]
+    fn eval_calls() {
+        let cases: &[(&str, Value)] = &[
+            (
+                "local function add(a, b) return a + b end return add(1, 2)",
+                Value::from_int(3),
+            ),
+            (
+                "local function fib(n) if n < 2 then return n end return fib(n - 1) + fib(n - 2) \
+                 end return fib(10)",
+                Value::from_int(55),
+            ),
+            (
+                "local obj = {n = 10} function obj:get(x) return self.n + x end return obj:get(5)",
+                Value::from_int(15),
+            ),
+            (
+                "local t = {a = {b = {c = 7}}} function t.a.b:get() return self.c end return \
+                 t.a.b:get()",
+                Value::from_int(7),
+            ),
+            (
+                "local function f(a, b) return b end return f(1)",
+                Value::from_nil(),
+            ),
+            (
+                "local function f(a) return a end return f(1, 2, 3)",
+                Value::from_int(1),
+            ),
+            (
+                "local function len(t) return #t end return len{1, 2, 3} + len\"ab\" + len[[abc]]",
+                Value::from_int(8),
+            ),
+            (
+                "local obj = {n = 1} function obj:add(t) return self.n + t[1] end return \
+                 obj:add{4}",
+                Value::from_int(5),
+            ),
+            (
+                "local function curry(a) return function(b) return a .. b end end return \
+                 #curry'x' 'yz'",
+                Value::from_int(3),
+            ),
+        ];
+
+        for (source, expected) in cases {
+            eval(source, |_, _, value| {
+                assert!(value == *expected, "{}", source)
+            });
+        }
+    }
+
+    #[test]
+    fn eval_multiple_results() {
+        let cases: &[(&str, Value)] = &[
+            (
+                "local function f() return 1, 2, 3 end local a, b, c, d = f() return c",
+                Value::from_int(3),
+            ),
+            (
+                "local function f() return 1, 2, 3 end local a, b, c, d = f() return d",
+                Value::from_nil(),
+            ),
+            (
+                "local function f() return 1, 2 end local a, b = f(), 10 return b",
+                Value::from_int(10),
+            ),
+            (
+                "local function f() return 1, 2 end local a, b = (f()) return b",
+                Value::from_nil(),
+            ),
+            (
+                "local function f() return 1, 2 end local function g(a, b, c) return c end return \
+                 g(0, f())",
+                Value::from_int(2),
+            ),
+            (
+                "local function f() return 1, 2 end local function g() return f() end local a, b \
+                 = g() return b",
+                Value::from_int(2),
+            ),
+            (
+                "local function f() return 1, 2 end local t = {f(), f()} return t[3]",
+                Value::from_int(2),
+            ),
+            (
+                "local function f() return 1, 2 end local t = {f(), f(), x = 1} return t[3]",
+                Value::from_nil(),
+            ),
+        ];
+
+        for (source, expected) in cases {
+            eval(source, |_, _, value| {
+                assert!(value == *expected, "{}", source)
+            });
+        }
+    }
+
+    #[test]
+    fn eval_varargs() {
+        let cases: &[(&str, Value)] = &[
+            (
+                "local function f(...) return select('#', ...) end return f(nil, nil)",
+                Value::from_int(2),
+            ),
+            (
+                "local function f(a, ...) return select('#', ...) end return f(1)",
+                Value::from_int(0),
+            ),
+            (
+                "local function f(...) local a, b = ... return b end return f(1, 2)",
+                Value::from_int(2),
+            ),
+            (
+                "local function f(...) local t = {...} return t[3] end return f(1, 2, 3)",
+                Value::from_int(3),
+            ),
+            (
+                "local function f(...) return ... end local a, b = f(1, 2) return b",
+                Value::from_int(2),
+            ),
+            ("return select(2, 10, 20, 30)", Value::from_int(20)),
+            ("return select(-1, 1, 2, 3)", Value::from_int(3)),
+            ("return select(5, 1, 2, 3)", Value::from_nil()),
+            ("return select('#')", Value::from_int(0)),
+        ];
+
+        for (source, expected) in cases {
+            eval(source, |_, _, value| {
+                assert!(value == *expected, "{}", source)
+            });
+        }
+    }
+
+    pub(crate) fn eval_error<F>(source: &str, check: F)
+    where
+        F: FnOnce(&mut VM, &Heap, RuntimeError),
+    {
+        let mut cache = NodeCache::new();
+        let (tree, reports) = parse(&mut cache, source);
+        assert!(reports.is_empty());
+
+        let heap = Heap::new();
+        let mut vm = VM::new(heap.clone());
+        let root = Root::cast(&tree).unwrap();
+        let error = match vm.eval(&root, cache.interner()) {
+            Err(Error::Runtime(error)) => error,
+            _ => panic!("expected a runtime error: {}", source),
+        };
+
+        // Rendering the error must not depend on anything but the source.
+        error.report(&SourceMap::new(source));
+        check(&mut vm, &heap, error);
+    }
+
+    #[test]
+    fn report_runtime_errors() {
+        let cases: &[(&str, &str)] = &[
+            (
+                "return nil + 1",
+                "attempt to perform arithmetic on a nil value",
+            ),
+            (
+                "return 1 - {}",
+                "attempt to perform arithmetic on a table value",
+            ),
+            ("return 1.5 | 1", "number has no integer representation"),
+            (
+                "return 1 & true",
+                "attempt to perform bitwise operation on a boolean value",
+            ),
+            ("return 1 // 0", "attempt to perform 'n//0'"),
+            ("return 1 % 0", "attempt to perform 'n%0'"),
+            ("return {} < 1", "attempt to compare table with number"),
+            ("return {} <= {}", "attempt to compare two table values"),
+            ("return 'a' .. {}", "attempt to concatenate a table value"),
+            ("local x return #x", "attempt to get length of a nil value"),
+            ("local t return t.x", "attempt to index a nil value"),
+            ("local t = 5 t[1] = 2", "attempt to index a number value"),
+            ("local t = {} t[nil] = 2", "index is nil"),
+            ("undefined()", "attempt to call a nil value"),
+            ("local t = {} t:m()", "attempt to call a nil value"),
+            (
+                "return select(0)",
+                "bad argument #1 to 'select' (index out of range)",
+            ),
+            (
+                "return select(math.mininteger, 1)",
+                "bad argument #1 to 'select' (index out of range)",
+            ),
+        ];
+
+        for (source, message) in cases {
+            eval_error(source, |_, _, error| {
+                assert_eq!(error.message(), *message, "{}", source);
+                assert!(error.span().is_some(), "{}", source);
+            });
+        }
+    }
+
+    #[test]
+    fn runtime_error_traceback() {
+        let source = "
+            local function f() return nil + 1 end
+            local function g() return (f()) end
+            return (g())
+        ";
+
+        eval_error(source, |_, _, error| {
+            let names: Vec<_> = error
+                .traceback()
+                .iter()
+                .map(|frame| frame.function.as_deref())
+                .collect();
+
+            assert_eq!(names, [Some("f"), Some("g"), None]);
+        });
+
+        let source = "
+            local function f() return nil + 1 end
+            local function g() return f() end
+            local ok = (g())
+        ";
+
+        eval_error(source, |_, _, error| {
+            let frames: Vec<_> = error
+                .traceback()
+                .iter()
+                .map(|frame| (frame.function.as_deref(), frame.tail_calls))
+                .collect();
+
+            assert_eq!(frames, [(None, true), (None, false)]);
+        });
+
+        eval_error("error({})", |_, _, error| {
+            assert_eq!(error.message(), "(error object is a table value)");
+            assert!(error.traceback()[0].span.is_none());
+        });
+    }
+
+    #[test]
+    fn eval_protected_calls() {
+        let cases: &[(&str, Value)] = &[
+            (
+                "local ok = pcall(function() return nil + 1 end) return ok",
+                Value::from_bool(false),
+            ),
+            (
+                "local ok, e = pcall(error, 42) return e",
+                Value::from_int(42),
+            ),
+            (
+                "local ok, a, b = pcall(function(x) return x, 2 end, 1) return b",
+                Value::from_int(2),
+            ),
+            (
+                "local x = 1 pcall(function() local x = 2 error() end) return x",
+                Value::from_int(1),
+            ),
+            ("return nil and nil + 1", Value::from_nil()),
+            ("return false or 5", Value::from_int(5)),
+            ("return 7 // 2 + -7 // 2", Value::from_int(-1)),
+            ("return -7 % 3", Value::from_int(2)),
+        ];
+
+        for (source, expected) in cases {
+            eval(source, |_, _, value| {
+                assert!(value == *expected, "{}", source)
+            });
+        }
+
+        eval(
+            "local ok, e = pcall(error, 'boom') return e",
+            |vm, heap, value| {
+                assert!(value == string(vm, heap, b"boom"));
+            },
+        );
+
+        eval("return 1 .. 2.0 .. 'x' .. 1e100", |vm, heap, value| {
+            assert!(value == string(vm, heap, b"12.0x1e+100"));
+        });
+    }
+
+    #[test]
+    fn eval_integers() {
+        let cases = &[
+            "return math.maxinteger + 1 == math.mininteger",
+            "return math.mininteger - 1 == math.maxinteger",
+            "return math.maxinteger * 2 == -2",
+            "return -math.mininteger == math.mininteger",
+            "return math.mininteger // -1 == math.mininteger",
+            "return math.mininteger % -1 == 0",
+            "return math.maxinteger < 2^63 and math.maxinteger + 0.0 == 2^63",
+            "return math.maxinteger ~= 2^63 and math.mininteger == -2^63",
+            "return 1 << 63 == math.mininteger and -1 >> 1 == math.maxinteger",
+            "return 1 << 64 == 0 and (1 << 47) + (1 << 47) == 1 << 48",
+            "return 0x7fffffffffffffff == math.maxinteger and 0xffffffffffffffff == -1",
+            "return math.type(9223372036854775808) == 'float'",
+            "return math.type(1 << 62) == 'integer' and math.type(2^62) == 'float'",
+            "return math.ult(1, -1) and not math.ult(-1, 1)",
+            "return math.tointeger(2^62) == 1 << 62 and math.tointeger(2^63) == nil",
+            "return 1 == 1.0 and 2^53 == 2^53 | 0 and 0.5 ~= 0",
+            "local t = {} t[1.0] = 'a' t[1 << 50] = 'b' return t[1] == 'a' and t[1 << 50] == 'b'",
+            "return tostring(math.maxinteger) == '9223372036854775807'",
+            "return math.mininteger .. '' == '-9223372036854775808'",
+        ];
+
+        for source in cases {
+            eval(source, |_, _, value| {
+                assert!(value == Value::from_bool(true), "{}", source)
+            });
+        }
+    }
+
+    #[test]
+    fn eval_control_flow() {
+        let cases: &[(&str, Value)] = &[
+            (
+                "local x = 1 do local x = 2 end return x",
+                Value::from_int(1),
+            ),
+            ("local x = 1 do x = 2 end return x", Value::from_int(2)),
+            (
+                "local i = 0 while true do i = i + 1 if i == 5 then break end end return i",
+                Value::from_int(5),
+            ),
+            (
+                "local i = 0 repeat local j = i i = i + 1 until j >= 3 return i",
+                Value::from_int(4),
+            ),
+            (
+                "local x = 2 if x == 1 then return 1 elseif x == 2 then return 2 else return 3 end",
+                Value::from_int(2),
+            ),
+            ("if nil then return 1 else return 3 end", Value::from_int(3)),
+        ];
+
+        for (source, expected) in cases {
+            eval(source, |_, _, value| {
+                assert!(value == *expected, "{}", source)
+            });
+        }
+    }
+
+    #[test]
+    fn eval_goto() {
+        let cases: &[(&str, Value)] = &[
+            (
+                "local n = 0
+                for i = 1, 10 do
+                    if i % 2 == 0 then goto continue end
+                    local x = i
+                    n = n + x
+                    ::continue::
+                end
+                return n",
+                Value::from_int(25),
+            ),
+            (
+                "local i = 0 ::top:: i = i + 1 if i < 5 then goto top end return i",
+                Value::from_int(5),
+            ),
+            (
+                "for i = 1, 3 do for j = 1, 3 do if i * j == 4 then goto out end end end
+                do return 0 end
+                ::out:: return 1",
+                Value::from_int(1),
+            ),
+            (
+                "y = 'global'
+                local n, seen = 0
+                ::again:: seen = y
+                local y = 'local'
+                n = n + 1
+                if n < 2 then goto again end
+                return seen == 'global'",
+                Value::from_bool(true),
+            ),
+            (
+                "local fs = {}
+                do
+                    local i = 1
+                    ::top::
+                    local x = i
+                    fs[i] = function() return x end
+                    i = i + 1
+                    if i <= 3 then goto top end
+                end
+                return fs[1]() + fs[2]() * 10 + fs[3]() * 100",
+                Value::from_int(321),
+            ),
+            (
+                "local log = ''
+                local mt = { __close = function() log = log .. 'c' end }
+                local i = 0
+                ::top::
+                local x <close> = setmetatable({}, mt)
+                i = i + 1
+                if i < 3 then goto top end
+                return #log",
+                Value::from_int(2),
+            ),
+        ];
+
+        for (source, expected) in cases {
+            eval(source, |_, _, value| {
+                assert!(value == *expected, "{}", source)
+            });
+        }
+    }
+
+    #[test]
+    fn report_goto_errors() {
+        let cases = &[
+            "goto nowhere",
+            "::a:: ::a::",
+            "goto a local x ::a:: x = 1",
+            "::a:: local function f() goto a end",
+        ];
+
+        for source in cases {
+            let mut cache = NodeCache::new();
+            let (_, reports) = parse(&mut cache, source);
+            assert_eq!(reports.len(), 1, "{}", source);
+        }
+    }
+
+    #[test]
+    fn eval_for_loops() {
+        let cases: &[(&str, Value)] = &[
+            (
+                "local n = 0 for i = 1, 10 do n = n + i end return n",
+                Value::from_int(55),
+            ),
+            (
+                "local n = 0 for i = 10, 1, -3 do n = n + i end return n",
+                Value::from_int(22),
+            ),
+            (
+                "local n = 0 for i = 1, 0 do n = n + 1 end return n",
+                Value::from_int(0),
+            ),
+            (
+                "local n = 0 for i = math.maxinteger - 2, math.maxinteger do n = n + 1 end return \
+                 n",
+                Value::from_int(3),
+            ),
+            (
+                "local n = 0 for i = math.mininteger, math.mininteger + 2 do n = n + 1 end return \
+                 n",
+                Value::from_int(3),
+            ),
+            (
+                "local n = 0 for i = math.mininteger, math.maxinteger, math.maxinteger do n = n + \
+                 1 end return n",
+                Value::from_int(3),
+            ),
+            (
+                "local n = 0 for i = 1, 3.5 do n = i end return n",
+                Value::from_int(3),
+            ),
+            (
+                "local n = 0 for i = 1, -math.huge do n = n + 1 end return n",
+                Value::from_int(0),
+            ),
+            (
+                "local n = 0 for i = 0, 1, 0.25 do n = n + 1 end return n",
+                Value::from_int(5),
+            ),
+            (
+                "local x for i = 1.0, 2 do x = i end return x",
+                Value::from_float(2.0),
+            ),
+            (
+                "local n = 0 for i = 1, math.huge do n = i if i == 5 then break end end return n",
+                Value::from_int(5),
+            ),
+            (
+                "local n = 0 for i = 1, 3 do local j = i i = 10 n = n + j end return n",
+                Value::from_int(6),
+            ),
+            (
+                "local fs = {} for i = 1, 3 do fs[i] = function() return i end end return fs[1]() \
+                 + fs[3]()",
+                Value::from_int(4),
+            ),
+            (
+                "local function range(n)
+                    return function(limit, i) if i < limit then return i + 1, i * 2 end end, n, 0
+                end
+                local sum = 0
+                for i, double in range(4) do sum = sum + i + double end
+                return sum",
+                Value::from_int(22),
+            ),
+            (
+                "local function iter(t, i) i = i + 1 if t[i] then return i, t[i] end end
+                local last
+                for i, v in iter, { 5, 6, 7 }, 0 do last = v if i == 2 then break end end
+                return last",
+                Value::from_int(6),
+            ),
+            (
+                "local closed = false
+                local closing = setmetatable({}, { __close = function() closed = true end })
+                for x in function() return 1 end, nil, nil, closing do break end
+                return closed",
+                Value::from_bool(true),
+            ),
+            (
+                "local function f()
+                    for i = 1, 10 do
+                        for j = 1, 10 do if i * j == 6 then return i end end
+                    end
+                end
+                return f()",
+                Value::from_int(1),
+            ),
+        ];
+
+        for (source, expected) in cases {
+            eval(source, |_, _, value| {
+                assert!(value == *expected, "{}", source)
+            });
+        }
+    }
+
+    #[test]
+    fn report_for_loop_errors() {
+        let cases: &[(&str, &str)] = &[
+            ("for i = 1, 10, 0 do end", "'for' step is zero"),
+            ("for i = 1.0, 10, 0 do end", "'for' step is zero"),
+            (
+                "for i = 'a', 10 do end",
+                "'for' initial value must be a number",
+            ),
+            ("for i = 1, {} do end", "'for' limit must be a number"),
+            ("for i = 1, 2, nil do end", "'for' step must be a number"),
+            ("for x in nil do end", "attempt to call a nil value"),
+            (
+                "for x in next, nil, nil, {} do end",
+                "variable '(for state)' got a non-closable value",
+            ),
+            ("for x in function() error('inner') end do end", "inner"),
+        ];
+
+        for (source, message) in cases {
+            eval_error(source, |_, _, error| {
+                assert_eq!(error.message(), *message, "{}", source);
+            });
+        }
+    }
+
+    #[test]
+    fn eval_metatables() {
+        let cases: &[(&str, Value)] = &[
+            (
+                "local Point = {} Point.__index = Point
+                function Point.new(x) return setmetatable({ x = x }, Point) end
+                function Point:get() return self.x end
+                return Point.new(3):get()",
+                Value::from_int(3),
+            ),
+            (
+                "local t = setmetatable({}, { __index = function(t, k) return k * 2 end })
+                return t[21]",
+                Value::from_int(42),
+            ),
+            (
+                "local log = {}
+                local t = setmetatable({}, { __newindex = function(t, k, v) rawset(log, k, v) end \
+                 })
+                t.x = 5
+                return rawget(t, 'x') == nil and log.x",
+                Value::from_int(5),
+            ),
+            (
+                "local t = setmetatable({}, { __call = function(self, a, b) return a + b end })
+                return t(1, 2)",
+                Value::from_int(3),
+            ),
+            (
+                "local mt = {}
+                mt.__add = function(a, b) return a.v + b end
+                mt.__unm = function(a) return -a.v end
+                mt.__len = function() return 10 end
+                mt.__concat = function(a, b) return 'cat' end
+                local t = setmetatable({ v = 1 }, mt)
+                return (t + 2) + -t + #t + #(t .. 'x')",
+                Value::from_int(15),
+            ),
+            (
+                "local mt = { __eq = function() return true end }
+                local a, b = setmetatable({}, mt), setmetatable({}, mt)
+                return a == b and a ~= 1 and not rawequal(a, b)",
+                Value::from_bool(true),
+            ),
+            (
+                "local mt = {}
+                mt.__lt = function(a, b) return a.v < b.v end
+                mt.__le = function(a, b) return a.v <= b.v end
+                local a, b = setmetatable({ v = 1 }, mt), setmetatable({ v = 2 }, mt)
+                return a < b and b > a and a <= a and not (a >= b)",
+                Value::from_bool(true),
+            ),
+            (
+                "local t = setmetatable({}, { __metatable = false })
+                return getmetatable(t)",
+                Value::from_bool(false),
+            ),
+            (
+                "local t = setmetatable({}, { __metatable = 1 })
+                return pcall(setmetatable, t, {})",
+                Value::from_bool(false),
+            ),
+            ("return rawlen({ 1, 2, 3 })", Value::from_int(3)),
+        ];
+
+        for (source, expected) in cases {
+            eval(source, |_, _, value| {
+                assert!(value == *expected, "{}", source)
+            });
+        }
+
+        let cases: &[(&str, &[u8])] = &[
+            (
+                "return tostring(setmetatable({}, { __tostring = function() return 'obj' end }))",
+                b"obj",
+            ),
+            (
+                "return tostring(1.5) .. tostring(nil) .. tostring(2)",
+                b"1.5nil2",
+            ),
+            ("return type(print)", b"nil"),
+            ("return type(setmetatable({}, {}))", b"table"),
+        ];
+
+        for (source, expected) in cases {
+            eval(source, |vm, heap, value| {
+                assert!(value == string(vm, heap, expected), "{}", source)
+            });
+        }
+
+        eval(
+            "return tostring(setmetatable({}, { __name = 'Point' }))",
+            |_, _, value| {
+                let string = unsafe { value.cast_string().unwrap().get_unchecked() };
+                assert!(string.starts_with(b"Point: 0x"));
+            },
+        );
+    }
+
+    #[test]
+    fn eval_to_be_closed() {
+        let cases: &[(&str, &[u8])] = &[
+            (
+                "local log = ''
+                local function closer(name)
+                    return setmetatable({}, { __close = function() log = log .. name end })
+                end
+                do
+                    local a <close> = closer('a')
+                    local b <close> = closer('b')
+                    local c <close> = nil
+                end
+                return log",
+                b"ba",
+            ),
+            (
+                "local log = ''
+                local mt = { __close = function(_, e) log = log .. e end }
+                pcall(function()
+                    local x <close> = setmetatable({}, mt)
+                    error('boom')
+                end)
+                return log",
+                b"boom",
+            ),
+            (
+                "local log = ''
+                local function f()
+                    local x <close> = setmetatable({}, { __close = function() log = log .. \
+                 'closed' end })
+                    return 'ret'
+                end
+                return f() .. log",
+                b"retclosed",
+            ),
+        ];
+
+        for (source, expected) in cases {
+            eval(source, |vm, heap, value| {
+                assert!(value == string(vm, heap, expected), "{}", source)
+            });
+        }
+    }
+
+    #[test]
+    fn report_metamethod_errors() {
+        let cases: &[(&str, &str)] = &[
+            (
+                "local x <close> = {}",
+                "variable 'x' got a non-closable value",
+            ),
+            (
+                "local t = {} setmetatable(t, { __index = t }) return t.x",
+                "'__index' chain too long; possible loop",
+            ),
+            (
+                "return tostring(setmetatable({}, { __tostring = function() return 1 end }))",
+                "'__tostring' must return a string",
+            ),
+            (
+                "setmetatable(setmetatable({}, { __metatable = 1 }), {})",
+                "cannot change a protected metatable",
+            ),
+            (
+                "return setmetatable({}, { __add = function() error('in add') end }) + 1",
+                "in add",
+            ),
+            (
+                "setmetatable(1, {})",
+                "bad argument #1 to 'setmetatable' (table expected, got number)",
+            ),
+        ];
+
+        for (source, message) in cases {
+            eval_error(source, |_, _, error| {
+                assert_eq!(error.message(), *message, "{}", source);
+            });
+        }
+    }
+
+    pub(crate) fn compile(source: &str) -> Rc<Proto> {
+        let mut cache = NodeCache::new();
+        let (tree, reports) = parse(&mut cache, source);
+        assert!(reports.is_empty());
+
+        let heap = Heap::new();
+        let mut vm = VM::new(heap.clone());
+        let ctx = Ctx::new(&mut vm.global, &heap, &mut vm.strings, &mut vm.integers);
+
+        match compiler::compile(&Root::cast(&tree).unwrap(), cache.interner(), &ctx) {
+            Ok(proto) => proto,
+            Err(_) => panic!("compilation failed"),
+        }
+    }
+
+    #[test]
+    fn compile_locals_to_registers() {
+        let proto = compile("local a, b = 1, 2 return a + b");
+        assert_eq!(
+            proto.code,
+            [
+                Instruction::LoadInt(0, 1),
+                Instruction::LoadInt(1, 2),
+                Instruction::Arith(Arith::Add, 2, 0, 1),
+                Instruction::Return(2, 1),
+                Instruction::Return(0, 0),
+            ]
+        );
+        assert_eq!(proto.registers, 3);
+        assert_eq!(proto.cells, 0);
+    }
+
+    #[test]
+    fn compile_captured_locals_to_cells() {
+        let proto = compile("local x, y = 0, 0 local function inc() x = x + 1 end return y");
+        assert!(proto.code.contains(&Instruction::NewCell(0)));
+        assert_eq!(proto.cells, 1);
+
+        let inc = &proto.protos[0];
+        assert_eq!(inc.captures, [Capture::Cell(0)]);
+        assert!(inc.code.contains(&Instruction::GetUpval(1, 0)));
+    }
+
+    // Runs a chunk in a VM that lives on, returning its first value.
+    fn run(vm: &mut VM, source: &str) -> Value {
+        let mut cache = NodeCache::new();
+        let (tree, reports) = parse(&mut cache, source);
+        assert!(reports.is_empty());
+
+        let root = Root::cast(&tree).unwrap();
+        match vm.eval(&root, cache.interner()) {
+            Ok(value) => value,
+            Err(_) => panic!("evaluation failed: {}", source),
+        }
+    }
+
+    #[test]
+    fn collect_strings() {
+        let mut vm = VM::new(Heap::new());
+        let source = "for i = 1, 1000 do local s, n = 'key' .. i, (1 << 50) + i end
+            kept, big = 'key' .. 1, (1 << 50) + 1
+            weak = setmetatable({}, { __mode = 'k' })
+            weak['weak' .. 'key'] = true
+
+            -- Removed fields do not keep their keys alive.
+            fields = {}
+            for i = 1, 20 do fields['field' .. i] = i end
+            for i = 1, 20 do fields['field' .. i] = nil end";
+        run(&mut vm, source);
+
+        let (strings, integers) = (vm.strings.len(), vm.integers.len());
+        vm.collect();
+        assert!(vm.strings.len() + 1019 <= strings);
+        assert!(vm.integers.len() + 999 <= integers);
+
+        // Strings and integers made again are the same.
+        let source = "return kept == 'key' .. 1 and big == (1 << 50) + 1
+            and rawequal(kept, 'key1') and weak.weakkey";
+        assert!(run(&mut vm, source) == Value::from_bool(true));
+    }
+
+    #[test]
+    fn collect_steps() {
+        let mut vm = VM::new(Heap::new());
+        let source = "kept, same = { name = 'kept' }, true
+            for i = 1, 100000 do
+                local t = { i, 'item' .. i }
+                local f = function() return t end
+                -- Strings made again while a cycle sweeps are kept.
+                last = 'key' .. i % 10
+                same = same and rawequal(last, 'key' .. i % 10)
+            end
+            return kept.name == 'kept' and last == 'key0' and same";
+        assert!(run(&mut vm, source) == Value::from_bool(true));
+
+        let stats = vm.heap.heuristics().stats();
+        assert!(stats.cycles > 0);
+        assert!(stats.total_freed > stats.allocated);
+    }
+
+    #[test]
+    fn collect_steps_nested() {
+        let mut vm = VM::new(Heap::new());
+        let source = "peak = 0
+            local function churn(n)
+                for i = 1, n do
+                    local t = { i, 'item' .. i }
+                    if i % 1000 == 0 and collectgarbage('count') > peak then
+                        peak = collectgarbage('count')
+                    end
+                end
+            end
+
+            -- Lua code run by native functions steps the collector as well.
+            local object = setmetatable({}, { __tostring = function()
+                churn(50000)
+                return 'object'
+            end })
+
+            local co = coroutine.wrap(function()
+                churn(50000)
+                coroutine.yield(tostring(object))
+                churn(50000)
+                return 'done'
+            end)
+            -- As do `__close` metamethods run while an error unwinds.
+            local ok, error = pcall(function()
+                local closed <close> = setmetatable({}, { __close = function()
+                    churn(50000)
+                end })
+                error({ 'item' .. 0 })
+            end)
+            return co() == 'object' and co() == 'done' and error[1] == 'item0'";
+        assert!(run(&mut vm, source) == Value::from_bool(true));
+
+        let heap = vm.heap.clone();
+        let key = string(&mut vm, &heap, b"peak");
+        let peak = vm.global.get(key).convert_float() * 1024.0;
+        // Far more is allocated than the heap ever holds.
+        assert!(peak < (1 << 20) as f64);
+        assert!(vm.stats().total_allocated > 16 << 20);
+    }
+
+    #[test]
+    fn collect_modes() {
+        let mut vm = VM::new(Heap::new());
+        let params = Generational::default();
+        vm.set_mode(Mode::Generational(params));
+        assert_eq!(vm.mode(), Mode::Generational(params));
+
+        run(&mut vm, "for i = 1, 100000 do local t = { i } end");
+        assert!(vm.stats().minor_collections > 0);
+
+        vm.set_mode(Mode::Incremental);
+        assert_eq!(vm.mode(), Mode::Incremental);
+        assert_eq!(vm.stats().live, vm.stats().allocated);
+    }
+
+    #[test]
+    fn eval_collectgarbage() {
+        let mut vm = VM::new(Heap::new());
+        run(
+            &mut vm,
+            "garbage = {} for i = 1, 1000 do garbage[i] = { i } end",
+        );
+        let source = "local count = collectgarbage('count')
+            garbage = nil
+            return collectgarbage() == 0 and collectgarbage('count') < count
+                and collectgarbage('isrunning')";
+        assert!(run(&mut vm, source) == Value::from_bool(true));
+
+        let source = "return collectgarbage('generational', 50) == 'incremental'
+            and collectgarbage('step') and collectgarbage('generational') == 'generational'";
+        assert!(run(&mut vm, source) == Value::from_bool(true));
+        let params = Generational {
+            minor_multiplier: 50,
+            ..Generational::default()
+        };
+        assert_eq!(vm.mode(), Mode::Generational(params));
+
+        // Coroutines collect right away as well.
+        let source = "coroutine.wrap(function()
+                stepped = collectgarbage('step')
+                mode = collectgarbage('incremental')
+            end)()
+            return mode == 'generational' and stepped";
+        assert!(run(&mut vm, source) == Value::from_bool(true));
+        assert_eq!(vm.mode(), Mode::Incremental);
+
+        eval_error("collectgarbage('stop')", |_, _, error| {
+            let message = "bad argument #1 to 'collectgarbage' (invalid option 'stop')";
+            assert_eq!(error.message(), message);
+        });
+    }
+
+    // The number of entries of the table in a global.
+    fn entries(vm: &mut VM, name: &str) -> usize {
+        let heap = vm.heap.clone();
+        let key = string(vm, &heap, name.as_bytes());
+        let table = vm.global.get(key).cast_table().unwrap();
+        unsafe { table.get_unchecked() }.len()
+    }
+
+    #[test]
+    fn collect_weak_tables() {
+        let mut vm = VM::new(Heap::new());
+        let source = "keys = setmetatable({}, { __mode = 'k' })
+            values = setmetatable({}, { __mode = 'v' })
+            both = setmetatable({}, { __mode = 'kv' })
+            kept = {}
+            for i = 1, 10 do
+                keys[{}] = i
+                values[i] = {}
+                both[{}] = {}
+            end
+            keys[kept], values.kept, both[kept] = 'kept', kept, kept
+
+            -- A value referring to its weak key does not keep the key alive.
+            local cycle = {}
+            keys[cycle] = { cycle }
+
+            -- Ephemerons chained from a reachable key survive, others do not.
+            chain = setmetatable({}, { __mode = 'k' })
+            root = {}
+            local key, lost = root, {}
+            for i = 1, 5 do
+                chain[key], chain[lost] = {}, {}
+                key, lost = chain[key], chain[lost]
+            end";
+        run(&mut vm, source);
+        vm.collect();
+        assert_eq!(entries(&mut vm, "keys"), 1);
+        assert_eq!(entries(&mut vm, "values"), 1);
+        assert_eq!(entries(&mut vm, "both"), 1);
+        assert_eq!(entries(&mut vm, "chain"), 5);
+
+        let source = "local key, n = root, 0
+            while chain[key] do key, n = chain[key], n + 1 end
+            return n == 5 and keys[kept] == 'kept' and values.kept == kept
+                and both[kept] == kept";
+        assert!(run(&mut vm, source) == Value::from_bool(true));
+
+        run(&mut vm, "kept, root = nil, nil");
+        vm.collect();
+        for name in ["keys", "values", "both", "chain"] {
+            assert_eq!(entries(&mut vm, name), 0, "{}", name);
+        }
+    }
+
+    #[test]
+    fn collect_finalizers() {
+        let mut vm = VM::new(Heap::new());
+        let warnings = Rc::new(RefCell::new(Vec::new()));
+        let log = warnings.clone();
+        vm.set_warn(Some(Rc::new(move |message: &str| {
+            log.borrow_mut().push(String::from(message))
+        })));
+
+        let source = "log, count = {}, 0
+            local mt = { __gc = function(o) log[#log + 1] = o.name end }
+            setmetatable({ name = 'a' }, mt)
+            kept = setmetatable({ name = 'b' }, mt)
+
+            -- Only a __gc field present when the metatable is set counts.
+            local late = {}
+            setmetatable({ name = 'c' }, late)
+            late.__gc = mt.__gc
+
+            setmetatable({}, { __gc = function(o) count = count + 1 resurrected = o end })
+            setmetatable({}, { __gc = function() error('boom') end })
+
+            -- Objects being finalized are removed from weak values only.
+            keys = setmetatable({}, { __mode = 'k' })
+            values = setmetatable({}, { __mode = 'v' })
+            local object = setmetatable({}, { __gc = function(o)
+                key, value = keys[o], values[1]
+            end })
+            keys[object], values[1] = 'key', object";
+        run(&mut vm, source);
+        vm.collect();
+
+        let source = "return #log == 1 and log[1] == 'a' and count == 1 and resurrected ~= nil
+            and key == 'key' and value == nil";
+        assert!(run(&mut vm, source) == Value::from_bool(true));
+        assert_eq!(warnings.borrow().len(), 1);
+        assert!(warnings.borrow()[0].starts_with("error in __gc ("));
+        assert!(warnings.borrow()[0].contains("boom"));
+
+        // The resurrected object is not finalized again.
+        run(&mut vm, "resurrected = nil");
+        vm.collect();
+        assert!(run(&mut vm, "return count") == Value::from_int(1));
+
+        // Dropping the VM runs the finalizers left, reachable or not.
+        run(
+            &mut vm,
+            "setmetatable(kept, nil) setmetatable(kept, { __gc = function() error('closed') end })",
+        );
+        drop(vm);
+        assert_eq!(warnings.borrow().len(), 2);
+        assert!(warnings.borrow()[1].contains("closed"));
+    }
+
+    #[test]
+    fn collect_without_warn() {
+        // Errors in finalizers are dropped and the other finalizers still run.
+        let mut vm = VM::new(Heap::new());
+        let source = "setmetatable({}, { __gc = function() error('dropped') end })
+            setmetatable({}, { __gc = function() done = true end })";
+        run(&mut vm, source);
+        vm.collect();
+        assert!(run(&mut vm, "return done") == Value::from_bool(true));
+    }
+
+    #[test]
+    fn collect_finalizer_limits() {
+        // Finalizers run within the limits of the call collecting, and those
+        // hitting an uncatchable limit are reported like any other error.
+        let mut vm = VM::new(Heap::new());
+        let warnings = Rc::new(RefCell::new(Vec::new()));
+        let log = warnings.clone();
+        vm.set_warn(Some(Rc::new(move |message: &str| {
+            log.borrow_mut().push(String::from(message))
+        })));
+        run(
+            &mut vm,
+            "setmetatable({}, { __gc = function() while true do end end })",
+        );
+
+        let mut cache = NodeCache::new();
+        let (tree, _) = parse(&mut cache, "collectgarbage()");
+        let root = Root::cast(&tree).unwrap();
+        let limits = Limits {
+            instructions: Some(10_000),
+            uncatchable: true,
+            ..Limits::default()
+        };
+        let _ = vm.eval_with_limits(&root, cache.interner(), limits);
+        assert_eq!(
+            *warnings.borrow(),
+            ["error in __gc (instruction limit exceeded)"]
+        );
+    }
+
+    #[test]
+    fn report_register_overflow() {
+        let names: Vec<_> = (0..300).map(|i| format!("x{}", i)).collect();
+        let source = format!("local {}", names.join(", "));
+
+        let mut cache = NodeCache::new();
+        let (tree, reports) = parse(&mut cache, &source);
+        assert!(reports.is_empty());
+
+        let mut vm = VM::new(Heap::new());
+        let root = Root::cast(&tree).unwrap();
+        assert!(matches!(
+            vm.eval(&root, cache.interner()),
+            Err(Error::Compile(_))
+        ));
+    }
+
+    #[test]
+    fn eval_dump_and_load() {
+        let cases: &[(&str, Value)] = &[
+            (
+                "local function add(a, b) return a + b end
+                return load(string.dump(add))(2, 3)",
+                Value::from_int(5),
+            ),
+            (
+                "local function add(a, b) return a + b end
+                return load(string.dump(add, true), 'add', 'b')(2, 3)",
+                Value::from_int(5),
+            ),
+            (
+                "local x = 1
+                local function inc() x = (x or 10) + 1 return x end
+                local f = load(string.dump(inc))
+                return f() + f()",
+                Value::from_int(23),
+            ),
+            (
+                "local function f(...)
+                    local n = 0
+                    for i = 1, select('#', ...) do n = n + select(i, ...) end
+                    local function g(k) return k * n end
+                    return g(2)
+                end
+                return load(string.dump(f))(1, 2, 3)",
+                Value::from_int(12),
+            ),
+            ("return load('return 1 + 2')()", Value::from_int(3)),
+            (
+                "local parts = { 'return ', '4', ' * 5' }
+                local i = 0
+                return load(function() i = i + 1 return parts[i] end)()",
+                Value::from_int(20),
+            ),
+        ];
+
+        for (source, expected) in cases {
+            eval(source, |_, _, value| {
+                assert!(value == *expected, "{}", source)
+            });
+        }
+    }
+
+    #[test]
+    fn report_load_errors() {
+        let cases: &[(&str, &[u8])] = &[
+            (
+                "local _, msg = load(string.dump(function() end), 'f', 't') return msg",
+                b"f: attempt to load a binary chunk (mode is 't')",
+            ),
+            (
+                "local _, msg = load('return 1', 'f', 'b') return msg",
+                b"f: attempt to load a text chunk (mode is 'b')",
+            ),
+            (
+                "return select(2, load(string.dump(function() end) .. 'x'))",
+                b"bad binary format (trailing bytes)",
+            ),
+            (
+                "return select(2, load('\\27Zaia'))",
+                b"truncated precompiled chunk",
+            ),
+            (
+                "return select(2, load('x = = 1'))",
+                b"[string \"x = = 1\"]:1: expected a statement",
+            ),
+            (
+                "local parts = {'x =', ' = 1'}
+                local i = 0
+                return select(2, load(function() i = i + 1 return parts[i] end))",
+                b"(load):1: expected a statement",
+            ),
+            (
+                "return select(2, load('local x = 1\\nx = = 1', '=chunk'))",
+                b"chunk:2: expected a statement",
+            ),
+            (
+                "return select(2, load('\\n\\nreturn \"\\\\q\"', 'f'))",
+                b"[string \"f\"]:3: invalid escape sequence",
+            ),
+            (
+                "return select(2, load('local x <const> = 1 x = 2'))",
+                b"[string \"local x <const> = 1 x = 2\"]:1: attempt to assign to const variable 'x'",
+            ),
+            (
+                "return select(2, load('for i = 1, 2 do end break local abcdefghijklmnopqrstuvwxyz'))",
+                b"[string \"for i = 1, 2 do end break local abcdefghijklm...\"]:1: break outside a loop at line 1",
+            ),
+            (
+                "return select(2, pcall(string.dump, pcall))",
+                b"unable to dump given function",
+            ),
+        ];
+
+        for (source, expected) in cases {
+            eval(source, |vm, heap, value| {
+                assert!(value == string(vm, heap, expected), "{}", source)
+            });
+        }
     }
 }
@@ -0,0 +1,342 @@
+//! Async calls, which run Lua code as a future.
+//!
+//! An async call runs its function in a dispatch loop of its own. When the
+//! code calls an async host function, the loop is suspended the way a
+//! coroutine is and the call waits on the future of the host function,
+//! continuing the loop with its outcome once it is ready. Nothing blocks in
+//! between, so the executor polling the call is free to run other tasks.
+
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use super::{
+    super::{
+        value::{HostFuture, Value},
+        Error,
+    },
+    ctx::Ctx,
+    eval,
+    interp::{self, Exit, Thread},
+    limits::Limits,
+    VM,
+};
+
+/// The future of a call made by [`VM::call_async`], resolving to the values
+/// returned by the called function.
+pub struct AsyncCall<'a> {
+    vm: &'a mut VM,
+    // The limits left to the call, whose instructions are counted down as
+    // the call is polled.
+    limits: Limits,
+    state: State,
+}
+
+struct State {
+    thread: Thread,
+    // The values to resume the loop with, taken once it runs.
+    input: Option<eval::Result<Vec<Value>>>,
+    // The future of the async host function the loop waits on.
+    future: Option<HostFuture>,
+}
+
+impl<'a> AsyncCall<'a> {
+    pub(super) fn new(vm: &'a mut VM, function: Value, args: Vec<Value>, limits: Limits) -> Self {
+        AsyncCall {
+            vm,
+            limits,
+            state: State {
+                thread: Thread::host(function),
+                input: Some(eval::Result::Value(args)),
+                future: None,
+            },
+        }
+    }
+}
+
+impl Future for AsyncCall<'_> {
+    type Output = Result<Vec<Value>, Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let call = self.get_mut();
+        let vm = &mut *call.vm;
+        let mut ctx = Ctx::new(&mut vm.global, &vm.heap, &mut vm.strings, &mut vm.integers);
+
+        ctx.set_limits(call.limits.clone());
+        ctx.set_source(vm.source.clone());
+        ctx.set_warn(vm.warn.clone());
+        ctx.set_hook(vm.hook.take());
+        let poll = call.state.resume(&ctx, cx);
+        vm.hook = ctx.hook();
+        call.limits.instructions = ctx.instructions_left();
+        poll
+    }
+}
+
+impl State {
+    // Runs the loop until the call is done or waits on a future that is not
+    // ready.
+    fn resume(&mut self, ctx: &Ctx, cx: &mut Context) -> Poll<Result<Vec<Value>, Error>> {
+        loop {
+            if let Some(future) = &mut self.future {
+                let completion = match future.as_mut().poll(cx) {
+                    Poll::Ready(completion) => completion,
+                    Poll::Pending => return Poll::Pending,
+                };
+
+                self.future = None;
+                self.input = Some(completion(ctx));
+            }
+
+            let input = self
+                .input
+                .take()
+                .expect("async call polled after completion");
+            match interp::resume_async(&mut self.thread, input, ctx) {
+                eval::Result::Value(Exit::Await(future)) => self.future = Some(future),
+                eval::Result::Value(Exit::Return(values)) => return Poll::Ready(Ok(values)),
+                // The loops of async calls do not belong to coroutines.
+                eval::Result::Value(Exit::Yield(_)) => unreachable!(),
+                eval::Result::Error(error) => return Poll::Ready(Err(error)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::Cell,
+        future::Future,
+        pin::Pin,
+        rc::Rc,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        task::{Context, Poll, Wake, Waker},
+    };
+
+    use super::super::{
+        super::{
+            gc::Heap,
+            value::{Completion, Value},
+            Error,
+        },
+        ctx::Ctx,
+        debug::{Hook, HookMask},
+        eval,
+        limits::Limits,
+        VM,
+    };
+    use crate::parser::{machinery::cstree::NodeCache, parse, syntax::Root};
+
+    // A future that is ready once it was polled a number of times.
+    struct Delay(i64);
+
+    impl Future for Delay {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+            if self.0 <= 0 {
+                return Poll::Ready(());
+            }
+
+            self.0 -= 1;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+
+    // Polls a future to completion, returning its output and how many times
+    // it woke its task.
+    fn block_on<F: Future>(future: F) -> (F::Output, usize) {
+        struct Counter(AtomicUsize);
+
+        impl Wake for Counter {
+            fn wake(self: Arc<Self>) {
+                self.0.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let counter = Arc::new(Counter(AtomicUsize::new(0)));
+        let waker = Waker::from(counter.clone());
+        let mut cx = Context::from_waker(&waker);
+        let mut future = Box::pin(future);
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return (output, counter.0.load(Ordering::Relaxed));
+            }
+        }
+    }
+
+    // A VM with the async host functions `sleep(n)`, which returns `n` after
+    // being pending `n` times, and `fetch(url)`, which returns "body" if the
+    // url is "ok" and fails otherwise.
+    fn host_vm() -> VM {
+        let mut vm = VM::new(Heap::new());
+        vm.set_async("sleep", |_, args| {
+            let n = match args.first() {
+                Some(value) if value.is_int() => value.cast_int(),
+                _ => 0,
+            };
+
+            Box::pin(async move {
+                Delay(n).await;
+                Box::new(move |ctx: &Ctx| eval::Result::Value(vec![ctx.int(n)])) as Completion
+            })
+        });
+
+        vm.set_async("fetch", |_, args| {
+            let ok = match args.first().and_then(|value| value.cast_string()) {
+                Some(url) => unsafe { &**url.get_unchecked() == b"ok" },
+                None => false,
+            };
+
+            Box::pin(async move {
+                Delay(1).await;
+                Box::new(move |ctx: &Ctx| {
+                    if ok {
+                        eval::Result::Value(vec![Value::from_string(ctx.intern(b"body"))])
+                    } else {
+                        let message = String::from("not found");
+                        eval::Result::Error(eval::runtime_error(message, ctx))
+                    }
+                }) as Completion
+            })
+        });
+
+        vm
+    }
+
+    #[test]
+    fn eval_async_host_functions() {
+        let cases: &[(&str, usize)] = &[
+            ("return sleep(3) + sleep(2) == 5", 5),
+            (
+                "local t = {}
+                for i = 1, 3 do t[i] = sleep(i) end
+                return #t == 3 and t[3] == 3",
+                6,
+            ),
+            ("return fetch('ok') == 'body'", 1),
+            // Failing futures raise their error at the call.
+            (
+                "local ok, err = pcall(fetch, 'missing')
+                return not ok and err == 'not found'",
+                1,
+            ),
+            (
+                "local ok, err = pcall(function() return fetch('missing') end)
+                return not ok and err == 'not found'",
+                1,
+            ),
+            (
+                "local t = setmetatable({}, { __index = function(t, k) return sleep(2) end })
+                return t.x == 2",
+                2,
+            ),
+            // Only the loop of the async call itself can wait.
+            (
+                "local co = coroutine.wrap(function() return sleep(1) end)
+                local ok, err = pcall(co)
+                return not ok and err == 'attempt to await outside of an async call'",
+                0,
+            ),
+            (
+                "local t = setmetatable({}, { __tostring = function() return sleep(1) end })
+                local ok, err = pcall(tostring, t)
+                return not ok and err == 'attempt to await outside of an async call'",
+                0,
+            ),
+        ];
+
+        for (source, expected) in cases {
+            let mut cache = NodeCache::new();
+            let (tree, reports) = parse(&mut cache, source);
+            assert!(reports.is_empty());
+
+            let mut vm = host_vm();
+            let root = Root::cast(&tree).unwrap();
+            let (result, wakes) = block_on(vm.eval_async(&root, cache.interner()));
+            assert!(
+                matches!(result, Ok(value) if value == Value::from_bool(true)),
+                "{}",
+                source
+            );
+            assert_eq!(wakes, *expected, "{}", source);
+        }
+
+        let source = "return fetch('missing')";
+        let mut cache = NodeCache::new();
+        let (tree, _) = parse(&mut cache, source);
+        let root = Root::cast(&tree).unwrap();
+        let mut vm = host_vm();
+        match block_on(vm.eval_async(&root, cache.interner())).0 {
+            Err(Error::Runtime(error)) => assert_eq!(error.message(), "not found"),
+            _ => panic!("the error was not raised"),
+        }
+
+        match vm.eval(&root, cache.interner()) {
+            Err(Error::Runtime(error)) =>
+                assert_eq!(error.message(), "attempt to await outside of an async call"),
+            _ => panic!("the error was not raised"),
+        }
+
+        let source = "return function(a, b) return sleep(a) * b end";
+        let mut cache = NodeCache::new();
+        let (tree, _) = parse(&mut cache, source);
+        let root = Root::cast(&tree).unwrap();
+        let function = vm.eval(&root, cache.interner()).ok().unwrap();
+        let args = vec![Value::from_int(2), Value::from_int(5)];
+        let (result, wakes) = block_on(vm.call_async(function, args));
+        assert!(matches!(result.ok().as_deref(), Some([value]) if *value == Value::from_int(10)));
+        assert_eq!(wakes, 2);
+    }
+
+    #[test]
+    fn eval_async_with_limits() {
+        let source = "local n = 0 while true do n = n + sleep(1) end";
+        let mut cache = NodeCache::new();
+        let (tree, _) = parse(&mut cache, source);
+        let root = Root::cast(&tree).unwrap();
+
+        // The instructions are counted across the polls the call takes, each
+        // of which runs fewer instructions than the limit.
+        let mut vm = host_vm();
+        let limits = Limits {
+            instructions: Some(10_000),
+            uncatchable: true,
+            ..Limits::default()
+        };
+        match block_on(vm.eval_async_with_limits(&root, cache.interner(), limits)).0 {
+            Err(Error::Limit(error)) => assert_eq!(error.message(), "instruction limit exceeded"),
+            _ => panic!("the limit was not hit"),
+        }
+
+        // The hook of the VM sees the instructions too.
+        let counted = Rc::new(Cell::new(0));
+        let count = counted.clone();
+        let hook = Hook::new(
+            move |_, _| {
+                count.set(count.get() + 1);
+                eval::Result::Value(())
+            },
+            HookMask::default(),
+            1,
+        );
+        vm.set_hook(Some(hook));
+        let limits = Limits {
+            instructions: Some(100),
+            ..Limits::default()
+        };
+        assert!(
+            block_on(vm.eval_async_with_limits(&root, cache.interner(), limits))
+                .0
+                .is_err()
+        );
+        assert!(counted.get() >= 100);
+    }
+}
@@ -0,0 +1,363 @@
+//! A compact binary form of parsed syntax trees, so that unchanged scripts can
+//! be loaded without running the lexer and parser again.
+//!
+//! A cache file starts with a fixed header:
+//!
+//! | bytes | contents                                  |
+//! |-------|-------------------------------------------|
+//! | 4     | the magic `ZAIA`                          |
+//! | 4     | the format [`VERSION`], little endian     |
+//! | 8     | a hash of the source text, little endian  |
+//! | 8     | a hash of the payload, little endian      |
+//!
+//! The payload is the table of token strings followed by the tree in
+//! preorder. All integers in the payload are LEB128 varints. Nodes are written
+//! as a `0` tag, their kind and their number of children; tokens as a `1` tag,
+//! their kind and the index of their text in the string table.
+
+use std::{
+    collections::HashMap,
+    fmt::{self, Display},
+    str,
+};
+
+use super::{
+    machinery::cstree::{
+        interning::{Key, Resolver},
+        GreenNode,
+        GreenNodeBuilder,
+        NodeCache,
+        NodeOrToken,
+        SyntaxKind,
+    },
+    syntax::SyntaxNode,
+};
+use crate::T;
+
+const MAGIC: &[u8; 4] = b"ZAIA";
+const HEADER_LEN: usize = 24;
+
+/// The version of the cache format, bumped whenever the format or the set of
+/// syntax kinds changes.
+pub const VERSION: u32 = 1;
+
+const NODE: u8 = 0;
+const TOKEN: u8 = 1;
+
+/// The reasons a cache file can be rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheError {
+    /// The file is not a syntax tree cache.
+    NotACache,
+    /// The file was written by a different version of the format.
+    Version(u32),
+    /// The file was built from a different source text.
+    Stale,
+    /// The file is truncated or damaged.
+    Corrupt,
+}
+
+impl Display for CacheError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotACache => f.write_str("not a syntax tree cache"),
+            Self::Version(version) => write!(
+                f,
+                "cache format version {} is not supported (expected {})",
+                version, VERSION
+            ),
+            Self::Stale => f.write_str("cache does not match the source"),
+            Self::Corrupt => f.write_str("cache is corrupt"),
+        }
+    }
+}
+
+/// Hashes bytes with 64-bit FNV-1a. Unlike the std hashers it is stable
+/// across processes and compiler versions.
+fn hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+/// Serializes a tree parsed from `source` with `cache`, together with the
+/// strings of its tokens.
+///
+/// Trees with parse errors can be saved too, but the reports are not part of
+/// the cache.
+pub fn save(cache: &NodeCache<'static>, root: &SyntaxNode, source: &str) -> Vec<u8> {
+    let mut writer = Writer {
+        interner: cache.interner(),
+        strings: HashMap::new(),
+        table: Vec::new(),
+        tree: Vec::new(),
+    };
+
+    writer.node(root.green());
+
+    let mut payload = Vec::with_capacity(writer.table.len() * 8 + writer.tree.len());
+    write_varint(&mut payload, writer.table.len() as u64);
+    for string in &writer.table {
+        write_varint(&mut payload, string.len() as u64);
+        payload.extend_from_slice(string.as_bytes());
+    }
+    payload.extend_from_slice(&writer.tree);
+
+    let mut bytes = Vec::with_capacity(HEADER_LEN + payload.len());
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&VERSION.to_le_bytes());
+    bytes.extend_from_slice(&hash(source.as_bytes()).to_le_bytes());
+    bytes.extend_from_slice(&hash(&payload).to_le_bytes());
+    bytes.extend_from_slice(&payload);
+    bytes
+}
+
+/// Rebuilds a tree saved with [`save`] into `cache`, interning its strings.
+///
+/// The cache is rejected if it was not built from `source` or fails to decode.
+pub fn load(
+    cache: &mut NodeCache<'static>,
+    bytes: &[u8],
+    source: &str,
+) -> Result<SyntaxNode, CacheError> {
+    if bytes.len() < HEADER_LEN || &bytes[..4] != MAGIC {
+        return Err(CacheError::NotACache);
+    }
+
+    let word = |at: usize| u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap());
+    let version = u32::from_le_bytes(bytes[4..8].try_into().unwrap());
+    if version != VERSION {
+        return Err(CacheError::Version(version));
+    }
+
+    if word(8) != hash(source.as_bytes()) {
+        return Err(CacheError::Stale);
+    }
+
+    let payload = &bytes[HEADER_LEN..];
+    if word(16) != hash(payload) {
+        return Err(CacheError::Corrupt);
+    }
+
+    Reader {
+        bytes: payload,
+        cursor: 0,
+    }
+    .tree(cache)
+    .map(SyntaxNode::new_root)
+}
+
+struct Writer<'i, I> {
+    interner: &'i I,
+    strings: HashMap<Key, u32>,
+    table: Vec<&'i str>,
+    tree: Vec<u8>,
+}
+
+impl<'i, I: Resolver> Writer<'i, I> {
+    fn node(&mut self, node: &GreenNode) {
+        self.tree.push(NODE);
+        write_varint(&mut self.tree, node.kind().0 as u64);
+        write_varint(&mut self.tree, node.children().len() as u64);
+
+        for child in node.children() {
+            match child {
+                NodeOrToken::Node(node) => self.node(node),
+                NodeOrToken::Token(token) => {
+                    let key = token.text_key();
+                    let index = match self.strings.get(&key) {
+                        Some(&index) => index,
+                        None => {
+                            let index = self.table.len() as u32;
+                            self.table.push(self.interner.resolve(&key));
+                            self.strings.insert(key, index);
+                            index
+                        },
+                    };
+
+                    self.tree.push(TOKEN);
+                    write_varint(&mut self.tree, token.kind().0 as u64);
+                    write_varint(&mut self.tree, index as u64);
+                },
+            }
+        }
+    }
+}
+
+fn write_varint(bytes: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        bytes.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+
+    bytes.push(value as u8);
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    cursor: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn byte(&mut self) -> Result<u8, CacheError> {
+        let byte = *self.bytes.get(self.cursor).ok_or(CacheError::Corrupt)?;
+        self.cursor += 1;
+        Ok(byte)
+    }
+
+    fn varint(&mut self) -> Result<u64, CacheError> {
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+
+        Err(CacheError::Corrupt)
+    }
+
+    // Reads a length that has to fit in the rest of the input.
+    fn len(&mut self) -> Result<usize, CacheError> {
+        match self.varint()? {
+            len if len <= (self.bytes.len() - self.cursor) as u64 => Ok(len as usize),
+            _ => Err(CacheError::Corrupt),
+        }
+    }
+
+    fn kind(&mut self) -> Result<SyntaxKind, CacheError> {
+        match self.varint()? {
+            kind if kind < T![__LAST] as u64 => Ok(SyntaxKind(kind as u16)),
+            _ => Err(CacheError::Corrupt),
+        }
+    }
+
+    fn string(&mut self) -> Result<&'a str, CacheError> {
+        let len = self.len()?;
+        let bytes = &self.bytes[self.cursor..self.cursor + len];
+        self.cursor += len;
+        str::from_utf8(bytes).map_err(|_| CacheError::Corrupt)
+    }
+
+    fn tree(mut self, cache: &mut NodeCache<'static>) -> Result<GreenNode, CacheError> {
+        let count = self.len()?;
+        let mut strings = Vec::with_capacity(count);
+        for _ in 0..count {
+            strings.push(self.string()?);
+        }
+
+        let mut builder = GreenNodeBuilder::with_cache(cache);
+        // The number of children still to be read for each open node. The
+        // tree is rebuilt without recursion so that a hostile file cannot
+        // overflow the stack.
+        let mut open = Vec::new();
+        if self.byte()? != NODE {
+            return Err(CacheError::Corrupt);
+        }
+
+        builder.start_node(self.kind()?);
+        open.push(self.len()?);
+
+        while let Some(remaining) = open.last_mut() {
+            if *remaining == 0 {
+                builder.finish_node();
+                open.pop();
+                continue;
+            }
+
+            *remaining -= 1;
+            match self.byte()? {
+                NODE => {
+                    builder.start_node(self.kind()?);
+                    open.push(self.len()?);
+                },
+                TOKEN => {
+                    let kind = self.kind()?;
+                    let text = strings
+                        .get(self.varint()? as usize)
+                        .ok_or(CacheError::Corrupt)?;
+                    builder.token(kind, text);
+                },
+                _ => return Err(CacheError::Corrupt),
+            }
+        }
+
+        if self.cursor != self.bytes.len() {
+            return Err(CacheError::Corrupt);
+        }
+
+        Ok(builder.finish().0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::{hash, load, save, CacheError, HEADER_LEN};
+    use crate::parser::{machinery::cstree::NodeCache, parse};
+
+    fn round_trip(source: &str) -> (Vec<u8>, String) {
+        let mut cache = NodeCache::new();
+        let (root, _) = parse(&mut cache, source);
+        (
+            save(&cache, &root, source),
+            root.debug(cache.interner(), true),
+        )
+    }
+
+    #[test]
+    fn load_saved_tree() {
+        let source = fs::read_to_string("test-files/mixed.lua").unwrap();
+        let (bytes, expected) = round_trip(&source);
+
+        let mut cache = NodeCache::new();
+        let root = load(&mut cache, &bytes, &source).unwrap();
+        assert_eq!(root.debug(cache.interner(), true), expected);
+    }
+
+    #[test]
+    fn reject_stale_cache() {
+        let (bytes, _) = round_trip("local x = 1");
+        let result = load(&mut NodeCache::new(), &bytes, "local x = 2");
+        assert_eq!(result.err(), Some(CacheError::Stale));
+    }
+
+    #[test]
+    fn reject_other_version() {
+        let (mut bytes, _) = round_trip("local x = 1");
+        bytes[4] = 0xff;
+        let result = load(&mut NodeCache::new(), &bytes, "local x = 1");
+        assert!(matches!(result, Err(CacheError::Version(_))));
+    }
+
+    #[test]
+    fn reject_corrupt_cache() {
+        let source = "local function f(a, b) return a .. b end print(f('x', 'y'))";
+        let (bytes, _) = round_trip(source);
+
+        assert_eq!(
+            load(&mut NodeCache::new(), &bytes[..3], source).err(),
+            Some(CacheError::NotACache)
+        );
+
+        for len in HEADER_LEN..bytes.len() {
+            let result = load(&mut NodeCache::new(), &bytes[..len], source);
+            assert_eq!(result.err(), Some(CacheError::Corrupt));
+        }
+
+        for at in HEADER_LEN..bytes.len() {
+            let mut bytes = bytes.clone();
+            bytes[at] ^= 0x41;
+            let result = load(&mut NodeCache::new(), &bytes, source);
+            assert_eq!(result.err(), Some(CacheError::Corrupt));
+
+            // With a matching payload hash the damage has to be caught, or
+            // tolerated, by the decoder itself.
+            let payload = hash(&bytes[HEADER_LEN..]);
+            bytes[16..HEADER_LEN].copy_from_slice(&payload.to_le_bytes());
+            let _ = load(&mut NodeCache::new(), &bytes, source);
+        }
+    }
+}
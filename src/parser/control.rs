@@ -129,6 +129,21 @@ impl<'cache, 'source> Parser<'cache, 'source> {
         Some(marker.complete(self))
     }
 
+    pub(super) fn r_goto(&mut self) -> Option<CompletedMarker> {
+        let marker = self.start(T![goto_stmt]);
+        self.expect(T![goto]);
+        self.r_ident();
+        Some(marker.complete(self))
+    }
+
+    pub(super) fn r_label(&mut self) -> Option<CompletedMarker> {
+        let marker = self.start(T![label_stmt]);
+        self.expect(T![::]);
+        self.r_ident();
+        self.expect(T![::]);
+        Some(marker.complete(self))
+    }
+
     pub(super) fn r_block<F>(&mut self, stop: F) -> Option<CompletedMarker>
     where
         F: Fn(SyntaxKind) -> bool,
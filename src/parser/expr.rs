@@ -7,7 +7,13 @@ use crate::{
             CALL_BINDING_POWER,
             INDEX_BINDING_POWER,
         },
-        classifiers::{token_is_expr_start, token_is_literal, token_is_unary_op},
+        classifiers::{
+            token_is_call_args_start,
+            token_is_expr_start,
+            token_is_literal,
+            token_is_unary_op,
+        },
+        literal,
     },
     T,
 };
@@ -38,7 +44,7 @@ impl<'cache, 'source> Parser<'cache, 'source> {
         loop {
             let t = self.at();
 
-            if t == T!['('] && CALL_BINDING_POWER >= min_bp {
+            if token_is_call_args_start(t) && CALL_BINDING_POWER >= min_bp {
                 let n = lhs.precede(self, T![func_call]);
                 let _rhs = self.r_func_call_args()?;
                 lhs = n.complete(self);
@@ -114,9 +120,19 @@ impl<'cache, 'source> Parser<'cache, 'source> {
         Some(marker.complete(self))
     }
 
-    fn r_literal(&mut self) -> Option<CompletedMarker> {
+    pub(super) fn r_literal(&mut self) -> Option<CompletedMarker> {
         let marker = self.start(T![literal_expr]);
         let kind = self.at();
+
+        if let Err(error) = literal::decode(kind, self.source(self.span())) {
+            let source = self.source(self.span());
+            let error = self
+                .new_error(error.to_string())
+                .with_label(format!("malformed literal \"{}\"", source));
+
+            self.report(error);
+        }
+
         self.expect(kind);
         Some(marker.complete(self))
     }
@@ -4,6 +4,20 @@ use crate::T;
 impl<'cache, 'source> Parser<'cache, 'source> {
     pub(super) fn r_func_call_args(&mut self) -> Option<CompletedMarker> {
         let marker = self.start(T![func_args]);
+
+        // `f"str"`, `f[[str]]` and `f{...}` pass a single argument.
+        match self.at() {
+            T![string] | T![long_string] => {
+                self.r_literal();
+                return Some(marker.complete(self));
+            },
+            T!['{'] => {
+                self.r_table();
+                return Some(marker.complete(self));
+            },
+            _ => {},
+        }
+
         self.expect(T!['(']);
 
         loop {
@@ -0,0 +1,279 @@
+use super::{
+    machinery::{diagnostic::Diagnostic, kind::SyntaxKind, source_map::SourceMap, span::Span},
+    syntax::SyntaxNode,
+};
+use crate::T;
+
+/// Checks that every `goto` refers to a visible label, that labels are not
+/// redefined and that every `break` is inside a loop, following the rules of
+/// Lua 5.4.
+///
+/// A label is visible in the entire block where it is defined, including
+/// nested blocks but excluding nested functions. A `goto` may jump to any
+/// visible label as long as it does not enter the scope of a local, except
+/// that a label followed only by void statements at the end of its block is
+/// considered outside of the scope of the locals declared in that block.
+pub(super) fn validate(root: &SyntaxNode, source: &str) -> Vec<Diagnostic> {
+    check(root, source)
+        .into_iter()
+        .map(|(span, message)| Diagnostic::new(span, message))
+        .collect()
+}
+
+// Returns the source span and message of every error in the tree.
+fn check(root: &SyntaxNode, source: &str) -> Vec<(Span, String)> {
+    let mut checker = Checker {
+        source,
+        map: SourceMap::new(source),
+        active: Vec::new(),
+        loops: 0,
+        errors: Vec::new(),
+    };
+
+    checker.function(root);
+    checker.errors
+}
+
+struct Checker<'source> {
+    source: &'source str,
+    map: SourceMap,
+    // The labels of the current function whose blocks are still open, in
+    // order of definition.
+    active: Vec<(&'source str, usize)>,
+    // The number of loops of the current function enclosing the statement
+    // being checked.
+    loops: usize,
+    errors: Vec<(Span, String)>,
+}
+
+// A `goto` whose label has not been found in the blocks checked so far.
+struct Pending<'source> {
+    label: &'source str,
+    span: Span,
+    line: usize,
+}
+
+impl<'source> Checker<'source> {
+    fn function(&mut self, body: &SyntaxNode) {
+        // Labels of the enclosing function are not visible.
+        let active = std::mem::take(&mut self.active);
+        let loops = std::mem::replace(&mut self.loops, 0);
+
+        for goto in self.block(body, false) {
+            let message = format!(
+                "no visible label '{}' for <goto> at line {}",
+                goto.label, goto.line
+            );
+            self.errors.push((goto.span, message));
+        }
+
+        self.active = active;
+        self.loops = loops;
+    }
+
+    // Checks a block and returns the gotos that must be resolved by an
+    // enclosing block.
+    fn block(&mut self, block: &SyntaxNode, repeat: bool) -> Vec<Pending<'source>> {
+        let stmts: Vec<_> = block.children().collect();
+        let depth = self.active.len();
+        let mut labels = Vec::new();
+        let mut pending = Vec::new();
+
+        for (i, stmt) in stmts.iter().enumerate() {
+            match stmt.kind() {
+                T![label_stmt] => {
+                    let name = match self.name(stmt) {
+                        Some(name) => name,
+                        None => continue,
+                    };
+
+                    let span = self.map.span(tree_span(stmt));
+                    let line = self.map.line(span.start());
+                    if let Some(&(_, defined)) =
+                        self.active.iter().find(|(label, _)| *label == name)
+                    {
+                        let message =
+                            format!("label '{}' already defined on line {}", name, defined);
+                        self.errors.push((span, message));
+                    }
+
+                    self.active.push((name, line));
+                    labels.push((name, i));
+                },
+                T![goto_stmt] =>
+                    if let Some(label) = self.name(stmt) {
+                        let span = self.map.span(tree_span(stmt));
+                        let line = self.map.line(span.start());
+                        pending.push((Pending { label, span, line }, i));
+                    },
+                T![break_stmt] =>
+                    if self.loops == 0 {
+                        let span = self.map.span(tree_span(stmt));
+                        let line = self.map.line(span.start());
+                        let message = format!("break outside a loop at line {}", line);
+                        self.errors.push((span, message));
+                    },
+                _ => {
+                    let mut nested = Vec::new();
+                    self.nested(stmt, &mut nested);
+                    pending.extend(nested.into_iter().map(|goto| (goto, i)));
+                },
+            }
+        }
+
+        self.active.truncate(depth);
+
+        let mut unresolved = Vec::new();
+        for (goto, from) in pending {
+            let to = match labels.iter().find(|(name, _)| *name == goto.label) {
+                Some(&(_, to)) => to,
+                None => {
+                    unresolved.push(goto);
+                    continue;
+                },
+            };
+
+            // The body of a `repeat` loop is followed by its condition, which
+            // can refer to the locals of the body.
+            let last = !repeat && stmts[to + 1..].iter().all(|stmt| is_void(stmt.kind()));
+            if to <= from || last {
+                continue;
+            }
+
+            let local = stmts[from + 1..to]
+                .iter()
+                .filter(|stmt| stmt.kind() == T![decl_stmt])
+                .find_map(|stmt| stmt.descendants().find(|node| node.kind() == T![ident]));
+
+            if let Some(local) = local {
+                let message = format!(
+                    "<goto {}> at line {} jumps into the scope of local '{}'",
+                    goto.label,
+                    goto.line,
+                    self.text(local)
+                );
+                self.errors.push((goto.span, message));
+            }
+        }
+
+        unresolved
+    }
+
+    // Checks the blocks and functions nested within a statement, collecting
+    // the gotos that leave them.
+    fn nested(&mut self, node: &SyntaxNode, pending: &mut Vec<Pending<'source>>) {
+        match node.kind() {
+            T![stmt_list] => {
+                let repeat = node
+                    .parent()
+                    .map_or(false, |parent| parent.kind() == T![repeat_stmt]);
+                pending.extend(self.block(node, repeat));
+            },
+            T![while_stmt] | T![repeat_stmt] | T![for_num_stmt] | T![for_gen_stmt] => {
+                self.loops += 1;
+                for child in node.children() {
+                    self.nested(child, pending);
+                }
+                self.loops -= 1;
+            },
+            T![func_stmt] | T![func_expr] => {
+                if let Some(body) = node.children().find(|child| child.kind() == T![stmt_list]) {
+                    self.function(body);
+                }
+            },
+            _ =>
+                for child in node.children() {
+                    self.nested(child, pending);
+                },
+        }
+    }
+
+    // The name of a label or the target of a goto.
+    fn name(&self, stmt: &SyntaxNode) -> Option<&'source str> {
+        let ident = stmt.children().find(|child| child.kind() == T![ident])?;
+        Some(self.text(ident))
+    }
+
+    fn text(&self, node: &SyntaxNode) -> &'source str {
+        &self.source[self.map.span(tree_span(node))]
+    }
+}
+
+fn tree_span(node: &SyntaxNode) -> Span {
+    let range = node.text_range();
+    Span::new(range.start().into(), range.end().into())
+}
+
+// Statements that do nothing, which may follow a label at the end of a block.
+fn is_void(kind: SyntaxKind) -> bool {
+    matches!(kind, T![label_stmt] | T![;])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::check;
+    use crate::parser::{machinery::cstree::NodeCache, parse};
+
+    fn errors(source: &str) -> Vec<String> {
+        let mut cache = NodeCache::new();
+        let (tree, _) = parse(&mut cache, source);
+        check(&tree, source)
+            .into_iter()
+            .map(|(_, message)| message)
+            .collect()
+    }
+
+    #[test]
+    fn accept_valid_gotos() {
+        let cases = &[
+            "for i = 1, 3 do if i == 2 then goto continue end local x = i ::continue:: end",
+            "while true do local x = 1 goto done end ::done::",
+            "::top:: local x = 1 goto top",
+            "do goto a end ::a:: ; ::b::",
+            "do ::a:: end ::a::",
+            "goto f local function f() ::f:: end ::f::",
+            "while true do if x then break end end",
+        ];
+
+        for source in cases {
+            assert!(errors(source).is_empty(), "{}", source);
+        }
+    }
+
+    #[test]
+    fn report_invalid_gotos() {
+        let cases: &[(&str, &str)] = &[
+            (
+                "goto nowhere",
+                "no visible label 'nowhere' for <goto> at line 1",
+            ),
+            (
+                "do ::a:: end goto a",
+                "no visible label 'a' for <goto> at line 1",
+            ),
+            (
+                "::a:: function f() goto a end",
+                "no visible label 'a' for <goto> at line 1",
+            ),
+            ("::a::\n::a::", "label 'a' already defined on line 1"),
+            ("::a::\ndo ::a:: end", "label 'a' already defined on line 1"),
+            (
+                "goto a\nlocal x = 1\n::a:: print(x)",
+                "<goto a> at line 1 jumps into the scope of local 'x'",
+            ),
+            (
+                "repeat\ngoto a\nlocal x ::a:: until x",
+                "<goto a> at line 2 jumps into the scope of local 'x'",
+            ),
+            ("break", "break outside a loop at line 1"),
+            (
+                "while true do\nlocal f = function() break end end",
+                "break outside a loop at line 2",
+            ),
+        ];
+
+        for (source, message) in cases {
+            assert_eq!(errors(source), [*message], "{}", source);
+        }
+    }
+}
@@ -26,6 +26,10 @@ pub fn token_is_expr_start(token: SyntaxKind) -> bool {
         || token == T![...]
 }
 
+pub fn token_is_call_args_start(token: SyntaxKind) -> bool {
+    matches!(token, T!['('] | T![string] | T![long_string] | T!['{'])
+}
+
 pub fn token_is_unary_op(token: SyntaxKind) -> bool {
     matches!(token, T![not] | T![+] | T![-] | T![#] | T![~])
 }
@@ -0,0 +1,57 @@
+use std::fmt::{self, Display};
+
+use super::span::Span;
+
+/// A problem found while parsing or compiling a chunk.
+///
+/// Parse errors are located in the source, compile errors in the syntax tree,
+/// see [`SourceMap`](super::source_map::SourceMap) to relate the two.
+pub struct Diagnostic {
+    span: Span,
+    message: String,
+    label: String,
+}
+
+impl Diagnostic {
+    /// Creates a diagnostic whose label repeats its message.
+    pub fn new(span: Span, message: impl Into<String>) -> Self {
+        let message = message.into();
+        Self {
+            span,
+            label: message.clone(),
+            message,
+        }
+    }
+
+    /// Replaces the label, which describes what was found at the span.
+    pub fn with_label(mut self, label: impl Into<String>) -> Self {
+        self.label = label.into();
+        self
+    }
+
+    pub fn span(&self) -> Span {
+        self.span
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    /// Builds a report to render the diagnostic with.
+    pub fn report(&self) -> ariadne::Report<Span> {
+        ariadne::Report::build(ariadne::ReportKind::Error, (), self.span.start() as usize)
+            .with_message(&self.message)
+            .with_label(ariadne::Label::new(self.span).with_message(&self.label))
+            .finish()
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
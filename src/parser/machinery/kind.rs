@@ -42,6 +42,8 @@ pub enum SyntaxKind {
     AssignStmt,
     LiteralExpr,
     AssignList,
+    GotoStmt,
+    LabelStmt,
 
     #[regex(r"[ \n\t\f\r]+", logos::skip)]
     Whitespace,
@@ -133,6 +135,9 @@ pub enum SyntaxKind {
     #[token("break")]
     Break,
 
+    #[token("goto")]
+    Goto,
+
     #[token("for")]
     For,
 
@@ -194,13 +199,15 @@ pub enum SyntaxKind {
     #[regex(r"[0-9]+", priority = 2)]
     Int,
 
-    #[regex(r"0x[0-9a-fA-F]+", priority = 6)]
+    #[regex(r"0[xX][0-9a-fA-F]+", priority = 6)]
     HexInt,
 
-    #[regex(r"[0-9]+(\.[0-9]+)?([eE][+-]?[0-9]+)?")]
+    #[regex(r"[0-9]+(\.[0-9]*)?([eE][+-]?[0-9]+)?")]
+    #[regex(r"\.[0-9]+([eE][+-]?[0-9]+)?")]
     Float,
 
-    #[regex(r"0x[0-9a-fA-F]+(\.[0-9a-fA-F]+)?([pP][+-][0-9a-fA-F]+)?")]
+    #[regex(r"0[xX][0-9a-fA-F]+(\.[0-9a-fA-F]*)?([pP][+-]?[0-9]+)?")]
+    #[regex(r"0[xX]\.[0-9a-fA-F]+([pP][+-]?[0-9]+)?")]
     HexFloat,
 
     #[regex(r"[a-zA-Z_][a-zA-Z0-9_]*", priority = 3)]
@@ -360,6 +367,8 @@ macro_rules! T {
     [literal_expr] => { $crate::parser::machinery::kind::SyntaxKind::LiteralExpr };
     [ident] => { $crate::parser::machinery::kind::SyntaxKind::Ident };
     [assign_list] => { $crate::parser::machinery::kind::SyntaxKind::AssignList };
+    [goto_stmt] => { $crate::parser::machinery::kind::SyntaxKind::GotoStmt };
+    [label_stmt] => { $crate::parser::machinery::kind::SyntaxKind::LabelStmt };
     [+] => { $crate::parser::machinery::kind::SyntaxKind::Plus };
     [-] => { $crate::parser::machinery::kind::SyntaxKind::Minus };
     [*] => { $crate::parser::machinery::kind::SyntaxKind::Star };
@@ -386,6 +395,7 @@ macro_rules! T {
     [in] => { $crate::parser::machinery::kind::SyntaxKind::In };
     [then] => { $crate::parser::machinery::kind::SyntaxKind::Then };
     [break] => { $crate::parser::machinery::kind::SyntaxKind::Break };
+    [goto] => { $crate::parser::machinery::kind::SyntaxKind::Goto };
     [for] => { $crate::parser::machinery::kind::SyntaxKind::For };
     [do] => { $crate::parser::machinery::kind::SyntaxKind::Do };
     [until] => { $crate::parser::machinery::kind::SyntaxKind::Until };
@@ -461,6 +471,7 @@ impl Display for SyntaxKind {
                 T![in] => "IN",
                 T![then] => "THEN",
                 T![break] => "BREAK",
+                T![goto] => "GOTO",
                 T![for] => "FOR",
                 T![do] => "DO",
                 T![until] => "UNTIL",
@@ -0,0 +1,454 @@
+use std::fmt::{self, Display};
+
+use super::kind::SyntaxKind;
+use crate::T;
+
+/// The decoded value of a literal token.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LiteralValue {
+    Nil,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    String(Vec<u8>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LiteralError {
+    MalformedNumber,
+    UnfinishedString,
+    InvalidEscape,
+    DecimalEscapeTooLarge,
+    HexadecimalEscapeTooShort,
+    UnicodeEscapeTooLarge,
+    MalformedUnicodeEscape,
+    NotALiteral,
+}
+
+impl Display for LiteralError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::MalformedNumber => "malformed number",
+            Self::UnfinishedString => "unfinished string",
+            Self::InvalidEscape => "invalid escape sequence",
+            Self::DecimalEscapeTooLarge => "decimal escape too large",
+            Self::HexadecimalEscapeTooShort => "hexadecimal digit expected",
+            Self::UnicodeEscapeTooLarge => "UTF-8 value too large",
+            Self::MalformedUnicodeEscape => "malformed unicode escape",
+            Self::NotALiteral => "not a literal",
+        })
+    }
+}
+
+/// Decodes the source text of a literal token into its value.
+pub fn decode(kind: SyntaxKind, text: &str) -> Result<LiteralValue, LiteralError> {
+    match kind {
+        T![nil] => Ok(LiteralValue::Nil),
+        T![true] => Ok(LiteralValue::Bool(true)),
+        T![false] => Ok(LiteralValue::Bool(false)),
+        T![int] => Ok(decode_int(text)),
+        T![hex_int] => decode_hex_int(text).map(LiteralValue::Int),
+        T![float] => decode_float(text).map(LiteralValue::Float),
+        T![hex_float] => decode_hex_float(text).map(LiteralValue::Float),
+        T![string] => decode_string(text).map(LiteralValue::String),
+        T![long_string] => decode_long_string(text).map(LiteralValue::String),
+        _ => Err(LiteralError::NotALiteral),
+    }
+}
+
+// Decimal integers that do not fit into an integer are converted to floats.
+fn decode_int(text: &str) -> LiteralValue {
+    match text.parse::<i64>() {
+        Ok(x) => LiteralValue::Int(x),
+        Err(_) => LiteralValue::Float(text.parse::<f64>().unwrap_or(f64::INFINITY)),
+    }
+}
+
+// Hexadecimal integers wrap around on overflow.
+fn decode_hex_int(text: &str) -> Result<i64, LiteralError> {
+    let digits = strip_hex_prefix(text)?;
+    let mut value: u64 = 0;
+
+    for c in digits.chars() {
+        let digit = c.to_digit(16).ok_or(LiteralError::MalformedNumber)?;
+        value = value.wrapping_mul(16).wrapping_add(digit as u64);
+    }
+
+    Ok(value as i64)
+}
+
+fn decode_float(text: &str) -> Result<f64, LiteralError> {
+    text.parse::<f64>()
+        .map_err(|_| LiteralError::MalformedNumber)
+}
+
+fn decode_hex_float(text: &str) -> Result<f64, LiteralError> {
+    let digits = strip_hex_prefix(text)?;
+    let (digits, exponent) = match digits.find(['p', 'P']) {
+        Some(idx) => {
+            let exponent = digits[idx + 1..]
+                .parse::<i32>()
+                .map_err(|_| LiteralError::MalformedNumber)?;
+
+            (&digits[..idx], exponent)
+        },
+        None => (digits, 0),
+    };
+
+    let mut mantissa: u64 = 0;
+    let mut exponent = exponent as i64;
+    let mut seen_dot = false;
+    let mut seen_digit = false;
+
+    for c in digits.chars() {
+        if c == '.' {
+            if seen_dot {
+                return Err(LiteralError::MalformedNumber);
+            }
+
+            seen_dot = true;
+            continue;
+        }
+
+        let digit = c.to_digit(16).ok_or(LiteralError::MalformedNumber)? as u64;
+        seen_digit = true;
+
+        // Once the mantissa is saturated further digits only scale the result.
+        if mantissa >> 59 == 0 {
+            mantissa = mantissa * 16 + digit;
+            if seen_dot {
+                exponent -= 4;
+            }
+        } else if !seen_dot {
+            exponent += 4;
+        }
+    }
+
+    if !seen_digit {
+        return Err(LiteralError::MalformedNumber);
+    }
+
+    Ok(ldexp(mantissa as f64, exponent))
+}
+
+fn ldexp(mut x: f64, mut exponent: i64) -> f64 {
+    while exponent > 0 && x.is_finite() && x != 0.0 {
+        let step = exponent.min(1000);
+        x *= 2f64.powi(step as i32);
+        exponent -= step;
+    }
+
+    while exponent < 0 && x != 0.0 {
+        let step = exponent.max(-1000);
+        x *= 2f64.powi(step as i32);
+        exponent -= step;
+    }
+
+    x
+}
+
+fn strip_hex_prefix(text: &str) -> Result<&str, LiteralError> {
+    text.strip_prefix("0x")
+        .or_else(|| text.strip_prefix("0X"))
+        .ok_or(LiteralError::MalformedNumber)
+}
+
+fn decode_string(text: &str) -> Result<Vec<u8>, LiteralError> {
+    let bytes = text.as_bytes();
+    let quote = *bytes.first().ok_or(LiteralError::UnfinishedString)?;
+
+    if bytes.len() < 2 || bytes[bytes.len() - 1] != quote || !matches!(quote, b'"' | b'\'') {
+        return Err(LiteralError::UnfinishedString);
+    }
+
+    let bytes = &bytes[1..bytes.len() - 1];
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' => i = decode_escape(bytes, i + 1, &mut out)?,
+            b'\n' | b'\r' => return Err(LiteralError::UnfinishedString),
+            byte => {
+                out.push(byte);
+                i += 1;
+            },
+        }
+    }
+
+    Ok(out)
+}
+
+// Decodes the escape sequence starting at `i`, just past the backslash, and
+// returns the index of the first byte after it.
+fn decode_escape(bytes: &[u8], i: usize, out: &mut Vec<u8>) -> Result<usize, LiteralError> {
+    let c = *bytes.get(i).ok_or(LiteralError::UnfinishedString)?;
+
+    let simple = match c {
+        b'a' => Some(0x07),
+        b'b' => Some(0x08),
+        b'f' => Some(0x0C),
+        b'n' => Some(b'\n'),
+        b'r' => Some(b'\r'),
+        b't' => Some(b'\t'),
+        b'v' => Some(0x0B),
+        b'\\' => Some(b'\\'),
+        b'"' => Some(b'"'),
+        b'\'' => Some(b'\''),
+        _ => None,
+    };
+
+    if let Some(byte) = simple {
+        out.push(byte);
+        return Ok(i + 1);
+    }
+
+    match c {
+        b'\n' | b'\r' => {
+            out.push(b'\n');
+            Ok(skip_newline(bytes, i))
+        },
+        // `\z` skips the following whitespace, including line breaks.
+        b'z' => {
+            let mut end = i + 1;
+            while bytes
+                .get(end)
+                .map_or(false, |b| b.is_ascii_whitespace() || *b == 0x0B)
+            {
+                end += 1;
+            }
+
+            Ok(end)
+        },
+        b'x' => {
+            let mut value = 0;
+            for offset in 1..=2 {
+                let digit = bytes
+                    .get(i + offset)
+                    .and_then(|b| (*b as char).to_digit(16))
+                    .ok_or(LiteralError::HexadecimalEscapeTooShort)?;
+
+                value = value * 16 + digit;
+            }
+
+            out.push(value as u8);
+            Ok(i + 3)
+        },
+        b'0'..=b'9' => {
+            let mut value: u32 = 0;
+            let mut end = i;
+            while end < bytes.len() && end < i + 3 && bytes[end].is_ascii_digit() {
+                value = value * 10 + (bytes[end] - b'0') as u32;
+                end += 1;
+            }
+
+            if value > u8::MAX as u32 {
+                return Err(LiteralError::DecimalEscapeTooLarge);
+            }
+
+            out.push(value as u8);
+            Ok(end)
+        },
+        b'u' => {
+            if bytes.get(i + 1) != Some(&b'{') {
+                return Err(LiteralError::MalformedUnicodeEscape);
+            }
+
+            let mut value: u32 = 0;
+            let mut end = i + 2;
+            while let Some(digit) = bytes.get(end).and_then(|b| (*b as char).to_digit(16)) {
+                if value > 0x7FFFFFF {
+                    return Err(LiteralError::UnicodeEscapeTooLarge);
+                }
+
+                value = value * 16 + digit;
+                end += 1;
+            }
+
+            if end == i + 2 || bytes.get(end) != Some(&b'}') {
+                return Err(LiteralError::MalformedUnicodeEscape);
+            }
+
+            if value > 0x7FFFFFFF {
+                return Err(LiteralError::UnicodeEscapeTooLarge);
+            }
+
+            encode_utf8(value, out);
+            Ok(end + 1)
+        },
+        _ => Err(LiteralError::InvalidEscape),
+    }
+}
+
+// Skips a newline sequence (`\n`, `\r`, `\n\r` or `\r\n`) starting at `i`.
+fn skip_newline(bytes: &[u8], i: usize) -> usize {
+    let first = bytes[i];
+    match bytes.get(i + 1) {
+        Some(&next) if matches!(next, b'\n' | b'\r') && next != first => i + 2,
+        _ => i + 1,
+    }
+}
+
+// Encodes a code point using the extended UTF-8 scheme from Lua which allows
+// values up to 2^31.
+fn encode_utf8(mut x: u32, out: &mut Vec<u8>) {
+    if x < 0x80 {
+        out.push(x as u8);
+        return;
+    }
+
+    let mut buf = [0; 6];
+    let mut len = 0;
+    let mut max_first = 0x3F;
+
+    while x > max_first {
+        buf[5 - len] = 0x80 | (x & 0x3F) as u8;
+        x >>= 6;
+        max_first >>= 1;
+        len += 1;
+    }
+
+    buf[5 - len] = ((!max_first << 1) | x) as u8;
+    out.extend_from_slice(&buf[5 - len..]);
+}
+
+fn decode_long_string(text: &str) -> Result<Vec<u8>, LiteralError> {
+    let bytes = text.as_bytes();
+    let level = bytes.iter().skip(1).take_while(|b| **b == b'=').count();
+    let delim_len = level + 2;
+
+    if bytes.len() < delim_len * 2 {
+        return Err(LiteralError::UnfinishedString);
+    }
+
+    let body = &bytes[delim_len..bytes.len() - delim_len];
+    let mut out = Vec::with_capacity(body.len());
+    let mut i = 0;
+
+    // A newline immediately following the opening bracket is not included.
+    if matches!(body.first(), Some(b'\n' | b'\r')) {
+        i = skip_newline(body, 0);
+    }
+
+    while i < body.len() {
+        match body[i] {
+            b'\n' | b'\r' => {
+                out.push(b'\n');
+                i = skip_newline(body, i);
+            },
+            byte => {
+                out.push(byte);
+                i += 1;
+            },
+        }
+    }
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, LiteralError, LiteralValue};
+    use crate::{
+        parser::{machinery::cstree::NodeCache, parse},
+        T,
+    };
+
+    fn string(text: &str) -> Result<LiteralValue, LiteralError> {
+        decode(T![string], text)
+    }
+
+    #[test]
+    fn decode_numbers() {
+        assert_eq!(decode(T![int], "581"), Ok(LiteralValue::Int(581)));
+        assert_eq!(
+            decode(T![int], "9223372036854775808"),
+            Ok(LiteralValue::Float(9223372036854775808.0))
+        );
+        assert_eq!(decode(T![hex_int], "0xFF"), Ok(LiteralValue::Int(255)));
+        assert_eq!(
+            decode(T![hex_int], "0xffffffffffffffff"),
+            Ok(LiteralValue::Int(-1))
+        );
+        assert_eq!(decode(T![float], "0.5e5"), Ok(LiteralValue::Float(50000.0)));
+        assert_eq!(decode(T![float], ".5"), Ok(LiteralValue::Float(0.5)));
+        assert_eq!(
+            decode(T![hex_float], "0xce.1"),
+            Ok(LiteralValue::Float(206.0625))
+        );
+        assert_eq!(
+            decode(T![hex_float], "0x1.9p-3"),
+            Ok(LiteralValue::Float(0.1953125))
+        );
+        assert_eq!(
+            decode(T![hex_float], "0xAp2"),
+            Ok(LiteralValue::Float(40.0))
+        );
+        assert_eq!(decode(T![float], "1e"), Err(LiteralError::MalformedNumber));
+    }
+
+    #[test]
+    fn decode_short_strings() {
+        assert_eq!(
+            string("'world'"),
+            Ok(LiteralValue::String(b"world".to_vec()))
+        );
+        assert_eq!(
+            string(r#""\a\b\f\n\r\t\v\\\"\'""#),
+            Ok(LiteralValue::String(
+                b"\x07\x08\x0C\n\r\t\x0B\\\"'".to_vec()
+            ))
+        );
+        assert_eq!(
+            string(r#""\x41\65\0669\u{48}\u{20AC}""#),
+            Ok(LiteralValue::String("AAB9H€".as_bytes().to_vec()))
+        );
+        assert_eq!(
+            string("\"a\\\r\nb\""),
+            Ok(LiteralValue::String(b"a\nb".to_vec()))
+        );
+        assert_eq!(
+            string(r#""\u{7FFFFFFF}""#),
+            Ok(LiteralValue::String(vec![
+                0xFD, 0xBF, 0xBF, 0xBF, 0xBF, 0xBF
+            ]))
+        );
+        assert_eq!(
+            string("\"a\\z  \n\t  b\\z\""),
+            Ok(LiteralValue::String(b"ab".to_vec()))
+        );
+        assert_eq!(string(r#""\q""#), Err(LiteralError::InvalidEscape));
+        assert_eq!(
+            string(r#""\256""#),
+            Err(LiteralError::DecimalEscapeTooLarge)
+        );
+        assert_eq!(
+            string(r#""\x4""#),
+            Err(LiteralError::HexadecimalEscapeTooShort)
+        );
+        assert_eq!(
+            string(r#""\u{80000000}""#),
+            Err(LiteralError::UnicodeEscapeTooLarge)
+        );
+        assert_eq!(string("\"a\nb\""), Err(LiteralError::UnfinishedString));
+    }
+
+    #[test]
+    fn decode_long_strings() {
+        assert_eq!(
+            decode(T![long_string], "[[woosh]]"),
+            Ok(LiteralValue::String(b"woosh".to_vec()))
+        );
+        assert_eq!(
+            decode(T![long_string], "[==[\r\nline\r\nnext]]]==]"),
+            Ok(LiteralValue::String(b"line\nnext]]".to_vec()))
+        );
+    }
+
+    #[test]
+    fn report_malformed_literal() {
+        let mut cache = NodeCache::new();
+        let (_, reports) = parse(&mut cache, r#"return "\q""#);
+        assert_eq!(reports.len(), 1);
+    }
+}
@@ -1,9 +1,12 @@
 pub mod binding_power;
 pub mod classifiers;
 pub mod cstree;
+pub mod diagnostic;
 pub mod event;
 pub mod kind;
+pub mod literal;
 pub mod marker;
 pub mod sink;
+pub mod source_map;
 pub mod span;
 pub mod state;
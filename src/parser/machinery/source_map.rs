@@ -0,0 +1,83 @@
+use logos::Logos;
+
+use super::{kind::SyntaxKind, span::Span};
+
+/// Translates offsets within a syntax tree back to offsets within its source.
+///
+/// The lexer drops whitespace and comments, so the text of a tree is the
+/// concatenation of its tokens and tree offsets drift from source offsets
+/// after the first skipped character. The map records where every token
+/// starts in both and the byte offset at which every line begins.
+pub struct SourceMap {
+    tokens: Vec<(u32, u32)>,
+    lines: Vec<u32>,
+}
+
+impl SourceMap {
+    pub fn new(source: &str) -> Self {
+        let mut tokens = Vec::new();
+        let mut offset = 0;
+
+        for (_, range) in SyntaxKind::lexer(source).spanned() {
+            tokens.push((offset, range.start as u32));
+            offset += range.len() as u32;
+        }
+
+        tokens.push((offset, source.len() as u32));
+
+        let lines = std::iter::once(0)
+            .chain(source.match_indices('\n').map(|(i, _)| i as u32 + 1))
+            .collect();
+
+        Self { tokens, lines }
+    }
+
+    /// Maps a single tree offset to the corresponding source offset.
+    pub fn offset(&self, offset: u32) -> u32 {
+        let index = match self.tokens.binary_search_by_key(&offset, |&(tree, _)| tree) {
+            Ok(index) => index,
+            Err(index) => index.saturating_sub(1),
+        };
+
+        let (tree, source) = self.tokens[index];
+        source + (offset - tree)
+    }
+
+    /// Maps a span within the tree to the span of source text it was parsed
+    /// from.
+    pub fn span(&self, span: Span) -> Span {
+        let start = self.offset(span.start());
+        let end = if span.end() > span.start() {
+            self.offset(span.end() - 1) + 1
+        } else {
+            start
+        };
+
+        Span::new(start, end)
+    }
+
+    /// Returns the 1-based line containing a source offset.
+    pub fn line(&self, offset: u32) -> usize {
+        match self.lines.binary_search(&offset) {
+            Ok(index) => index + 1,
+            Err(index) => index,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SourceMap, Span};
+
+    #[test]
+    fn map_tree_spans() {
+        let source = "local  x = 1\n-- comment\nreturn x";
+        let map = SourceMap::new(source);
+
+        // The tree text is "localx=1returnx".
+        assert_eq!(map.span(Span::new(5, 6)), Span::new(7, 8));
+        assert_eq!(map.span(Span::new(8, 15)), Span::new(24, 32));
+        assert_eq!(map.line(map.offset(14)), 3);
+        assert_eq!(map.line(0), 1);
+    }
+}
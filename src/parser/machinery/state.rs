@@ -4,6 +4,7 @@ use logos::Logos;
 
 use super::{
     cstree::{GreenNode, NodeCache},
+    diagnostic::Diagnostic,
     event::Event,
     kind::SyntaxKind,
     marker::Marker,
@@ -18,7 +19,7 @@ pub struct State<'cache, 'source> {
     cursor: usize,
     source: &'source str,
     events: Vec<Event>,
-    reports: Vec<ariadne::Report<Span>>,
+    reports: Vec<Diagnostic>,
 }
 
 impl<'cache, 'source> State<'cache, 'source> {
@@ -30,7 +31,7 @@ impl<'cache, 'source> State<'cache, 'source> {
                 .map(|(kind, range)| (kind, Span::from_range(range))),
         );
 
-        tokens.push((T![eof], Span::from_range(0..0)));
+        tokens.push((T![eof], Span::from_range(source.len()..source.len())));
         let estimated_events = source.len() / 4;
 
         State {
@@ -43,19 +44,24 @@ impl<'cache, 'source> State<'cache, 'source> {
         }
     }
 
+    // Error recovery may move past the end, where every token is `eof`.
     pub fn at(&self) -> SyntaxKind {
-        self.tokens[self.cursor].0
+        self.tokens
+            .get(self.cursor)
+            .map_or(T![eof], |(kind, _)| *kind)
     }
 
     pub fn peek(&self) -> SyntaxKind {
-        self.tokens[self.cursor + 1..]
+        self.tokens
             .iter()
+            .skip(self.cursor + 1)
             .find_map(|(t, _)| t.is_trivia().not().then(|| *t))
-            .unwrap()
+            .unwrap_or(T![eof])
     }
 
     pub fn span(&self) -> Span {
-        self.tokens[self.cursor].1
+        let last = self.tokens.len() - 1;
+        self.tokens[self.cursor.min(last)].1
     }
 
     pub fn start(&mut self, kind: SyntaxKind) -> Marker {
@@ -72,30 +78,21 @@ impl<'cache, 'source> State<'cache, 'source> {
             self.bump();
             true
         } else {
-            self.report(
-                self.new_error()
-                    .with_message("unexpected token")
-                    .with_label(self.new_label().with_message(format!(
-                        "expected token {} but found {}",
-                        kind,
-                        self.at()
-                    )))
-                    .finish(),
-            );
+            self.report(self.new_error("unexpected token").with_label(format!(
+                "expected token {} but found {}",
+                kind,
+                self.at()
+            )));
             false
         }
     }
 
-    pub fn report(&mut self, error: ariadne::Report<Span>) {
+    pub fn report(&mut self, error: Diagnostic) {
         self.reports.push(error);
     }
 
-    pub fn new_error(&self) -> ariadne::ReportBuilder<Span> {
-        ariadne::Report::build(ariadne::ReportKind::Error, (), self.span().start() as usize)
-    }
-
-    pub fn new_label(&self) -> ariadne::Label<Span> {
-        ariadne::Label::new(self.span())
+    pub fn new_error(&self, message: impl Into<String>) -> Diagnostic {
+        Diagnostic::new(self.span(), message)
     }
 
     fn bump(&mut self) {
@@ -114,7 +111,7 @@ impl<'cache, 'source> State<'cache, 'source> {
     pub fn error_eat_until(&mut self, one_of: &[SyntaxKind]) -> Span {
         let marker = self.start(T![invalid]);
         let mut last_span = self.span();
-        while !one_of.contains(&self.at()) {
+        while !one_of.contains(&self.at()) && self.at() != T![eof] {
             self.bump();
             last_span = self.span();
         }
@@ -123,7 +120,7 @@ impl<'cache, 'source> State<'cache, 'source> {
         last_span
     }
 
-    pub fn finish(self) -> (GreenNode, Vec<ariadne::Report<Span>>) {
+    pub fn finish(self) -> (GreenNode, Vec<Diagnostic>) {
         let tree = Sink::new(self.cache, &self.tokens, self.events, self.source).finish();
         (tree, self.reports)
     }
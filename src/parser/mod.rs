@@ -1,9 +1,12 @@
 mod assign;
+pub mod cache;
 mod control;
 mod expr;
 mod function;
+mod goto;
 mod item;
 pub mod machinery;
+pub mod resolve;
 mod simple_expr;
 mod stmt;
 pub mod syntax;
@@ -11,7 +14,7 @@ mod table;
 
 use std::ops::{Deref, DerefMut};
 
-use machinery::{cstree::NodeCache, span::Span, state::State};
+use machinery::{cstree::NodeCache, diagnostic::Diagnostic, state::State};
 use syntax::SyntaxNode;
 
 use crate::T;
@@ -33,7 +36,7 @@ impl<'cache, 'source> Parser<'cache, 'source> {
         marker.complete(self);
     }
 
-    fn run(mut self) -> (SyntaxNode, Vec<ariadne::Report<Span>>) {
+    fn run(mut self) -> (SyntaxNode, Vec<Diagnostic>) {
         self.root();
         let (root, reports) = self.state.finish();
         (SyntaxNode::new_root(root), reports)
@@ -54,11 +57,10 @@ impl<'cache, 'source> DerefMut for Parser<'cache, 'source> {
     }
 }
 
-pub fn parse(
-    cache: &mut NodeCache<'static>,
-    source: &str,
-) -> (SyntaxNode, Vec<ariadne::Report<Span>>) {
-    Parser::new(cache, source).run()
+pub fn parse(cache: &mut NodeCache<'static>, source: &str) -> (SyntaxNode, Vec<Diagnostic>) {
+    let (root, mut reports) = Parser::new(cache, source).run();
+    reports.extend(resolve::validate(&root, cache.interner(), source));
+    (root, reports)
 }
 
 #[cfg(test)]
@@ -97,4 +99,6 @@ mod tests {
     parse_and_verify!(literal, "test-files/literal.lua");
     parse_and_verify!(comment, "test-files/comment.lua");
     parse_and_verify!(mixed, "test-files/mixed.lua");
+    parse_and_verify!(goto, "test-files/goto.lua");
+    parse_and_verify!(call, "test-files/call.lua");
 }
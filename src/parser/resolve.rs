@@ -0,0 +1,647 @@
+//! Static scope resolution.
+//!
+//! Before a chunk is compiled, every identifier that names a variable is
+//! classified as a local of the function it appears in, an upvalue of that
+//! function or a field of the global table. Locals are numbered by slot, in
+//! order of declaration within their function, with the parameters first.
+//! Upvalues are numbered in order of first use and record whether they are
+//! captured from a local or an upvalue of the enclosing function.
+//!
+//! Assignments to `<const>` and `<close>` variables are reported here, along
+//! with the checks of `goto` and `break` statements.
+
+use std::collections::HashMap;
+
+use super::{
+    goto,
+    machinery::{
+        cstree::interning::TokenInterner,
+        diagnostic::Diagnostic,
+        source_map::SourceMap,
+        span::Span,
+    },
+    syntax::{
+        BinaryOperator,
+        Block,
+        Decl,
+        DeclModifier,
+        Expr,
+        ForGen,
+        ForNum,
+        Func,
+        Ident,
+        If,
+        Root,
+        Stmt,
+        SyntaxNode,
+        TableEntry,
+    },
+};
+
+/// What a variable name refers to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Binding {
+    /// The local with the given slot in the enclosing function.
+    Local(u32),
+    /// The upvalue with the given index in the enclosing function.
+    Upvalue(u16),
+    /// A field of the global table.
+    Global,
+}
+
+/// Where a function finds one of its upvalues when it is created.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capture {
+    /// The local with the given slot in the enclosing function.
+    Local(u32),
+    /// The upvalue with the given index in the enclosing function.
+    Upvalue(u16),
+}
+
+/// The attribute of a local declared with `<const>` or `<close>`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Attrib {
+    Const,
+    Close,
+}
+
+#[derive(Debug, Clone)]
+pub struct Local {
+    pub name: String,
+    pub attrib: Option<Attrib>,
+    /// Whether any nested function refers to the local.
+    pub captured: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Upvalue {
+    pub name: String,
+    pub capture: Capture,
+}
+
+/// The variables of a function.
+#[derive(Debug, Clone, Default)]
+pub struct Function {
+    /// Every local declared in the function, indexed by slot.
+    pub locals: Vec<Local>,
+    pub upvalues: Vec<Upvalue>,
+}
+
+/// The result of resolving a chunk.
+#[derive(Debug, Default)]
+pub struct Resolution {
+    // Keyed by the offset of the identifier in the tree.
+    bindings: HashMap<u32, Binding>,
+    main: Function,
+    // Keyed by the offset of the body of the function in the tree.
+    functions: HashMap<u32, Function>,
+    errors: Vec<(Span, String)>,
+}
+
+impl Resolution {
+    /// What an identifier refers to, or nothing if it does not name a
+    /// variable, like the name of a field or a label.
+    pub fn binding(&self, ident: &Ident) -> Option<Binding> {
+        self.bindings.get(&ident.span().start()).copied()
+    }
+
+    /// The main function of the chunk.
+    pub fn main(&self) -> &Function {
+        &self.main
+    }
+
+    /// The function with the given body.
+    pub fn function(&self, body: &Block) -> Option<&Function> {
+        self.functions.get(&body.span().start())
+    }
+
+    /// The tree span and message of every assignment to a read-only variable.
+    pub fn errors(&self) -> &[(Span, String)] {
+        &self.errors
+    }
+}
+
+/// Resolves every variable of a chunk parsed with `interner`.
+pub fn resolve(root: &Root, interner: &TokenInterner) -> Resolution {
+    let mut resolver = Resolver {
+        interner,
+        functions: Vec::new(),
+        resolution: Resolution::default(),
+    };
+
+    resolver.open(None);
+    resolver.stmts(root.block());
+    resolver.resolution.main = resolver.close();
+    resolver.resolution
+}
+
+/// Reports the scope errors of a chunk: assignments to read-only variables
+/// and misplaced `goto` and `break` statements.
+pub(super) fn validate(
+    root: &SyntaxNode,
+    interner: &TokenInterner,
+    source: &str,
+) -> Vec<Diagnostic> {
+    let mut reports = goto::validate(root, source);
+    let root = match Root::cast(root) {
+        Some(root) => root,
+        None => return reports,
+    };
+
+    let map = SourceMap::new(source);
+    for (span, message) in resolve(&root, interner).errors {
+        reports.push(Diagnostic::new(map.span(span), message));
+    }
+
+    reports
+}
+
+struct Scope<'i> {
+    function: Function,
+    // The locals in scope, innermost last.
+    active: Vec<(&'i str, u32)>,
+    // Whether each upvalue refers to a read-only local.
+    readonly: Vec<bool>,
+}
+
+struct Resolver<'i> {
+    interner: &'i TokenInterner,
+    functions: Vec<Scope<'i>>,
+    resolution: Resolution,
+}
+
+impl<'i> Resolver<'i> {
+    fn scope(&mut self) -> &mut Scope<'i> {
+        self.functions.last_mut().unwrap()
+    }
+
+    fn open(&mut self, method: Option<&'i str>) {
+        self.functions.push(Scope {
+            function: Function::default(),
+            active: Vec::new(),
+            readonly: Vec::new(),
+        });
+
+        if let Some(name) = method {
+            self.declare_name(name, None);
+        }
+    }
+
+    fn close(&mut self) -> Function {
+        self.functions.pop().unwrap().function
+    }
+
+    fn name(&self, ident: &Ident) -> Option<&'i str> {
+        ident.name(self.interner)
+    }
+
+    fn declare_name(&mut self, name: &'i str, attrib: Option<Attrib>) -> u32 {
+        let scope = self.scope();
+        let slot = scope.function.locals.len() as u32;
+        scope.function.locals.push(Local {
+            name: name.to_owned(),
+            attrib,
+            captured: false,
+        });
+        scope.active.push((name, slot));
+        slot
+    }
+
+    fn declare(&mut self, ident: &Ident, attrib: Option<Attrib>) {
+        if let Some(name) = self.name(ident) {
+            let slot = self.declare_name(name, attrib);
+            self.bind(ident, Binding::Local(slot));
+        }
+    }
+
+    fn bind(&mut self, ident: &Ident, binding: Binding) {
+        self.resolution
+            .bindings
+            .insert(ident.span().start(), binding);
+    }
+
+    // Runs `f` in a block whose locals go out of scope at its end.
+    fn scoped(&mut self, f: impl FnOnce(&mut Self)) {
+        let depth = self.scope().active.len();
+        f(self);
+        self.scope().active.truncate(depth);
+    }
+
+    // Looks up a name in the function at `level`, capturing it from the
+    // enclosing functions if needed. Also returns whether the variable is
+    // read-only.
+    fn find(&mut self, level: usize, name: &str) -> (Binding, bool) {
+        let scope = &self.functions[level];
+        if let Some(&(_, slot)) = scope.active.iter().rev().find(|(local, _)| *local == name) {
+            let readonly = scope.function.locals[slot as usize].attrib.is_some();
+            return (Binding::Local(slot), readonly);
+        }
+
+        let upvalues = &scope.function.upvalues;
+        if let Some(index) = upvalues.iter().position(|upvalue| upvalue.name == name) {
+            return (Binding::Upvalue(index as u16), scope.readonly[index]);
+        }
+
+        if level == 0 {
+            return (Binding::Global, false);
+        }
+
+        let (capture, readonly) = match self.find(level - 1, name) {
+            (Binding::Local(slot), readonly) => {
+                let parent = &mut self.functions[level - 1].function;
+                parent.locals[slot as usize].captured = true;
+                (Capture::Local(slot), readonly)
+            },
+            (Binding::Upvalue(index), readonly) => (Capture::Upvalue(index), readonly),
+            (Binding::Global, _) => return (Binding::Global, false),
+        };
+
+        let scope = &mut self.functions[level];
+        scope.function.upvalues.push(Upvalue {
+            name: name.to_owned(),
+            capture,
+        });
+        scope.readonly.push(readonly);
+        (
+            Binding::Upvalue(scope.function.upvalues.len() as u16 - 1),
+            readonly,
+        )
+    }
+
+    fn reference(&mut self, ident: &Ident) {
+        if let Some(name) = self.name(ident) {
+            let (binding, _) = self.find(self.functions.len() - 1, name);
+            self.bind(ident, binding);
+        }
+    }
+
+    fn assign(&mut self, ident: &Ident) {
+        if let Some(name) = self.name(ident) {
+            let (binding, readonly) = self.find(self.functions.len() - 1, name);
+            if readonly {
+                let message = format!("attempt to assign to const variable '{}'", name);
+                self.resolution.errors.push((ident.span(), message));
+            }
+
+            self.bind(ident, binding);
+        }
+    }
+
+    fn stmts(&mut self, stmts: impl Iterator<Item = Stmt>) {
+        for stmt in stmts {
+            self.stmt(&stmt);
+        }
+    }
+
+    fn block(&mut self, stmts: impl Iterator<Item = Stmt>) {
+        self.scoped(|resolver| resolver.stmts(stmts));
+    }
+
+    fn stmt(&mut self, stmt: &Stmt) {
+        match stmt {
+            Stmt::Decl(decl) => self.decl(decl),
+            Stmt::Assign(assign) => {
+                for value in assign.values().into_iter().flatten() {
+                    self.expr(&value);
+                }
+
+                for target in assign.targets().into_iter().flatten() {
+                    match target {
+                        Expr::Ident(ident) => self.assign(&ident),
+                        target => self.expr(&target),
+                    }
+                }
+            },
+            Stmt::Func(func) => self.func_stmt(func),
+            Stmt::Expr(expr) => self.expr(expr),
+            Stmt::Break(_) | Stmt::Goto(_) | Stmt::Label(_) => (),
+            Stmt::Return(stmt) =>
+                for expr in stmt.exprs().into_iter().flatten() {
+                    self.expr(&expr);
+                },
+            Stmt::Do(stmt) => self.block(stmt.stmts()),
+            Stmt::While(stmt) => {
+                self.opt_expr(stmt.cond());
+                if let Some(body) = stmt.block() {
+                    self.block(body);
+                }
+            },
+            Stmt::Repeat(stmt) => self.scoped(|resolver| {
+                // The condition is in the scope of the locals of the body.
+                if let Some(body) = stmt.block() {
+                    resolver.stmts(body);
+                }
+
+                resolver.opt_expr(stmt.cond());
+            }),
+            Stmt::If(stmt) => self.r#if(stmt),
+            Stmt::ForNum(stmt) => self.for_num(stmt),
+            Stmt::ForGen(stmt) => self.for_gen(stmt),
+        }
+    }
+
+    fn decl(&mut self, decl: &Decl) {
+        if let Some(func) = decl.function() {
+            // The local is in scope within its own function.
+            if let Some(Expr::Ident(ident)) = func.target() {
+                self.declare(&ident, None);
+            }
+
+            self.function(&func_parts(&func), None);
+            return;
+        }
+
+        for value in decl.values().into_iter().flatten() {
+            self.expr(&value);
+        }
+
+        for target in decl.targets() {
+            let attrib = target.modifier().map(|modifier| match modifier {
+                DeclModifier::Const => Attrib::Const,
+                DeclModifier::Close => Attrib::Close,
+            });
+
+            if let Some(ident) = target.name() {
+                self.declare(&ident, attrib);
+            }
+        }
+    }
+
+    fn func_stmt(&mut self, func: &Func) {
+        let mut method = None;
+        match func.target() {
+            Some(Expr::Ident(ident)) => self.assign(&ident),
+            Some(Expr::BinaryOp(op)) if matches!(op.op(), Some(BinaryOperator::Method)) => {
+                self.opt_expr(op.lhs());
+                method = Some("self");
+            },
+            target => self.opt_expr(target),
+        }
+
+        self.function(&func_parts(func), method);
+    }
+
+    fn function(&mut self, (args, body): &(Vec<Ident>, Option<Block>), method: Option<&'i str>) {
+        self.open(method);
+        for arg in args {
+            self.declare(arg, None);
+        }
+
+        if let Some(body) = body {
+            self.stmts(body.stmts());
+        }
+
+        let function = self.close();
+        if let Some(body) = body {
+            self.resolution
+                .functions
+                .insert(body.span().start(), function);
+        }
+    }
+
+    fn r#if(&mut self, stmt: &If) {
+        self.opt_expr(stmt.cond());
+        if let Some(body) = stmt.stmts() {
+            self.block(body);
+        }
+
+        if let Some(chain) = stmt.else_chain() {
+            if let Some(elseif) = chain.elseif_block() {
+                self.r#if(&elseif);
+            } else if let Some(body) = chain.else_block() {
+                self.block(body);
+            }
+        }
+    }
+
+    fn for_num(&mut self, stmt: &ForNum) {
+        let counter = stmt.counter();
+        if let Some((_, init)) = &counter {
+            self.expr(init);
+        }
+
+        self.opt_expr(stmt.end());
+        self.opt_expr(stmt.step());
+        self.scoped(|resolver| {
+            if let Some((ident, _)) = &counter {
+                resolver.declare(ident, None);
+            }
+
+            if let Some(body) = stmt.block() {
+                resolver.stmts(body);
+            }
+        });
+    }
+
+    fn for_gen(&mut self, stmt: &ForGen) {
+        for value in stmt.values().into_iter().flatten() {
+            self.expr(&value);
+        }
+
+        self.scoped(|resolver| {
+            for target in stmt.targets().into_iter().flatten() {
+                resolver.declare(&target, None);
+            }
+
+            if let Some(body) = stmt.block() {
+                resolver.stmts(body);
+            }
+        });
+    }
+
+    fn opt_expr(&mut self, expr: Option<Expr>) {
+        if let Some(expr) = expr {
+            self.expr(&expr);
+        }
+    }
+
+    fn expr(&mut self, expr: &Expr) {
+        match expr {
+            Expr::Ident(ident) => self.reference(ident),
+            Expr::Literal(_) | Expr::VarArg(_) => (),
+            Expr::Func(func) => {
+                let args = func.args().into_iter().flatten().collect();
+                self.function(&(args, func.block()), None);
+            },
+            Expr::Table(table) =>
+                for entry in table.entries() {
+                    match entry {
+                        TableEntry::Array(entry) => self.opt_expr(entry.value()),
+                        // The field is a name, not a variable.
+                        TableEntry::Map(entry) => self.opt_expr(entry.value()),
+                        TableEntry::Generic(entry) => {
+                            self.opt_expr(entry.index());
+                            self.opt_expr(entry.value());
+                        },
+                    }
+                },
+            Expr::PrefixOp(op) => self.opt_expr(op.rhs()),
+            Expr::BinaryOp(op) => {
+                self.opt_expr(op.lhs());
+                // The right hand side of `.` and `:` is a field name.
+                if !matches!(
+                    op.op(),
+                    Some(BinaryOperator::Property | BinaryOperator::Method)
+                ) {
+                    self.opt_expr(op.rhs());
+                }
+            },
+            Expr::FuncCall(call) => {
+                self.opt_expr(call.target());
+                for arg in call.args().into_iter().flatten() {
+                    self.expr(&arg);
+                }
+            },
+            Expr::Index(index) => {
+                self.opt_expr(index.target());
+                self.opt_expr(index.index());
+            },
+            Expr::Paren(paren) => self.opt_expr(paren.inner()),
+        }
+    }
+}
+
+fn func_parts(func: &Func) -> (Vec<Ident>, Option<Block>) {
+    (func.args().into_iter().flatten().collect(), func.block())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{resolve, Binding, Capture, Resolution};
+    use crate::parser::{
+        machinery::cstree::NodeCache,
+        parse,
+        syntax::{Ident, Root},
+    };
+
+    fn resolved(source: &str) -> (Resolution, Vec<(String, Option<Binding>)>) {
+        let mut cache = NodeCache::new();
+        let (root, reports) = parse(&mut cache, source);
+        let resolution = resolve(&Root::cast(&root).unwrap(), cache.interner());
+        let idents = root
+            .descendants()
+            .filter_map(Ident::cast)
+            .map(|ident| {
+                let name = ident.name(cache.interner()).unwrap().to_owned();
+                (name, resolution.binding(&ident))
+            })
+            .collect();
+
+        assert!(reports.is_empty());
+        (resolution, idents)
+    }
+
+    fn errors(source: &str) -> Vec<String> {
+        let mut cache = NodeCache::new();
+        let (root, _) = parse(&mut cache, source);
+        let resolution = resolve(&Root::cast(&root).unwrap(), cache.interner());
+        let errors = resolution.errors();
+        errors.iter().map(|(_, message)| message.clone()).collect()
+    }
+
+    #[test]
+    fn classify_idents() {
+        let source = "
+            local a, b = 1, x
+            function f(c) return a + c, t.y, { z = b } end
+            local function g() return g, self end
+            function t:m() return self end
+        ";
+        let (resolution, idents) = resolved(source);
+
+        let names: Vec<_> = idents
+            .iter()
+            .map(|(name, binding)| format!("{} {:?}", name, binding))
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "a Some(Local(0))",
+                "b Some(Local(1))",
+                "x Some(Global)",
+                "f Some(Global)",
+                "c Some(Local(0))",
+                "a Some(Upvalue(0))",
+                "c Some(Local(0))",
+                "t Some(Global)",
+                "y None",
+                "z None",
+                "b Some(Upvalue(1))",
+                "g Some(Local(2))",
+                "g Some(Upvalue(0))",
+                "self Some(Global)",
+                "t Some(Global)",
+                "m None",
+                "self Some(Local(0))",
+            ]
+        );
+
+        let main = resolution.main();
+        let captured: Vec<_> = main.locals.iter().map(|local| local.captured).collect();
+        assert_eq!(captured, [true, true, true]);
+    }
+
+    #[test]
+    fn capture_through_functions() {
+        let source = "
+            local a, b = 1, 2
+            local function f()
+                local c = b
+                return function() return a, c end
+            end
+        ";
+        let mut cache = NodeCache::new();
+        let (root, _) = parse(&mut cache, source);
+        let root = Root::cast(&root).unwrap();
+        let resolution = resolve(&root, cache.interner());
+
+        let main = resolution.main();
+        let captured: Vec<_> = main.locals.iter().map(|local| local.captured).collect();
+        assert_eq!(captured, [true, true, false]);
+
+        let mut functions: Vec<_> = resolution.functions.values().collect();
+        functions.sort_by_key(|function| function.locals.len());
+        let (inner, outer) = (functions[0], functions[1]);
+
+        let captures: Vec<_> = outer.upvalues.iter().map(|up| up.capture).collect();
+        assert_eq!(captures, [Capture::Local(1), Capture::Local(0)]);
+        assert!(outer.locals[0].captured);
+
+        let captures: Vec<_> = inner.upvalues.iter().map(|up| up.capture).collect();
+        assert_eq!(captures, [Capture::Upvalue(1), Capture::Local(0)]);
+    }
+
+    #[test]
+    fn report_readonly_assignments() {
+        assert!(errors("local x <const> = 1 local x = 2 x = 3").is_empty());
+        assert!(errors("local x <const> = 1 do local x = 2 x = 3 end").is_empty());
+        assert!(errors("local t <const> = {} t.x = 1").is_empty());
+
+        assert_eq!(
+            errors("local x <const> = 1 x = 2"),
+            ["attempt to assign to const variable 'x'"]
+        );
+        assert_eq!(
+            errors("local x <close> = nil function x() end"),
+            ["attempt to assign to const variable 'x'"]
+        );
+        assert_eq!(
+            errors("local x <const> = 1 local function f() return function() x = 2 end end"),
+            ["attempt to assign to const variable 'x'"]
+        );
+        assert_eq!(
+            errors("local a, b <const> = 1, 2 a, b = b, a"),
+            ["attempt to assign to const variable 'b'"]
+        );
+    }
+
+    #[test]
+    fn report_through_parse() {
+        let mut cache = NodeCache::new();
+        let (_, reports) = parse(&mut cache, "local x <const> = 1\nx = 2");
+        assert_eq!(reports.len(), 1);
+
+        let (_, reports) = parse(&mut cache, "goto nowhere");
+        assert_eq!(reports.len(), 1);
+    }
+}
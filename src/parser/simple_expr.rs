@@ -1,4 +1,7 @@
-use super::{machinery::marker::CompletedMarker, Parser};
+use super::{
+    machinery::{classifiers::token_is_call_args_start, marker::CompletedMarker},
+    Parser,
+};
 use crate::T;
 
 impl<'cache, 'source> Parser<'cache, 'source> {
@@ -12,7 +15,7 @@ impl<'cache, 'source> Parser<'cache, 'source> {
         loop {
             let t = self.at();
 
-            if t == T!['('] && allow_call {
+            if token_is_call_args_start(t) && allow_call {
                 let n = lhs.precede(self, T![func_call]);
                 let _rhs = self.r_func_call_args()?;
                 lhs = n.complete(self);
@@ -12,6 +12,8 @@ const STATEMENT_RECOVERY: &[SyntaxKind] = &[
     T![for],
     T![return],
     T![break],
+    T![goto],
+    T![::],
     T![function],
     T![local],
 ];
@@ -26,6 +28,8 @@ impl<'cache, 'source> Parser<'cache, 'source> {
             T![for] => self.r_for(),
             T![return] => self.r_return(),
             T![break] => self.r_break(),
+            T![goto] => self.r_goto(),
+            T![::] => self.r_label(),
             T![function] => self.r_func(false),
             T![local] => self.r_decl(),
             T![ident] | T!['('] => self.r_maybe_assign(),
@@ -35,13 +39,8 @@ impl<'cache, 'source> Parser<'cache, 'source> {
                 let span = self.error_eat_until(STATEMENT_RECOVERY);
                 let source = self.source(span);
                 let error = self
-                    .new_error()
-                    .with_message("expected a statement")
-                    .with_label(
-                        self.new_label()
-                            .with_message(format!("expected a statement but got \"{}\"", source,)),
-                    )
-                    .finish();
+                    .new_error("expected a statement")
+                    .with_label(format!("expected a statement but got \"{}\"", source));
 
                 self.report(error);
                 None
@@ -1,9 +1,11 @@
 use crate::{
     parser::machinery::{
-        classifiers::token_is_literal,
         cstree,
         cstree::interning::TokenInterner,
+        diagnostic::Diagnostic,
         kind::SyntaxKind,
+        literal::{self, LiteralValue},
+        span::Span,
     },
     T,
 };
@@ -36,16 +38,22 @@ pub type SyntaxElement = cstree::NodeOrToken<SyntaxNode, SyntaxToken>;
 
 macro_rules! ast_node {
     ($name:ident, $kind:expr) => {
-        #[derive(PartialEq, Eq, Hash)]
+        #[derive(Clone, PartialEq, Eq, Hash)]
         pub struct $name(SyntaxNode);
         impl $name {
-            fn cast(node: &SyntaxNode) -> Option<Self> {
+            pub fn cast(node: &SyntaxNode) -> Option<Self> {
                 if node.kind() == $kind {
                     Some(Self(node.clone()))
                 } else {
                     None
                 }
             }
+
+            /// The span of the node within the syntax tree.
+            pub fn span(&self) -> Span {
+                let range = self.0.text_range();
+                Span::new(range.start().into(), range.end().into())
+            }
         }
     };
 }
@@ -53,7 +61,7 @@ macro_rules! ast_node {
 ast_node!(Root, T![root]);
 
 impl Root {
-    pub fn block(&self) -> impl Iterator<Item = Stmt> + '_ {
+    pub fn block(&self) -> impl Iterator<Item = Stmt> + Clone + '_ {
         self.0.children().filter_map(Stmt::cast)
     }
 }
@@ -64,6 +72,8 @@ pub enum Stmt {
     Func(Func),
     Expr(Expr),
     Break(Break),
+    Goto(Goto),
+    Label(Label),
     Return(Return),
     Do(Do),
     While(While),
@@ -80,6 +90,8 @@ impl Stmt {
             T![assign_stmt] => Assign::cast(node).map(Self::Assign)?,
             T![func_stmt] => Func::cast(node).map(Self::Func)?,
             T![break_stmt] => Break::cast(node).map(Self::Break)?,
+            T![goto_stmt] => Goto::cast(node).map(Self::Goto)?,
+            T![label_stmt] => Label::cast(node).map(Self::Label)?,
             T![return_stmt] => Return::cast(node).map(Self::Return)?,
             T![do_stmt] => Do::cast(node).map(Self::Do)?,
             T![while_stmt] => While::cast(node).map(Self::While)?,
@@ -101,33 +113,63 @@ pub enum Expr {
     BinaryOp(BinaryOp),
     FuncCall(FuncCall),
     Index(Index),
-    VarArg,
+    Paren(Paren),
+    VarArg(VarArg),
 }
 
 impl Expr {
     fn cast(node: &SyntaxNode) -> Option<Self> {
         Some(match node.kind() {
             T![ident] => Ident::cast(node).map(Self::Ident)?,
-            T![vararg_expr] => Self::VarArg,
+            T![vararg_expr] => VarArg::cast(node).map(Self::VarArg)?,
             T![func_expr] => FuncExpr::cast(node).map(Self::Func)?,
             T![table_expr] => Table::cast(node).map(Self::Table)?,
             T![prefix_op] => PrefixOp::cast(node).map(Self::PrefixOp)?,
             T![bin_op] => BinaryOp::cast(node).map(Self::BinaryOp)?,
             T![func_call] => FuncCall::cast(node).map(Self::FuncCall)?,
             T![index] => Index::cast(node).map(Self::Index)?,
-            T![expr] => node.first_child().and_then(Expr::cast)?,
-            t if token_is_literal(t) => Literal::cast(node).map(Self::Literal)?,
+            T![expr] => Paren::cast(node).map(Self::Paren)?,
+            T![literal_expr] => Literal::cast(node).map(Self::Literal)?,
             _ => return None,
         })
     }
+
+    pub fn span(&self) -> Span {
+        match self {
+            Self::Ident(ident) => ident.span(),
+            Self::Literal(literal) => literal.span(),
+            Self::Func(func) => func.span(),
+            Self::Table(table) => table.span(),
+            Self::PrefixOp(prefix_op) => prefix_op.span(),
+            Self::BinaryOp(binary_op) => binary_op.span(),
+            Self::FuncCall(call) => call.span(),
+            Self::Index(index) => index.span(),
+            Self::Paren(paren) => paren.span(),
+            Self::VarArg(vararg) => vararg.span(),
+        }
+    }
 }
 
+ast_node!(VarArg, T![vararg_expr]);
+
 ast_node!(Decl, T![decl_stmt]);
 
 impl Decl {
     pub fn targets(&self) -> impl Iterator<Item = DeclTarget> + '_ {
         self.0.children().filter_map(DeclTarget::cast)
     }
+
+    pub fn values(&self) -> Option<impl Iterator<Item = Expr> + '_> {
+        let list = self
+            .0
+            .children()
+            .find(|node| node.kind() == T![expr_list])?;
+        Some(list.children().filter_map(Expr::cast))
+    }
+
+    pub fn function(&self) -> Option<Func> {
+        self.0.first_child().and_then(Func::cast)
+    }
 }
 
 ast_node!(DeclTarget, T![decl_target]);
@@ -163,8 +205,14 @@ impl DeclModifier {
 ast_node!(Literal, T![literal_expr]);
 
 impl Literal {
-    pub fn value(&self) {
-        panic!()
+    pub fn value(&self, interner: &TokenInterner) -> Result<LiteralValue, Diagnostic> {
+        let token = self.0.first_token().unwrap();
+        let text = token.resolve_text(interner);
+
+        literal::decode(token.kind(), text).map_err(|error| {
+            Diagnostic::new(self.span(), error.to_string())
+                .with_label(format!("malformed literal \"{}\"", text))
+        })
     }
 }
 
@@ -227,7 +275,10 @@ ast_node!(BinaryOp, T![bin_op]);
 
 impl BinaryOp {
     pub fn op(&self) -> Option<BinaryOperator> {
-        self.0.first_token().and_then(BinaryOperator::cast)
+        self.0
+            .children_with_tokens()
+            .find_map(|element| element.into_token())
+            .and_then(BinaryOperator::cast)
     }
 
     pub fn lhs(&self) -> Option<Expr> {
@@ -308,6 +359,14 @@ impl Index {
     }
 }
 
+ast_node!(Paren, T![expr]);
+
+impl Paren {
+    pub fn inner(&self) -> Option<Expr> {
+        self.0.first_child().and_then(Expr::cast)
+    }
+}
+
 ast_node!(FuncCall, T![func_call]);
 
 impl FuncCall {
@@ -331,8 +390,12 @@ impl Func {
         Some(self.0.children().nth(1)?.children().filter_map(Ident::cast))
     }
 
-    pub fn block(&self) -> Option<Stmt> {
-        self.0.last_child().and_then(Stmt::cast)
+    pub fn is_vararg(&self) -> bool {
+        self.0.children().nth(1).map_or(false, has_vararg)
+    }
+
+    pub fn block(&self) -> Option<Block> {
+        self.0.last_child().and_then(Block::cast)
     }
 }
 
@@ -343,8 +406,26 @@ impl FuncExpr {
         Some(self.0.first_child()?.children().filter_map(Ident::cast))
     }
 
-    pub fn block(&self) -> Option<Stmt> {
-        self.0.last_child().and_then(Stmt::cast)
+    pub fn is_vararg(&self) -> bool {
+        self.0.first_child().map_or(false, has_vararg)
+    }
+
+    pub fn block(&self) -> Option<Block> {
+        self.0.last_child().and_then(Block::cast)
+    }
+}
+
+fn has_vararg(args: &SyntaxNode) -> bool {
+    args.children_with_tokens()
+        .filter_map(|element| element.into_token())
+        .any(|token| token.kind() == T![...])
+}
+
+ast_node!(Block, T![stmt_list]);
+
+impl Block {
+    pub fn stmts(&self) -> impl Iterator<Item = Stmt> + Clone + '_ {
+        self.0.children().filter_map(Stmt::cast)
     }
 }
 
@@ -403,10 +484,34 @@ impl TableEntry {
             _ => panic!(),
         })
     }
+
+    pub fn span(&self) -> Span {
+        match self {
+            Self::Array(entry) => entry.span(),
+            Self::Map(entry) => entry.span(),
+            Self::Generic(entry) => entry.span(),
+        }
+    }
 }
 
 ast_node!(Break, T![break_stmt]);
 
+ast_node!(Goto, T![goto_stmt]);
+
+impl Goto {
+    pub fn label(&self) -> Option<Ident> {
+        self.0.first_child().and_then(Ident::cast)
+    }
+}
+
+ast_node!(Label, T![label_stmt]);
+
+impl Label {
+    pub fn name(&self) -> Option<Ident> {
+        self.0.first_child().and_then(Ident::cast)
+    }
+}
+
 ast_node!(Return, T![return_stmt]);
 
 impl Return {
@@ -418,8 +523,11 @@ impl Return {
 ast_node!(Do, T![do_stmt]);
 
 impl Do {
-    pub fn stmts(&self) -> impl Iterator<Item = Stmt> + '_ {
-        self.0.children().filter_map(Stmt::cast)
+    pub fn stmts(&self) -> impl Iterator<Item = Stmt> + Clone + '_ {
+        self.0
+            .first_child()
+            .into_iter()
+            .flat_map(|block| block.children().filter_map(Stmt::cast))
     }
 }
 
@@ -430,8 +538,10 @@ impl While {
         self.0.first_child().and_then(Expr::cast)
     }
 
-    pub fn block(&self) -> Option<impl Iterator<Item = Stmt> + '_> {
-        Some(self.0.last_child()?.children().filter_map(Stmt::cast))
+    pub fn block(&self) -> Option<impl Iterator<Item = Stmt> + Clone + '_> {
+        // The body is a `do` statement wrapping the statement list.
+        let body = self.0.last_child()?.first_child()?;
+        Some(body.children().filter_map(Stmt::cast))
     }
 }
 
@@ -442,7 +552,7 @@ impl Repeat {
         self.0.last_child().and_then(Expr::cast)
     }
 
-    pub fn block(&self) -> Option<impl Iterator<Item = Stmt> + '_> {
+    pub fn block(&self) -> Option<impl Iterator<Item = Stmt> + Clone + '_> {
         Some(self.0.first_child()?.children().filter_map(Stmt::cast))
     }
 }
@@ -451,7 +561,7 @@ ast_node!(If, T![if_stmt]);
 
 impl If {
     fn cast_else(node: &SyntaxNode) -> Option<Self> {
-        if node.kind() == T![elseif] {
+        if node.kind() == T![if_stmt] {
             Some(Self(node.clone()))
         } else {
             None
@@ -462,7 +572,7 @@ impl If {
         self.0.first_child().and_then(Expr::cast)
     }
 
-    pub fn stmts(&self) -> Option<impl Iterator<Item = Stmt> + '_> {
+    pub fn stmts(&self) -> Option<impl Iterator<Item = Stmt> + Clone + '_> {
         Some(self.0.children().nth(1)?.children().filter_map(Stmt::cast))
     }
 
@@ -474,7 +584,7 @@ impl If {
 ast_node!(ElseChain, T![else_chain]);
 
 impl ElseChain {
-    pub fn else_block(&self) -> Option<impl Iterator<Item = Stmt> + '_> {
+    pub fn else_block(&self) -> Option<impl Iterator<Item = Stmt> + Clone + '_> {
         let token = self.0.first_token()?;
 
         if token.kind() == T![else] {
@@ -517,8 +627,10 @@ impl ForNum {
         None
     }
 
-    pub fn block(&self) -> Option<impl Iterator<Item = Stmt> + '_> {
-        Some(self.0.last_child()?.children().filter_map(Stmt::cast))
+    pub fn block(&self) -> Option<impl Iterator<Item = Stmt> + Clone + '_> {
+        // The body is a `do` statement wrapping the statement list.
+        let body = self.0.last_child()?.first_child()?;
+        Some(body.children().filter_map(Stmt::cast))
     }
 }
 
@@ -533,7 +645,9 @@ impl ForGen {
         Some(self.0.children().nth(1)?.children().filter_map(Expr::cast))
     }
 
-    pub fn block(&self) -> Option<impl Iterator<Item = Stmt> + '_> {
-        Some(self.0.last_child()?.children().filter_map(Stmt::cast))
+    pub fn block(&self) -> Option<impl Iterator<Item = Stmt> + Clone + '_> {
+        // The body is a `do` statement wrapping the statement list.
+        let body = self.0.last_child()?.first_child()?;
+        Some(body.children().filter_map(Stmt::cast))
     }
 }
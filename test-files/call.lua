@@ -0,0 +1,9 @@
+require "mod"
+print [[long
+string]]
+node {
+    name = "root",
+    node { name = "leaf" },
+}
+obj:method "arg"
+local x = f "a" "b" { 1 }
@@ -0,0 +1,11 @@
+for i = 1, 10 do
+    if i % 2 == 0 then
+        goto continue
+    end
+    print(i)
+    ::continue::
+end
+
+::top::
+x = x + 1
+if x < 10 then goto top end